@@ -33,9 +33,22 @@
 //! A function `get_memory_images` is also provided to get every vm image from the configuration
 //! files.
 //!
+//! Configs using `extends` are merged with the configs they extend, see `src/vmm/overlay.rs`.
+//!
+//! When the `AXVISOR_COMPRESS_IMAGES` environment variable is set to `gzip`, the kernel and
+//! ramdisk images embedded for the `memory` image location are gzip-compressed into `OUT_DIR`
+//! first, the hypervisor decompresses them into guest memory at load time. Images that are
+//! already compressed and ELF kernels are embedded as they are. So are images with a
+//! `<image>_sha256` digest in the config, which is checked against the embedded bytes.
+//!
+//! With the `guest-archive` feature, the directory named by the `AXVISOR_GUEST_DIR` environment
+//! variable is packed into a newc cpio archive in `OUT_DIR`, returned by the generated
+//! `guest_archive` function and mounted read-only at `/guest` by `src/vmm/guestfs.rs`. Without
+//! the feature or the variable, `guest_archive` returns an empty archive.
+//!
 //! This build script reruns if the `AXVISOR_VM_CONFIGS` environment variable changes, or if the
-//! `build.rs` file changes, or if any of the files in the paths specified by `AXVISOR_VM_CONFIGS`
-//! change.
+//! `build.rs` file changes, or if any of the files in the paths specified by `AXVISOR_VM_CONFIGS`,
+//! or the configs they extend, change.
 use std::{
     env,
     ffi::OsString,
@@ -44,16 +57,25 @@ use std::{
     path::{Path, PathBuf},
 };
 
-use anyhow::Context;
+use anyhow::{Context, bail};
+use flate2::{Compression, write::GzEncoder};
 use quote::quote;
 use toml::Table;
 
+extern crate alloc;
+
+/// `extends` resolution, shared with the hypervisor for the configs read from the filesystem.
+#[path = "src/vmm/overlay.rs"]
+mod overlay;
+
 /// A configuration file that has been read from disk.
 struct ConfigFile {
     /// The path to the configuration file.
     pub path: OsString,
-    /// The contents of the configuration file.
+    /// The contents of the configuration file, with the configs it extends merged in.
     pub content: String,
+    /// The paths of the configs it extends.
+    pub bases: Vec<String>,
 }
 
 /// Gets the paths (colon-separated) from the `AXVISOR_VM_CONFIGS` environment variable.
@@ -78,7 +100,25 @@ fn get_configs() -> Result<Vec<ConfigFile>, String> {
                     let content = fs::read_to_string(&path_buf).map_err(|e| {
                         format!("Failed to read file {}: {}", path_buf.display(), e)
                     })?;
-                    Ok(ConfigFile { path, content })
+                    let resolved = overlay::resolve_content(
+                        &path_buf.display().to_string(),
+                        &content,
+                        &mut |file| {
+                            fs::read_to_string(file)
+                                .map_err(|e| format!("Failed to read file {file}: {e}"))
+                        },
+                    )?;
+                    // Configs without `extends` are kept as written, comments included.
+                    let content = if resolved.bases.is_empty() {
+                        content
+                    } else {
+                        toml::to_string(&resolved.table).map_err(|e| e.to_string())?
+                    };
+                    Ok(ConfigFile {
+                        path,
+                        content,
+                        bases: resolved.bases,
+                    })
                 })
                 .collect()
         })
@@ -117,6 +157,9 @@ struct MemoryImage {
     pub dtb: Option<PathBuf>,
     pub bios: Option<PathBuf>,
     pub ramdisk: Option<PathBuf>,
+    /// Whether the kernel and the ramdisk have a digest in the config.
+    pub kernel_digest: bool,
+    pub ramdisk_digest: bool,
 }
 
 fn parse_config_file(config_file: &ConfigFile) -> Option<MemoryImage> {
@@ -160,15 +203,62 @@ fn parse_config_file(config_file: &ConfigFile) -> Option<MemoryImage> {
         .and_then(|v| v.as_str())
         .map(|v| convert_to_absolute(&config_file.path, v));
 
+    let kernel_table = config.get("kernel")?.as_table()?;
+    let kernel_digest = kernel_table.contains_key("kernel_sha256");
+    let ramdisk_digest = kernel_table.contains_key("ramdisk_sha256");
+
     Some(MemoryImage {
         id,
         kernel,
         dtb,
         bios,
         ramdisk,
+        kernel_digest,
+        ramdisk_digest,
     })
 }
 
+/// Returns whether the embedded kernel and ramdisk images are to be gzip-compressed.
+fn compress_images() -> anyhow::Result<bool> {
+    match env::var("AXVISOR_COMPRESS_IMAGES").as_deref() {
+        Err(_) | Ok("") | Ok("none") => Ok(false),
+        Ok("gzip") => Ok(true),
+        Ok(other) => bail!("AXVISOR_COMPRESS_IMAGES={other} is not supported, use gzip or none"),
+    }
+}
+
+/// Returns the path of the file to embed for an image, compressing the image into `OUT_DIR` if
+/// `compress` is set.
+fn embedded_image(image: &Path, name: &str, id: usize, compress: bool) -> anyhow::Result<String> {
+    // gzip, zstd, LZ4 (legacy and frame) and ELF magic numbers.
+    const KEEP_AS_IS: &[&[u8]] = &[
+        b"\x1f\x8b",
+        b"\x28\xb5\x2f\xfd",
+        b"\x02\x21\x4c\x18",
+        b"\x04\x22\x4d\x18",
+        b"\x7fELF",
+    ];
+
+    let image = image
+        .canonicalize()
+        .with_context(|| format!("Path {} not found", image.display()))?;
+    if !compress {
+        return Ok(image.display().to_string());
+    }
+
+    let data = fs::read(&image).with_context(|| format!("Failed to read {}", image.display()))?;
+    println!("cargo:rerun-if-changed={}", image.display());
+    if KEEP_AS_IS.iter().any(|magic| data.starts_with(magic)) {
+        return Ok(image.display().to_string());
+    }
+
+    let output = PathBuf::from(env::var("OUT_DIR")?).join(format!("vm{id}-{name}.gz"));
+    let mut encoder = GzEncoder::new(fs::File::create(&output)?, Compression::best());
+    encoder.write_all(&data)?;
+    encoder.finish()?;
+    Ok(output.display().to_string())
+}
+
 /// Generate function to load guest images from config
 /// Toml file must be provided to load from memory.
 fn generate_guest_img_loading_functions(
@@ -176,16 +266,17 @@ fn generate_guest_img_loading_functions(
     config_files: Vec<ConfigFile>,
 ) -> anyhow::Result<()> {
     let mut memory_images = vec![];
+    let compress = compress_images()?;
 
     for config_file in config_files {
         if let Some(files) = parse_config_file(&config_file) {
             let id = files.id;
-            let kernel = files
-                .kernel
-                .canonicalize()
-                .with_context(|| format!("Path {} not found", files.kernel.display()))?
-                .display()
-                .to_string();
+            let kernel = embedded_image(
+                &files.kernel,
+                "kernel",
+                id,
+                compress && !files.kernel_digest,
+            )?;
             let dtb = match files.dtb {
                 Some(v) => {
                     let s = v.canonicalize().unwrap().display().to_string();
@@ -204,7 +295,7 @@ fn generate_guest_img_loading_functions(
 
             let ramdisk = match files.ramdisk {
                 Some(v) => {
-                    let s = v.canonicalize().unwrap().display().to_string();
+                    let s = embedded_image(&v, "ramdisk", id, compress && !files.ramdisk_digest)?;
                     quote! { Some(include_bytes!(#s)) }
                 }
                 None => quote! { None },
@@ -251,6 +342,121 @@ fn generate_guest_img_loading_functions(
     Ok(())
 }
 
+/// Packs the directory named by `AXVISOR_GUEST_DIR` into `$(OUT_DIR)/guest.cpio` and generates
+/// the `guest_archive` function returning it.
+fn generate_guest_archive(out_file: &mut fs::File) -> anyhow::Result<()> {
+    println!("cargo:rerun-if-env-changed=AXVISOR_GUEST_DIR");
+    let dir = env::var_os("AXVISOR_GUEST_DIR");
+    let enabled = env::var_os("CARGO_FEATURE_GUEST_ARCHIVE").is_some();
+    let archive = match dir {
+        Some(dir) if enabled => {
+            let dir = PathBuf::from(dir);
+            println!("cargo:rerun-if-changed={}", dir.display());
+            let mut archive = Vec::new();
+            let mut ino = 0;
+            append_cpio_dir(&mut archive, &dir, "", &mut ino)
+                .with_context(|| format!("failed to pack {}", dir.display()))?;
+            append_cpio_entry(&mut archive, "TRAILER!!!", 0, 0, &[]);
+
+            let output = PathBuf::from(env::var("OUT_DIR")?).join("guest.cpio");
+            fs::write(&output, &archive)?;
+            Some(output)
+        }
+        Some(_) => {
+            println!(
+                "cargo:warning=AXVISOR_GUEST_DIR is ignored without the guest-archive feature"
+            );
+            None
+        }
+        None => {
+            if enabled {
+                println!(
+                    "cargo:warning=the guest-archive feature is enabled but AXVISOR_GUEST_DIR is \
+                     not set, nothing is mounted at /guest"
+                );
+            }
+            None
+        }
+    };
+
+    let body = match archive {
+        Some(path) => {
+            let path = path.display().to_string();
+            quote! { include_bytes!(#path) }
+        }
+        None => quote! { &[] },
+    };
+    let output = quote! {
+        pub fn guest_archive() -> &'static [u8] {
+            #body
+        }
+    };
+    let syntax_tree = syn::parse2(output).unwrap();
+    writeln!(out_file, "{}", prettyplease::unparse(&syntax_tree))?;
+    Ok(())
+}
+
+/// Appends the entries of `dir` to a cpio archive, in name order so that builds are reproducible.
+/// Symbolic links are followed, files that are neither regular files nor directories are skipped.
+fn append_cpio_dir(
+    archive: &mut Vec<u8>,
+    dir: &Path,
+    prefix: &str,
+    ino: &mut u32,
+) -> anyhow::Result<()> {
+    let mut entries = fs::read_dir(dir)?.collect::<Result<Vec<_>, _>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+    for entry in entries {
+        let name = entry.file_name();
+        let Some(name) = name.to_str() else {
+            bail!("{}: not a UTF-8 file name", entry.path().display());
+        };
+        let name = format!("{prefix}{name}");
+        let metadata = fs::metadata(entry.path())?;
+        *ino += 1;
+        if metadata.is_dir() {
+            append_cpio_entry(archive, &name, *ino, 0o040_555, &[]);
+            append_cpio_dir(archive, &entry.path(), &format!("{name}/"), ino)?;
+        } else if metadata.is_file() {
+            let data = fs::read(entry.path())?;
+            append_cpio_entry(archive, &name, *ino, 0o100_444, &data);
+        }
+    }
+    Ok(())
+}
+
+/// Appends an entry to a newc cpio archive.
+fn append_cpio_entry(archive: &mut Vec<u8>, name: &str, ino: u32, mode: u32, data: &[u8]) {
+    let align = |archive: &mut Vec<u8>| archive.resize(archive.len().next_multiple_of(4), 0);
+    let name_size = name.len() as u32 + 1;
+    // ino, mode, uid, gid, nlink, mtime, filesize, devmajor, devminor, rdevmajor, rdevminor,
+    // namesize, check
+    let fields = [
+        ino,
+        mode,
+        0,
+        0,
+        1,
+        0,
+        data.len() as u32,
+        0,
+        0,
+        0,
+        0,
+        name_size,
+        0,
+    ];
+    archive.extend_from_slice(b"070701");
+    for field in fields {
+        archive.extend_from_slice(format!("{field:08x}").as_bytes());
+    }
+    archive.extend_from_slice(name.as_bytes());
+    archive.push(0);
+    align(archive);
+    archive.extend_from_slice(data);
+    align(archive);
+}
+
 fn main() -> anyhow::Result<()> {
     let arch = std::env::var("CARGO_CFG_TARGET_ARCH").unwrap();
 
@@ -270,8 +476,11 @@ fn main() -> anyhow::Result<()> {
     let mut output_file = open_output_file();
 
     println!("cargo:rerun-if-env-changed=AXVISOR_VM_CONFIGS");
+    println!("cargo:rerun-if-env-changed=AXVISOR_COMPRESS_IMAGES");
     println!("cargo:rerun-if-changed=build.rs");
 
+    generate_guest_archive(&mut output_file)?;
+
     writeln!(
         output_file,
         "pub fn static_vm_configs() -> Vec<&'static str> {{"
@@ -289,6 +498,9 @@ fn main() -> anyhow::Result<()> {
                         "cargo:rerun-if-changed={}",
                         PathBuf::from(config_file.path.clone()).display()
                     );
+                    for base in &config_file.bases {
+                        println!("cargo:rerun-if-changed={base}");
+                    }
                 }
                 writeln!(output_file, "    ]")?;
             }
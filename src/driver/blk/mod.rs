@@ -12,9 +12,19 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+//! Block device drivers, and raw reads from the registered block devices.
+//!
+//! The raw reads load guest images from block devices without a filesystem, see
+//! `vmm::images::block`. They take the command queue of a device, so a device used by the
+//! filesystem cannot be read this way.
+
+use alloc::{collections::BTreeMap, format, string::String};
 #[cfg(any(feature = "sdmmc", feature = "phytium-blk"))]
 use core::ptr::NonNull;
 
+use rd_block::{Block, CmdQueue};
+use spin::Mutex;
+
 #[cfg(any(feature = "sdmmc", feature = "phytium-blk"))]
 use rdif_block::Interface;
 
@@ -102,3 +112,72 @@ impl PlatformDeviceBlock for PlatformDevice {
         self.register(rd_block::Block::new(dev, &DmaImpl));
     }
 }
+
+/// Maximum number of blocks read by one request.
+const MAX_BLOCKS_PER_READ: usize = 128;
+
+/// Command queues of the block devices opened for raw reads, by device index.
+static RAW_QUEUES: Mutex<BTreeMap<usize, CmdQueue>> = Mutex::new(BTreeMap::new());
+
+/// Returns the number of registered block devices, which are indexed in registration order.
+pub fn device_count() -> usize {
+    rdrive::get_list::<Block>().len()
+}
+
+/// Calls `f` with the command queue of the block device `index`, created on first use.
+fn with_queue<R>(index: usize, f: impl FnOnce(&mut CmdQueue) -> R) -> Result<R, String> {
+    let mut queues = RAW_QUEUES.lock();
+    if !queues.contains_key(&index) {
+        let device = rdrive::get_list::<Block>()
+            .into_iter()
+            .nth(index)
+            .ok_or_else(|| format!("no block device blk{index}"))?;
+        let queue = device
+            .lock()
+            .map_err(|err| format!("blk{index}: {err:?}"))?
+            .create_queue()
+            .ok_or_else(|| format!("blk{index} is in use, e.g. by the filesystem"))?;
+        queues.insert(index, queue);
+    }
+    Ok(f(queues.get_mut(&index).unwrap()))
+}
+
+/// Returns the size in bytes of the block device `index` and of its blocks.
+pub fn device_size(index: usize) -> Result<(u64, usize), String> {
+    with_queue(index, |queue| {
+        (
+            queue.num_blocks() as u64 * queue.block_size() as u64,
+            queue.block_size(),
+        )
+    })
+}
+
+/// Reads `buf.len()` bytes at byte `offset` of the block device `index`.
+pub fn read_at(index: usize, offset: u64, buf: &mut [u8]) -> Result<(), String> {
+    with_queue(index, |queue| {
+        let block_size = queue.block_size();
+        let end = offset + buf.len() as u64;
+        if end > queue.num_blocks() as u64 * block_size as u64 {
+            return Err(format!("blk{index}: read beyond the end of the device"));
+        }
+
+        let mut block = (offset / block_size as u64) as usize;
+        // Offset of the next byte to read in the next block read.
+        let mut skip = (offset % block_size as u64) as usize;
+        let mut filled = 0;
+        while filled < buf.len() {
+            let count = (skip + buf.len() - filled)
+                .div_ceil(block_size)
+                .min(MAX_BLOCKS_PER_READ);
+            for data in queue.read_blocks_blocking(block, count) {
+                let data = data.map_err(|err| format!("blk{index}: {err:?}"))?;
+                let len = (data.len() - skip).min(buf.len() - filled);
+                buf[filled..filled + len].copy_from_slice(&data[skip..skip + len]);
+                filled += len;
+                skip = 0;
+            }
+            block += count;
+        }
+        Ok(())
+    })?
+}
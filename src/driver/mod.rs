@@ -20,7 +20,7 @@ use core::ptr::NonNull;
 
 use rdrive::probe::OnProbeError;
 
-mod blk;
+pub mod blk;
 mod soc;
 // mod serial;
 
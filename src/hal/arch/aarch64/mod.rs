@@ -121,6 +121,43 @@ pub fn inject_interrupt_gic_v3(vector: usize) {
     debug!("Virtual interrupt {vector} injected successfully in LR{free_lr}");
 }
 
+/// Returns a random number from the `RNDR` register, if the CPU implements FEAT_RNG and the
+/// entropy source could provide one.
+pub fn hw_random_u64() -> Option<u64> {
+    let isar0: u64;
+    // SAFETY: ID registers are always readable at EL2.
+    unsafe { core::arch::asm!("mrs {}, id_aa64isar0_el1", out(reg) isar0) };
+    if (isar0 >> 60) & 0xf == 0 {
+        return None;
+    }
+
+    let (value, nzcv): (u64, u64);
+    // SAFETY: `RNDR` is implemented as checked above. It sets `PSTATE.Z` on failure.
+    unsafe {
+        core::arch::asm!(
+            "mrs {value}, s3_3_c2_c4_0",
+            "mrs {nzcv}, nzcv",
+            value = out(reg) value,
+            nzcv = out(reg) nzcv,
+        )
+    };
+    (nzcv & (1 << 30) == 0).then_some(value)
+}
+
+/// Returns whether the CPU supports stage-1 translation with pages of `size` bytes, the page size
+/// a guest kernel is built for.
+pub fn page_size_supported(size: usize) -> bool {
+    let mmfr0: u64;
+    // SAFETY: ID registers are always readable at EL2.
+    unsafe { core::arch::asm!("mrs {}, id_aa64mmfr0_el1", out(reg) mmfr0) };
+    match size {
+        0x1000 => (mmfr0 >> 28) & 0xf != 0xf,   // TGran4
+        0x4000 => (mmfr0 >> 20) & 0xf != 0,     // TGran16
+        0x1_0000 => (mmfr0 >> 24) & 0xf != 0xf, // TGran64
+        _ => false,
+    }
+}
+
 pub fn hardware_check() {
     let pa_bits = match ID_AA64MMFR0_EL1.read_as_enum(ID_AA64MMFR0_EL1::PARange) {
         Some(ID_AA64MMFR0_EL1::PARange::Value::Bits_32) => 32,
@@ -15,4 +15,26 @@
 pub mod cache;
 
 pub fn hardware_check() {}
+
+/// Returns a random number from `RDRAND`, if the CPU supports it.
+pub fn hw_random_u64() -> Option<u64> {
+    use core::arch::x86_64::{__cpuid, _rdrand64_step};
+
+    #[target_feature(enable = "rdrand")]
+    unsafe fn rdrand() -> Option<u64> {
+        let mut value = 0;
+        // Intel recommends retrying a few times on transient failures.
+        for _ in 0..10 {
+            if unsafe { _rdrand64_step(&mut value) } == 1 {
+                return Some(value);
+            }
+        }
+        None
+    }
+
+    // SAFETY: `cpuid` is always available on x86_64.
+    let has_rdrand = unsafe { __cpuid(1) }.ecx & (1 << 30) != 0;
+    // SAFETY: RDRAND support is checked above.
+    has_rdrand.then(|| unsafe { rdrand() }).flatten()
+}
 pub fn inject_interrupt(_vector: u8) {}
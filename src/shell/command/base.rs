@@ -14,15 +14,17 @@
 
 use std::collections::BTreeMap;
 #[cfg(feature = "fs")]
-use std::fs::{self, File, FileType};
-#[cfg(feature = "fs")]
+use std::fs::{self, File};
+#[cfg(any(feature = "fs", feature = "guest-archive"))]
 use std::io::{self, Read, Write};
 use std::println;
 use std::string::{String, ToString};
 
 use crate::shell::command::{CommandNode, FlagDef, ParsedCommand};
+#[cfg(any(feature = "fs", feature = "guest-archive"))]
+use crate::vmm::guestfs;
 
-#[cfg(feature = "fs")]
+#[cfg(any(feature = "fs", feature = "guest-archive"))]
 macro_rules! print_err {
     ($cmd: literal, $msg: expr) => {
         println!("{}: {}", $cmd, $msg);
@@ -44,21 +46,18 @@ fn split_whitespace(s: &str) -> (&str, &str) {
     }
 }
 
-#[cfg(feature = "fs")]
+#[cfg(any(feature = "fs", feature = "guest-archive"))]
 fn do_ls(cmd: &ParsedCommand) {
     let args = &cmd.positional_args;
     let show_long = cmd.flags.get("long").unwrap_or(&false);
     let show_all = cmd.flags.get("all").unwrap_or(&false);
 
-    let _current_dir = std::env::current_dir().unwrap();
-
     fn show_entry_info(path: &str, entry: &str, show_long: bool) -> io::Result<()> {
         if show_long {
-            let metadata = fs::metadata(path)?;
-            let size = metadata.len();
-            let file_type = metadata.file_type();
-            let file_type_char = file_type_to_char(file_type);
-            let rwx = file_perm_to_rwx(metadata.permissions().mode());
+            let metadata = guestfs::metadata(path)?;
+            let size = metadata.size();
+            let file_type_char = file_type_to_char(metadata.mode());
+            let rwx = file_perm_to_rwx(metadata.mode());
             let rwx = unsafe { core::str::from_utf8_unchecked(&rwx) };
             println!("{}{} {:>8} {}", file_type_char, rwx, size, entry);
         } else {
@@ -70,7 +69,7 @@ fn do_ls(cmd: &ParsedCommand) {
     fn list_one(name: &str, print_name: bool, show_long: bool, show_all: bool) -> io::Result<()> {
         use std::vec::Vec;
 
-        let is_dir = fs::metadata(name)?.is_dir();
+        let is_dir = guestfs::metadata(name)?.is_dir();
         if !is_dir {
             return show_entry_info(name, name, show_long);
         }
@@ -79,8 +78,8 @@ fn do_ls(cmd: &ParsedCommand) {
             println!("{}:", name);
         }
 
-        let mut entries = fs::read_dir(name)?
-            .filter_map(|e| e.ok())
+        let mut entries = guestfs::read_dir(name)?
+            .into_iter()
             .map(|e| e.file_name())
             .filter(|name| show_all || !name.starts_with('.'))
             .collect::<Vec<_>>();
@@ -111,7 +110,7 @@ fn do_ls(cmd: &ParsedCommand) {
     }
 }
 
-#[cfg(feature = "fs")]
+#[cfg(any(feature = "fs", feature = "guest-archive"))]
 fn do_cat(cmd: &ParsedCommand) {
     let args = &cmd.positional_args;
 
@@ -122,7 +121,7 @@ fn do_cat(cmd: &ParsedCommand) {
 
     fn cat_one(fname: &str) -> io::Result<()> {
         let mut buf = [0; 1024];
-        let mut file = File::open(fname)?;
+        let mut file = guestfs::File::open(fname)?;
         loop {
             let n = file.read(&mut buf)?;
             if n > 0 {
@@ -148,6 +147,7 @@ fn do_echo(cmd: &ParsedCommand) {
     let args_str = args.join(" ");
 
     fn echo_file(fname: &str, text_list: &[&str]) -> io::Result<()> {
+        guestfs::check_writable(fname)?;
         let mut file = File::create(fname)?;
         for text in text_list {
             file.write_all(text.as_bytes())?;
@@ -192,6 +192,7 @@ fn do_mkdir(cmd: &ParsedCommand) {
     }
 
     fn mkdir_one(path: &str, create_parents: bool) -> io::Result<()> {
+        guestfs::check_writable(path)?;
         if create_parents {
             fs::create_dir_all(path)
         } else {
@@ -219,6 +220,7 @@ fn do_rm(cmd: &ParsedCommand) {
     }
 
     fn rm_one(path: &str, rm_dir: bool, recursive: bool, force: bool) -> io::Result<()> {
+        guestfs::check_writable(path)?;
         let metadata = fs::metadata(path);
 
         if force && metadata.is_err() {
@@ -464,6 +466,8 @@ fn do_mv(cmd: &ParsedCommand) {
 // Helper function to move file or directory (handles cross-filesystem moves)
 #[cfg(feature = "fs")]
 fn move_file_or_dir(source: &str, dest: &str) -> io::Result<()> {
+    guestfs::check_writable(source)?;
+    guestfs::check_writable(dest)?;
     // Try simple rename first (works within same filesystem)
     match fs::rename(source, dest) {
         Ok(()) => Ok(()),
@@ -495,7 +499,7 @@ fn do_touch(cmd: &ParsedCommand) {
     }
 
     for filename in args {
-        if let Err(e) = File::create(filename) {
+        if let Err(e) = guestfs::check_writable(filename).and_then(|_| File::create(filename)) {
             print_err!("touch", filename, e);
         }
     }
@@ -515,7 +519,7 @@ fn do_cp(cmd: &ParsedCommand) {
     let dest = &args[1];
 
     // Check if source file/directory exists
-    let src_metadata = match fs::metadata(source) {
+    let src_metadata = match guestfs::metadata(source) {
         Ok(metadata) => metadata,
         Err(e) => {
             print_err!("cp", format_args!("cannot access '{source}'"), e);
@@ -541,7 +545,8 @@ fn do_cp(cmd: &ParsedCommand) {
 // Manually implement file copy
 #[cfg(feature = "fs")]
 fn copy_file(src: &str, dst: &str) -> io::Result<()> {
-    let mut src_file = File::open(src)?;
+    guestfs::check_writable(dst)?;
+    let mut src_file = guestfs::File::open(src)?;
     let mut dst_file = File::create(dst)?;
 
     let mut buffer = [0; 4096];
@@ -559,19 +564,18 @@ fn copy_file(src: &str, dst: &str) -> io::Result<()> {
 #[cfg(feature = "fs")]
 fn copy_dir_recursive(src: &str, dst: &str) -> io::Result<()> {
     // Create target directory
+    guestfs::check_writable(dst)?;
     fs::create_dir(dst)?;
 
     // Read source directory contents
-    let entries = fs::read_dir(src)?;
+    let entries = guestfs::read_dir(src)?;
 
-    for entry_result in entries {
-        let entry = entry_result?;
+    for entry in entries {
         let file_name = entry.file_name();
         let src_path = format!("{src}/{file_name}");
         let dst_path = format!("{dst}/{file_name}");
 
-        let metadata = entry.file_type();
-        if metadata.is_dir() {
+        if guestfs::metadata(&src_path)?.is_dir() {
             copy_dir_recursive(&src_path, &dst_path)?;
         } else {
             copy_file(&src_path, &dst_path)?;
@@ -580,29 +584,22 @@ fn copy_dir_recursive(src: &str, dst: &str) -> io::Result<()> {
     Ok(())
 }
 
-#[cfg(feature = "fs")]
-fn file_type_to_char(ty: FileType) -> char {
-    if ty.is_char_device() {
-        'c'
-    } else if ty.is_block_device() {
-        'b'
-    } else if ty.is_socket() {
-        's'
-    } else if ty.is_fifo() {
-        'p'
-    } else if ty.is_symlink() {
-        'l'
-    } else if ty.is_dir() {
-        'd'
-    } else if ty.is_file() {
-        '-'
-    } else {
-        '?'
+#[cfg(any(feature = "fs", feature = "guest-archive"))]
+fn file_type_to_char(mode: u32) -> char {
+    match mode & 0o170_000 {
+        0o020_000 => 'c',
+        0o060_000 => 'b',
+        0o140_000 => 's',
+        0o010_000 => 'p',
+        0o120_000 => 'l',
+        0o040_000 => 'd',
+        0o100_000 => '-',
+        _ => '?',
     }
 }
 
 #[rustfmt::skip]
-#[cfg(feature = "fs")]
+#[cfg(any(feature = "fs", feature = "guest-archive"))]
 const fn file_perm_to_rwx(mode: u32) -> [u8; 9] {
     let mut perm = [b'-'; 9];
     macro_rules! set {
@@ -621,7 +618,7 @@ const fn file_perm_to_rwx(mode: u32) -> [u8; 9] {
 
 pub fn build_base_cmd(tree: &mut BTreeMap<String, CommandNode>) {
     // ls Command
-    #[cfg(feature = "fs")]
+    #[cfg(any(feature = "fs", feature = "guest-archive"))]
     tree.insert(
         "ls".to_string(),
         CommandNode::new("List directory contents")
@@ -640,7 +637,7 @@ pub fn build_base_cmd(tree: &mut BTreeMap<String, CommandNode>) {
     );
 
     // cat Command
-    #[cfg(feature = "fs")]
+    #[cfg(any(feature = "fs", feature = "guest-archive"))]
     tree.insert(
         "cat".to_string(),
         CommandNode::new("Display file contents")
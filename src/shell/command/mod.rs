@@ -296,8 +296,11 @@ impl CommandParser {
                 } else {
                     return Err(ParseError::UnknownOption(format!("--{name}")));
                 }
-            } else if token.starts_with('-') && token.len() > 1 {
-                // Short options/flags
+            } else if token.starts_with('-')
+                && token.len() > 1
+                && !token[1..].starts_with(|c: char| c.is_ascii_digit())
+            {
+                // Short options/flags, a `-` followed by a digit is a negative number
                 let chars: Vec<char> = token[1..].chars().collect();
                 for (j, &ch) in chars.iter().enumerate() {
                     if Self::is_short_flag(ch, command_node) {
@@ -20,12 +20,12 @@ use std::{
 };
 
 use axvm::VMStatus;
-#[cfg(feature = "fs")]
-use std::fs::read_to_string;
 
+#[cfg(any(feature = "fs", feature = "guest-archive"))]
+use crate::vmm::config::read_vm_config;
 use crate::{
     shell::command::{CommandNode, FlagDef, OptionDef, ParsedCommand},
-    vmm::{add_running_vm_count, vcpus, vm_list, with_vm},
+    vmm::{export, lifecycle, reconfig, validate, vcpus, vm_list, with_vm},
 };
 
 /// Check if a VM can transition to Running state.
@@ -103,12 +103,17 @@ fn vm_help(_cmd: &ParsedCommand) {
     println!();
     println!("Most commonly used vm commands:");
     println!("  create    Create a new virtual machine");
+    println!("  validate  Check VM config files without creating VMs");
     println!("  start     Start a virtual machine");
     println!("  stop      Stop a virtual machine");
     println!("  suspend   Suspend (pause) a running virtual machine");
     println!("  resume    Resume a suspended virtual machine");
     println!("  restart   Restart a virtual machine");
     println!("  delete    Delete a virtual machine");
+    println!("  settime   Show or set the emulated RTC time of a VM");
+    println!("  export    Write the effective config of a VM as TOML");
+    println!("  set       Change config keys of a Loaded or Stopped VM");
+    println!("  edit      Replace the config of a Loaded or Stopped VM");
     println!();
     println!("Information commands:");
     println!("  list      Show table of all VMs");
@@ -117,11 +122,12 @@ fn vm_help(_cmd: &ParsedCommand) {
     println!("            - --full: complete detailed information");
     println!("            - --config: show configuration");
     println!("            - --stats: show statistics");
+    println!("            - --measurements: show the measured boot log");
     println!();
     println!("Use 'vm <command> --help' for more information on a specific command.");
 }
 
-#[cfg(feature = "fs")]
+#[cfg(any(feature = "fs", feature = "guest-archive"))]
 fn vm_create(cmd: &ParsedCommand) {
     let args = &cmd.positional_args;
 
@@ -139,21 +145,33 @@ fn vm_create(cmd: &ParsedCommand) {
         println!("Creating VM from config: {}", config_path);
 
         use crate::vmm::config::init_guest_vm;
-        match read_to_string(config_path) {
-            Ok(raw_cfg) => match init_guest_vm(&raw_cfg) {
-                Ok(vm_id) => {
-                    println!(
-                        "✓ Successfully created VM[{}] from config: {}",
-                        vm_id, config_path
-                    );
+        match read_vm_config(config_path) {
+            Ok(raw_cfg) => {
+                let raw_cfg = match apply_cmdline_options(cmd, raw_cfg) {
+                    Ok(raw_cfg) => raw_cfg,
+                    Err(e) => {
+                        println!("✗ Failed to apply command line options: {}", e);
+                        continue;
+                    }
+                };
+                let diagnostics = validate::validate(&raw_cfg);
+                if !diagnostics.is_empty() {
+                    println!("✗ Invalid VM config {}:", config_path);
+                    print_diagnostics(&diagnostics);
+                    continue;
                 }
-                Err(_) => {
-                    println!(
-                        "✗ Failed to create VM from {}: Configuration error or panic occurred",
-                        config_path
-                    );
+                match init_guest_vm(&raw_cfg) {
+                    Ok(vm_id) => {
+                        println!(
+                            "✓ Successfully created VM[{}] from config: {}",
+                            vm_id, config_path
+                        );
+                    }
+                    Err(e) => {
+                        println!("✗ Failed to create VM from {}: {:?}", config_path, e);
+                    }
                 }
-            },
+            }
             Err(e) => {
                 println!("✗ Failed to read config file {}: {:?}", config_path, e);
             }
@@ -172,7 +190,91 @@ fn vm_create(cmd: &ParsedCommand) {
     }
 }
 
-#[cfg(feature = "fs")]
+/// Applies the `--cmdline*` options of `vm create` to the `[kernel]` section of a raw VM config.
+#[cfg(any(feature = "fs", feature = "guest-archive"))]
+fn apply_cmdline_options(cmd: &ParsedCommand, raw_cfg: String) -> Result<String, String> {
+    use toml::{Table, Value};
+
+    use crate::vmm::ext_config::split_params;
+
+    const OPTIONS: [&str; 3] = ["cmdline", "cmdline-append", "cmdline-remove"];
+    if !OPTIONS.iter().any(|opt| cmd.options.contains_key(*opt)) {
+        return Ok(raw_cfg);
+    }
+
+    let mut table: Table = raw_cfg.parse().map_err(|e| format!("{e}"))?;
+    let kernel = table
+        .entry("kernel")
+        .or_insert_with(|| Value::Table(Table::new()))
+        .as_table_mut()
+        .ok_or("kernel must be a table")?;
+
+    if let Some(cmdline) = cmd.options.get("cmdline") {
+        kernel.insert("cmdline".to_string(), Value::String(cmdline.clone()));
+    }
+    for (opt, key) in [
+        ("cmdline-append", "cmdline_append"),
+        ("cmdline-remove", "cmdline_remove"),
+    ] {
+        let Some(params) = cmd.options.get(opt) else {
+            continue;
+        };
+        // The options add to the parameters of the config file.
+        let mut list = match kernel.remove(key) {
+            None => Vec::new(),
+            Some(Value::String(s)) => split_params(&s)
+                .into_iter()
+                .map(|p| Value::String(p.to_string()))
+                .collect(),
+            Some(Value::Array(list)) => list,
+            Some(_) => return Err(format!("kernel.{key} must be a string or an array")),
+        };
+        list.extend(
+            split_params(params)
+                .into_iter()
+                .map(|p| Value::String(p.to_string())),
+        );
+        kernel.insert(key.to_string(), Value::Array(list));
+    }
+
+    toml::to_string(&table).map_err(|e| format!("{e}"))
+}
+
+#[cfg(any(feature = "fs", feature = "guest-archive"))]
+fn vm_validate(cmd: &ParsedCommand) {
+    let args = &cmd.positional_args;
+
+    if args.is_empty() {
+        println!("Error: No VM configuration file specified");
+        println!("Usage: vm validate <CONFIG_FILE>...");
+        return;
+    }
+
+    for config_path in args.iter() {
+        match read_vm_config(config_path) {
+            Ok(raw_cfg) => {
+                let diagnostics = validate::validate(&raw_cfg);
+                if diagnostics.is_empty() {
+                    println!("✓ {} is a valid VM config", config_path);
+                } else {
+                    println!("✗ {}: {} error(s)", config_path, diagnostics.len());
+                    print_diagnostics(&diagnostics);
+                }
+            }
+            Err(e) => {
+                println!("✗ Failed to read config file {}: {:?}", config_path, e);
+            }
+        }
+    }
+}
+
+fn print_diagnostics(diagnostics: &[validate::Diagnostic]) {
+    for diagnostic in diagnostics {
+        println!("    - {}", diagnostic);
+    }
+}
+
+#[cfg(any(feature = "fs", feature = "guest-archive"))]
 fn vm_start(cmd: &ParsedCommand) {
     let args = &cmd.positional_args;
     let detach = cmd.flags.get("detach").unwrap_or(&false);
@@ -225,31 +327,10 @@ fn vm_start(cmd: &ParsedCommand) {
 /// Start a single VM by setting up vCPUs and calling boot.
 /// Returns Ok(()) if successful, Err otherwise.
 fn start_single_vm(vm: crate::vmm::VMRef) -> Result<(), &'static str> {
-    let vm_id = vm.id();
-    let status = vm.vm_status();
-
     // Validate state transition using helper function
-    can_start_vm(status)?;
-
-    // Set up primary virtual CPU before starting
-    vcpus::setup_vm_primary_vcpu(vm.clone());
-
-    // Boot the VM
-    match vm.boot() {
-        Ok(_) => {
-            // Transition to Running state and notify the primary VCpu
-            // Note: Since the VCpu task is created directly in the wait queue (blocked state),
-            // we can immediately notify it without waiting for it to be scheduled first.
-            vcpus::notify_primary_vcpu(vm_id);
-            add_running_vm_count(1);
-            Ok(())
-        }
-        Err(err) => {
-            // Revert status on failure
-            error!("Failed to boot VM[{}]: {:?}", vm_id, err);
-            Err("Failed to boot VM")
-        }
-    }
+    can_start_vm(vm.vm_status())?;
+
+    lifecycle::start_vm(vm)
 }
 
 fn start_vm_by_id(vm_id: usize) {
@@ -266,6 +347,15 @@ fn start_vm_by_id(vm_id: usize) {
     }
 }
 
+/// Starts a VM from scrubbed memory and freshly loaded images if it ran before.
+fn reset_and_start_vm_by_id(vm_id: usize) {
+    if let Err(err) = lifecycle::reset_vm(vm_id) {
+        println!("✗ VM[{}] cannot be set up again: {:?}", vm_id, err);
+        return;
+    }
+    start_vm_by_id(vm_id);
+}
+
 fn vm_stop(cmd: &ParsedCommand) {
     let args = &cmd.positional_args;
     let force = cmd.flags.get("force").unwrap_or(&false);
@@ -375,7 +465,7 @@ fn restart_vm_by_id(vm_id: usize, force: bool) {
         VMStatus::Stopped | VMStatus::Loaded => {
             // VM is already stopped, just start it
             println!("VM[{}] is already stopped, starting...", vm_id);
-            start_vm_by_id(vm_id);
+            reset_and_start_vm_by_id(vm_id);
         }
         VMStatus::Suspended | VMStatus::Running => {
             // Stop the VM (this will wake up suspended VCpus automatically)
@@ -423,7 +513,7 @@ fn restart_vm_by_id(vm_id: usize, force: bool) {
 
             // Now restart the VM
             println!("Starting VM[{}]...", vm_id);
-            start_vm_by_id(vm_id);
+            reset_and_start_vm_by_id(vm_id);
         }
         VMStatus::Stopping => {
             if force {
@@ -702,6 +792,12 @@ fn delete_vm_by_id(vm_id: usize, keep_data: bool) {
     match crate::vmm::vm_list::remove_vm(vm_id) {
         Some(vm) => {
             println!("✓ VM[{}] removed from VM list", vm_id);
+            crate::vmm::events::clear(vm_id);
+            crate::vmm::emu::pl031::clear(vm_id);
+            crate::vmm::emu::watchdog::clear(vm_id);
+            let scrub_all = crate::vmm::ext_config::get(vm_id).scrub_all_memory;
+            crate::vmm::ext_config::remove(vm_id);
+            crate::vmm::measure::clear(vm_id);
 
             // Wait for vCPU threads to exit if VM has VCpu tasks
             match status {
@@ -734,6 +830,8 @@ fn delete_vm_by_id(vm_id: usize, keep_data: bool) {
                     vcpus::cleanup_vm_vcpus(vm_id);
                 }
             }
+            // The vCPUs are gone, the shared memory no other VM uses can be freed.
+            crate::vmm::shm::detach(vm_id);
 
             if keep_data {
                 println!("✓ VM[{}] deleted (configuration and data preserved)", vm_id);
@@ -747,7 +845,7 @@ fn delete_vm_by_id(vm_id: usize, keep_data: bool) {
                 println!("  [Debug] VM Arc strong_count: {}", count);
 
                 if count == 1 {
-                    println!("  ✓ Perfect! VM will be freed immediately when function returns");
+                    println!("  ✓ Perfect! VM will be freed once its memory is scrubbed");
                 } else {
                     println!(
                         "  ⚠ Warning: Unexpected Arc count {}, possible reference leak!",
@@ -761,9 +859,10 @@ fn delete_vm_by_id(vm_id: usize, keep_data: bool) {
                 // - Remove log files
             }
 
-            // When function returns, the 'vm' variable is dropped
-            // Since Arc count is 1, AxVM::drop() is called immediately
-            println!("  VM[{}] will be freed now", vm_id);
+            // The memory is zeroed in the background, then AxVM::drop() is called when the
+            // scrubber drops the last reference.
+            println!("  VM[{}] memory will be scrubbed and freed", vm_id);
+            crate::vmm::scrub::release(vm, scrub_all);
         }
         None => {
             println!(
@@ -778,7 +877,185 @@ fn delete_vm_by_id(vm_id: usize, keep_data: bool) {
     println!("✓ VM[{}] deletion completed", vm_id);
 }
 
-#[cfg(feature = "fs")]
+fn vm_settime(cmd: &ParsedCommand) {
+    use crate::vmm::emu::pl031;
+
+    let args = &cmd.positional_args;
+
+    if args.is_empty() {
+        println!("Error: No VM specified");
+        println!("Usage: vm settime <VM_ID> [UNIX_SECONDS | +SECONDS | -SECONDS]");
+        return;
+    }
+
+    let vm_id = match args[0].parse::<usize>() {
+        Ok(vm_id) => vm_id,
+        Err(_) => {
+            println!("Error: Invalid VM ID: {}", args[0]);
+            return;
+        }
+    };
+
+    if with_vm(vm_id, |_| ()).is_none() {
+        println!("✗ VM[{}] not found", vm_id);
+        return;
+    }
+
+    if let Some(time) = args.get(1) {
+        let result = if let Some(delta) = time.strip_prefix('+') {
+            delta
+                .parse::<i64>()
+                .map(|delta| pl031::set_offset(vm_id, pl031::offset(vm_id) + delta))
+        } else if let Some(delta) = time.strip_prefix('-') {
+            delta
+                .parse::<i64>()
+                .map(|delta| pl031::set_offset(vm_id, pl031::offset(vm_id) - delta))
+        } else {
+            time.parse::<u64>()
+                .map(|unix_secs| pl031::set_time(vm_id, unix_secs))
+        };
+
+        if result.is_err() {
+            println!("Error: Invalid time: {}", time);
+            return;
+        }
+        println!("✓ VM[{}] RTC updated", vm_id);
+    }
+
+    println!(
+        "VM[{}] RTC time: {} (offset {:+}s from host wall time)",
+        vm_id,
+        pl031::guest_time(vm_id),
+        pl031::offset(vm_id)
+    );
+}
+
+fn vm_export(cmd: &ParsedCommand) {
+    let args = &cmd.positional_args;
+
+    if args.is_empty() {
+        println!("Error: No VM specified");
+        println!("Usage: vm export <VM_ID> [PATH]");
+        return;
+    }
+
+    let vm_id = match args[0].parse::<usize>() {
+        Ok(vm_id) => vm_id,
+        Err(_) => {
+            println!("Error: Invalid VM ID: {}", args[0]);
+            return;
+        }
+    };
+
+    let Some(vm) = vm_list::get_vm_by_id(vm_id) else {
+        println!("✗ VM[{}] not found", vm_id);
+        return;
+    };
+
+    let config = match export::effective_config(&vm) {
+        Ok(config) => config,
+        Err(e) => {
+            println!("✗ Failed to export VM[{}] config: {:?}", vm_id, e);
+            return;
+        }
+    };
+
+    let Some(path) = args.get(1) else {
+        println!("{}", config);
+        return;
+    };
+
+    #[cfg(feature = "fs")]
+    {
+        use std::{fs::File, io::Write};
+
+        match File::create(path).and_then(|mut file| file.write_all(config.as_bytes())) {
+            Ok(()) => println!("✓ VM[{}] config exported to {}", vm_id, path),
+            Err(e) => println!("✗ Failed to write {}: {:?}", path, e),
+        }
+    }
+    #[cfg(not(feature = "fs"))]
+    println!(
+        "✗ Cannot write {}: AxVisor is built without the fs feature",
+        path
+    );
+}
+
+fn vm_set(cmd: &ParsedCommand) {
+    let args = &cmd.positional_args;
+
+    if args.len() < 2 {
+        println!("Error: No VM or key specified");
+        println!("Usage: vm set <VM_ID> <KEY=VALUE>...");
+        return;
+    }
+
+    let Some(vm_id) = parse_reconfigurable_vm_id(&args[0]) else {
+        return;
+    };
+
+    match reconfig::with_assignments(vm_id, &args[1..]) {
+        Ok(raw_cfg) => reconfigure_vm(vm_id, &raw_cfg),
+        Err(e) => println!("✗ Failed to change VM[{}] config: {:?}", vm_id, e),
+    }
+}
+
+#[cfg(any(feature = "fs", feature = "guest-archive"))]
+fn vm_edit(cmd: &ParsedCommand) {
+    let args = &cmd.positional_args;
+
+    if args.len() != 2 {
+        println!("Error: Expected a VM ID and a config file");
+        println!("Usage: vm edit <VM_ID> <CONFIG_FILE>");
+        return;
+    }
+
+    let Some(vm_id) = parse_reconfigurable_vm_id(&args[0]) else {
+        return;
+    };
+
+    match read_vm_config(&args[1]).and_then(|raw_cfg| reconfig::with_vm_id(vm_id, &raw_cfg)) {
+        Ok(raw_cfg) => reconfigure_vm(vm_id, &raw_cfg),
+        Err(e) => println!("✗ Failed to read {}: {:?}", args[1], e),
+    }
+}
+
+/// Parses the ID of a VM to reconfigure, which must be `Loaded` or `Stopped`.
+fn parse_reconfigurable_vm_id(arg: &str) -> Option<usize> {
+    let Ok(vm_id) = arg.parse::<usize>() else {
+        println!("Error: Invalid VM ID: {}", arg);
+        return None;
+    };
+    match with_vm(vm_id, |vm| vm.vm_status()) {
+        None => println!("✗ VM[{}] not found", vm_id),
+        Some(VMStatus::Loaded | VMStatus::Stopped) => return Some(vm_id),
+        Some(status) => {
+            println!("✗ VM[{}] is {}", vm_id, status.as_str());
+            println!("  Only Loaded or Stopped VMs can be reconfigured, use 'vm stop' first");
+        }
+    }
+    None
+}
+
+fn reconfigure_vm(vm_id: usize, raw_cfg: &str) {
+    let diagnostics = validate::validate_replacing(raw_cfg, vm_id);
+    if !diagnostics.is_empty() {
+        println!(
+            "✗ New config of VM[{}] is invalid, VM left unchanged:",
+            vm_id
+        );
+        print_diagnostics(&diagnostics);
+        return;
+    }
+
+    println!("Reconfiguring VM[{}]...", vm_id);
+    match reconfig::reconfigure(vm_id, raw_cfg) {
+        Ok(()) => println!("✓ VM[{}] reconfigured, it is now Loaded", vm_id),
+        Err(e) => println!("✗ Failed to reconfigure VM[{}]: {:?}", vm_id, e),
+    }
+}
+
+#[cfg(any(feature = "fs", feature = "guest-archive"))]
 fn vm_list_simple() {
     let vms = vm_list::get_vm_list();
     println!("ID    NAME           STATE      VCPU   MEMORY");
@@ -896,6 +1173,7 @@ fn vm_show(cmd: &ParsedCommand) {
     let show_config = cmd.flags.get("config").unwrap_or(&false);
     let show_stats = cmd.flags.get("stats").unwrap_or(&false);
     let show_full = cmd.flags.get("full").unwrap_or(&false);
+    let show_measurements = cmd.flags.get("measurements").unwrap_or(&false);
 
     if args.is_empty() {
         println!("Error: No VM specified");
@@ -905,6 +1183,7 @@ fn vm_show(cmd: &ParsedCommand) {
         println!("  -f, --full     Show full detailed information");
         println!("  -c, --config   Show configuration details");
         println!("  -s, --stats    Show statistics");
+        println!("  -m, --measurements  Show the measured boot log");
         println!();
         println!("Use 'vm list' to see all VMs");
         return;
@@ -916,7 +1195,7 @@ fn vm_show(cmd: &ParsedCommand) {
         if *show_full {
             show_vm_full_details(vm_id);
         } else {
-            show_vm_basic_details(vm_id, *show_config, *show_stats);
+            show_vm_basic_details(vm_id, *show_config, *show_stats, *show_measurements);
         }
     } else {
         println!("Error: Invalid VM ID: {}", vm_name);
@@ -924,7 +1203,12 @@ fn vm_show(cmd: &ParsedCommand) {
 }
 
 /// Show basic VM information (default view)
-fn show_vm_basic_details(vm_id: usize, show_config: bool, show_stats: bool) {
+fn show_vm_basic_details(
+    vm_id: usize,
+    show_config: bool,
+    show_stats: bool,
+    show_measurements: bool,
+) {
     match with_vm(vm_id, |vm| {
         let status = vm.vm_status();
 
@@ -1012,6 +1296,12 @@ fn show_vm_basic_details(vm_id: usize, show_config: bool, show_stats: bool) {
             );
         }
 
+        if show_measurements {
+            show_vm_measurements(vm_id);
+        }
+
+        show_vm_events(vm_id);
+
         println!();
         println!("Use 'vm show {} --full' for detailed information", vm_id);
     }) {
@@ -1022,6 +1312,51 @@ fn show_vm_basic_details(vm_id: usize, show_config: bool, show_stats: bool) {
     }
 }
 
+/// Show the events recorded for a VM (guest panics, watchdog timeouts...), if any.
+fn show_vm_events(vm_id: usize) {
+    let events = crate::vmm::events::events(vm_id);
+    if events.is_empty() {
+        return;
+    }
+
+    println!();
+    println!("Events:");
+    for event in events {
+        let detail = if event.detail.is_empty() {
+            String::new()
+        } else {
+            format!(" ({})", event.detail)
+        };
+        println!(
+            "  [{}.{:03}] {}{}",
+            event.time.as_secs(),
+            event.time.subsec_millis(),
+            event.kind,
+            detail
+        );
+    }
+}
+
+/// Show the measured boot log of a VM: what was loaded into it and the final hash chain.
+fn show_vm_measurements(vm_id: usize) {
+    use crate::vmm::measure;
+
+    println!();
+    println!("Measurements (SHA-256):");
+    let Some(log) = measure::log(vm_id) else {
+        println!("  (none)");
+        return;
+    };
+    for measurement in &log.measurements {
+        println!(
+            "  {:<10} {}",
+            measurement.name,
+            measure::hex(&measurement.digest)
+        );
+    }
+    println!("  {:<10} {}", "chain", measure::hex(&log.chain));
+}
+
 /// Show full detailed information about a specific VM (--full flag)
 fn show_vm_full_details(vm_id: usize) {
     match with_vm(vm_id, |vm| {
@@ -1239,6 +1574,12 @@ fn show_vm_full_details(vm_id: usize) {
         println!("  MMIO Devices:   {}", mmio_dev_count);
         println!("  SysReg Devices: {}", sysreg_dev_count);
 
+        // Measured boot log
+        show_vm_measurements(vm_id);
+
+        // Events
+        show_vm_events(vm_id);
+
         // Additional Statistics
         println!();
         println!("Additional Statistics:");
@@ -1270,7 +1611,7 @@ fn show_vm_full_details(vm_id: usize) {
 
 /// Build the VM command tree and register it.
 pub fn build_vm_cmd(tree: &mut BTreeMap<String, CommandNode>) {
-    #[cfg(feature = "fs")]
+    #[cfg(any(feature = "fs", feature = "guest-archive"))]
     let create_cmd = CommandNode::new("Create a new virtual machine")
         .with_handler(vm_create)
         .with_usage("vm create [OPTIONS] <CONFIG_FILE>...")
@@ -1289,13 +1630,39 @@ pub fn build_vm_cmd(tree: &mut BTreeMap<String, CommandNode>) {
                 .with_short('m')
                 .with_long("memory"),
         )
+        .with_option(
+            OptionDef::new(
+                "cmdline",
+                "Guest kernel command line, replaces the configured one",
+            )
+            .with_long("cmdline"),
+        )
+        .with_option(
+            OptionDef::new(
+                "cmdline-append",
+                "Parameters appended to the guest command line",
+            )
+            .with_long("cmdline-append"),
+        )
+        .with_option(
+            OptionDef::new(
+                "cmdline-remove",
+                "Parameters removed from the guest command line",
+            )
+            .with_long("cmdline-remove"),
+        )
         .with_flag(
             FlagDef::new("force", "Force creation without confirmation")
                 .with_short('f')
                 .with_long("force"),
         );
 
-    #[cfg(feature = "fs")]
+    #[cfg(any(feature = "fs", feature = "guest-archive"))]
+    let validate_cmd = CommandNode::new("Check VM config files without creating VMs")
+        .with_handler(vm_validate)
+        .with_usage("vm validate <CONFIG_FILE>...");
+
+    #[cfg(any(feature = "fs", feature = "guest-archive"))]
     let start_cmd = CommandNode::new("Start a virtual machine")
         .with_handler(vm_start)
         .with_usage("vm start [OPTIONS] [VM_ID...]")
@@ -1351,6 +1718,23 @@ pub fn build_vm_cmd(tree: &mut BTreeMap<String, CommandNode>) {
         )
         .with_flag(FlagDef::new("keep-data", "Keep VM data").with_long("keep-data"));
 
+    let settime_cmd = CommandNode::new("Show or set the emulated RTC time of a VM")
+        .with_handler(vm_settime)
+        .with_usage("vm settime <VM_ID> [UNIX_SECONDS | +SECONDS | -SECONDS]");
+
+    let export_cmd = CommandNode::new("Write the effective config of a VM as TOML")
+        .with_handler(vm_export)
+        .with_usage("vm export <VM_ID> [PATH]");
+
+    let set_cmd = CommandNode::new("Change config keys of a Loaded or Stopped VM")
+        .with_handler(vm_set)
+        .with_usage("vm set <VM_ID> <KEY=VALUE>...");
+
+    #[cfg(any(feature = "fs", feature = "guest-archive"))]
+    let edit_cmd = CommandNode::new("Replace the config of a Loaded or Stopped VM")
+        .with_handler(vm_edit)
+        .with_usage("vm edit <VM_ID> <CONFIG_FILE>");
+
     let list_cmd = CommandNode::new("Show virtual machine lists")
         .with_handler(vm_list)
         .with_usage("vm list [OPTIONS]")
@@ -1378,6 +1762,11 @@ pub fn build_vm_cmd(tree: &mut BTreeMap<String, CommandNode>) {
             FlagDef::new("stats", "Show device statistics")
                 .with_short('s')
                 .with_long("stats"),
+        )
+        .with_flag(
+            FlagDef::new("measurements", "Show the measured boot log")
+                .with_short('m')
+                .with_long("measurements"),
         );
 
     // main VM command
@@ -1389,10 +1778,12 @@ pub fn build_vm_cmd(tree: &mut BTreeMap<String, CommandNode>) {
             CommandNode::new("Show VM help").with_handler(vm_help),
         );
 
-    #[cfg(feature = "fs")]
+    #[cfg(any(feature = "fs", feature = "guest-archive"))]
     {
         vm_node = vm_node
             .add_subcommand("create", create_cmd)
+            .add_subcommand("validate", validate_cmd)
+            .add_subcommand("edit", edit_cmd)
             .add_subcommand("start", start_cmd);
     }
 
@@ -1402,6 +1793,9 @@ pub fn build_vm_cmd(tree: &mut BTreeMap<String, CommandNode>) {
         .add_subcommand("resume", resume_cmd)
         .add_subcommand("restart", restart_cmd)
         .add_subcommand("delete", delete_cmd)
+        .add_subcommand("settime", settime_cmd)
+        .add_subcommand("export", export_cmd)
+        .add_subcommand("set", set_cmd)
         .add_subcommand("list", list_cmd)
         .add_subcommand("show", show_cmd);
 
@@ -12,20 +12,26 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use axaddrspace::GuestPhysAddr;
-use axerrno::AxResult;
+use axaddrspace::{GuestPhysAddr, MappingFlags};
+use axerrno::{AxResult, ax_err_type};
 use axvm::{
     VMMemoryRegion,
-    config::{AxVMConfig, AxVMCrateConfig, VmMemMappingType},
+    config::{AxVMConfig, AxVMCrateConfig, VmMemConfig, VmMemMappingType},
 };
 use core::alloc::Layout;
+use memory_addr::PAGE_SIZE_4K;
 
-use crate::vmm::{VM, images::ImageLoader, vm_list::push_vm};
+use crate::vmm::{
+    VM, VMRef,
+    events::VmEventKind,
+    images::{ImageLoader, LinuxHeader, PLACEMENT_ALIGN},
+    vm_list::{push_vm, remove_vm},
+};
 
 #[cfg(target_arch = "aarch64")]
 use crate::vmm::fdt::*;
 
-use alloc::sync::Arc;
+use alloc::{sync::Arc, vec::Vec};
 
 #[allow(clippy::module_inception, dead_code)]
 pub mod config {
@@ -37,31 +43,34 @@ pub mod config {
         vec![]
     }
 
-    /// Read VM configs from filesystem
-    #[cfg(feature = "fs")]
+    /// Read VM configs from filesystem, or from the guest archive.
+    #[cfg(any(feature = "fs", feature = "guest-archive"))]
     pub fn filesystem_vm_configs() -> Vec<String> {
-        use axstd::fs;
+        use crate::vmm::guestfs as fs;
         use axstd::io::{BufReader, Read};
 
-        let config_dir = "/guest/vm_default";
+        let config_dir = crate::vmm::manifest::config_dir();
 
         let mut configs = Vec::new();
 
         debug!("Read VM config files from filesystem.");
 
-        let entries = match fs::read_dir(config_dir) {
+        let entries = match fs::read_dir(&config_dir) {
             Ok(entries) => {
                 info!("Find dir: {}", config_dir);
                 entries
             }
             Err(_e) => {
                 info!("NOT find dir: {} in filesystem", config_dir);
-                return configs;
+                return Vec::new();
             }
         };
 
-        for entry in entries.flatten() {
-            let path = entry.path();
+        // Without a boot manifest, VMs are created in the order of their file names.
+        let mut paths: Vec<String> = entries.into_iter().map(|entry| entry.path()).collect();
+        paths.sort();
+
+        for path in paths {
             // Check if the file has a .toml extension
             let path_str = path.as_str();
             debug!("Considering file: {}", path_str);
@@ -70,7 +79,7 @@ pub mod config {
                 let file_size = toml_file
                     .metadata()
                     .expect("Failed to get file metadata")
-                    .len() as usize;
+                    .size() as usize;
 
                 info!("File {} size: {}", path_str, file_size);
 
@@ -92,20 +101,11 @@ pub mod config {
                         let content = alloc::string::String::from_utf8(buffer)
                             .expect("Failed to convert bytes to UTF-8 string");
 
-                        if content.contains("[base]")
-                            && content.contains("[kernel]")
-                            && content.contains("[devices]")
-                        {
-                            configs.push(content);
-                            info!(
-                                "TOML config: {} is valid, start the virtual machine directly now. ",
-                                path_str
-                            );
-                        } else {
-                            warn!(
-                                "File {} does not appear to contain valid VM config structure",
-                                path_str
-                            );
+                        // The content is checked by `init_guest_vm`, which reports every
+                        // problem found in it.
+                        match super::resolve_vm_config(path_str, &content) {
+                            Ok((content, bases)) => configs.push((path, content, bases)),
+                            Err(e) => error!("Failed to resolve config file {}: {}", path_str, e),
                         }
                     }
                     Err(e) => {
@@ -115,11 +115,20 @@ pub mod config {
             }
         }
 
+        // Configs extended by other configs of the directory are not VMs by themselves.
+        let bases: Vec<String> = configs
+            .iter()
+            .flat_map(|(_, _, bases)| bases.iter().cloned())
+            .collect();
         configs
+            .into_iter()
+            .filter(|(path, ..)| !bases.contains(path))
+            .map(|(_, content, _)| content)
+            .collect()
     }
 
-    /// Fallback function for when "fs" feature is not enabled
-    #[cfg(not(feature = "fs"))]
+    /// Fallback function for when neither the "fs" nor the "guest-archive" feature is enabled
+    #[cfg(not(any(feature = "fs", feature = "guest-archive")))]
     pub fn filesystem_vm_configs() -> Vec<String> {
         Vec::new()
     }
@@ -138,14 +147,45 @@ pub fn get_vm_dtb_arc(_vm_cfg: &AxVMConfig) -> Option<Arc<[u8]>> {
     None
 }
 
-pub fn init_guest_vms() {
+/// Reads a VM config file, with the configs it extends merged in.
+#[cfg(any(feature = "fs", feature = "guest-archive"))]
+pub fn read_vm_config(path: &str) -> AxResult<String> {
+    let content = super::guestfs::read_to_string(path)
+        .map_err(|err| ax_err_type!(NotFound, format!("{path}: {err:?}")))?;
+    resolve_vm_config(path, &content)
+        .map(|(content, _)| content)
+        .map_err(|err| ax_err_type!(InvalidInput, err))
+}
+
+/// Merges a VM config read from `path` with the configs it extends, returns the merged config
+/// and the paths of the configs it extends.
+#[cfg(any(feature = "fs", feature = "guest-archive"))]
+fn resolve_vm_config(path: &str, content: &str) -> Result<(String, Vec<String>), String> {
+    let resolved = super::overlay::resolve_content(path, content, &mut |file| {
+        super::guestfs::read_to_string(file).map_err(|err| format!("{file}: {err:?}"))
+    })?;
+    if resolved.bases.is_empty() {
+        return Ok((content.into(), resolved.bases));
+    }
+    let merged = toml::to_string(&resolved.table).map_err(|err| format!("{path}: {err}"))?;
+    Ok((merged, resolved.bases))
+}
+
+/// Creates the VMs to be set up at boot, returns the IDs of those to be booted, in boot order.
+pub fn init_guest_vms() -> Vec<usize> {
     // Initialize the DTB cache in the fdt module
     #[cfg(target_arch = "aarch64")]
     {
         init_dtb_cache();
     }
 
-    // First try to get configs from filesystem if fs feature is enabled
+    // A boot manifest, on the filesystem or in the guest archive, takes precedence over the rest.
+    #[cfg(any(feature = "fs", feature = "guest-archive"))]
+    if let Some(manifest) = super::manifest::load() {
+        return init_guest_vms_from_manifest(&manifest);
+    }
+
+    // First try to get configs from the filesystem or the guest archive
     let mut gvm_raw_configs = config::filesystem_vm_configs();
 
     // If no filesystem configs found, fallback to static configs
@@ -161,24 +201,72 @@ pub fn init_guest_vms() {
         gvm_raw_configs.extend(static_configs.into_iter().map(|s| s.into()));
     }
 
+    let mut autostart = Vec::new();
     for raw_cfg_str in gvm_raw_configs {
         debug!("Initializing guest VM with config: {:#?}", raw_cfg_str);
-        if let Err(e) = init_guest_vm(&raw_cfg_str) {
-            error!("Failed to initialize guest VM: {e:?}");
+        match init_guest_vm(&raw_cfg_str) {
+            Ok(vm_id) => autostart.push(vm_id),
+            Err(e) => error!("Failed to initialize guest VM: {e:?}"),
         }
     }
+    autostart
 }
 
-pub fn init_guest_vm(raw_cfg: &str) -> AxResult<usize> {
-    let vm_create_config =
-        AxVMCrateConfig::from_toml(raw_cfg).expect("Failed to resolve VM config");
-
-    if let Some(linux) = super::images::get_image_header(&vm_create_config) {
-        debug!(
-            "VM[{}] Linux header: {:#x?}",
-            vm_create_config.base.id, linux
-        );
+#[cfg(any(feature = "fs", feature = "guest-archive"))]
+fn init_guest_vms_from_manifest(manifest: &super::manifest::Manifest) -> Vec<usize> {
+    use super::manifest::ManifestEntry;
+
+    let init_entries = |entries: &[ManifestEntry], autostart: &mut Vec<usize>| {
+        let mut created = 0;
+        for entry in entries {
+            let vm_id = read_vm_config(&entry.config).and_then(|raw_cfg| init_guest_vm(&raw_cfg));
+            match vm_id {
+                Ok(vm_id) => {
+                    info!("VM[{vm_id}] created from {}", entry.config);
+                    created += 1;
+                    if entry.autostart {
+                        autostart.push(vm_id);
+                    }
+                }
+                Err(e) => error!("Failed to initialize guest VM {}: {e:?}", entry.config),
+            }
+        }
+        created
+    };
+
+    let mut autostart = Vec::new();
+    if init_entries(&manifest.vms, &mut autostart) == 0 && !manifest.fallback.is_empty() {
+        warn!("No VM of the boot manifest could be created, using the fallback set");
+        init_entries(&manifest.fallback, &mut autostart);
     }
+    autostart
+}
+
+pub fn init_guest_vm(raw_cfg: &str) -> AxResult<usize> {
+    let (vm_create_config, ext_cfg) =
+        super::validate::parse_and_validate(raw_cfg).map_err(|diagnostics| {
+            for diagnostic in &diagnostics {
+                error!("Invalid VM config: {diagnostic}");
+            }
+            ax_err_type!(
+                InvalidInput,
+                format!("VM config has {} error(s)", diagnostics.len())
+            )
+        })?;
+
+    let vm_id = vm_create_config.base.id;
+    build_guest_vm(vm_create_config, ext_cfg).inspect_err(|_| super::shm::detach(vm_id))
+}
+
+/// Creates, sets up and loads a VM from a checked config, leaving it in `Loaded` state.
+///
+/// On failure the VM is forgotten, but stays attached to the shared memory regions it was
+/// mapped into, see [`super::shm::detach`].
+pub(super) fn build_guest_vm(
+    vm_create_config: AxVMCrateConfig,
+    ext_cfg: super::ext_config::ExtConfig,
+) -> AxResult<usize> {
+    let linux_header = super::images::get_image_header(&vm_create_config);
 
     #[cfg(target_arch = "aarch64")]
     let mut vm_config = AxVMConfig::from(vm_create_config.clone());
@@ -186,6 +274,12 @@ pub fn init_guest_vm(raw_cfg: &str) -> AxResult<usize> {
     #[cfg(not(target_arch = "aarch64"))]
     let vm_config = AxVMConfig::from(vm_create_config.clone());
 
+    // The measured boot log of the VM starts with its config.
+    super::measure::start(vm_config.id(), &ext_cfg.source);
+    let shared_memory = ext_cfg.shared_memory.clone();
+    // Needed by the FDT setup and the image loader, e.g. for the guest command line.
+    super::ext_config::insert(vm_config.id(), ext_cfg);
+
     // Handle FDT-related operations for aarch64
     #[cfg(target_arch = "aarch64")]
     handle_fdt_operations(&mut vm_config, &vm_create_config);
@@ -194,11 +288,27 @@ pub fn init_guest_vm(raw_cfg: &str) -> AxResult<usize> {
     info!("Creating VM[{}] {:?}", vm_config.id(), vm_config.name());
 
     // Create VM.
-    let vm = VM::new(vm_config).expect("Failed to create VM");
-    let vm_id = vm.id();
+    let vm_id = vm_config.id();
+    let vm = match VM::new(vm_config) {
+        Ok(vm) => vm,
+        Err(err) => {
+            error!("VM[{vm_id}] cannot be created: {err:?}");
+            discard_vm(vm_id);
+            return Err(err);
+        }
+    };
     push_vm(vm.clone());
 
-    vm_alloc_memorys(&vm_create_config, &vm);
+    if let Err(err) = vm_alloc_memorys(&vm_create_config, &vm) {
+        error!("VM[{vm_id}] failed to set up its memory: {err:?}");
+        discard_vm(vm_id);
+        return Err(err);
+    }
+    if let Err(err) = super::shm::attach(&vm, &shared_memory) {
+        error!("VM[{vm_id}] failed to map its shared memory: {err:?}");
+        discard_vm(vm_id);
+        return Err(err);
+    }
 
     let main_mem = vm
         .memory_regions()
@@ -206,35 +316,113 @@ pub fn init_guest_vm(raw_cfg: &str) -> AxResult<usize> {
         .cloned()
         .expect("VM must have at least one memory region");
 
-    config_guest_address(&vm, &main_mem);
+    config_guest_address(&vm, &main_mem, linux_header.as_ref());
 
     // Load corresponding images for VM.
     info!("VM[{}] created success, loading images...", vm.id());
 
     let mut loader = ImageLoader::new(main_mem, vm_create_config, vm.clone());
-    loader.load().expect("Failed to load VM images");
+    if let Err(err) = loader.load() {
+        // A VM whose images cannot be loaded, e.g. corrupted ones, is not created.
+        error!("VM[{vm_id}] failed to load its images: {err:?}");
+        drop(loader);
+        discard_vm(vm_id);
+        return Err(err);
+    }
 
-    if let Err(e) = vm.init() {
-        panic!("VM[{}] setup failed: {:?}", vm.id(), e);
+    if let Err(err) = vm.init() {
+        error!("VM[{vm_id}] setup failed: {err:?}");
+        discard_vm(vm_id);
+        return Err(err);
     }
 
+    super::emu::setup_emu_devices(&vm);
+
     vm.set_vm_status(axvm::VMStatus::Loaded);
 
     Ok(vm_id)
 }
 
-fn config_guest_address(vm: &VM, main_memory: &VMMemoryRegion) {
+/// Loads the images of a stopped VM again into its memory, which is zeroed first, so that it
+/// boots as it did when it was created. Memory, devices and state kept by VM ID are reused.
+///
+/// The images are measured again into a new measured boot log, and checked against the digests
+/// of the config (`*_sha256`) only, so images updated since the VM was created are booted. A
+/// restart event tells whether they changed. On failure the VM is left `Stopped`.
+pub(super) fn reload_guest_vm(vm: &VMRef) -> AxResult {
+    let vm_id = vm.id();
+    let ext_cfg = super::ext_config::get(vm_id);
+    let (vm_create_config, _) =
+        super::validate::parse_and_validate_replacing(&ext_cfg.source, vm_id).map_err(
+            |diagnostics| {
+                for diagnostic in &diagnostics {
+                    error!("Invalid VM config: {diagnostic}");
+                }
+                ax_err_type!(
+                    InvalidInput,
+                    format!("VM config has {} error(s)", diagnostics.len())
+                )
+            },
+        )?;
+
+    super::scrub::zero_memory(vm, ext_cfg.scrub_all_memory);
+
+    let main_mem = vm
+        .memory_regions()
+        .first()
+        .cloned()
+        .expect("VM must have at least one memory region");
+    let previous = super::measure::log(vm_id).map(|log| log.chain);
+    super::measure::start(vm_id, &ext_cfg.source);
+    ImageLoader::new(main_mem, vm_create_config, vm.clone()).load()?;
+
+    let chain = super::measure::log(vm_id).map(|log| log.chain);
+    let detail = match chain {
+        Some(chain) if Some(chain) != previous => format!(
+            "images changed, measurement chain {}",
+            super::measure::hex(&chain)
+        ),
+        _ => String::from("images unchanged"),
+    };
+    super::events::record(vm_id, VmEventKind::Restart, detail);
+    info!("VM[{vm_id}] images loaded again");
+    Ok(())
+}
+
+/// Forgets a VM that failed to be loaded, but for its shared memory.
+fn discard_vm(vm_id: usize) {
+    remove_vm(vm_id);
+    super::ext_config::remove(vm_id);
+    super::measure::clear(vm_id);
+}
+
+fn config_guest_address(vm: &VM, main_memory: &VMMemoryRegion, linux: Option<&LinuxHeader>) {
     const MB: usize = 1024 * 1024;
     vm.with_config(|config| {
         if main_memory.is_identical() {
-            debug!(
-                "Adjusting kernel load address from {:#x} to {:#x}",
-                config.image_config.kernel_load_gpa, main_memory.gpa
-            );
             let mut kernel_addr = main_memory.gpa;
             if config.image_config.bios_load_gpa.is_some() {
                 kernel_addr += MB * 2; // leave 2MB for BIOS
             }
+            // Place Linux kernels as their boot header requires.
+            if let Some(linux) = linux
+                && let Some(addr) = linux.next_load_addr(kernel_addr.as_usize())
+            {
+                kernel_addr = GuestPhysAddr::from(addr);
+                let end = main_memory.gpa.as_usize() + main_memory.size();
+                if let Some(size) = linux.memory_size()
+                    && addr.saturating_add(size) > end
+                {
+                    warn!(
+                        "VM[{}] the kernel needs {size:#x} bytes from {addr:#x}, but main memory ends at {end:#x}",
+                        vm.id()
+                    );
+                }
+            }
+            debug!(
+                "Adjusting kernel load address from {:#x} to {:#x}",
+                config.image_config.kernel_load_gpa, kernel_addr
+            );
 
             config.image_config.kernel_load_gpa = kernel_addr;
             config.cpu_config.bsp_entry = kernel_addr;
@@ -243,29 +431,83 @@ fn config_guest_address(vm: &VM, main_memory: &VMMemoryRegion) {
     });
 }
 
-fn vm_alloc_memorys(vm_create_config: &AxVMCrateConfig, vm: &VM) {
-    const MB: usize = 1024 * 1024;
-    const ALIGN: usize = 2 * MB;
-
+fn vm_alloc_memorys(vm_create_config: &AxVMCrateConfig, vm: &VM) -> AxResult {
     for memory in &vm_create_config.kernel.memory_regions {
+        let align = match memory.map_type {
+            // The guest address is the host one, so the region is placed where a kernel can be
+            // loaded at its start.
+            VmMemMappingType::MapIdentical => PLACEMENT_ALIGN,
+            _ => region_align(memory.gpa, memory.size),
+        };
+        let layout = Layout::from_size_align(memory.size, align).map_err(|_| {
+            ax_err_type!(
+                InvalidInput,
+                format!("invalid memory region of {:#x} bytes", memory.size)
+            )
+        })?;
         match memory.map_type {
             VmMemMappingType::MapAlloc => {
-                vm.alloc_memory_region(
-                    Layout::from_size_align(memory.size, ALIGN).unwrap(),
-                    Some(GuestPhysAddr::from(memory.gpa)),
-                )
-                .expect("Failed to allocate memory region for VM");
+                vm.alloc_memory_region(layout, Some(GuestPhysAddr::from(memory.gpa)))?;
             }
             VmMemMappingType::MapIdentical => {
-                vm.alloc_memory_region(Layout::from_size_align(memory.size, ALIGN).unwrap(), None)
-                    .expect("Failed to allocate memory region for VM");
+                vm.alloc_memory_region(layout, None)?;
             }
             VmMemMappingType::MapReserved => {
                 info!("VM[{}] map same region: {:#x?}", vm.id(), memory);
-                let layout = Layout::from_size_align(memory.size, ALIGN).unwrap();
-                vm.map_reserved_memory_region(layout, Some(GuestPhysAddr::from(memory.gpa)))
-                    .expect("Failed to map memory region for VM");
+                vm.map_reserved_memory_region(layout, Some(GuestPhysAddr::from(memory.gpa)))?;
             }
         }
+        apply_region_flags(vm, memory)?;
     }
+    Ok(())
+}
+
+/// The alignment a memory region at `gpa` of `size` bytes needs: 2 MiB when both are 2 MiB
+/// aligned, so that it can be mapped with block entries, and a page otherwise.
+fn region_align(gpa: usize, size: usize) -> usize {
+    const BLOCK_SIZE: usize = 0x20_0000;
+    if gpa.is_multiple_of(BLOCK_SIZE) && size.is_multiple_of(BLOCK_SIZE) {
+        BLOCK_SIZE
+    } else {
+        PAGE_SIZE_4K
+    }
+}
+
+/// Maps the memory region allocated for `memory` with the stage-2 permissions and attributes of
+/// its config. `axvm` maps every region it allocates as read-write-execute normal memory, so a
+/// region is only mapped again when its config asks for something else, before any vCPU of the
+/// VM runs.
+fn apply_region_flags(vm: &VM, memory: &VmMemConfig) -> AxResult {
+    let flags = MappingFlags::from_bits_truncate(memory.flags);
+    let restricted =
+        !flags.contains(MappingFlags::READ | MappingFlags::WRITE | MappingFlags::EXECUTE);
+    if !restricted && !flags.intersects(MappingFlags::DEVICE | MappingFlags::UNCACHED) {
+        return Ok(());
+    }
+    // Identical regions get their address from the allocator, the last one allocated is the
+    // region of `memory`.
+    let region = vm
+        .memory_regions()
+        .into_iter()
+        .rev()
+        .find(|region| match memory.map_type {
+            VmMemMappingType::MapIdentical => region.is_identical() && region.size() == memory.size,
+            _ => region.gpa == GuestPhysAddr::from(memory.gpa),
+        })
+        .ok_or_else(|| {
+            ax_err_type!(
+                NotFound,
+                format!("no memory region allocated at {:#x}", memory.gpa)
+            )
+        })?;
+    let hpa = std::os::arceos::modules::axhal::mem::virt_to_phys(region.hva);
+    info!(
+        "VM[{}] memory region [{:#x}, {:#x}) mapped with {:?}",
+        vm.id(),
+        region.gpa,
+        region.gpa + region.size(),
+        flags
+    );
+    vm.unmap_region(region.gpa, region.size())?;
+    vm.map_region(region.gpa, hpa, region.size(), flags)
 }
@@ -0,0 +1,224 @@
+// Copyright 2025 The Axvisor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Emulated devices implemented by AxVisor itself.
+//!
+//! `axdevice` only instantiates the device types it knows about. Entries of `emu_devices` whose
+//! `emu_type` is `0x0` (dummy) and whose name matches one of the device models in this module are
+//! instantiated here instead, and registered as MMIO devices of the VM after `vm.init()`.
+//!
+//! ```toml
+//! emu_devices = [
+//!   # Name        Base-Ipa     Ipa_len  Alloc-Irq Emu-Type EmuConfig
+//!   ["arm,pl031", 0x0901_0000, 0x1000, 34,       0x0,     [0]],
+//!   ["arm,sp805", 0x0902_0000, 0x1000, 35,       0x0,     [0]],
+//!   ["qemu,pvpanic-mmio", 0x0903_0000, 0x2, 0,   0x0,     [0]],
+//!   ["virtio,rng", 0x0a00_0000, 0x200,  48,       0x0,     [0]],
+//! ]
+//! ```
+
+pub mod pl031;
+pub mod pvpanic;
+pub mod virtio;
+pub mod watchdog;
+
+use alloc::{string::String, sync::Arc, vec::Vec};
+
+use axaddrspace::GuestPhysAddr;
+use axdevice_base::BaseDeviceOps;
+use axvm::config::{EmulatedDeviceConfig, EmulatedDeviceType};
+use cpumask::CpuMask;
+
+use crate::vmm::{
+    VMRef,
+    ext_config::{self, ShareConfig},
+    vm_list,
+};
+
+/// Frequency of the clock driving the emulated devices, also exposed to the guest as the
+/// `apb-pclk` fixed clock.
+pub const EMU_CLOCK_HZ: u64 = 24_000_000;
+
+/// Description of the device tree node of an emulated device.
+///
+/// Used by the FDT generation code to expose hypervisor-side devices to the guest.
+#[cfg_attr(not(target_arch = "aarch64"), allow(dead_code))]
+#[derive(Debug, Clone)]
+pub struct EmuDeviceNode {
+    /// Node name without unit address, e.g. `rtc`.
+    pub name: &'static str,
+    /// Values of the `compatible` property.
+    pub compatible: &'static [&'static str],
+    /// Base guest physical address of the device.
+    pub base_gpa: usize,
+    /// Length of the MMIO region.
+    pub length: usize,
+    /// GIC interrupt ID (INTID) of the device, if any.
+    pub irq_id: Option<usize>,
+    /// Values of the `clock-names` property, all backed by the `apb-pclk` fixed clock.
+    pub clocks: &'static [&'static str],
+}
+
+#[cfg_attr(not(target_arch = "aarch64"), allow(dead_code))]
+impl EmuDeviceNode {
+    /// Returns the node name with its unit address, e.g. `rtc@9010000`.
+    pub fn node_name(&self) -> String {
+        format!("{}@{:x}", self.name, self.base_gpa)
+    }
+}
+
+/// Returns whether the given `emu_devices` entry is handled by this module.
+fn is_hv_device(dev: &EmulatedDeviceConfig) -> bool {
+    dev.emu_type == EmulatedDeviceType::Dummy
+}
+
+/// Instantiates the hypervisor-side emulated devices declared in the VM config and registers
+/// them on the VM.
+///
+/// Must be called after `vm.init()`.
+pub fn setup_emu_devices(vm: &VMRef) {
+    let emu_devices: Vec<EmulatedDeviceConfig> = vm.with_config(|cfg| cfg.emu_devices().to_vec());
+
+    for dev in emu_devices.iter().filter(|dev| is_hv_device(dev)) {
+        match dev.name.as_str() {
+            pl031::COMPATIBLE => {
+                let rtc = pl031::Pl031::new(vm.id(), dev);
+                info!(
+                    "VM[{}] emulated PL031 RTC at {:#x}, irq {}",
+                    vm.id(),
+                    dev.base_gpa,
+                    dev.irq_id
+                );
+                register_mmio_device(vm, Arc::new(rtc));
+            }
+            pvpanic::COMPATIBLE => {
+                let pvpanic = pvpanic::PvPanic::new(vm.id(), dev);
+                info!("VM[{}] emulated pvpanic at {:#x}", vm.id(), dev.base_gpa);
+                register_mmio_device(vm, Arc::new(pvpanic));
+            }
+            virtio::rng::COMPATIBLE => {
+                let rng = virtio::rng::VirtioRng::new(vm.id(), dev);
+                info!(
+                    "VM[{}] emulated virtio-rng at {:#x}, irq {}",
+                    vm.id(),
+                    dev.base_gpa,
+                    dev.irq_id
+                );
+                let transport =
+                    virtio::VirtioMmio::new(vm.id(), dev.base_gpa, dev.length, dev.irq_id, rng);
+                register_mmio_device(vm, Arc::new(transport));
+            }
+            watchdog::SP805_COMPATIBLE => {
+                let wdt = watchdog::Sp805::new(vm.id(), dev);
+                info!(
+                    "VM[{}] emulated SP805 watchdog at {:#x}, irq {}",
+                    vm.id(),
+                    dev.base_gpa,
+                    dev.irq_id
+                );
+                register_mmio_device(vm, Arc::new(wdt));
+            }
+            watchdog::GENERIC_COMPATIBLE => {
+                let wdt = watchdog::GenericWdt::new(vm.id(), dev);
+                info!("VM[{}] emulated watchdog at {:#x}", vm.id(), dev.base_gpa);
+                register_mmio_device(vm, Arc::new(wdt));
+            }
+            name => {
+                warn!(
+                    "VM[{}] unknown emulated device {:?}, ignored",
+                    vm.id(),
+                    name
+                );
+            }
+        }
+    }
+
+    for share in ext_config::get(vm.id()).shares {
+        setup_share(vm, share);
+    }
+}
+
+#[cfg(feature = "fs")]
+fn setup_share(vm: &VMRef, share: ShareConfig) {
+    info!(
+        "VM[{}] sharing {} through virtio-9p at {:#x}, irq {}",
+        vm.id(),
+        share.describe(),
+        share.base_gpa,
+        share.irq_id
+    );
+    let (base_gpa, length, irq_id) = (share.base_gpa, share.length, share.irq_id);
+    let p9 = virtio::p9::VirtioP9::new(share);
+    let transport = virtio::VirtioMmio::new(vm.id(), base_gpa, length, irq_id, p9);
+    register_mmio_device(vm, Arc::new(transport));
+}
+
+#[cfg(not(feature = "fs"))]
+fn setup_share(vm: &VMRef, share: ShareConfig) {
+    warn!(
+        "VM[{}] share {} needs the \"fs\" feature, ignored",
+        vm.id(),
+        share.describe()
+    );
+}
+
+/// Returns the device tree nodes of the hypervisor-side emulated devices of the VM.
+#[cfg_attr(not(target_arch = "aarch64"), allow(dead_code))]
+pub fn fdt_nodes(vm: &VMRef) -> Vec<EmuDeviceNode> {
+    let mut nodes: Vec<EmuDeviceNode> = vm.with_config(|cfg| {
+        cfg.emu_devices()
+            .iter()
+            .filter(|dev| is_hv_device(dev))
+            .filter_map(|dev| match dev.name.as_str() {
+                pl031::COMPATIBLE => Some(pl031::fdt_node(dev)),
+                pvpanic::COMPATIBLE => Some(pvpanic::fdt_node(dev)),
+                virtio::rng::COMPATIBLE => {
+                    Some(virtio::fdt_node(dev.base_gpa, dev.length, dev.irq_id))
+                }
+                watchdog::SP805_COMPATIBLE => Some(watchdog::sp805_fdt_node(dev)),
+                watchdog::GENERIC_COMPATIBLE => Some(watchdog::generic_fdt_node(dev)),
+                _ => None,
+            })
+            .collect()
+    });
+    if cfg!(feature = "fs") {
+        nodes.extend(
+            ext_config::get(vm.id())
+                .shares
+                .iter()
+                .map(|share| virtio::fdt_node(share.base_gpa, share.length, share.irq_id)),
+        );
+    }
+    nodes
+}
+
+/// Registers an emulated MMIO device on the VM.
+fn register_mmio_device(vm: &VMRef, dev: Arc<dyn BaseDeviceOps<axaddrspace::GuestPhysAddrRange>>) {
+    vm.get_devices().add_mmio_dev(dev);
+}
+
+/// Injects the interrupt of an emulated device into the primary vCPU of the VM.
+pub(crate) fn inject_irq(vm_id: usize, irq_id: usize) {
+    let Some(vm) = vm_list::get_vm_by_id(vm_id) else {
+        return;
+    };
+    if let Err(err) = vm.inject_interrupt_to_vcpu(CpuMask::one_shot(0), irq_id) {
+        warn!("VM[{vm_id}] failed to inject emulated device irq {irq_id}: {err:?}");
+    }
+}
+
+/// Returns the offset of `addr` from the start of the device.
+pub(crate) fn reg_offset(addr: GuestPhysAddr, base: GuestPhysAddr) -> usize {
+    addr.as_usize() - base.as_usize()
+}
@@ -0,0 +1,254 @@
+// Copyright 2025 The Axvisor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Emulated ARM PrimeCell PL031 real time clock.
+//!
+//! The time seen by the guest is `axhal::time::wall_time()` plus a per-VM offset in seconds. The
+//! offset is initialized from the first entry of the device's `cfg_list` (seconds to add, a second
+//! entry of `1` makes it negative), changed by guest writes to `RTCLR` and by the `vm settime`
+//! shell command. Offsets are kept per VM ID until the VM is deleted, so they survive `vm restart`
+//! and the VM being set up again.
+
+use alloc::{collections::BTreeMap, sync::Arc};
+use core::time::Duration;
+use std::os::arceos::modules::axhal;
+
+use axaddrspace::{GuestPhysAddr, GuestPhysAddrRange, device::AccessWidth};
+use axdevice_base::{BaseDeviceOps, EmuDeviceType};
+use axerrno::AxResult;
+use axvm::config::EmulatedDeviceConfig;
+use kspin::SpinNoIrq;
+
+use super::EmuDeviceNode;
+use crate::vmm::timer;
+
+/// Name of `emu_devices` entries handled by this device model.
+pub const COMPATIBLE: &str = "arm,pl031";
+
+/// Data register, current time in seconds.
+const RTCDR: usize = 0x000;
+/// Match register.
+const RTCMR: usize = 0x004;
+/// Load register, writing it sets the current time.
+const RTCLR: usize = 0x008;
+/// Control register, bit 0 starts the counter.
+const RTCCR: usize = 0x00c;
+/// Interrupt mask set/clear register.
+const RTCIMSC: usize = 0x010;
+/// Raw interrupt status register.
+const RTCRIS: usize = 0x014;
+/// Masked interrupt status register.
+const RTCMIS: usize = 0x018;
+/// Interrupt clear register.
+const RTCICR: usize = 0x01c;
+/// Start of the peripheral and PrimeCell identification registers.
+const RTC_ID_BASE: usize = 0xfe0;
+
+/// `RTCPeriphID0-3` followed by `RTCPCellID0-3`.
+const RTC_ID: [u32; 8] = [0x31, 0x10, 0x14, 0x00, 0x0d, 0xf0, 0x05, 0xb1];
+
+/// Per-VM offsets of the guest RTC relative to the host wall time, in seconds.
+static RTC_OFFSETS: SpinNoIrq<BTreeMap<usize, i64>> = SpinNoIrq::new(BTreeMap::new());
+
+/// Returns the RTC offset of the VM in seconds.
+pub fn offset(vm_id: usize) -> i64 {
+    RTC_OFFSETS.lock().get(&vm_id).copied().unwrap_or(0)
+}
+
+/// Sets the RTC offset of the VM in seconds.
+pub fn set_offset(vm_id: usize, offset: i64) {
+    RTC_OFFSETS.lock().insert(vm_id, offset);
+}
+
+/// Forgets the RTC offset of a deleted VM.
+pub fn clear(vm_id: usize) {
+    RTC_OFFSETS.lock().remove(&vm_id);
+}
+
+/// Sets the guest RTC of the VM to the given UNIX time in seconds.
+pub fn set_time(vm_id: usize, unix_secs: u64) {
+    set_offset(vm_id, unix_secs as i64 - host_secs());
+}
+
+/// Returns the current guest RTC time of the VM in seconds.
+pub fn guest_time(vm_id: usize) -> u64 {
+    (host_secs() + offset(vm_id)).max(0) as u64
+}
+
+fn host_secs() -> i64 {
+    axhal::time::wall_time().as_secs() as i64
+}
+
+/// Mutable register state of the RTC.
+struct Pl031State {
+    mr: u32,
+    cr: u32,
+    imsc: u32,
+    ris: u32,
+    /// Token of the pending match timer.
+    match_timer: Option<usize>,
+    /// Bumped whenever the match timer is re-armed, so stale callbacks are ignored.
+    generation: u64,
+}
+
+/// An emulated PL031 RTC.
+pub struct Pl031 {
+    vm_id: usize,
+    base: GuestPhysAddr,
+    length: usize,
+    irq_id: usize,
+    state: Arc<SpinNoIrq<Pl031State>>,
+}
+
+impl Pl031 {
+    /// Creates the RTC of a VM from its `emu_devices` entry. The offset of the config only applies
+    /// if the VM has none yet.
+    pub fn new(vm_id: usize, cfg: &EmulatedDeviceConfig) -> Self {
+        let secs = cfg.cfg_list.first().copied().unwrap_or(0) as i64;
+        let negative = cfg.cfg_list.get(1).copied().unwrap_or(0) != 0;
+        RTC_OFFSETS
+            .lock()
+            .entry(vm_id)
+            .or_insert(if negative { -secs } else { secs });
+
+        Self {
+            vm_id,
+            base: GuestPhysAddr::from(cfg.base_gpa),
+            length: cfg.length,
+            irq_id: cfg.irq_id,
+            state: Arc::new(SpinNoIrq::new(Pl031State {
+                mr: 0,
+                cr: 1,
+                imsc: 0,
+                ris: 0,
+                match_timer: None,
+                generation: 0,
+            })),
+        }
+    }
+
+    /// Re-arms the match interrupt timer according to `RTCMR` and `RTCIMSC`.
+    fn update_match_timer(&self, state: &mut Pl031State) {
+        if let Some(token) = state.match_timer.take() {
+            timer::cancel_timer(token);
+        }
+        state.generation += 1;
+
+        let now = guest_time(self.vm_id) as u32;
+        if state.imsc & 1 == 0 || state.mr <= now {
+            return;
+        }
+
+        let deadline = axhal::time::wall_time() + Duration::from_secs((state.mr - now) as u64);
+        let generation = state.generation;
+        let vm_id = self.vm_id;
+        let irq_id = self.irq_id;
+        let weak_state = Arc::downgrade(&self.state);
+
+        state.match_timer = Some(timer::register_timer(
+            deadline.as_nanos() as u64,
+            move |_| {
+                // The device may have been dropped together with its VM.
+                let Some(state) = weak_state.upgrade() else {
+                    return;
+                };
+                let mut state = state.lock();
+                if state.generation != generation {
+                    return;
+                }
+                state.match_timer = None;
+                state.ris |= 1;
+                if state.imsc & 1 != 0 {
+                    super::inject_irq(vm_id, irq_id);
+                }
+            },
+        ));
+    }
+}
+
+impl BaseDeviceOps<GuestPhysAddrRange> for Pl031 {
+    fn emu_type(&self) -> EmuDeviceType {
+        EmuDeviceType::Dummy
+    }
+
+    fn address_range(&self) -> GuestPhysAddrRange {
+        GuestPhysAddrRange::from_start_size(self.base, self.length)
+    }
+
+    fn handle_read(&self, addr: GuestPhysAddr, _width: AccessWidth) -> AxResult<usize> {
+        let offset = super::reg_offset(addr, self.base);
+        let state = self.state.lock();
+        let val = match offset {
+            RTCDR => guest_time(self.vm_id) as u32,
+            RTCMR => state.mr,
+            RTCLR => guest_time(self.vm_id) as u32,
+            RTCCR => state.cr,
+            RTCIMSC => state.imsc,
+            RTCRIS => state.ris,
+            RTCMIS => state.ris & state.imsc,
+            RTC_ID_BASE..0x1000 if offset.is_multiple_of(4) => RTC_ID[(offset - RTC_ID_BASE) / 4],
+            _ => {
+                debug!("VM[{}] PL031 read from unknown offset {offset:#x}", self.vm_id);
+                0
+            }
+        };
+        Ok(val as usize)
+    }
+
+    fn handle_write(&self, addr: GuestPhysAddr, _width: AccessWidth, val: usize) -> AxResult {
+        let offset = super::reg_offset(addr, self.base);
+        let val = val as u32;
+        let mut state = self.state.lock();
+        match offset {
+            RTCMR => {
+                state.mr = val;
+                self.update_match_timer(&mut state);
+            }
+            RTCLR => {
+                info!("VM[{}] guest sets RTC to {val}", self.vm_id);
+                set_time(self.vm_id, val as u64);
+                self.update_match_timer(&mut state);
+            }
+            RTCCR => {
+                // The counter cannot be stopped once started, as on real hardware.
+                state.cr |= val & 1;
+            }
+            RTCIMSC => {
+                state.imsc = val & 1;
+                self.update_match_timer(&mut state);
+            }
+            RTCICR => state.ris &= !val,
+            _ => {
+                debug!(
+                    "VM[{}] PL031 write {val:#x} to read-only or unknown offset {offset:#x}",
+                    self.vm_id
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Returns the device tree node of the RTC.
+#[cfg_attr(not(target_arch = "aarch64"), allow(dead_code))]
+pub fn fdt_node(cfg: &EmulatedDeviceConfig) -> EmuDeviceNode {
+    EmuDeviceNode {
+        name: "rtc",
+        compatible: &["arm,pl031", "arm,primecell"],
+        base_gpa: cfg.base_gpa,
+        length: cfg.length,
+        irq_id: (cfg.irq_id != 0).then_some(cfg.irq_id),
+        clocks: &["apb_pclk"],
+    }
+}
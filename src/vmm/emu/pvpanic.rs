@@ -0,0 +1,144 @@
+// Copyright 2025 The Axvisor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Emulated pvpanic device, as implemented by QEMU's `pvpanic-mmio`.
+//!
+//! The device has a single byte register. Reading it returns the events supported by the device,
+//! the guest writes the event it wants to report. Every reported event is recorded in the VM's
+//! [`events`] log. On `PVPANIC_PANICKED`, the action selected by the first entry of `cfg_list` is
+//! taken: `0` pauses the VM for inspection, `1` restarts it, `2` shuts it down.
+
+use alloc::string::String;
+
+use axaddrspace::{GuestPhysAddr, GuestPhysAddrRange, device::AccessWidth};
+use axdevice_base::{BaseDeviceOps, EmuDeviceType};
+use axerrno::AxResult;
+use axvm::config::EmulatedDeviceConfig;
+
+use super::EmuDeviceNode;
+use crate::vmm::{
+    events::{self, VmEventKind},
+    lifecycle,
+};
+
+/// Name of `emu_devices` entries handled by this device model.
+pub const COMPATIBLE: &str = "qemu,pvpanic-mmio";
+
+/// The guest panicked.
+const PVPANIC_PANICKED: usize = 1 << 0;
+/// The guest loaded a crash kernel, it will take care of the panic itself.
+const PVPANIC_CRASH_LOADED: usize = 1 << 1;
+/// The guest is shutting down normally.
+const PVPANIC_SHUTDOWN: usize = 1 << 2;
+
+/// Events supported by the device.
+const PVPANIC_EVENTS: usize = PVPANIC_PANICKED | PVPANIC_CRASH_LOADED | PVPANIC_SHUTDOWN;
+
+/// What to do with the VM when the guest reports a panic.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PanicAction {
+    /// Keep the VM paused for inspection.
+    Pause,
+    /// Stop and boot the VM again.
+    Restart,
+    /// Stop the VM.
+    Shutdown,
+}
+
+/// An emulated pvpanic device.
+pub struct PvPanic {
+    vm_id: usize,
+    base: GuestPhysAddr,
+    length: usize,
+    action: PanicAction,
+}
+
+impl PvPanic {
+    /// Creates the pvpanic device of a VM from its `emu_devices` entry.
+    pub fn new(vm_id: usize, cfg: &EmulatedDeviceConfig) -> Self {
+        let action = match cfg.cfg_list.first().copied().unwrap_or(0) {
+            0 => PanicAction::Pause,
+            1 => PanicAction::Restart,
+            2 => PanicAction::Shutdown,
+            other => {
+                warn!("VM[{vm_id}] unknown pvpanic action {other}, using pause");
+                PanicAction::Pause
+            }
+        };
+
+        Self {
+            vm_id,
+            base: GuestPhysAddr::from(cfg.base_gpa),
+            length: cfg.length,
+            action,
+        }
+    }
+}
+
+impl BaseDeviceOps<GuestPhysAddrRange> for PvPanic {
+    fn emu_type(&self) -> EmuDeviceType {
+        EmuDeviceType::Dummy
+    }
+
+    fn address_range(&self) -> GuestPhysAddrRange {
+        GuestPhysAddrRange::from_start_size(self.base, self.length)
+    }
+
+    fn handle_read(&self, addr: GuestPhysAddr, _width: AccessWidth) -> AxResult<usize> {
+        match super::reg_offset(addr, self.base) {
+            0 => Ok(PVPANIC_EVENTS),
+            _ => Ok(0),
+        }
+    }
+
+    fn handle_write(&self, addr: GuestPhysAddr, _width: AccessWidth, val: usize) -> AxResult {
+        if super::reg_offset(addr, self.base) != 0 {
+            return Ok(());
+        }
+
+        // A guest with a crash kernel loaded reports it instead of the panic, and handles the
+        // panic itself.
+        if val & PVPANIC_CRASH_LOADED != 0 {
+            events::record(self.vm_id, VmEventKind::GuestCrashLoaded, String::new());
+        } else if val & PVPANIC_PANICKED != 0 {
+            events::record(
+                self.vm_id,
+                VmEventKind::GuestPanic,
+                format!("action: {:?}", self.action),
+            );
+            match self.action {
+                PanicAction::Pause => lifecycle::pause_vm(self.vm_id),
+                PanicAction::Restart => lifecycle::spawn_restart(self.vm_id),
+                PanicAction::Shutdown => lifecycle::stop_vm(self.vm_id),
+            }
+        }
+        if val & PVPANIC_SHUTDOWN != 0 {
+            events::record(self.vm_id, VmEventKind::GuestShutdown, String::new());
+        }
+        Ok(())
+    }
+}
+
+/// Returns the device tree node of the pvpanic device.
+#[cfg_attr(not(target_arch = "aarch64"), allow(dead_code))]
+pub fn fdt_node(cfg: &EmulatedDeviceConfig) -> EmuDeviceNode {
+    EmuDeviceNode {
+        name: "pvpanic",
+        compatible: &["qemu,pvpanic-mmio"],
+        base_gpa: cfg.base_gpa,
+        length: cfg.length,
+        irq_id: None,
+        clocks: &[],
+    }
+}
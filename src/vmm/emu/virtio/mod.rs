@@ -0,0 +1,377 @@
+// Copyright 2025 The Axvisor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Virtio devices over the virtio-mmio transport (version 2, split virtqueues).
+//!
+//! [`VirtioMmio`] implements the transport registers and the virtqueues, the device models only
+//! implement [`VirtioDevice`]. Requests are handled outside of the transport lock, which keeps
+//! IRQs disabled, since device models may block on I/O.
+
+#[cfg(feature = "fs")]
+pub mod p9;
+pub mod queue;
+pub mod rng;
+
+use alloc::vec::Vec;
+use std::sync::Mutex;
+
+use axaddrspace::{GuestPhysAddr, GuestPhysAddrRange, device::AccessWidth};
+use axdevice_base::{BaseDeviceOps, EmuDeviceType};
+use axerrno::AxResult;
+use kspin::SpinNoIrq;
+
+use self::queue::{DescChain, QUEUE_SIZE_MAX, Queue};
+use super::EmuDeviceNode;
+use crate::vmm::{VMRef, vm_list};
+
+const VIRTIO_MMIO_MAGIC_VALUE: usize = 0x000;
+const VIRTIO_MMIO_VERSION: usize = 0x004;
+const VIRTIO_MMIO_DEVICE_ID: usize = 0x008;
+const VIRTIO_MMIO_VENDOR_ID: usize = 0x00c;
+const VIRTIO_MMIO_DEVICE_FEATURES: usize = 0x010;
+const VIRTIO_MMIO_DEVICE_FEATURES_SEL: usize = 0x014;
+const VIRTIO_MMIO_DRIVER_FEATURES: usize = 0x020;
+const VIRTIO_MMIO_DRIVER_FEATURES_SEL: usize = 0x024;
+const VIRTIO_MMIO_QUEUE_SEL: usize = 0x030;
+const VIRTIO_MMIO_QUEUE_NUM_MAX: usize = 0x034;
+const VIRTIO_MMIO_QUEUE_NUM: usize = 0x038;
+const VIRTIO_MMIO_QUEUE_READY: usize = 0x044;
+const VIRTIO_MMIO_QUEUE_NOTIFY: usize = 0x050;
+const VIRTIO_MMIO_INTERRUPT_STATUS: usize = 0x060;
+const VIRTIO_MMIO_INTERRUPT_ACK: usize = 0x064;
+const VIRTIO_MMIO_STATUS: usize = 0x070;
+const VIRTIO_MMIO_QUEUE_DESC_LOW: usize = 0x080;
+const VIRTIO_MMIO_QUEUE_DESC_HIGH: usize = 0x084;
+const VIRTIO_MMIO_QUEUE_DRIVER_LOW: usize = 0x090;
+const VIRTIO_MMIO_QUEUE_DRIVER_HIGH: usize = 0x094;
+const VIRTIO_MMIO_QUEUE_DEVICE_LOW: usize = 0x0a0;
+const VIRTIO_MMIO_QUEUE_DEVICE_HIGH: usize = 0x0a4;
+const VIRTIO_MMIO_CONFIG_GENERATION: usize = 0x0fc;
+const VIRTIO_MMIO_CONFIG: usize = 0x100;
+
+/// "virt" in little endian.
+const VIRTIO_MAGIC: u32 = 0x7472_6976;
+/// "AXVS" in little endian.
+const VIRTIO_VENDOR_ID: u32 = 0x5356_5841;
+
+/// Feature bit of devices compliant with virtio 1.0 or later, required by virtio-mmio version 2.
+const VIRTIO_F_VERSION_1: u64 = 1 << 32;
+
+/// `InterruptStatus` bit signaling used buffers.
+const VIRTIO_MMIO_INT_VRING: u32 = 1 << 0;
+
+/// The driver has finished setting up the device.
+const VIRTIO_STATUS_DRIVER_OK: u32 = 4;
+
+/// A virtio device model behind a [`VirtioMmio`] transport.
+pub trait VirtioDevice: Send + 'static {
+    /// Virtio device ID, e.g. 4 for an entropy source.
+    fn device_id(&self) -> u32;
+
+    /// Device specific feature bits, `VIRTIO_F_VERSION_1` is added by the transport.
+    fn features(&self) -> u64 {
+        0
+    }
+
+    /// Number of virtqueues of the device.
+    fn num_queues(&self) -> usize;
+
+    /// Reads 32 bits of the device configuration space at the given offset.
+    fn read_config(&self, _offset: usize) -> u32 {
+        0
+    }
+
+    /// Called when the driver sets `DRIVER_OK`, with the negotiated feature bits.
+    fn activate(&mut self, _features: u64) {}
+
+    /// Handles a descriptor chain made available on `queue`. Returns the number of bytes written
+    /// to the chain.
+    fn handle_chain(&mut self, vm: &VMRef, queue: usize, chain: &DescChain) -> AxResult<usize>;
+}
+
+/// Transport state, shared by all the virtqueues of the device.
+struct Transport {
+    status: u32,
+    device_features_sel: u32,
+    driver_features_sel: u32,
+    driver_features: u64,
+    queue_sel: usize,
+    queues: Vec<Queue>,
+    interrupt_status: u32,
+}
+
+impl Transport {
+    fn reset(&mut self) {
+        self.status = 0;
+        self.driver_features = 0;
+        self.queue_sel = 0;
+        self.interrupt_status = 0;
+        self.queues.iter_mut().for_each(Queue::reset);
+    }
+
+    fn selected_queue(&mut self) -> Option<&mut Queue> {
+        self.queues.get_mut(self.queue_sel)
+    }
+}
+
+/// A virtio device exposed through the virtio-mmio transport.
+pub struct VirtioMmio<D> {
+    vm_id: usize,
+    base: GuestPhysAddr,
+    length: usize,
+    irq_id: usize,
+    transport: SpinNoIrq<Transport>,
+    device: Mutex<D>,
+}
+
+impl<D: VirtioDevice> VirtioMmio<D> {
+    /// Creates the transport of a device at `base_gpa`, raising `irq_id`.
+    pub fn new(vm_id: usize, base_gpa: usize, length: usize, irq_id: usize, device: D) -> Self {
+        let queues = (0..device.num_queues()).map(|_| Queue::default()).collect();
+        Self {
+            vm_id,
+            base: GuestPhysAddr::from(base_gpa),
+            length,
+            irq_id,
+            transport: SpinNoIrq::new(Transport {
+                status: 0,
+                device_features_sel: 0,
+                driver_features_sel: 0,
+                driver_features: 0,
+                queue_sel: 0,
+                queues,
+                interrupt_status: 0,
+            }),
+            device: Mutex::new(device),
+        }
+    }
+
+    fn device_features(&self) -> u64 {
+        self.device.lock().features() | VIRTIO_F_VERSION_1
+    }
+
+    /// Processes all the chains available on the queue, each one with the transport unlocked.
+    fn notify_queue(&self, index: usize) {
+        let Some(vm) = vm_list::get_vm_by_id(self.vm_id) else {
+            return;
+        };
+        let mut notify = false;
+        loop {
+            let popped = {
+                let mut transport = self.transport.lock();
+                if transport.status & VIRTIO_STATUS_DRIVER_OK == 0 {
+                    break;
+                }
+                match transport.queues.get_mut(index) {
+                    Some(queue) => queue.pop(&vm),
+                    None => break,
+                }
+            };
+            let chain = match popped {
+                Ok(Some(chain)) => chain,
+                Ok(None) => break,
+                Err(err) => {
+                    warn!("VM[{}] virtio queue {index} error: {err:?}", vm.id());
+                    break;
+                }
+            };
+            let written = self
+                .device
+                .lock()
+                .handle_chain(&vm, index, &chain)
+                .unwrap_or_else(|err| {
+                    warn!("VM[{}] virtio request failed: {err:?}", vm.id());
+                    0
+                });
+            let mut transport = self.transport.lock();
+            let Some(queue) = transport.queues.get_mut(index) else {
+                break;
+            };
+            if let Err(err) = queue.push_used(&vm, chain.head, written) {
+                warn!("VM[{}] virtio queue {index} error: {err:?}", vm.id());
+                break;
+            }
+            notify = true;
+        }
+        if notify {
+            self.transport.lock().interrupt_status |= VIRTIO_MMIO_INT_VRING;
+            super::inject_irq(self.vm_id, self.irq_id);
+        }
+    }
+}
+
+/// Replaces the low or high 32 bits of `reg`.
+fn set_half(reg: &mut u64, high: bool, val: u32) {
+    if high {
+        *reg = (*reg & 0xffff_ffff) | ((val as u64) << 32);
+    } else {
+        *reg = (*reg & !0xffff_ffff) | val as u64;
+    }
+}
+
+impl<D: VirtioDevice> BaseDeviceOps<GuestPhysAddrRange> for VirtioMmio<D> {
+    fn emu_type(&self) -> EmuDeviceType {
+        EmuDeviceType::Dummy
+    }
+
+    fn address_range(&self) -> GuestPhysAddrRange {
+        GuestPhysAddrRange::from_start_size(self.base, self.length)
+    }
+
+    fn handle_read(&self, addr: GuestPhysAddr, width: AccessWidth) -> AxResult<usize> {
+        let offset = super::reg_offset(addr, self.base);
+        // Registers of the device model are read without holding the transport lock.
+        match offset {
+            VIRTIO_MMIO_DEVICE_ID => return Ok(self.device.lock().device_id() as usize),
+            VIRTIO_MMIO_DEVICE_FEATURES => {
+                let sel = self.transport.lock().device_features_sel;
+                let val = match sel {
+                    0 => self.device_features() as u32,
+                    1 => (self.device_features() >> 32) as u32,
+                    _ => 0,
+                };
+                return Ok(val as usize);
+            }
+            VIRTIO_MMIO_CONFIG.. => {
+                // The configuration space may be read with any access width.
+                let val = self.device.lock().read_config(offset - VIRTIO_MMIO_CONFIG);
+                let val = match width.size() {
+                    1 => val & 0xff,
+                    2 => val & 0xffff,
+                    _ => val,
+                };
+                return Ok(val as usize);
+            }
+            _ => {}
+        }
+        let mut transport = self.transport.lock();
+        let val = match offset {
+            VIRTIO_MMIO_MAGIC_VALUE => VIRTIO_MAGIC,
+            VIRTIO_MMIO_VERSION => 2,
+            VIRTIO_MMIO_VENDOR_ID => VIRTIO_VENDOR_ID,
+            VIRTIO_MMIO_QUEUE_NUM_MAX => match transport.selected_queue() {
+                Some(_) => QUEUE_SIZE_MAX as u32,
+                None => 0,
+            },
+            VIRTIO_MMIO_QUEUE_READY => {
+                transport.selected_queue().is_some_and(|queue| queue.ready) as u32
+            }
+            VIRTIO_MMIO_INTERRUPT_STATUS => transport.interrupt_status,
+            VIRTIO_MMIO_STATUS => transport.status,
+            VIRTIO_MMIO_CONFIG_GENERATION => 0,
+            _ => 0,
+        };
+        Ok(val as usize)
+    }
+
+    fn handle_write(&self, addr: GuestPhysAddr, _width: AccessWidth, val: usize) -> AxResult {
+        let offset = super::reg_offset(addr, self.base);
+        let val = val as u32;
+        match offset {
+            VIRTIO_MMIO_QUEUE_NOTIFY => {
+                self.notify_queue(val as usize);
+                return Ok(());
+            }
+            VIRTIO_MMIO_STATUS if val & VIRTIO_STATUS_DRIVER_OK != 0 => {
+                let (activate, driver_features) = {
+                    let transport = self.transport.lock();
+                    let activate = transport.status & VIRTIO_STATUS_DRIVER_OK == 0;
+                    (activate, transport.driver_features)
+                };
+                if activate {
+                    let features = driver_features & self.device_features();
+                    self.device.lock().activate(features);
+                }
+                self.transport.lock().status = val;
+                return Ok(());
+            }
+            _ => {}
+        }
+        let mut transport = self.transport.lock();
+        match offset {
+            VIRTIO_MMIO_DEVICE_FEATURES_SEL => transport.device_features_sel = val,
+            VIRTIO_MMIO_DRIVER_FEATURES_SEL => transport.driver_features_sel = val,
+            VIRTIO_MMIO_DRIVER_FEATURES => {
+                let high = transport.driver_features_sel == 1;
+                set_half(&mut transport.driver_features, high, val);
+            }
+            VIRTIO_MMIO_QUEUE_SEL => transport.queue_sel = val as usize,
+            VIRTIO_MMIO_QUEUE_NUM => {
+                if let Some(queue) = transport.selected_queue() {
+                    queue.num = (val as u16).min(QUEUE_SIZE_MAX);
+                }
+            }
+            VIRTIO_MMIO_QUEUE_READY => {
+                if let Some(queue) = transport.selected_queue() {
+                    queue.ready = val & 1 != 0;
+                }
+            }
+            VIRTIO_MMIO_QUEUE_DESC_LOW | VIRTIO_MMIO_QUEUE_DESC_HIGH => {
+                if let Some(queue) = transport.selected_queue() {
+                    set_half(
+                        &mut queue.desc_table,
+                        offset == VIRTIO_MMIO_QUEUE_DESC_HIGH,
+                        val,
+                    );
+                }
+            }
+            VIRTIO_MMIO_QUEUE_DRIVER_LOW | VIRTIO_MMIO_QUEUE_DRIVER_HIGH => {
+                if let Some(queue) = transport.selected_queue() {
+                    set_half(
+                        &mut queue.avail_ring,
+                        offset == VIRTIO_MMIO_QUEUE_DRIVER_HIGH,
+                        val,
+                    );
+                }
+            }
+            VIRTIO_MMIO_QUEUE_DEVICE_LOW | VIRTIO_MMIO_QUEUE_DEVICE_HIGH => {
+                if let Some(queue) = transport.selected_queue() {
+                    set_half(
+                        &mut queue.used_ring,
+                        offset == VIRTIO_MMIO_QUEUE_DEVICE_HIGH,
+                        val,
+                    );
+                }
+            }
+            VIRTIO_MMIO_INTERRUPT_ACK => transport.interrupt_status &= !val,
+            VIRTIO_MMIO_STATUS => {
+                if val == 0 {
+                    transport.reset();
+                } else {
+                    transport.status = val;
+                }
+            }
+            _ => {
+                debug!(
+                    "VM[{}] virtio-mmio write {val:#x} to read-only or unknown offset {offset:#x}",
+                    self.vm_id
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Returns the device tree node of a virtio-mmio transport.
+#[cfg_attr(not(target_arch = "aarch64"), allow(dead_code))]
+pub fn fdt_node(base_gpa: usize, length: usize, irq_id: usize) -> EmuDeviceNode {
+    EmuDeviceNode {
+        name: "virtio_mmio",
+        compatible: &["virtio,mmio"],
+        base_gpa,
+        length,
+        irq_id: (irq_id != 0).then_some(irq_id),
+        clocks: &[],
+    }
+}
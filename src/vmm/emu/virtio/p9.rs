@@ -0,0 +1,663 @@
+// Copyright 2025 The Axvisor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Virtio-9p device exporting a hypervisor directory with the 9P2000.L protocol.
+//!
+//! Shares are declared with `[[devices.shares]]` (see [`crate::vmm::ext_config`]) and mounted in
+//! the guest with `mount -t 9p -o trans=virtio,version=9p2000.L <tag> <dir>`.
+//!
+//! The hypervisor filesystem has no notion of owners, links or timestamps: files are reported as
+//! owned by root, and only the subset of 9P2000.L needed for regular files and directories is
+//! implemented. Walking never leaves the exported directory, `..` at its root stays at the root.
+
+use alloc::{
+    collections::BTreeMap,
+    string::{String, ToString},
+    vec,
+    vec::Vec,
+};
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Seek, SeekFrom, Write},
+};
+
+use axerrno::AxResult;
+
+use super::{VirtioDevice, queue::DescChain};
+use crate::vmm::{VMRef, ext_config::ShareConfig};
+
+/// Virtio device ID of 9P transports.
+const VIRTIO_ID_9P: u32 = 9;
+/// The mount tag is available in the configuration space.
+const VIRTIO_9P_MOUNT_TAG: u64 = 1 << 0;
+
+/// Largest message size accepted, including the header.
+const MAX_MSIZE: u32 = 128 * 1024;
+/// Smallest message size accepted, the Linux client never asks for less.
+const MIN_MSIZE: u32 = 4096;
+/// Size of the `size[4] type[1] tag[2]` header.
+const HEADER_SIZE: usize = 7;
+
+const P9_VERSION: &str = "9P2000.L";
+
+const RLERROR: u8 = 7;
+const TSTATFS: u8 = 8;
+const TLOPEN: u8 = 12;
+const TLCREATE: u8 = 14;
+const TGETATTR: u8 = 24;
+const TSETATTR: u8 = 26;
+const TXATTRWALK: u8 = 30;
+const TREADDIR: u8 = 40;
+const TFSYNC: u8 = 50;
+const TMKDIR: u8 = 72;
+const TRENAMEAT: u8 = 74;
+const TUNLINKAT: u8 = 76;
+const TVERSION: u8 = 100;
+const TATTACH: u8 = 104;
+const TFLUSH: u8 = 108;
+const TWALK: u8 = 110;
+const TREAD: u8 = 116;
+const TWRITE: u8 = 118;
+const TCLUNK: u8 = 120;
+const TREMOVE: u8 = 122;
+
+const QTDIR: u8 = 0x80;
+const QTFILE: u8 = 0x00;
+
+const S_IFDIR: u32 = 0o040000;
+const S_IFREG: u32 = 0o100000;
+
+const O_ACCMODE: u32 = 0o3;
+const O_WRONLY: u32 = 0o1;
+const O_RDWR: u32 = 0o2;
+const O_TRUNC: u32 = 0o1000;
+const O_APPEND: u32 = 0o2000;
+
+/// `Tunlinkat` flag removing a directory.
+const AT_REMOVEDIR: u32 = 0x200;
+/// `Tsetattr` flag setting the file size.
+const P9_SETATTR_SIZE: u32 = 0x8;
+/// `Rgetattr` fields filled in: mode, nlink, uid, gid, rdev, times, ino, size and blocks.
+const P9_GETATTR_BASIC: u64 = 0x7ff;
+
+const ENOENT: u32 = 2;
+const EIO: u32 = 5;
+const EBADF: u32 = 9;
+const EACCES: u32 = 13;
+const EEXIST: u32 = 17;
+const ENOTDIR: u32 = 20;
+const EISDIR: u32 = 21;
+const EINVAL: u32 = 22;
+const ENOSPC: u32 = 28;
+const EROFS: u32 = 30;
+const ENOSYS: u32 = 38;
+const ENOTEMPTY: u32 = 39;
+const EOPNOTSUPP: u32 = 95;
+
+/// Result of a 9P request, the error being a Linux errno.
+type P9Result<T = ()> = Result<T, u32>;
+
+fn errno(err: io::Error) -> u32 {
+    match err {
+        io::Error::NotFound => ENOENT,
+        io::Error::AlreadyExists => EEXIST,
+        io::Error::NotADirectory => ENOTDIR,
+        io::Error::IsADirectory => EISDIR,
+        io::Error::DirectoryNotEmpty => ENOTEMPTY,
+        io::Error::InvalidInput | io::Error::InvalidData => EINVAL,
+        io::Error::PermissionDenied => EACCES,
+        io::Error::StorageFull => ENOSPC,
+        io::Error::Unsupported => EOPNOTSUPP,
+        _ => EIO,
+    }
+}
+
+/// Reader of the fields of a T-message.
+struct MsgReader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> MsgReader<'a> {
+    fn bytes(&mut self, len: usize) -> P9Result<&'a [u8]> {
+        if self.data.len() < len {
+            return Err(EINVAL);
+        }
+        let (head, tail) = self.data.split_at(len);
+        self.data = tail;
+        Ok(head)
+    }
+
+    fn u8(&mut self) -> P9Result<u8> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> P9Result<u16> {
+        Ok(u16::from_le_bytes(self.bytes(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> P9Result<u32> {
+        Ok(u32::from_le_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> P9Result<u64> {
+        Ok(u64::from_le_bytes(self.bytes(8)?.try_into().unwrap()))
+    }
+
+    fn str(&mut self) -> P9Result<String> {
+        let len = self.u16()? as usize;
+        let bytes = self.bytes(len)?;
+        String::from_utf8(bytes.to_vec()).map_err(|_| EINVAL)
+    }
+}
+
+/// Writer of the fields of an R-message.
+struct MsgWriter {
+    data: Vec<u8>,
+}
+
+impl MsgWriter {
+    fn new(msg_type: u8, tag: u16) -> Self {
+        let mut data = vec![0; 4];
+        data.push(msg_type);
+        data.extend_from_slice(&tag.to_le_bytes());
+        Self { data }
+    }
+
+    fn u8(&mut self, v: u8) -> &mut Self {
+        self.data.push(v);
+        self
+    }
+
+    fn u16(&mut self, v: u16) -> &mut Self {
+        self.data.extend_from_slice(&v.to_le_bytes());
+        self
+    }
+
+    fn u32(&mut self, v: u32) -> &mut Self {
+        self.data.extend_from_slice(&v.to_le_bytes());
+        self
+    }
+
+    fn u64(&mut self, v: u64) -> &mut Self {
+        self.data.extend_from_slice(&v.to_le_bytes());
+        self
+    }
+
+    fn str(&mut self, s: &str) -> &mut Self {
+        self.u16(s.len() as u16);
+        self.data.extend_from_slice(s.as_bytes());
+        self
+    }
+
+    fn qid(&mut self, qid: Qid) -> &mut Self {
+        self.u8(qid.ty).u32(0).u64(qid.path)
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        let size = self.data.len() as u32;
+        self.data[..4].copy_from_slice(&size.to_le_bytes());
+        self.data
+    }
+}
+
+/// Unique identification of a file.
+#[derive(Debug, Clone, Copy)]
+struct Qid {
+    ty: u8,
+    path: u64,
+}
+
+/// A file or directory of the share referenced by the guest.
+struct Fid {
+    /// Path relative to the exported directory, empty for its root.
+    rel_path: String,
+    file: Option<File>,
+    /// Directory entries, loaded by `Treaddir` at offset 0.
+    dir_entries: Vec<(String, Qid)>,
+}
+
+/// A virtio-9p device exporting a directory.
+pub struct VirtioP9 {
+    share: ShareConfig,
+    msize: u32,
+    fids: BTreeMap<u32, Fid>,
+}
+
+impl VirtioP9 {
+    /// Creates the 9P server of a share.
+    pub fn new(share: ShareConfig) -> Self {
+        Self {
+            share,
+            msize: MAX_MSIZE,
+            fids: BTreeMap::new(),
+        }
+    }
+
+    fn host_path(&self, rel_path: &str) -> String {
+        if rel_path.is_empty() {
+            self.share.path.clone()
+        } else {
+            format!("{}/{}", self.share.path.trim_end_matches('/'), rel_path)
+        }
+    }
+
+    fn qid(&self, rel_path: &str) -> P9Result<Qid> {
+        let metadata = fs::metadata(&self.host_path(rel_path)).map_err(errno)?;
+        Ok(Qid {
+            ty: if metadata.is_dir() { QTDIR } else { QTFILE },
+            path: path_hash(rel_path),
+        })
+    }
+
+    fn fid(&self, fid: u32) -> P9Result<&Fid> {
+        self.fids.get(&fid).ok_or(EBADF)
+    }
+
+    fn fid_mut(&mut self, fid: u32) -> P9Result<&mut Fid> {
+        self.fids.get_mut(&fid).ok_or(EBADF)
+    }
+
+    fn check_writable(&self) -> P9Result {
+        if self.share.read_only {
+            Err(EROFS)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Handles a T-message and returns the R-message.
+    fn handle_message(&mut self, msg: &[u8]) -> Vec<u8> {
+        let mut reader = MsgReader { data: msg };
+        let (msg_type, tag) = match (reader.u32(), reader.u8(), reader.u16()) {
+            (Ok(_size), Ok(msg_type), Ok(tag)) => (msg_type, tag),
+            _ => return lerror(0, EINVAL),
+        };
+
+        let mut writer = MsgWriter::new(msg_type + 1, tag);
+        match self.dispatch(msg_type, &mut reader, &mut writer) {
+            Ok(()) => writer.finish(),
+            Err(ecode) => {
+                if ecode == ENOSYS {
+                    debug!("9p request type {msg_type} is not supported");
+                }
+                lerror(tag, ecode)
+            }
+        }
+    }
+
+    fn dispatch(&mut self, msg_type: u8, r: &mut MsgReader, w: &mut MsgWriter) -> P9Result {
+        match msg_type {
+            TVERSION => {
+                let msize = r.u32()?;
+                if msize < MIN_MSIZE {
+                    return Err(EINVAL);
+                }
+                let msize = msize.min(MAX_MSIZE);
+                let version = r.str()?;
+                // A new session starts, all the fids are released.
+                self.fids.clear();
+                self.msize = msize;
+                let version = if version.starts_with(P9_VERSION) {
+                    P9_VERSION
+                } else {
+                    "unknown"
+                };
+                w.u32(msize).str(version);
+            }
+            TATTACH => {
+                let fid = r.u32()?;
+                let qid = self.qid("")?;
+                self.fids.insert(
+                    fid,
+                    Fid {
+                        rel_path: String::new(),
+                        file: None,
+                        dir_entries: Vec::new(),
+                    },
+                );
+                w.qid(qid);
+            }
+            TWALK => {
+                let fid = r.u32()?;
+                let newfid = r.u32()?;
+                let nwname = r.u16()?;
+                let mut rel_path = self.fid(fid)?.rel_path.clone();
+                let mut qids = Vec::new();
+                for i in 0..nwname {
+                    let name = r.str()?;
+                    let next = match walk(&rel_path, &name) {
+                        Some(next) => next,
+                        None if i == 0 => return Err(ENOENT),
+                        None => break,
+                    };
+                    match self.qid(&next) {
+                        Ok(qid) => qids.push(qid),
+                        Err(ecode) if i == 0 => return Err(ecode),
+                        // A partial walk returns the qids found, without creating `newfid`.
+                        Err(_) => break,
+                    }
+                    rel_path = next;
+                }
+                if qids.len() == nwname as usize {
+                    self.fids.insert(
+                        newfid,
+                        Fid {
+                            rel_path,
+                            file: None,
+                            dir_entries: Vec::new(),
+                        },
+                    );
+                }
+                w.u16(qids.len() as u16);
+                for qid in qids {
+                    w.qid(qid);
+                }
+            }
+            TLOPEN => {
+                let fid = r.u32()?;
+                let flags = r.u32()?;
+                let rel_path = self.fid(fid)?.rel_path.clone();
+                let qid = self.qid(&rel_path)?;
+                if qid.ty == QTFILE {
+                    let file = self.open(&self.host_path(&rel_path), flags, false)?;
+                    self.fid_mut(fid)?.file = Some(file);
+                } else if flags & O_ACCMODE != 0 {
+                    return Err(EISDIR);
+                }
+                w.qid(qid).u32(0);
+            }
+            TLCREATE => {
+                let fid = r.u32()?;
+                let name = r.str()?;
+                let flags = r.u32()?;
+                self.check_writable()?;
+                let rel_path = child(&self.fid(fid)?.rel_path, &name)?;
+                let file = self.open(&self.host_path(&rel_path), flags, true)?;
+                let qid = self.qid(&rel_path)?;
+                let entry = self.fid_mut(fid)?;
+                entry.rel_path = rel_path;
+                entry.file = Some(file);
+                w.qid(qid).u32(0);
+            }
+            TGETATTR => {
+                let fid = r.u32()?;
+                let rel_path = self.fid(fid)?.rel_path.clone();
+                let metadata = fs::metadata(&self.host_path(&rel_path)).map_err(errno)?;
+                let qid = self.qid(&rel_path)?;
+                let file_type = if metadata.is_dir() { S_IFDIR } else { S_IFREG };
+                let mode = file_type | (metadata.permissions().mode() & 0o7777);
+                let size = metadata.len();
+                w.u64(P9_GETATTR_BASIC).qid(qid).u32(mode);
+                // uid, gid, nlink, rdev, size, blksize, blocks
+                w.u32(0).u32(0).u64(1).u64(0).u64(size);
+                w.u64(4096).u64(size.div_ceil(512));
+                // atime, mtime, ctime, btime, gen, data_version
+                for _ in 0..10 {
+                    w.u64(0);
+                }
+            }
+            TSETATTR => {
+                let fid = r.u32()?;
+                let valid = r.u32()?;
+                let _mode = r.u32()?;
+                let _uid = r.u32()?;
+                let _gid = r.u32()?;
+                let size = r.u64()?;
+                if valid & P9_SETATTR_SIZE != 0 {
+                    self.check_writable()?;
+                    let path = self.host_path(&self.fid(fid)?.rel_path);
+                    OpenOptions::new()
+                        .write(true)
+                        .open(&path)
+                        .and_then(|file| file.set_len(size))
+                        .map_err(errno)?;
+                }
+                // Other attributes are not supported by the hypervisor filesystem and ignored.
+            }
+            TREADDIR => {
+                let fid = r.u32()?;
+                let offset = r.u64()? as usize;
+                let count =
+                    (r.u32()? as usize).min((self.msize as usize).saturating_sub(HEADER_SIZE + 4));
+                if offset == 0 {
+                    let rel_path = self.fid(fid)?.rel_path.clone();
+                    let entries = self.read_dir(&rel_path)?;
+                    self.fid_mut(fid)?.dir_entries = entries;
+                }
+                let mut data = MsgWriter { data: Vec::new() };
+                for (index, (name, qid)) in
+                    self.fid(fid)?.dir_entries.iter().enumerate().skip(offset)
+                {
+                    let len = 13 + 8 + 1 + 2 + name.len();
+                    if data.data.len() + len > count {
+                        break;
+                    }
+                    data.qid(*qid).u64(index as u64 + 1);
+                    data.u8(if qid.ty == QTDIR { 4 } else { 8 }).str(name);
+                }
+                w.u32(data.data.len() as u32);
+                w.data.extend_from_slice(&data.data);
+            }
+            TREAD => {
+                let fid = r.u32()?;
+                let offset = r.u64()?;
+                let count =
+                    (r.u32()? as usize).min((self.msize as usize).saturating_sub(HEADER_SIZE + 4));
+                let file = self.fid_mut(fid)?.file.as_mut().ok_or(EBADF)?;
+                file.seek(SeekFrom::Start(offset)).map_err(errno)?;
+                let mut buf = vec![0; count];
+                let mut read = 0;
+                while read < count {
+                    match file.read(&mut buf[read..]).map_err(errno)? {
+                        0 => break,
+                        n => read += n,
+                    }
+                }
+                w.u32(read as u32);
+                w.data.extend_from_slice(&buf[..read]);
+            }
+            TWRITE => {
+                let fid = r.u32()?;
+                let offset = r.u64()?;
+                let count = r.u32()? as usize;
+                let data = r.bytes(count)?;
+                self.check_writable()?;
+                let file = self.fid_mut(fid)?.file.as_mut().ok_or(EBADF)?;
+                file.seek(SeekFrom::Start(offset)).map_err(errno)?;
+                file.write_all(data).map_err(errno)?;
+                w.u32(count as u32);
+            }
+            TMKDIR => {
+                let dfid = r.u32()?;
+                let name = r.str()?;
+                self.check_writable()?;
+                let rel_path = child(&self.fid(dfid)?.rel_path, &name)?;
+                fs::create_dir(&self.host_path(&rel_path)).map_err(errno)?;
+                w.qid(self.qid(&rel_path)?);
+            }
+            TUNLINKAT => {
+                let dfid = r.u32()?;
+                let name = r.str()?;
+                let flags = r.u32()?;
+                self.check_writable()?;
+                let path = self.host_path(&child(&self.fid(dfid)?.rel_path, &name)?);
+                if flags & AT_REMOVEDIR != 0 {
+                    fs::remove_dir(&path).map_err(errno)?;
+                } else {
+                    fs::remove_file(&path).map_err(errno)?;
+                }
+            }
+            TRENAMEAT => {
+                let olddirfid = r.u32()?;
+                let oldname = r.str()?;
+                let newdirfid = r.u32()?;
+                let newname = r.str()?;
+                self.check_writable()?;
+                let old = self.host_path(&child(&self.fid(olddirfid)?.rel_path, &oldname)?);
+                let new = self.host_path(&child(&self.fid(newdirfid)?.rel_path, &newname)?);
+                fs::rename(&old, &new).map_err(errno)?;
+            }
+            TREMOVE => {
+                let fid = r.u32()?;
+                let entry = self.fids.remove(&fid).ok_or(EBADF)?;
+                self.check_writable()?;
+                if entry.rel_path.is_empty() {
+                    return Err(EACCES);
+                }
+                let path = self.host_path(&entry.rel_path);
+                drop(entry);
+                if fs::metadata(&path).map_err(errno)?.is_dir() {
+                    fs::remove_dir(&path).map_err(errno)?;
+                } else {
+                    fs::remove_file(&path).map_err(errno)?;
+                }
+            }
+            TSTATFS => {
+                let _fid = r.u32()?;
+                // V9FS_MAGIC, bsize, blocks, bfree, bavail, files, ffree, fsid, namelen
+                w.u32(0x0102_1997).u32(4096);
+                w.u64(0).u64(0).u64(0).u64(0).u64(0).u64(0).u32(255);
+            }
+            TFSYNC => {
+                let fid = r.u32()?;
+                if let Some(file) = self.fid_mut(fid)?.file.as_mut() {
+                    file.flush().map_err(errno)?;
+                }
+            }
+            TCLUNK => {
+                let fid = r.u32()?;
+                self.fids.remove(&fid).ok_or(EBADF)?;
+            }
+            TFLUSH => {
+                // Requests are handled synchronously, there is nothing to cancel.
+            }
+            TXATTRWALK => return Err(EOPNOTSUPP),
+            _ => return Err(ENOSYS),
+        }
+        Ok(())
+    }
+
+    fn open(&self, path: &str, flags: u32, create: bool) -> P9Result<File> {
+        let access = flags & O_ACCMODE;
+        let write = access == O_WRONLY || access == O_RDWR;
+        if write || create || flags & O_TRUNC != 0 {
+            self.check_writable()?;
+        }
+        OpenOptions::new()
+            .read(access != O_WRONLY)
+            .write(write || create)
+            .append(flags & O_APPEND != 0)
+            .truncate(flags & O_TRUNC != 0)
+            .create(create)
+            .open(path)
+            .map_err(errno)
+    }
+
+    fn read_dir(&self, rel_path: &str) -> P9Result<Vec<(String, Qid)>> {
+        let mut names: Vec<String> = fs::read_dir(&self.host_path(rel_path))
+            .map_err(errno)?
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.file_name())
+            .filter(|name| name != "." && name != "..")
+            .collect();
+        names.sort();
+
+        let mut entries = vec![
+            (".".to_string(), self.qid(rel_path)?),
+            ("..".to_string(), self.qid(&walk(rel_path, "..").unwrap())?),
+        ];
+        for name in names {
+            let rel = child(rel_path, &name)?;
+            if let Ok(qid) = self.qid(&rel) {
+                entries.push((name, qid));
+            }
+        }
+        Ok(entries)
+    }
+}
+
+/// Builds an `Rlerror` reply.
+fn lerror(tag: u16, ecode: u32) -> Vec<u8> {
+    let mut w = MsgWriter::new(RLERROR, tag);
+    w.u32(ecode);
+    w.finish()
+}
+
+/// Returns the path of the entry `name` of the directory `dir`, rejecting names that would
+/// escape it.
+fn child(dir: &str, name: &str) -> P9Result<String> {
+    if name.is_empty() || name == "." || name == ".." || name.contains('/') {
+        return Err(EINVAL);
+    }
+    Ok(if dir.is_empty() {
+        name.to_string()
+    } else {
+        format!("{dir}/{name}")
+    })
+}
+
+/// Returns the path reached by walking `name` from `dir`, `..` stopping at the root.
+fn walk(dir: &str, name: &str) -> Option<String> {
+    match name {
+        "." => Some(dir.to_string()),
+        ".." => Some(
+            dir.rsplit_once('/')
+                .map_or("", |(parent, _)| parent)
+                .to_string(),
+        ),
+        _ => child(dir, name).ok(),
+    }
+}
+
+/// FNV-1a hash of the path, used as the qid path since the filesystem has no inode numbers.
+fn path_hash(rel_path: &str) -> u64 {
+    rel_path.bytes().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x0000_0100_0000_01b3)
+    })
+}
+
+impl VirtioDevice for VirtioP9 {
+    fn device_id(&self) -> u32 {
+        VIRTIO_ID_9P
+    }
+
+    fn features(&self) -> u64 {
+        VIRTIO_9P_MOUNT_TAG
+    }
+
+    fn num_queues(&self) -> usize {
+        1
+    }
+
+    fn read_config(&self, offset: usize) -> u32 {
+        // struct virtio_9p_config { le16 tag_len; u8 tag[]; }
+        let tag = self.share.tag.as_bytes();
+        let mut config = Vec::with_capacity(2 + tag.len());
+        config.extend_from_slice(&(tag.len() as u16).to_le_bytes());
+        config.extend_from_slice(tag);
+
+        let mut bytes = [0u8; 4];
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            *byte = config.get(offset + i).copied().unwrap_or(0);
+        }
+        u32::from_le_bytes(bytes)
+    }
+
+    fn handle_chain(&mut self, vm: &VMRef, _queue: usize, chain: &DescChain) -> AxResult<usize> {
+        // Messages larger than the negotiated size are rejected before being read.
+        let request = chain.read_all(vm, self.msize as usize)?;
+        let reply = self.handle_message(&request);
+        chain.write_all(vm, &reply)
+    }
+}
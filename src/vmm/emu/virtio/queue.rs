@@ -0,0 +1,230 @@
+// Copyright 2025 The Axvisor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Split virtqueues living in guest memory.
+
+use alloc::vec::Vec;
+use core::sync::atomic::{Ordering, fence};
+
+use axaddrspace::GuestPhysAddr;
+use axerrno::{AxResult, ax_err};
+
+use crate::vmm::VMRef;
+
+/// The buffer continues in the `next` descriptor.
+const VIRTQ_DESC_F_NEXT: u16 = 1;
+/// The buffer is write-only for the device.
+const VIRTQ_DESC_F_WRITE: u16 = 2;
+/// The buffer contains a table of indirect descriptors.
+const VIRTQ_DESC_F_INDIRECT: u16 = 4;
+
+/// Size of a descriptor in the descriptor table.
+const DESC_SIZE: usize = 16;
+
+/// Maximum number of entries of a virtqueue supported by the device models.
+pub const QUEUE_SIZE_MAX: u16 = 256;
+
+/// A guest buffer described by a virtqueue descriptor.
+#[derive(Debug, Clone, Copy)]
+pub struct Desc {
+    pub addr: GuestPhysAddr,
+    pub len: usize,
+    pub writable: bool,
+}
+
+/// A descriptor chain popped from the available ring.
+#[derive(Debug)]
+pub struct DescChain {
+    /// Index of the head descriptor, used to return the chain in the used ring.
+    pub head: u16,
+    pub descs: Vec<Desc>,
+}
+
+impl DescChain {
+    /// Total size of the buffers the device may write to.
+    pub fn writable_len(&self) -> usize {
+        self.descs
+            .iter()
+            .filter(|d| d.writable)
+            .map(|d| d.len)
+            .sum()
+    }
+
+    /// Reads the content of the device-readable buffers, which must not exceed `max_len` bytes in
+    /// total.
+    pub fn read_all(&self, vm: &VMRef, max_len: usize) -> AxResult<Vec<u8>> {
+        let len = self
+            .descs
+            .iter()
+            .filter(|d| !d.writable)
+            .try_fold(0usize, |len, d| len.checked_add(d.len))
+            .filter(|&len| len <= max_len);
+        let Some(len) = len else {
+            return ax_err!(InvalidData, "virtqueue request is too large");
+        };
+
+        let mut data = Vec::with_capacity(len);
+        for desc in self.descs.iter().filter(|d| !d.writable) {
+            let start = data.len();
+            data.resize(start + desc.len, 0);
+            read_guest(vm, desc.addr, &mut data[start..])?;
+        }
+        Ok(data)
+    }
+
+    /// Writes `data` to the device-writable buffers, returns the number of bytes written.
+    pub fn write_all(&self, vm: &VMRef, data: &[u8]) -> AxResult<usize> {
+        let mut written = 0;
+        for desc in self.descs.iter().filter(|d| d.writable) {
+            if written == data.len() {
+                break;
+            }
+            let len = desc.len.min(data.len() - written);
+            write_guest(vm, desc.addr, &data[written..written + len])?;
+            written += len;
+        }
+        Ok(written)
+    }
+}
+
+/// Copies guest memory at `gpa` into `buf`.
+pub fn read_guest(vm: &VMRef, gpa: GuestPhysAddr, buf: &mut [u8]) -> AxResult {
+    let mut pos = 0;
+    for region in vm.get_image_load_region(gpa, buf.len())? {
+        let len = region.len().min(buf.len() - pos);
+        buf[pos..pos + len].copy_from_slice(&region[..len]);
+        pos += len;
+    }
+    if pos < buf.len() {
+        return ax_err!(InvalidInput, "guest buffer is not fully mapped");
+    }
+    Ok(())
+}
+
+/// Copies `data` into guest memory at `gpa`.
+pub fn write_guest(vm: &VMRef, gpa: GuestPhysAddr, data: &[u8]) -> AxResult {
+    let mut pos = 0;
+    for region in vm.get_image_load_region(gpa, data.len())? {
+        let len = region.len().min(data.len() - pos);
+        region[..len].copy_from_slice(&data[pos..pos + len]);
+        pos += len;
+    }
+    if pos < data.len() {
+        return ax_err!(InvalidInput, "guest buffer is not fully mapped");
+    }
+    Ok(())
+}
+
+/// State of a split virtqueue, as programmed by the driver through the MMIO transport.
+#[derive(Debug, Default)]
+pub struct Queue {
+    pub num: u16,
+    pub ready: bool,
+    pub desc_table: u64,
+    pub avail_ring: u64,
+    pub used_ring: u64,
+    /// Next index of the available ring to be processed.
+    last_avail_idx: u16,
+}
+
+impl Queue {
+    /// Returns the queue to its state after a device reset.
+    pub fn reset(&mut self) {
+        *self = Self::default();
+    }
+
+    fn gpa(addr: u64) -> GuestPhysAddr {
+        GuestPhysAddr::from(addr as usize)
+    }
+
+    /// Address of the element at `offset` in a ring or table at `base`, which the driver may
+    /// place anywhere.
+    fn elem_gpa(base: u64, offset: u64) -> AxResult<GuestPhysAddr> {
+        match base.checked_add(offset) {
+            Some(addr) => Ok(Self::gpa(addr)),
+            None => ax_err!(InvalidData, "virtqueue address out of range"),
+        }
+    }
+
+    /// Pops the next descriptor chain made available by the driver.
+    pub fn pop(&mut self, vm: &VMRef) -> AxResult<Option<DescChain>> {
+        if !self.ready || self.num == 0 {
+            return Ok(None);
+        }
+
+        let avail_idx: u16 = vm.read_from_guest_of(Self::elem_gpa(self.avail_ring, 2)?)?;
+        if avail_idx == self.last_avail_idx {
+            return Ok(None);
+        }
+        // Read the ring entries only after the index.
+        fence(Ordering::Acquire);
+
+        let slot = (self.last_avail_idx % self.num) as u64;
+        let head: u16 = vm.read_from_guest_of(Self::elem_gpa(self.avail_ring, 4 + slot * 2)?)?;
+        self.last_avail_idx = self.last_avail_idx.wrapping_add(1);
+
+        let descs = self.read_chain(vm, self.desc_table, self.num, head)?;
+        Ok(Some(DescChain { head, descs }))
+    }
+
+    fn read_chain(&self, vm: &VMRef, table: u64, size: u16, head: u16) -> AxResult<Vec<Desc>> {
+        let mut descs = Vec::new();
+        let mut index = head;
+        // A chain cannot be longer than the table, this protects against loops.
+        for _ in 0..size {
+            if index >= size {
+                return ax_err!(InvalidData, "virtqueue descriptor index out of range");
+            }
+            let entry = Self::elem_gpa(table, index as u64 * DESC_SIZE as u64)?;
+            let addr: u64 = vm.read_from_guest_of(entry)?;
+            let len: u32 = vm.read_from_guest_of(entry + 8)?;
+            let flags: u16 = vm.read_from_guest_of(entry + 12)?;
+            let next: u16 = vm.read_from_guest_of(entry + 14)?;
+
+            if flags & VIRTQ_DESC_F_INDIRECT != 0 {
+                if table != self.desc_table {
+                    return ax_err!(InvalidData, "nested indirect virtqueue descriptors");
+                }
+                let count = (len as usize / DESC_SIZE).min(u16::MAX as usize) as u16;
+                descs.extend(self.read_chain(vm, addr, count, 0)?);
+            } else {
+                descs.push(Desc {
+                    addr: Self::gpa(addr),
+                    len: len as usize,
+                    writable: flags & VIRTQ_DESC_F_WRITE != 0,
+                });
+            }
+
+            if flags & VIRTQ_DESC_F_NEXT == 0 {
+                return Ok(descs);
+            }
+            index = next;
+        }
+        ax_err!(InvalidData, "virtqueue descriptor chain loops")
+    }
+
+    /// Returns a processed chain to the driver, `len` being the number of bytes written to it.
+    pub fn push_used(&mut self, vm: &VMRef, head: u16, len: usize) -> AxResult {
+        let idx_gpa = Self::elem_gpa(self.used_ring, 2)?;
+        let used_idx: u16 = vm.read_from_guest_of(idx_gpa)?;
+        let slot = (used_idx % self.num) as u64;
+        let elem = Self::elem_gpa(self.used_ring, 4 + slot * 8)?;
+        vm.write_to_guest_of(elem, &(head as u32))?;
+        vm.write_to_guest_of(elem + 4, &(len as u32))?;
+        // The driver must see the element before the new index.
+        fence(Ordering::Release);
+        vm.write_to_guest_of(idx_gpa, &used_idx.wrapping_add(1))?;
+        Ok(())
+    }
+}
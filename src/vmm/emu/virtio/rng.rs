@@ -0,0 +1,120 @@
+// Copyright 2025 The Axvisor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Virtio entropy device.
+//!
+//! Random bytes come from the host CPU's RNG (`RNDR` on aarch64, `RDRAND` on x86_64) when
+//! available, or from a ChaCha20 DRBG otherwise. The DRBG is seeded from the host RNG and the
+//! system timers. With `cfg_list = [1, SEED...]` the DRBG is always used and seeded from the
+//! given words only, which makes the guest entropy reproducible: use it for testing only.
+
+use alloc::vec;
+use std::os::arceos::modules::axhal;
+
+use axerrno::AxResult;
+use axvm::config::EmulatedDeviceConfig;
+use rand_chacha::{
+    ChaCha20Rng,
+    rand_core::{RngCore, SeedableRng},
+};
+
+use super::{VirtioDevice, queue::DescChain};
+use crate::{hal::arch::hw_random_u64, vmm::VMRef};
+
+/// Name of `emu_devices` entries handled by this device model.
+pub const COMPATIBLE: &str = "virtio,rng";
+
+/// Virtio device ID of entropy sources.
+const VIRTIO_ID_RNG: u32 = 4;
+
+/// Maximum number of bytes returned for a single request.
+const MAX_REQUEST_SIZE: usize = 4096;
+
+/// Where the random bytes come from.
+enum EntropySource {
+    /// The host CPU's RNG, with a DRBG as fallback if it fails.
+    Host(ChaCha20Rng),
+    /// A DRBG only.
+    Drbg(ChaCha20Rng),
+}
+
+/// A virtio entropy device.
+pub struct VirtioRng {
+    source: EntropySource,
+}
+
+impl VirtioRng {
+    /// Creates the entropy device of a VM from its `emu_devices` entry.
+    pub fn new(vm_id: usize, cfg: &EmulatedDeviceConfig) -> Self {
+        let source = match cfg.cfg_list.first().copied().unwrap_or(0) {
+            0 if hw_random_u64().is_some() => EntropySource::Host(seeded_drbg()),
+            0 => {
+                info!("VM[{vm_id}] no host RNG available, virtio-rng uses a DRBG");
+                EntropySource::Drbg(seeded_drbg())
+            }
+            _ => {
+                warn!("VM[{vm_id}] virtio-rng uses a fixed seed, do not use it in production");
+                let mut seed = [0u8; 32];
+                for (chunk, word) in seed.chunks_mut(8).zip(cfg.cfg_list[1..].iter()) {
+                    chunk.copy_from_slice(&(*word as u64).to_le_bytes());
+                }
+                EntropySource::Drbg(ChaCha20Rng::from_seed(seed))
+            }
+        };
+        Self { source }
+    }
+
+    fn fill(&mut self, buf: &mut [u8]) {
+        match &mut self.source {
+            EntropySource::Host(fallback) => {
+                for chunk in buf.chunks_mut(8) {
+                    let value = hw_random_u64().unwrap_or_else(|| fallback.next_u64());
+                    chunk.copy_from_slice(&value.to_le_bytes()[..chunk.len()]);
+                }
+            }
+            EntropySource::Drbg(drbg) => drbg.fill_bytes(buf),
+        }
+    }
+}
+
+/// Returns a DRBG seeded from the host RNG, if any, and the system timers.
+fn seeded_drbg() -> ChaCha20Rng {
+    let mut seed = [0u8; 32];
+    let words = [
+        hw_random_u64().unwrap_or(0),
+        axhal::time::wall_time().as_nanos() as u64,
+        axhal::time::monotonic_time_nanos(),
+        hw_random_u64().unwrap_or(0) ^ axhal::time::current_ticks(),
+    ];
+    for (chunk, word) in seed.chunks_mut(8).zip(words) {
+        chunk.copy_from_slice(&word.to_le_bytes());
+    }
+    ChaCha20Rng::from_seed(seed)
+}
+
+impl VirtioDevice for VirtioRng {
+    fn device_id(&self) -> u32 {
+        VIRTIO_ID_RNG
+    }
+
+    fn num_queues(&self) -> usize {
+        1
+    }
+
+    fn handle_chain(&mut self, vm: &VMRef, _queue: usize, chain: &DescChain) -> AxResult<usize> {
+        let mut buf = vec![0u8; chain.writable_len().min(MAX_REQUEST_SIZE)];
+        self.fill(&mut buf);
+        chain.write_all(vm, &buf)
+    }
+}
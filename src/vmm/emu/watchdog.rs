@@ -0,0 +1,574 @@
+// Copyright 2025 The Axvisor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Emulated watchdogs.
+//!
+//! Two device models are provided:
+//!
+//! - `arm,sp805`: the ARM SP805 watchdog, driven by Linux's `sp805_wdt` driver. Its counter runs
+//!   at [`EMU_CLOCK_HZ`]. The first time the counter reaches zero the interrupt is raised and the
+//!   counter is reloaded, if it reaches zero again before the guest clears the interrupt and
+//!   `WdogControl.RESEN` is set, the timeout action is taken.
+//! - `axvisor,wdt`: a minimal watchdog for architectures without a standard one, see the register
+//!   constants below.
+//!
+//! The first entry of the device's `cfg_list` selects the timeout action: `0` restarts the VM,
+//! `1` stops it, `2` stops it and saves its memory (see [`lifecycle::spawn_snapshot_and_stop`]).
+//! For `axvisor,wdt`, the second entry is the initial timeout in milliseconds.
+//!
+//! A restarted VM keeps its devices, so its watchdogs are put back in their reset state by
+//! [`reset`] before it boots again.
+
+use alloc::{
+    boxed::Box,
+    collections::BTreeMap,
+    sync::{Arc, Weak},
+    vec::Vec,
+};
+use std::os::arceos::modules::axhal;
+
+use axaddrspace::{GuestPhysAddr, GuestPhysAddrRange, device::AccessWidth};
+use axdevice_base::{BaseDeviceOps, EmuDeviceType};
+use axerrno::AxResult;
+use axvm::{VMStatus, config::EmulatedDeviceConfig};
+use kspin::SpinNoIrq;
+
+use super::{EMU_CLOCK_HZ, EmuDeviceNode};
+use crate::vmm::{
+    events::{self, VmEventKind},
+    lifecycle, timer, vm_list,
+};
+
+/// Name of `emu_devices` entries handled by the SP805 model.
+pub const SP805_COMPATIBLE: &str = "arm,sp805";
+/// Name of `emu_devices` entries handled by the generic model.
+pub const GENERIC_COMPATIBLE: &str = "axvisor,wdt";
+
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+const NANOS_PER_MILLI: u64 = 1_000_000;
+
+/// Load register, the value the counter restarts from.
+const WDOG_LOAD: usize = 0x000;
+/// Current value of the counter.
+const WDOG_VALUE: usize = 0x004;
+/// Control register.
+const WDOG_CONTROL: usize = 0x008;
+/// Interrupt clear register, any write clears the interrupt and reloads the counter.
+const WDOG_INTCLR: usize = 0x00c;
+/// Raw interrupt status register.
+const WDOG_RIS: usize = 0x010;
+/// Masked interrupt status register.
+const WDOG_MIS: usize = 0x014;
+/// Lock register, other registers are read-only unless it is unlocked.
+const WDOG_LOCK: usize = 0xc00;
+/// Start of the peripheral and PrimeCell identification registers.
+const WDOG_ID_BASE: usize = 0xfe0;
+
+/// `WdogControl` bit enabling the counter and the interrupt.
+const WDOG_CONTROL_INTEN: u32 = 1 << 0;
+/// `WdogControl` bit enabling the reset output.
+const WDOG_CONTROL_RESEN: u32 = 1 << 1;
+/// Value written to `WdogLock` to unlock the registers.
+const WDOG_UNLOCK_KEY: u32 = 0x1acc_e551;
+
+/// `WdogPeriphID0-3` followed by `WdogPCellID0-3`.
+const WDOG_ID: [u32; 8] = [0x05, 0x18, 0x14, 0x00, 0x0d, 0xf0, 0x05, 0xb1];
+
+/// Generic watchdog: control register, bit 0 enables the watchdog.
+const WDT_CTRL: usize = 0x00;
+/// Generic watchdog: timeout in milliseconds.
+const WDT_TIMEOUT_MS: usize = 0x04;
+/// Generic watchdog: any write restarts the countdown.
+const WDT_KICK: usize = 0x08;
+/// Generic watchdog: read-only, milliseconds left before the timeout.
+const WDT_REMAINING_MS: usize = 0x0c;
+
+/// Timeout used by the generic watchdog if none is given in `cfg_list`.
+const WDT_DEFAULT_TIMEOUT_MS: u32 = 30_000;
+
+/// What to do with the VM when its watchdog expires.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimeoutAction {
+    /// Stop and boot the VM again.
+    Restart,
+    /// Stop the VM.
+    Stop,
+    /// Stop the VM and save its memory.
+    SnapshotAndStop,
+}
+
+impl TimeoutAction {
+    fn from_cfg(cfg: &EmulatedDeviceConfig) -> Self {
+        match cfg.cfg_list.first().copied().unwrap_or(0) {
+            0 => Self::Restart,
+            1 => Self::Stop,
+            2 => Self::SnapshotAndStop,
+            other => {
+                warn!(
+                    "Unknown watchdog action {other} for {} at {:#x}, using restart",
+                    cfg.name, cfg.base_gpa
+                );
+                Self::Restart
+            }
+        }
+    }
+
+    /// Takes the action on the VM, unless it is not running anymore.
+    fn run(self, vm_id: usize) {
+        match vm_list::get_vm_by_id(vm_id).map(|vm| vm.vm_status()) {
+            Some(VMStatus::Running) => {}
+            _ => return,
+        }
+        events::record(
+            vm_id,
+            VmEventKind::WatchdogTimeout,
+            format!("action: {self:?}"),
+        );
+        match self {
+            Self::Restart => lifecycle::spawn_restart(vm_id),
+            Self::Stop => lifecycle::stop_vm(vm_id),
+            Self::SnapshotAndStop => lifecycle::spawn_snapshot_and_stop(vm_id),
+        }
+    }
+}
+
+fn now_ns() -> u64 {
+    axhal::time::wall_time().as_nanos() as u64
+}
+
+/// A one-shot countdown backed by a VMM timer.
+struct Countdown {
+    /// Wall time in nanoseconds at which the countdown expires, if running.
+    deadline_ns: Option<u64>,
+    /// Token of the pending timer.
+    token: Option<usize>,
+    /// Bumped whenever the countdown is re-armed or stopped, so stale callbacks are ignored.
+    generation: u64,
+}
+
+impl Countdown {
+    const fn new() -> Self {
+        Self {
+            deadline_ns: None,
+            token: None,
+            generation: 0,
+        }
+    }
+
+    fn stop(&mut self) {
+        if let Some(token) = self.token.take() {
+            timer::cancel_timer(token);
+        }
+        self.deadline_ns = None;
+        self.generation += 1;
+    }
+
+    fn remaining_ns(&self) -> u64 {
+        self.deadline_ns
+            .map_or(0, |deadline| deadline.saturating_sub(now_ns()))
+    }
+}
+
+/// Resets of the watchdogs of each VM, returning `false` once the watchdog is dropped.
+static VM_WATCHDOG_RESETS: SpinNoIrq<BTreeMap<usize, Vec<Box<dyn Fn() -> bool + Send>>>> =
+    SpinNoIrq::new(BTreeMap::new());
+
+/// Puts the watchdogs of the VM back in their reset state, stopping their countdowns. Called
+/// before the VM is restarted, so that a countdown of its previous run does not expire in the
+/// next one.
+pub fn reset(vm_id: usize) {
+    if let Some(resets) = VM_WATCHDOG_RESETS.lock().get_mut(&vm_id) {
+        // Watchdogs of a previous setup of a reconfigured VM are dropped with it.
+        resets.retain(|reset| reset());
+    }
+}
+
+/// Forgets the watchdogs of the VM, called when it is deleted.
+pub fn clear(vm_id: usize) {
+    VM_WATCHDOG_RESETS.lock().remove(&vm_id);
+}
+
+/// Registers the state of a watchdog of the VM to be reset by [`reset`].
+fn register<S: WatchdogState>(vm_id: usize, state: &Arc<SpinNoIrq<S>>) {
+    let weak_state = Arc::downgrade(state);
+    VM_WATCHDOG_RESETS
+        .lock()
+        .entry(vm_id)
+        .or_default()
+        .push(Box::new(move || {
+            let Some(state) = weak_state.upgrade() else {
+                return false;
+            };
+            let mut state = state.lock();
+            state.countdown().stop();
+            state.reset();
+            true
+        }));
+}
+
+/// State of a watchdog model that owns a [`Countdown`].
+trait WatchdogState: Send + 'static {
+    fn countdown(&mut self) -> &mut Countdown;
+
+    /// Puts the registers back in their reset state, the countdown is already stopped.
+    fn reset(&mut self);
+
+    /// Called with the state locked when the countdown expires. Returns the action to take once
+    /// the lock is released.
+    fn expired(this: &Arc<SpinNoIrq<Self>>, state: &mut Self) -> Option<TimeoutAction>;
+}
+
+/// (Re)starts the countdown of `state`, which must be the locked content of `this`.
+fn arm<S: WatchdogState>(this: &Arc<SpinNoIrq<S>>, state: &mut S, vm_id: usize, timeout_ns: u64) {
+    let countdown = state.countdown();
+    countdown.stop();
+
+    let deadline = now_ns() + timeout_ns;
+    let generation = countdown.generation;
+    let weak_state: Weak<SpinNoIrq<S>> = Arc::downgrade(this);
+
+    countdown.deadline_ns = Some(deadline);
+    countdown.token = Some(timer::register_timer(deadline, move |_| {
+        // The device may have been dropped together with its VM.
+        let Some(this) = weak_state.upgrade() else {
+            return;
+        };
+        let action = {
+            let mut state = this.lock();
+            let countdown = state.countdown();
+            if countdown.generation != generation {
+                return;
+            }
+            countdown.token = None;
+            countdown.deadline_ns = None;
+            S::expired(&this, &mut *state)
+        };
+        if let Some(action) = action {
+            action.run(vm_id);
+        }
+    }));
+}
+
+/// Mutable register state of the SP805.
+struct Sp805State {
+    vm_id: usize,
+    irq_id: usize,
+    action: TimeoutAction,
+    load: u32,
+    control: u32,
+    ris: bool,
+    locked: bool,
+    countdown: Countdown,
+}
+
+impl Sp805State {
+    fn load_ns(&self) -> u64 {
+        // The counter reaches zero after `load + 1` ticks.
+        (self.load as u64 + 1) * NANOS_PER_SEC / EMU_CLOCK_HZ
+    }
+
+    fn value(&self) -> u32 {
+        if self.countdown.deadline_ns.is_none() {
+            return self.load;
+        }
+        (self.countdown.remaining_ns() * EMU_CLOCK_HZ / NANOS_PER_SEC).min(u32::MAX as u64) as u32
+    }
+}
+
+impl WatchdogState for Sp805State {
+    fn countdown(&mut self) -> &mut Countdown {
+        &mut self.countdown
+    }
+
+    fn reset(&mut self) {
+        self.load = u32::MAX;
+        self.control = 0;
+        self.ris = false;
+        self.locked = false;
+    }
+
+    fn expired(this: &Arc<SpinNoIrq<Self>>, state: &mut Self) -> Option<TimeoutAction> {
+        if state.ris {
+            if state.control & WDOG_CONTROL_RESEN != 0 {
+                // Like a reset, the watchdog stays disabled until the guest programs it again.
+                state.control = 0;
+                state.ris = false;
+                return Some(state.action);
+            }
+        } else {
+            state.ris = true;
+            super::inject_irq(state.vm_id, state.irq_id);
+        }
+        let (vm_id, load_ns) = (state.vm_id, state.load_ns());
+        arm(this, state, vm_id, load_ns);
+        None
+    }
+}
+
+/// An emulated ARM SP805 watchdog.
+pub struct Sp805 {
+    vm_id: usize,
+    base: GuestPhysAddr,
+    length: usize,
+    state: Arc<SpinNoIrq<Sp805State>>,
+}
+
+impl Sp805 {
+    /// Creates the watchdog of a VM from its `emu_devices` entry.
+    pub fn new(vm_id: usize, cfg: &EmulatedDeviceConfig) -> Self {
+        let state = Arc::new(SpinNoIrq::new(Sp805State {
+            vm_id,
+            irq_id: cfg.irq_id,
+            action: TimeoutAction::from_cfg(cfg),
+            load: u32::MAX,
+            control: 0,
+            ris: false,
+            locked: false,
+            countdown: Countdown::new(),
+        }));
+        register(vm_id, &state);
+        Self {
+            vm_id,
+            base: GuestPhysAddr::from(cfg.base_gpa),
+            length: cfg.length,
+            state,
+        }
+    }
+
+    /// Restarts the counter from `WdogLoad` if it is enabled, stops it otherwise.
+    fn reload(&self, state: &mut Sp805State) {
+        if state.control & WDOG_CONTROL_INTEN != 0 {
+            let load_ns = state.load_ns();
+            arm(&self.state, state, self.vm_id, load_ns);
+        } else {
+            state.countdown.stop();
+        }
+    }
+}
+
+impl BaseDeviceOps<GuestPhysAddrRange> for Sp805 {
+    fn emu_type(&self) -> EmuDeviceType {
+        EmuDeviceType::Dummy
+    }
+
+    fn address_range(&self) -> GuestPhysAddrRange {
+        GuestPhysAddrRange::from_start_size(self.base, self.length)
+    }
+
+    fn handle_read(&self, addr: GuestPhysAddr, _width: AccessWidth) -> AxResult<usize> {
+        let offset = super::reg_offset(addr, self.base);
+        let state = self.state.lock();
+        let val = match offset {
+            WDOG_LOAD => state.load,
+            WDOG_VALUE => state.value(),
+            WDOG_CONTROL => state.control,
+            WDOG_RIS => state.ris as u32,
+            WDOG_MIS => (state.ris && state.control & WDOG_CONTROL_INTEN != 0) as u32,
+            WDOG_LOCK => state.locked as u32,
+            WDOG_ID_BASE..0x1000 if offset.is_multiple_of(4) => {
+                WDOG_ID[(offset - WDOG_ID_BASE) / 4]
+            }
+            _ => {
+                debug!(
+                    "VM[{}] SP805 read from unknown offset {offset:#x}",
+                    self.vm_id
+                );
+                0
+            }
+        };
+        Ok(val as usize)
+    }
+
+    fn handle_write(&self, addr: GuestPhysAddr, _width: AccessWidth, val: usize) -> AxResult {
+        let offset = super::reg_offset(addr, self.base);
+        let val = val as u32;
+        let mut state = self.state.lock();
+        if offset == WDOG_LOCK {
+            state.locked = val != WDOG_UNLOCK_KEY;
+            return Ok(());
+        }
+        if state.locked {
+            debug!(
+                "VM[{}] SP805 write to offset {offset:#x} ignored, registers are locked",
+                self.vm_id
+            );
+            return Ok(());
+        }
+        match offset {
+            WDOG_LOAD => {
+                // Writing the load register restarts the counter immediately.
+                state.load = val.max(1);
+                self.reload(&mut state);
+            }
+            WDOG_CONTROL => {
+                let was_enabled = state.control & WDOG_CONTROL_INTEN != 0;
+                state.control = val & (WDOG_CONTROL_INTEN | WDOG_CONTROL_RESEN);
+                if was_enabled != (state.control & WDOG_CONTROL_INTEN != 0) {
+                    self.reload(&mut state);
+                }
+            }
+            WDOG_INTCLR => {
+                state.ris = false;
+                self.reload(&mut state);
+            }
+            _ => {
+                debug!(
+                    "VM[{}] SP805 write {val:#x} to read-only or unknown offset {offset:#x}",
+                    self.vm_id
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Returns the device tree node of the SP805 watchdog.
+#[cfg_attr(not(target_arch = "aarch64"), allow(dead_code))]
+pub fn sp805_fdt_node(cfg: &EmulatedDeviceConfig) -> EmuDeviceNode {
+    EmuDeviceNode {
+        name: "watchdog",
+        compatible: &["arm,sp805", "arm,primecell"],
+        base_gpa: cfg.base_gpa,
+        length: cfg.length,
+        irq_id: (cfg.irq_id != 0).then_some(cfg.irq_id),
+        clocks: &["wdog_clk", "apb_pclk"],
+    }
+}
+
+/// Mutable register state of the generic watchdog.
+struct GenericWdtState {
+    action: TimeoutAction,
+    enabled: bool,
+    timeout_ms: u32,
+    /// Timeout given by `cfg_list`, restored on reset.
+    initial_timeout_ms: u32,
+    countdown: Countdown,
+}
+
+impl WatchdogState for GenericWdtState {
+    fn countdown(&mut self) -> &mut Countdown {
+        &mut self.countdown
+    }
+
+    fn reset(&mut self) {
+        self.enabled = false;
+        self.timeout_ms = self.initial_timeout_ms;
+    }
+
+    fn expired(_this: &Arc<SpinNoIrq<Self>>, state: &mut Self) -> Option<TimeoutAction> {
+        state.enabled = false;
+        Some(state.action)
+    }
+}
+
+/// An emulated `axvisor,wdt` watchdog.
+pub struct GenericWdt {
+    vm_id: usize,
+    base: GuestPhysAddr,
+    length: usize,
+    state: Arc<SpinNoIrq<GenericWdtState>>,
+}
+
+impl GenericWdt {
+    /// Creates the watchdog of a VM from its `emu_devices` entry.
+    pub fn new(vm_id: usize, cfg: &EmulatedDeviceConfig) -> Self {
+        let timeout_ms = match cfg.cfg_list.get(1).copied() {
+            Some(ms) if ms != 0 => ms as u32,
+            _ => WDT_DEFAULT_TIMEOUT_MS,
+        };
+        let state = Arc::new(SpinNoIrq::new(GenericWdtState {
+            action: TimeoutAction::from_cfg(cfg),
+            enabled: false,
+            timeout_ms,
+            initial_timeout_ms: timeout_ms,
+            countdown: Countdown::new(),
+        }));
+        register(vm_id, &state);
+        Self {
+            vm_id,
+            base: GuestPhysAddr::from(cfg.base_gpa),
+            length: cfg.length,
+            state,
+        }
+    }
+
+    /// Restarts the countdown if the watchdog is enabled, stops it otherwise.
+    fn kick(&self, state: &mut GenericWdtState) {
+        if state.enabled {
+            let timeout_ns = state.timeout_ms as u64 * NANOS_PER_MILLI;
+            arm(&self.state, state, self.vm_id, timeout_ns);
+        } else {
+            state.countdown.stop();
+        }
+    }
+}
+
+impl BaseDeviceOps<GuestPhysAddrRange> for GenericWdt {
+    fn emu_type(&self) -> EmuDeviceType {
+        EmuDeviceType::Dummy
+    }
+
+    fn address_range(&self) -> GuestPhysAddrRange {
+        GuestPhysAddrRange::from_start_size(self.base, self.length)
+    }
+
+    fn handle_read(&self, addr: GuestPhysAddr, _width: AccessWidth) -> AxResult<usize> {
+        let offset = super::reg_offset(addr, self.base);
+        let state = self.state.lock();
+        let val = match offset {
+            WDT_CTRL => state.enabled as u32,
+            WDT_TIMEOUT_MS => state.timeout_ms,
+            WDT_REMAINING_MS => (state.countdown.remaining_ns() / NANOS_PER_MILLI) as u32,
+            _ => 0,
+        };
+        Ok(val as usize)
+    }
+
+    fn handle_write(&self, addr: GuestPhysAddr, _width: AccessWidth, val: usize) -> AxResult {
+        let offset = super::reg_offset(addr, self.base);
+        let val = val as u32;
+        let mut state = self.state.lock();
+        match offset {
+            WDT_CTRL => {
+                state.enabled = val & 1 != 0;
+                self.kick(&mut state);
+            }
+            WDT_TIMEOUT_MS => {
+                state.timeout_ms = val.max(1);
+                self.kick(&mut state);
+            }
+            WDT_KICK => self.kick(&mut state),
+            _ => {
+                debug!(
+                    "VM[{}] watchdog write {val:#x} to read-only or unknown offset {offset:#x}",
+                    self.vm_id
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Returns the device tree node of the generic watchdog.
+#[cfg_attr(not(target_arch = "aarch64"), allow(dead_code))]
+pub fn generic_fdt_node(cfg: &EmulatedDeviceConfig) -> EmuDeviceNode {
+    EmuDeviceNode {
+        name: "watchdog",
+        compatible: &["axvisor,wdt"],
+        base_gpa: cfg.base_gpa,
+        length: cfg.length,
+        irq_id: None,
+        clocks: &[],
+    }
+}
@@ -0,0 +1,105 @@
+// Copyright 2025 The Axvisor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Per-VM log of notable guest events, such as panics and watchdog timeouts.
+//!
+//! Events are kept per VM ID until the VM is deleted, so they survive `vm restart` and can be
+//! inspected with `vm show`.
+
+use alloc::{
+    collections::{BTreeMap, VecDeque},
+    string::String,
+    vec::Vec,
+};
+use core::{fmt, time::Duration};
+use std::os::arceos::modules::axhal;
+
+use kspin::SpinNoIrq;
+
+/// Maximum number of events kept per VM, older events are dropped first.
+const MAX_EVENTS_PER_VM: usize = 32;
+
+/// Kind of a VM event.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VmEventKind {
+    /// The guest reported a panic through pvpanic.
+    GuestPanic,
+    /// The guest reported that a crash kernel is loaded through pvpanic.
+    GuestCrashLoaded,
+    /// The guest reported a regular shutdown through pvpanic.
+    GuestShutdown,
+    /// An emulated watchdog of the VM expired.
+    WatchdogTimeout,
+    /// The images of the VM were loaded again to restart it.
+    Restart,
+}
+
+impl fmt::Display for VmEventKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::GuestPanic => "guest panic",
+            Self::GuestCrashLoaded => "guest crash kernel loaded",
+            Self::GuestShutdown => "guest shutdown",
+            Self::WatchdogTimeout => "watchdog timeout",
+            Self::Restart => "restart",
+        })
+    }
+}
+
+/// An event recorded for a VM.
+#[derive(Debug, Clone)]
+pub struct VmEvent {
+    /// Host wall time at which the event was recorded.
+    pub time: Duration,
+    /// What happened.
+    pub kind: VmEventKind,
+    /// Additional information, e.g. the action taken by the hypervisor.
+    pub detail: String,
+}
+
+static VM_EVENTS: SpinNoIrq<BTreeMap<usize, VecDeque<VmEvent>>> = SpinNoIrq::new(BTreeMap::new());
+
+/// Records an event for the VM.
+pub fn record(vm_id: usize, kind: VmEventKind, detail: String) {
+    let event = VmEvent {
+        time: axhal::time::wall_time(),
+        kind,
+        detail,
+    };
+    warn!(
+        "VM[{vm_id}] event at {:?}: {} ({})",
+        event.time, event.kind, event.detail
+    );
+
+    let mut events = VM_EVENTS.lock();
+    let log = events.entry(vm_id).or_default();
+    if log.len() == MAX_EVENTS_PER_VM {
+        log.pop_front();
+    }
+    log.push_back(event);
+}
+
+/// Returns the events recorded for the VM, oldest first.
+pub fn events(vm_id: usize) -> Vec<VmEvent> {
+    VM_EVENTS
+        .lock()
+        .get(&vm_id)
+        .map(|log| log.iter().cloned().collect())
+        .unwrap_or_default()
+}
+
+/// Forgets the events of the VM, called when it is deleted.
+pub fn clear(vm_id: usize) {
+    VM_EVENTS.lock().remove(&vm_id);
+}
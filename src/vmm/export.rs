@@ -0,0 +1,173 @@
+// Copyright 2025 The Axvisor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Export of the configuration a VM actually runs with.
+//!
+//! The config of a VM is completed at creation: physical CPU sets, passthrough regions and SPIs
+//! are resolved from the device trees and load addresses may be relocated. The exported config is
+//! the one the VM was created from with these values filled in, so that it can be used to create
+//! the same VM again. Values that cannot be configured, such as the SPIs, are listed in an
+//! `[effective]` table for information only, it is ignored when the config is loaded.
+
+use alloc::{string::String, vec::Vec};
+
+use axerrno::{AxResult, ax_err_type};
+use toml::{Table, Value};
+
+use super::{VMRef, ext_config};
+
+/// Name of the informational table of exported configs.
+pub const EFFECTIVE_TABLE: &str = "effective";
+
+fn int(value: usize) -> Value {
+    Value::Integer(value as i64)
+}
+
+fn ints(values: impl IntoIterator<Item = usize>) -> Value {
+    Value::Array(values.into_iter().map(int).collect())
+}
+
+fn section<'a>(table: &'a mut Table, name: &str) -> &'a mut Table {
+    if !matches!(table.get(name), Some(Value::Table(_))) {
+        table.insert(name.into(), Value::Table(Table::new()));
+    }
+    match table.get_mut(name) {
+        Some(Value::Table(section)) => section,
+        _ => unreachable!(),
+    }
+}
+
+/// Returns the effective configuration of a VM as TOML.
+pub fn effective_config(vm: &VMRef) -> AxResult<String> {
+    let source = ext_config::get(vm.id()).source;
+    let mut table: Table = source.parse().map_err(|err| {
+        ax_err_type!(
+            InvalidData,
+            format!("VM[{}] has no usable source config: {err}", vm.id())
+        )
+    })?;
+
+    let affinities = vm.get_vcpu_affinities_pcpu_ids();
+    let base = section(&mut table, "base");
+    base.insert("cpu_num".into(), int(vm.vcpu_num()));
+    if affinities.iter().all(|(_, mask, _)| mask.is_some()) {
+        base.insert(
+            "phys_cpu_sets".into(),
+            ints(affinities.iter().filter_map(|(_, mask, _)| *mask)),
+        );
+    }
+
+    let memory_regions = vm.memory_regions();
+    let configured: Vec<Value> = match table.get("kernel").and_then(|k| k.get("memory_regions")) {
+        Some(Value::Array(regions)) => regions.clone(),
+        _ => Vec::new(),
+    };
+    let regions = memory_regions
+        .iter()
+        .enumerate()
+        .map(|(index, region)| {
+            // Keep the flags and the mapping type of the config, fill in the actual address.
+            let (flags, map_type) = match configured.get(index).and_then(Value::as_array) {
+                Some(entry) if entry.len() == 4 => (entry[2].clone(), entry[3].clone()),
+                _ => (int(0x7), int(region.is_identical() as usize)),
+            };
+            Value::Array(vec![
+                int(region.gpa.as_usize()),
+                int(region.size()),
+                flags,
+                map_type,
+            ])
+        })
+        .collect();
+
+    let mut effective = Table::new();
+    effective.insert(
+        "status".into(),
+        Value::String(format!("{:?}", vm.vm_status())),
+    );
+    effective.insert(
+        "vcpu_pcpu_ids".into(),
+        ints(affinities.iter().map(|(_, _, pcpu_id)| *pcpu_id)),
+    );
+    effective.insert(
+        "memory_hva".into(),
+        ints(memory_regions.iter().map(|region| region.hva.as_usize())),
+    );
+
+    vm.with_config(|cfg| {
+        let image = cfg.image_config();
+        let kernel = section(&mut table, "kernel");
+        kernel.insert("entry_point".into(), int(cfg.bsp_entry().as_usize()));
+        kernel.insert(
+            "kernel_load_addr".into(),
+            int(image.kernel_load_gpa.as_usize()),
+        );
+        for (key, addr) in [
+            ("dtb_load_addr", image.dtb_load_gpa),
+            ("bios_load_addr", image.bios_load_gpa),
+            ("ramdisk_load_addr", image.ramdisk_load_gpa),
+        ] {
+            if let Some(addr) = addr {
+                kernel.insert(key.into(), int(addr.as_usize()));
+            }
+        }
+        kernel.insert("memory_regions".into(), Value::Array(regions));
+
+        let devices = section(&mut table, "devices");
+        devices.insert(
+            "passthrough_devices".into(),
+            Value::Array(
+                cfg.pass_through_devices()
+                    .iter()
+                    .map(|dev| {
+                        Value::Array(vec![
+                            Value::String(dev.name.clone()),
+                            int(dev.base_gpa),
+                            int(dev.base_hpa),
+                            int(dev.length),
+                            int(dev.irq_id),
+                        ])
+                    })
+                    .collect(),
+            ),
+        );
+        devices.insert(
+            "passthrough_addresses".into(),
+            Value::Array(
+                cfg.pass_through_addresses()
+                    .iter()
+                    .map(|addr| ints([addr.base_gpa, addr.length]))
+                    .collect(),
+            ),
+        );
+
+        effective.insert(
+            "interrupt_mode".into(),
+            Value::String(format!("{:?}", cfg.interrupt_mode())),
+        );
+        #[cfg(target_arch = "aarch64")]
+        effective.insert(
+            "passthrough_spis".into(),
+            ints(cfg.pass_through_spis().iter().map(|&spi| spi as usize)),
+        );
+    });
+
+    table.insert(EFFECTIVE_TABLE.into(), Value::Table(effective));
+    toml::to_string(&table).map_err(|err| {
+        ax_err_type!(
+            InvalidData,
+            format!("VM[{}] config cannot be serialized: {err}", vm.id())
+        )
+    })
+}
@@ -0,0 +1,381 @@
+// Copyright 2025 The Axvisor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! VM configuration keys handled by AxVisor itself rather than by `axvmconfig`.
+//!
+//! [`parse`] takes these keys out of the raw TOML before it is handed to
+//! `AxVMCrateConfig::from_toml`, and the parsed values are kept per VM ID until the VM is deleted.
+//!
+//! ```toml
+//! [[devices.shares]]
+//! tag = "share"                 # mount tag seen by the guest
+//! path = "/guest/share/vm1"     # exported hypervisor directory
+//! read_only = false
+//! base_gpa = 0x0a00_0200        # virtio-mmio transport of the share
+//! length = 0x200
+//! irq_id = 49
+//!
+//! [[shared_memory]]
+//! name = "ring0"                # the VMs declaring the same name share the region
+//! size = 0x10_0000
+//! memory_type = "uncached"      # "normal" (default), "uncached" or "device"
+//! gpa = 0x9000_0000             # where this VM sees the region
+//! access = "rw"                 # "rw" (default) or "ro", for this VM
+//!
+//! [kernel]
+//! cmdline = "console=ttyAMA0 root=/dev/vda"  # replaces the bootargs of the DTB
+//! cmdline_append = ["rw", "quiet"]           # or a single string
+//! cmdline_remove = ["ro", "earlycon"]        # `key` removes `key` and `key=...`
+//! fit_config = "conf-rk3588"                # FIT configuration booted by a FIT image kernel
+//! scrub_all_memory = true                   # also zero identical and reserved regions
+//! # checked when the images are loaded, also dtb_, ramdisk_ and bios_sha256
+//! kernel_sha256 = "9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08"
+//! ```
+
+use alloc::{collections::BTreeMap, string::String, vec::Vec};
+
+use axerrno::{AxResult, ax_err, ax_err_type};
+use kspin::SpinNoIrq;
+use toml::{Table, Value};
+
+/// A hypervisor directory exported to the guest through virtio-9p.
+#[derive(Debug, Clone)]
+pub struct ShareConfig {
+    /// Mount tag of the share, e.g. `mount -t 9p -o trans=virtio <tag> /mnt`.
+    pub tag: String,
+    /// Exported directory in the hypervisor filesystem.
+    pub path: String,
+    /// Whether the guest is denied any modification of the share.
+    pub read_only: bool,
+    /// Base guest physical address of the virtio-mmio transport.
+    pub base_gpa: usize,
+    /// Length of the virtio-mmio transport region.
+    pub length: usize,
+    /// GIC interrupt ID (INTID) of the transport.
+    pub irq_id: usize,
+}
+
+/// Memory type of a shared memory region.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemoryType {
+    /// Cacheable normal memory.
+    Normal,
+    /// Non-cacheable normal memory.
+    Uncached,
+    /// Device memory.
+    Device,
+}
+
+/// A memory region shared with other VMs, see `shm`.
+#[derive(Debug, Clone)]
+pub struct SharedMemoryConfig {
+    /// Name of the region, shared by the VMs declaring the same name.
+    pub name: String,
+    pub size: usize,
+    pub memory_type: MemoryType,
+    /// Base guest physical address of the region in this VM.
+    pub gpa: usize,
+    /// Whether this VM can only read the region.
+    pub read_only: bool,
+}
+
+/// Changes to the guest kernel command line.
+#[derive(Debug, Clone, Default)]
+pub struct CmdlineConfig {
+    /// Replaces the command line provided by the DTB or the boot protocol.
+    pub cmdline: Option<String>,
+    /// Parameters appended to the command line.
+    pub append: Vec<String>,
+    /// Parameters removed from the command line, by exact match or by key.
+    pub remove: Vec<String>,
+}
+
+/// Expected SHA-256 digests of the image files of a VM, checked when they are loaded.
+#[derive(Debug, Clone, Default)]
+pub struct ImageDigests {
+    pub kernel: Option<[u8; 32]>,
+    pub dtb: Option<[u8; 32]>,
+    pub ramdisk: Option<[u8; 32]>,
+    pub bios: Option<[u8; 32]>,
+}
+
+/// AxVisor specific configuration of a VM.
+#[derive(Debug, Clone, Default)]
+pub struct ExtConfig {
+    /// Directories shared with the guest.
+    pub shares: Vec<ShareConfig>,
+    /// Memory regions shared with other VMs.
+    pub shared_memory: Vec<SharedMemoryConfig>,
+    /// Guest kernel command line.
+    pub cmdline: CmdlineConfig,
+    /// Configuration of a FIT image kernel to boot, its default configuration if not set.
+    pub fit_config: Option<String>,
+    /// Digests of the images.
+    pub digests: ImageDigests,
+    /// Whether the identically mapped and reserved memory regions are zeroed with the allocated
+    /// ones when the VM is deleted or set up again.
+    pub scrub_all_memory: bool,
+    /// The complete config the VM was created from, used to export and edit it.
+    pub source: String,
+}
+
+static EXT_CONFIGS: SpinNoIrq<BTreeMap<usize, ExtConfig>> = SpinNoIrq::new(BTreeMap::new());
+
+/// Splits a raw VM config into the part understood by `axvmconfig` and the AxVisor specific
+/// configuration.
+pub fn parse(raw_cfg: &str) -> AxResult<(String, ExtConfig)> {
+    let mut table: Table = raw_cfg
+        .parse()
+        .map_err(|err| ax_err_type!(InvalidInput, format!("invalid VM config: {err}")))?;
+
+    let mut ext = ExtConfig {
+        source: raw_cfg.into(),
+        ..Default::default()
+    };
+
+    // Informational table of exported configs, see `export`.
+    table.remove(super::export::EFFECTIVE_TABLE);
+
+    if let Some(Value::Table(devices)) = table.get_mut("devices")
+        && let Some(shares) = devices.remove("shares")
+    {
+        let Value::Array(shares) = shares else {
+            return ax_err!(InvalidInput, "devices.shares must be an array of tables");
+        };
+        for (index, share) in shares.iter().enumerate() {
+            ext.shares.push(parse_share(share).map_err(|msg| {
+                ax_err_type!(InvalidInput, format!("devices.shares[{index}]: {msg}"))
+            })?);
+        }
+    }
+
+    if let Some(shared_memory) = table.remove("shared_memory") {
+        let Value::Array(shared_memory) = shared_memory else {
+            return ax_err!(InvalidInput, "shared_memory must be an array of tables");
+        };
+        for (index, shm) in shared_memory.iter().enumerate() {
+            ext.shared_memory
+                .push(parse_shared_memory(shm).map_err(|msg| {
+                    ax_err_type!(InvalidInput, format!("shared_memory[{index}]: {msg}"))
+                })?);
+        }
+    }
+
+    if let Some(Value::Table(kernel)) = table.get_mut("kernel") {
+        if let Some(cmdline) = kernel.remove("cmdline") {
+            let Value::String(cmdline) = cmdline else {
+                return ax_err!(InvalidInput, "kernel.cmdline must be a string");
+            };
+            ext.cmdline.cmdline = Some(cmdline);
+        }
+        if let Some(fit_config) = kernel.remove("fit_config") {
+            let Value::String(fit_config) = fit_config else {
+                return ax_err!(InvalidInput, "kernel.fit_config must be a string");
+            };
+            ext.fit_config = Some(fit_config);
+        }
+        if let Some(scrub_all_memory) = kernel.remove("scrub_all_memory") {
+            let Value::Boolean(scrub_all_memory) = scrub_all_memory else {
+                return ax_err!(InvalidInput, "kernel.scrub_all_memory must be a boolean");
+            };
+            ext.scrub_all_memory = scrub_all_memory;
+        }
+        for (key, digest) in [
+            ("kernel_sha256", &mut ext.digests.kernel),
+            ("dtb_sha256", &mut ext.digests.dtb),
+            ("ramdisk_sha256", &mut ext.digests.ramdisk),
+            ("bios_sha256", &mut ext.digests.bios),
+        ] {
+            if let Some(value) = kernel.remove(key) {
+                *digest =
+                    Some(parse_sha256(&value).map_err(|msg| {
+                        ax_err_type!(InvalidInput, format!("kernel.{key}: {msg}"))
+                    })?);
+            }
+        }
+        for (key, params) in [
+            ("cmdline_append", &mut ext.cmdline.append),
+            ("cmdline_remove", &mut ext.cmdline.remove),
+        ] {
+            if let Some(value) = kernel.remove(key) {
+                *params = parse_params(&value)
+                    .map_err(|msg| ax_err_type!(InvalidInput, format!("kernel.{key}: {msg}")))?;
+            }
+        }
+    }
+
+    let stripped = toml::to_string(&table)
+        .map_err(|err| ax_err_type!(InvalidInput, format!("invalid VM config: {err}")))?;
+    Ok((stripped, ext))
+}
+
+fn parse_share(value: &Value) -> Result<ShareConfig, String> {
+    let table = value.as_table().ok_or("must be a table")?;
+    Ok(ShareConfig {
+        tag: get_str(table, "tag")?,
+        path: get_str(table, "path")?,
+        read_only: match table.get("read_only") {
+            None => false,
+            Some(value) => value.as_bool().ok_or("read_only must be a boolean")?,
+        },
+        base_gpa: get_usize(table, "base_gpa")?,
+        length: get_usize(table, "length")?,
+        irq_id: get_usize(table, "irq_id")?,
+    })
+}
+
+fn parse_shared_memory(value: &Value) -> Result<SharedMemoryConfig, String> {
+    let table = value.as_table().ok_or("must be a table")?;
+    let memory_type = match table.get("memory_type") {
+        None => MemoryType::Normal,
+        Some(value) => match value.as_str() {
+            Some("normal") => MemoryType::Normal,
+            Some("uncached") => MemoryType::Uncached,
+            Some("device") => MemoryType::Device,
+            _ => return Err("memory_type must be \"normal\", \"uncached\" or \"device\"".into()),
+        },
+    };
+    let read_only = match table.get("access") {
+        None => false,
+        Some(value) => match value.as_str() {
+            Some("rw") => false,
+            Some("ro") => true,
+            _ => return Err("access must be \"rw\" or \"ro\"".into()),
+        },
+    };
+    Ok(SharedMemoryConfig {
+        name: get_str(table, "name")?,
+        size: get_usize(table, "size")?,
+        memory_type,
+        gpa: get_usize(table, "gpa")?,
+        read_only,
+    })
+}
+
+/// Parameters given either as a string or as an array of strings.
+fn parse_params(value: &Value) -> Result<Vec<String>, String> {
+    match value {
+        Value::String(params) => Ok(split_params(params).into_iter().map(String::from).collect()),
+        Value::Array(params) => params
+            .iter()
+            .map(|param| {
+                param
+                    .as_str()
+                    .map(String::from)
+                    .ok_or_else(|| "must be a string or an array of strings".into())
+            })
+            .collect(),
+        _ => Err("must be a string or an array of strings".into()),
+    }
+}
+
+/// Splits a command line into parameters at whitespace outside double quotes, which are kept,
+/// e.g. `dyndbg="file foo.c +p"` is one parameter as for the Linux kernel.
+pub fn split_params(params: &str) -> Vec<&str> {
+    let mut split = Vec::new();
+    let mut start = None;
+    let mut in_quotes = false;
+    for (pos, ch) in params.char_indices() {
+        if ch.is_whitespace() && !in_quotes {
+            if let Some(start) = start.take() {
+                split.push(&params[start..pos]);
+            }
+        } else {
+            start.get_or_insert(pos);
+            if ch == '"' {
+                in_quotes = !in_quotes;
+            }
+        }
+    }
+    if let Some(start) = start {
+        split.push(&params[start..]);
+    }
+    split
+}
+
+/// Parses a SHA-256 digest written as 64 hexadecimal digits.
+fn parse_sha256(value: &Value) -> Result<[u8; 32], String> {
+    let hex = value.as_str().ok_or("must be a string")?;
+    if hex.len() != 64 || !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Err("must be 64 hexadecimal digits".into());
+    }
+    let mut digest = [0u8; 32];
+    for (byte, pair) in digest.iter_mut().zip(hex.as_bytes().chunks(2)) {
+        // Only ASCII hex digits, checked above.
+        *byte = u8::from_str_radix(core::str::from_utf8(pair).unwrap(), 16).unwrap();
+    }
+    Ok(digest)
+}
+
+fn get_str(table: &Table, key: &str) -> Result<String, String> {
+    match table.get(key) {
+        Some(Value::String(s)) => Ok(s.clone()),
+        Some(_) => Err(format!("{key} must be a string")),
+        None => Err(format!("missing {key}")),
+    }
+}
+
+fn get_usize(table: &Table, key: &str) -> Result<usize, String> {
+    match table.get(key) {
+        Some(Value::Integer(i)) if *i >= 0 => Ok(*i as usize),
+        Some(_) => Err(format!("{key} must be a non-negative integer")),
+        None => Err(format!("missing {key}")),
+    }
+}
+
+/// Stores the AxVisor specific configuration of a VM.
+pub fn insert(vm_id: usize, ext: ExtConfig) {
+    EXT_CONFIGS.lock().insert(vm_id, ext);
+}
+
+/// Returns the AxVisor specific configuration of a VM.
+pub fn get(vm_id: usize) -> ExtConfig {
+    EXT_CONFIGS.lock().get(&vm_id).cloned().unwrap_or_default()
+}
+
+/// Forgets the configuration of a VM, called when it is deleted.
+pub fn remove(vm_id: usize) {
+    EXT_CONFIGS.lock().remove(&vm_id);
+}
+
+impl ShareConfig {
+    /// Short description used in logs, e.g. `share:/guest/share/vm1 (ro)`.
+    pub fn describe(&self) -> String {
+        let mode = if self.read_only { "ro" } else { "rw" };
+        format!("{}:{} ({mode})", self.tag, self.path)
+    }
+}
+
+impl CmdlineConfig {
+    /// Whether the command line is left as provided.
+    pub fn is_empty(&self) -> bool {
+        self.cmdline.is_none() && self.append.is_empty() && self.remove.is_empty()
+    }
+
+    /// Applies the changes to the command line `provided` by the DTB or the boot protocol.
+    pub fn apply(&self, provided: &str) -> String {
+        let base = self.cmdline.as_deref().unwrap_or(provided);
+        let mut params: Vec<&str> = split_params(base)
+            .into_iter()
+            .filter(|param| {
+                !self.remove.iter().any(|removed| {
+                    *param == removed
+                        || param.split_once('=').is_some_and(|(key, _)| key == removed)
+                })
+            })
+            .collect();
+        params.extend(self.append.iter().map(String::as_str));
+        params.join(" ")
+    }
+}
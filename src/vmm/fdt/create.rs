@@ -18,13 +18,18 @@ use alloc::{
 };
 use core::ptr::NonNull;
 
-use super::vm_fdt::{FdtWriter, FdtWriterNode};
+use super::vm_fdt::{FdtReserveEntry, FdtWriter, FdtWriterNode};
 use axaddrspace::GuestPhysAddr;
 use axvm::{VMMemoryRegion, config::AxVMCrateConfig};
 use fdt_parser::{Fdt, Node};
 use memory_addr::MemoryAddr;
 
-use crate::vmm::{VMRef, images::load_vm_image_from_memory};
+use crate::vmm::{
+    VMRef,
+    emu::{EMU_CLOCK_HZ, EmuDeviceNode},
+    ext_config::{CmdlineConfig, SharedMemoryConfig},
+    images::load_vm_image_from_memory,
+};
 
 // use crate::vmm::fdt::print::{print_fdt, print_guest_fdt};
 /// Generate guest FDT and return DTB data
@@ -274,10 +279,102 @@ fn add_memory_node(new_memory: &[VMMemoryRegion], new_fdt: &mut FdtWriter) {
     new_fdt.property_string("device_type", "memory").unwrap();
 }
 
-pub fn update_fdt(fdt_src: NonNull<u8>, dtb_size: usize, vm: VMRef) {
-    let mut new_fdt = FdtWriter::new().unwrap();
+/// Add nodes of the emulated devices provided by the hypervisor itself.
+///
+/// `max_phandle` is the largest phandle used by the source FDT, new phandles are allocated above it.
+fn add_emu_device_nodes(nodes: &[EmuDeviceNode], max_phandle: u32, new_fdt: &mut FdtWriter) {
+    const GIC_SPI: u32 = 0;
+    const IRQ_TYPE_LEVEL_HIGH: u32 = 4;
+    const SPI_BASE: usize = 32;
+
+    // AMBA primecells need an `apb_pclk` clock to be probed by Linux. All the clocks of the
+    // emulated devices are backed by this single fixed clock.
+    let apb_pclk = nodes.iter().any(|node| !node.clocks.is_empty()).then(|| {
+        let phandle = max_phandle + 1;
+        let clk_node = new_fdt.begin_node("apb-pclk").unwrap();
+        new_fdt.property_string("compatible", "fixed-clock").unwrap();
+        new_fdt.property_u32("#clock-cells", 0).unwrap();
+        new_fdt
+            .property_u32("clock-frequency", EMU_CLOCK_HZ as u32)
+            .unwrap();
+        new_fdt
+            .property_string("clock-output-names", "apb_pclk")
+            .unwrap();
+        new_fdt.property_phandle(phandle).unwrap();
+        new_fdt.end_node(clk_node).unwrap();
+        phandle
+    });
+
+    for node in nodes {
+        info!("Adding emulated device node: {}", node.node_name());
+        let dev_node = new_fdt.begin_node(&node.node_name()).unwrap();
+        new_fdt
+            .property_string_list(
+                "compatible",
+                node.compatible.iter().map(|s| s.to_string()).collect(),
+            )
+            .unwrap();
+        let (gpa, size) = (node.base_gpa as u64, node.length as u64);
+        new_fdt
+            .property_array_u32(
+                "reg",
+                &[
+                    (gpa >> 32) as u32,
+                    gpa as u32,
+                    (size >> 32) as u32,
+                    size as u32,
+                ],
+            )
+            .unwrap();
+        if let Some(irq_id) = node.irq_id
+            && irq_id >= SPI_BASE
+        {
+            new_fdt
+                .property_array_u32(
+                    "interrupts",
+                    &[GIC_SPI, (irq_id - SPI_BASE) as u32, IRQ_TYPE_LEVEL_HIGH],
+                )
+                .unwrap();
+        }
+        if let Some(phandle) = apb_pclk
+            && !node.clocks.is_empty()
+        {
+            new_fdt
+                .property_array_u32("clocks", &vec![phandle; node.clocks.len()])
+                .unwrap();
+            new_fdt
+                .property_string_list(
+                    "clock-names",
+                    node.clocks.iter().map(|s| s.to_string()).collect(),
+                )
+                .unwrap();
+        }
+        new_fdt.end_node(dev_node).unwrap();
+    }
+}
+
+/// Adapts the FDT to the VM and loads it into guest memory. `initrd` is the address and size of
+/// the loaded ramdisk, which is passed to the guest through `chosen` and reserved with a
+/// `/memreserve/` entry.
+pub fn update_fdt(
+    fdt_src: NonNull<u8>,
+    dtb_size: usize,
+    vm: VMRef,
+    initrd: Option<(GuestPhysAddr, usize)>,
+) {
+    let reservations: Vec<_> = initrd
+        .and_then(|(gpa, size)| FdtReserveEntry::new(gpa.as_usize() as u64, size as u64).ok())
+        .into_iter()
+        .collect();
+    let mut new_fdt = FdtWriter::new_with_mem_reserv(&reservations).unwrap();
     let mut previous_node_level = 0;
     let mut node_stack: Vec<FdtWriterNode> = Vec::new();
+    let mut max_phandle = 0;
+    let ext_config = crate::vmm::ext_config::get(vm.id());
+    let (cmdline, shared_memory) = (ext_config.cmdline, ext_config.shared_memory);
+    let mut bootargs_written = false;
+    let mut chosen_written = false;
+    let mut reserved_memory_written = shared_memory.is_empty();
 
     let fdt_bytes = unsafe { core::slice::from_raw_parts(fdt_src.as_ptr(), dtb_size) };
     let fdt = Fdt::from_bytes(fdt_bytes)
@@ -313,7 +410,7 @@ pub fn update_fdt(fdt_src: NonNull<u8>, dtb_size: usize, vm: VMRef) {
                     );
                 } else if prop.name == "bootargs" {
                     let bootargs_str = prop.str();
-                    let modified_bootargs = bootargs_str.replace(" ro ", " rw ");
+                    let modified_bootargs = guest_bootargs(&cmdline, bootargs_str);
 
                     if modified_bootargs != bootargs_str {
                         info!(
@@ -325,6 +422,7 @@ pub fn update_fdt(fdt_src: NonNull<u8>, dtb_size: usize, vm: VMRef) {
                     new_fdt
                         .property_string(prop.name, &modified_bootargs)
                         .unwrap();
+                    bootargs_written = true;
                 } else {
                     debug!(
                         "Find property: {}, belonging to node: {}",
@@ -334,10 +432,30 @@ pub fn update_fdt(fdt_src: NonNull<u8>, dtb_size: usize, vm: VMRef) {
                     new_fdt.property(prop.name, prop.raw_value()).unwrap();
                 }
             }
+            if !bootargs_written && !cmdline.is_empty() {
+                let bootargs = cmdline.apply("");
+                info!("Adding bootargs: {bootargs}");
+                new_fdt.property_string("bootargs", &bootargs).unwrap();
+            }
+            add_initrd_properties(initrd, &mut new_fdt);
+            chosen_written = true;
         } else {
+            // Cells of the `reg` of the children, the defaults of the spec if not given.
+            let (mut address_cells, mut size_cells) = (2, 1);
             for prop in node.propertys() {
+                if prop.name == "phandle" || prop.name == "linux,phandle" {
+                    max_phandle = max_phandle.max(prop.u32());
+                } else if prop.name == "#address-cells" {
+                    address_cells = prop.u32();
+                } else if prop.name == "#size-cells" {
+                    size_cells = prop.u32();
+                }
                 new_fdt.property(prop.name, prop.raw_value()).unwrap();
             }
+            if node.level == 2 && node.name() == "reserved-memory" && !reserved_memory_written {
+                add_shared_memory_nodes(&shared_memory, address_cells, size_cells, &mut new_fdt);
+                reserved_memory_written = true;
+            }
         }
     }
 
@@ -353,6 +471,29 @@ pub fn update_fdt(fdt_src: NonNull<u8>, dtb_size: usize, vm: VMRef) {
             let memory_node = new_fdt.begin_node("memory").unwrap();
             add_memory_node(&memory_regions, &mut new_fdt);
             new_fdt.end_node(memory_node).unwrap();
+
+            add_emu_device_nodes(&crate::vmm::emu::fdt_nodes(&vm), max_phandle, &mut new_fdt);
+
+            if !reserved_memory_written {
+                let reserved_node = new_fdt.begin_node("reserved-memory").unwrap();
+                new_fdt.property_u32("#address-cells", 2).unwrap();
+                new_fdt.property_u32("#size-cells", 2).unwrap();
+                new_fdt.property_null("ranges").unwrap();
+                add_shared_memory_nodes(&shared_memory, 2, 2, &mut new_fdt);
+                new_fdt.end_node(reserved_node).unwrap();
+                reserved_memory_written = true;
+            }
+
+            if !chosen_written && (!cmdline.is_empty() || initrd.is_some()) {
+                let chosen_node = new_fdt.begin_node("chosen").unwrap();
+                if !cmdline.is_empty() {
+                    let bootargs = cmdline.apply("");
+                    info!("Adding chosen node with bootargs: {bootargs}");
+                    new_fdt.property_string("bootargs", &bootargs).unwrap();
+                }
+                add_initrd_properties(initrd, &mut new_fdt);
+                new_fdt.end_node(chosen_node).unwrap();
+            }
         }
     }
 
@@ -363,6 +504,11 @@ pub fn update_fdt(fdt_src: NonNull<u8>, dtb_size: usize, vm: VMRef) {
     let new_fdt_bytes = new_fdt.finish().unwrap();
 
     // crate::vmm::fdt::print::print_guest_fdt(new_fdt_bytes.as_slice());
+    crate::vmm::measure::extend(
+        vm.id(),
+        "guest-dtb",
+        crate::vmm::measure::sha256(&new_fdt_bytes),
+    );
     let vm_clone = vm.clone();
     let dest_addr = calculate_dtb_load_addr(vm, new_fdt_bytes.len());
     info!(
@@ -375,6 +521,63 @@ pub fn update_fdt(fdt_src: NonNull<u8>, dtb_size: usize, vm: VMRef) {
         .expect("Failed to load VM images");
 }
 
+/// Adds the shared memory regions of the VM as children of the `reserved-memory` node, whose
+/// `reg` properties have the given numbers of cells.
+fn add_shared_memory_nodes(
+    regions: &[SharedMemoryConfig],
+    address_cells: u32,
+    size_cells: u32,
+    new_fdt: &mut FdtWriter,
+) {
+    fn push_cells(cells: &mut Vec<u32>, value: usize, count: u32) {
+        for index in (0..count).rev() {
+            cells.push((value as u64).checked_shr(32 * index).unwrap_or(0) as u32);
+        }
+    }
+
+    for shm in regions {
+        let node_name = format!("{}@{:x}", shm.name, shm.gpa);
+        info!(
+            "Adding shared memory node {node_name}, size {:#x}",
+            shm.size
+        );
+        let shm_node = new_fdt.begin_node(&node_name).unwrap();
+        new_fdt
+            .property_string("compatible", "axvisor,shared-memory")
+            .unwrap();
+        let mut reg = Vec::new();
+        push_cells(&mut reg, shm.gpa, address_cells);
+        push_cells(&mut reg, shm.size, size_cells);
+        new_fdt.property_array_u32("reg", &reg).unwrap();
+        new_fdt.property_null("no-map").unwrap();
+        if shm.read_only {
+            new_fdt.property_null("read-only").unwrap();
+        }
+        new_fdt.end_node(shm_node).unwrap();
+    }
+}
+
+/// Writes the `linux,initrd-start` and `linux,initrd-end` properties of the `chosen` node.
+fn add_initrd_properties(initrd: Option<(GuestPhysAddr, usize)>, new_fdt: &mut FdtWriter) {
+    let Some((gpa, size)) = initrd else {
+        return;
+    };
+    let (start, end) = (gpa.as_usize() as u64, (gpa.as_usize() + size) as u64);
+    info!("Adding initrd [{start:#x}, {end:#x}) to chosen node");
+    new_fdt.property_u64("linux,initrd-start", start).unwrap();
+    new_fdt.property_u64("linux,initrd-end", end).unwrap();
+}
+
+/// Returns the guest bootargs from those of the DTB. Without any `cmdline*` key in the VM config,
+/// a read-only root is turned into a read-write one.
+fn guest_bootargs(cmdline: &CmdlineConfig, provided: &str) -> String {
+    if cmdline.is_empty() {
+        provided.replace(" ro ", " rw ")
+    } else {
+        cmdline.apply(provided)
+    }
+}
+
 fn calculate_dtb_load_addr(vm: VMRef, fdt_size: usize) -> GuestPhysAddr {
     const MB: usize = 1024 * 1024;
 
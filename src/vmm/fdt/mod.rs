@@ -95,6 +95,18 @@ pub fn get_developer_provided_dtb(
     vm_cfg: &AxVMConfig,
     crate_config: &AxVMCrateConfig,
 ) -> Option<Vec<u8>> {
+    // The FDT of a FIT image or multi-file uImage kernel comes first.
+    let fit_config = crate::vmm::ext_config::get(vm_cfg.id()).fit_config;
+    match crate::vmm::images::get_kernel_uboot_fdt(crate_config, fit_config.as_deref()) {
+        Some(Ok(dtb)) => {
+            info!("DTB in the kernel image, size: 0x{:x}", dtb.len());
+            return Some(dtb);
+        }
+        // The image loader fails on the same error.
+        Some(Err(msg)) => error!("VM[{}] kernel FDT: {msg}", vm_cfg.id()),
+        None => {}
+    }
+
     match crate_config.kernel.image_location.as_deref() {
         Some("memory") => {
             let vm_imags = config::get_memory_images()
@@ -106,7 +118,7 @@ pub fn get_developer_provided_dtb(
                 return Some(dtb.to_vec());
             }
         }
-        #[cfg(feature = "fs")]
+        #[cfg(any(feature = "fs", feature = "guest-archive"))]
         Some("fs") => {
             use axerrno::ax_err_type;
             use std::io::{BufReader, Read};
@@ -129,8 +141,20 @@ pub fn get_developer_provided_dtb(
                 return Some(dtb_buffer);
             }
         }
+        Some("block") => {
+            if let Some(dtb_path) = &crate_config.kernel.dtb_path {
+                match crate::vmm::images::block::read_image(dtb_path) {
+                    Ok(dtb) => {
+                        info!("DTB on block device, size: 0x{:x}", dtb.len());
+                        return Some(dtb);
+                    }
+                    // The image loader fails on the same error.
+                    Err(msg) => error!("VM[{}] DTB: {msg}", vm_cfg.id()),
+                }
+            }
+        }
         _ => unimplemented!(
-            "Check your \"image_location\" in config.toml, \"memory\" and \"fs\" are supported,\n."
+            "Check your \"image_location\" in config.toml, \"memory\", \"fs\" and \"block\" are supported,\n."
         ),
     }
     None
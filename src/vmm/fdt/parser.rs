@@ -14,7 +14,10 @@
 
 //! FDT parsing and processing functionality.
 
-use alloc::{string::ToString, vec::Vec};
+use alloc::{
+    string::{String, ToString},
+    vec::Vec,
+};
 use axvm::config::{AxVMConfig, AxVMCrateConfig, PassThroughDeviceConfig};
 use fdt_parser::{Fdt, FdtHeader, PciRange, PciSpace};
 
@@ -409,3 +412,49 @@ pub fn update_provided_fdt(provided_dtb: &[u8], host_dtb: &[u8], crate_config: &
     let provided_dtb_data = update_cpu_node(&provided_fdt, &host_fdt, crate_config);
     crate_guest_fdt_with_cache(provided_dtb_data, crate_config);
 }
+
+/// Returns the `reg` values of the CPU nodes of the host FDT, i.e. the valid `phys_cpu_ids`, in
+/// the order used to compute physical CPU indices.
+pub fn host_cpu_ids() -> Vec<usize> {
+    let Ok(fdt) = Fdt::from_bytes(get_host_fdt()) else {
+        return Vec::new();
+    };
+    let mut ids = Vec::new();
+    for cpu_node in fdt.find_nodes("/cpus/cpu") {
+        if let Some(mut cpu_reg) = cpu_node.reg()
+            && let Some(r) = cpu_reg.next()
+            && !ids.contains(&(r.address as usize))
+        {
+            ids.push(r.address as usize);
+        }
+    }
+    ids
+}
+
+/// Returns the host memory ranges reserved by the FDT, from both the memory reservation block
+/// and the `/reserved-memory` node, as `(address, size)` pairs.
+pub fn host_reserved_memory() -> Vec<(usize, usize)> {
+    let Ok(fdt) = Fdt::from_bytes(get_host_fdt()) else {
+        return Vec::new();
+    };
+    let mut ranges: Vec<(usize, usize)> = fdt
+        .memory_reservation_block()
+        .map(|region| (region.address as usize, region.size))
+        .collect();
+    for node in fdt.reserved_memory() {
+        if let Some(reg) = node.reg() {
+            ranges.extend(reg.map(|r| (r.address as usize, r.size.unwrap_or(0))));
+        }
+    }
+    ranges
+}
+
+/// Returns the `bootargs` of the `/chosen` node of the host FDT, i.e. the hypervisor command line.
+pub fn host_bootargs() -> Option<String> {
+    let fdt = Fdt::from_bytes(get_host_fdt()).ok()?;
+    let chosen = fdt.all_nodes().find(|node| node.name() == "chosen")?;
+    chosen
+        .propertys()
+        .find(|prop| prop.name == "bootargs")
+        .map(|prop| prop.str().to_string())
+}
@@ -115,7 +115,6 @@ impl FdtReserveEntry {
     ///
     /// * address: Physical address of the beginning of the reserved region.
     /// * size: Size of the reserved region in bytes.
-    #[allow(dead_code)]
     pub fn new(address: u64, size: u64) -> Result<Self> {
         if address.checked_add(size).is_none() || size == 0 {
             return Err(Error::InvalidMemoryReservation);
@@ -0,0 +1,455 @@
+// Copyright 2025 The Axvisor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Read access to the files of VM configs and images.
+//!
+//! With the `guest-archive` feature, `build.rs` packs the directory named by the
+//! `AXVISOR_GUEST_DIR` environment variable into a newc cpio archive built into AxVisor, which is
+//! mounted read-only at [`MOUNT_POINT`]. Its files are read through the functions of this module
+//! like those of the filesystem, which serves every other path when the `fs` feature is enabled.
+//! Boards without usable storage thus boot from `/guest/axvisor.toml` or `/guest/vm_default` and
+//! `image_location = "fs"` configs.
+//!
+//! The archive is not part of the ArceOS filesystem: paths under [`MOUNT_POINT`] only resolve to
+//! it through this module, and writes to them are refused, see [`check_writable`].
+
+use alloc::{collections::BTreeMap, string::String, vec::Vec};
+use std::io::{self, Read, Seek, SeekFrom};
+
+use spin::Once;
+
+/// Where the archive is mounted.
+pub const MOUNT_POINT: &str = "/guest";
+
+/// Magic number of a newc cpio header.
+const CPIO_MAGIC: &[u8; 6] = b"070701";
+/// Size of a newc cpio header: the magic and 13 fields of 8 hexadecimal digits.
+const CPIO_HEADER_SIZE: usize = 110;
+/// Name of the entry ending a cpio archive.
+const CPIO_TRAILER: &str = "TRAILER!!!";
+
+/// File type bits of a mode.
+const S_IFMT: u32 = 0o170_000;
+const S_IFDIR: u32 = 0o040_000;
+const S_IFREG: u32 = 0o100_000;
+
+/// A file or directory of the archive.
+struct Entry {
+    mode: u32,
+    data: &'static [u8],
+}
+
+impl Entry {
+    fn is_dir(&self) -> bool {
+        self.mode & S_IFMT == S_IFDIR
+    }
+}
+
+/// The files of the archive, by path relative to [`MOUNT_POINT`], `""` being the archive root.
+static ARCHIVE: Once<BTreeMap<String, Entry>> = Once::new();
+
+fn archive() -> &'static BTreeMap<String, Entry> {
+    ARCHIVE.call_once(|| {
+        let data = super::config::config::guest_archive();
+        if data.is_empty() {
+            return BTreeMap::new();
+        }
+        match parse_cpio(data) {
+            Ok(entries) => {
+                info!(
+                    "Guest archive: {} entries, {} bytes, mounted at {MOUNT_POINT}",
+                    entries.len(),
+                    data.len()
+                );
+                entries
+            }
+            Err(msg) => {
+                error!("Ignoring the guest archive: {msg}");
+                BTreeMap::new()
+            }
+        }
+    })
+}
+
+/// Parses a newc cpio archive, adding the directories it leaves implicit.
+fn parse_cpio(data: &'static [u8]) -> Result<BTreeMap<String, Entry>, String> {
+    let align = |offset: usize| offset.next_multiple_of(4);
+    let mut entries = BTreeMap::new();
+    entries.insert(
+        String::new(),
+        Entry {
+            mode: S_IFDIR | 0o555,
+            data: &[],
+        },
+    );
+
+    let mut offset = 0;
+    loop {
+        let header = data
+            .get(offset..offset + CPIO_HEADER_SIZE)
+            .ok_or_else(|| format!("truncated header at {offset:#x}"))?;
+        if !header.starts_with(CPIO_MAGIC) {
+            return Err(format!(
+                "bad magic at {offset:#x}, only newc archives are supported"
+            ));
+        }
+        let field = |index: usize| {
+            let digits = &header[6 + index * 8..14 + index * 8];
+            core::str::from_utf8(digits)
+                .ok()
+                .and_then(|digits| usize::from_str_radix(digits, 16).ok())
+                .ok_or_else(|| format!("bad header field at {offset:#x}"))
+        };
+        let (mode, file_size, name_size) = (field(1)? as u32, field(6)?, field(11)?);
+
+        let name_start = offset + CPIO_HEADER_SIZE;
+        let name = data
+            .get(name_start..name_start + name_size)
+            .and_then(|name| name.strip_suffix(b"\0"))
+            .and_then(|name| core::str::from_utf8(name).ok())
+            .ok_or_else(|| format!("bad entry name at {offset:#x}"))?;
+        if name == CPIO_TRAILER {
+            return Ok(entries);
+        }
+
+        let data_start = align(name_start + name_size);
+        let file_data = data
+            .get(data_start..data_start + file_size)
+            .ok_or_else(|| format!("{name}: truncated data"))?;
+        offset = align(data_start + file_size);
+
+        let path = normalize(name);
+        if path.is_empty() {
+            continue;
+        }
+        // Parents may not have entries of their own, or come after their children.
+        let mut parent = path.as_str();
+        while let Some((dir, _)) = parent.rsplit_once('/') {
+            entries.entry(dir.into()).or_insert(Entry {
+                mode: S_IFDIR | 0o555,
+                data: &[],
+            });
+            parent = dir;
+        }
+        match mode & S_IFMT {
+            S_IFDIR | S_IFREG => {
+                entries.insert(
+                    path,
+                    Entry {
+                        mode,
+                        data: file_data,
+                    },
+                );
+            }
+            _ => debug!("Guest archive: skipping {name}, neither a file nor a directory"),
+        }
+    }
+}
+
+/// Joins the components of a path, resolving `.` and `..`, without leading or trailing `/`.
+fn normalize(path: &str) -> String {
+    let mut components: Vec<&str> = Vec::new();
+    for component in path.split('/') {
+        match component {
+            "" | "." => {}
+            ".." => {
+                components.pop();
+            }
+            component => components.push(component),
+        }
+    }
+    components.join("/")
+}
+
+/// Where a path is.
+enum Location<'a> {
+    /// In the archive, relative to [`MOUNT_POINT`].
+    Archive(String),
+    /// Anywhere else.
+    Host(&'a str),
+}
+
+fn locate(path: &str) -> Location<'_> {
+    if archive().is_empty() {
+        return Location::Host(path);
+    }
+    #[cfg(feature = "fs")]
+    let absolute = if path.starts_with('/') {
+        normalize(path)
+    } else {
+        match std::env::current_dir() {
+            Ok(dir) => normalize(&format!("{dir}/{path}")),
+            Err(_) => return Location::Host(path),
+        }
+    };
+    #[cfg(not(feature = "fs"))]
+    let absolute = normalize(path);
+
+    match absolute.strip_prefix(mount_name()) {
+        Some("") => Location::Archive(String::new()),
+        Some(rest) if rest.starts_with('/') => Location::Archive(rest[1..].into()),
+        _ => Location::Host(path),
+    }
+}
+
+/// Name of the mount point in the root directory.
+fn mount_name() -> &'static str {
+    MOUNT_POINT.trim_start_matches('/')
+}
+
+fn archive_entry(path: &str) -> io::Result<&'static Entry> {
+    archive().get(path).ok_or(io::Error::NotFound)
+}
+
+/// Metadata of a file or directory.
+#[derive(Debug, Clone, Copy)]
+pub struct Metadata {
+    mode: u32,
+    size: u64,
+}
+
+impl Metadata {
+    /// Size of the file in bytes.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    pub fn is_dir(&self) -> bool {
+        self.mode & S_IFMT == S_IFDIR
+    }
+
+    /// File type and permission bits, as `st_mode`.
+    pub fn mode(&self) -> u32 {
+        self.mode
+    }
+}
+
+#[cfg(feature = "fs")]
+impl From<std::fs::Metadata> for Metadata {
+    fn from(metadata: std::fs::Metadata) -> Self {
+        let ty = metadata.file_type();
+        let type_bits = if ty.is_dir() {
+            S_IFDIR
+        } else if ty.is_file() {
+            S_IFREG
+        } else if ty.is_symlink() {
+            0o120_000
+        } else if ty.is_char_device() {
+            0o020_000
+        } else if ty.is_block_device() {
+            0o060_000
+        } else if ty.is_fifo() {
+            0o010_000
+        } else if ty.is_socket() {
+            0o140_000
+        } else {
+            0
+        };
+        Self {
+            mode: type_bits | (metadata.permissions().mode() & 0o7777),
+            size: metadata.len(),
+        }
+    }
+}
+
+/// Returns the metadata of a file or directory.
+pub fn metadata(path: &str) -> io::Result<Metadata> {
+    match locate(path) {
+        Location::Archive(path) => {
+            let entry = archive_entry(&path)?;
+            Ok(Metadata {
+                mode: entry.mode,
+                size: entry.data.len() as u64,
+            })
+        }
+        #[cfg(feature = "fs")]
+        Location::Host(path) => std::fs::metadata(path).map(Metadata::from),
+        // Without a filesystem, the root only holds the mount point.
+        #[cfg(not(feature = "fs"))]
+        Location::Host(path) if normalize(path).is_empty() && !archive().is_empty() => {
+            Ok(Metadata {
+                mode: S_IFDIR | 0o555,
+                size: 0,
+            })
+        }
+        #[cfg(not(feature = "fs"))]
+        Location::Host(_) => Err(io::Error::NotFound),
+    }
+}
+
+/// An entry of a directory.
+#[derive(Debug, Clone)]
+pub struct DirEntry {
+    path: String,
+    name: String,
+}
+
+impl DirEntry {
+    /// Path of the entry, the directory path joined with its name.
+    pub fn path(&self) -> String {
+        self.path.clone()
+    }
+
+    pub fn file_name(&self) -> String {
+        self.name.clone()
+    }
+}
+
+/// Returns the entries of a directory, in no particular order.
+pub fn read_dir(path: &str) -> io::Result<Vec<DirEntry>> {
+    let entry = |name: &str| DirEntry {
+        path: format!("{}/{name}", path.trim_end_matches('/')),
+        name: name.into(),
+    };
+    match locate(path) {
+        Location::Archive(dir) => {
+            if !archive_entry(&dir)?.is_dir() {
+                return Err(io::Error::NotADirectory);
+            }
+            let prefix = if dir.is_empty() { dir } else { dir + "/" };
+            Ok(archive()
+                .range(prefix.clone()..)
+                .map(|(path, _)| path)
+                .skip_while(|path| path.is_empty())
+                .take_while(|path| path.starts_with(&prefix))
+                .map(|path| &path[prefix.len()..])
+                .filter(|name| !name.contains('/'))
+                .map(entry)
+                .collect())
+        }
+        #[cfg(feature = "fs")]
+        Location::Host(path) => {
+            let mut entries = Vec::new();
+            for dir_entry in std::fs::read_dir(path)? {
+                let dir_entry = dir_entry?;
+                entries.push(DirEntry {
+                    path: dir_entry.path(),
+                    name: dir_entry.file_name(),
+                });
+            }
+            if normalize(path).is_empty() && !archive().is_empty() {
+                entries.retain(|dir_entry| dir_entry.name != MOUNT_POINT[1..]);
+                entries.push(entry(mount_name()));
+            }
+            Ok(entries)
+        }
+        #[cfg(not(feature = "fs"))]
+        Location::Host(path) => {
+            metadata(path)?;
+            Ok(vec![entry(mount_name())])
+        }
+    }
+}
+
+/// A file opened for reading.
+pub enum File {
+    Archive {
+        data: &'static [u8],
+        position: usize,
+    },
+    #[cfg(feature = "fs")]
+    Host(std::fs::File),
+}
+
+impl File {
+    pub fn open(path: &str) -> io::Result<Self> {
+        match locate(path) {
+            Location::Archive(path) => {
+                let entry = archive_entry(&path)?;
+                if entry.is_dir() {
+                    return Err(io::Error::IsADirectory);
+                }
+                Ok(Self::Archive {
+                    data: entry.data,
+                    position: 0,
+                })
+            }
+            #[cfg(feature = "fs")]
+            Location::Host(path) => std::fs::File::open(path).map(Self::Host),
+            #[cfg(not(feature = "fs"))]
+            Location::Host(path) => Err(match metadata(path) {
+                Ok(_) => io::Error::IsADirectory,
+                Err(err) => err,
+            }),
+        }
+    }
+
+    pub fn metadata(&self) -> io::Result<Metadata> {
+        match self {
+            Self::Archive { data, .. } => Ok(Metadata {
+                mode: S_IFREG | 0o444,
+                size: data.len() as u64,
+            }),
+            #[cfg(feature = "fs")]
+            Self::Host(file) => file.metadata().map(Metadata::from),
+        }
+    }
+}
+
+impl Read for File {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Self::Archive { data, position } => {
+                let rest = &data[(*position).min(data.len())..];
+                let len = buf.len().min(rest.len());
+                buf[..len].copy_from_slice(&rest[..len]);
+                *position += len;
+                Ok(len)
+            }
+            #[cfg(feature = "fs")]
+            Self::Host(file) => file.read(buf),
+        }
+    }
+}
+
+impl Seek for File {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        match self {
+            Self::Archive { data, position } => {
+                let new_position = match pos {
+                    SeekFrom::Start(offset) => Some(offset),
+                    SeekFrom::End(offset) => (data.len() as u64).checked_add_signed(offset),
+                    SeekFrom::Current(offset) => (*position as u64).checked_add_signed(offset),
+                }
+                .ok_or(io::Error::InvalidInput)?;
+                *position = new_position as usize;
+                Ok(new_position)
+            }
+            #[cfg(feature = "fs")]
+            Self::Host(file) => file.seek(pos),
+        }
+    }
+}
+
+/// Reads a whole file.
+pub fn read(path: &str) -> io::Result<Vec<u8>> {
+    let mut file = File::open(path)?;
+    let mut data = Vec::with_capacity(file.metadata()?.size() as usize);
+    file.read_to_end(&mut data)?;
+    Ok(data)
+}
+
+/// Reads a whole file as UTF-8 text.
+pub fn read_to_string(path: &str) -> io::Result<String> {
+    String::from_utf8(read(path)?).map_err(|_| io::Error::InvalidData)
+}
+
+/// Fails with `PermissionDenied` if `path` is in the read-only archive.
+#[cfg(feature = "fs")]
+pub fn check_writable(path: &str) -> io::Result<()> {
+    match locate(path) {
+        Location::Archive(_) => Err(io::Error::PermissionDenied),
+        Location::Host(_) => Ok(()),
+    }
+}
@@ -15,23 +15,34 @@
 use axaddrspace::{GuestPhysAddr, MappingFlags};
 use axerrno::{AxResult, ax_err, ax_err_type};
 use axhvc::{HyperCallCode, HyperCallResult};
+use axvm::config::AxVMCrateConfig;
 
 use crate::vmm::ivc::{self, IVCChannel};
-use crate::vmm::{VCpuRef, VMRef};
+use crate::vmm::{VCpuRef, VMRef, ext_config, measure};
+
+/// AxVisor specific hypercall, not defined by `axhvc`: copies the measured boot log of the VM,
+/// see `measure::encode`, to the guest buffer at GPA `args[0]` of `args[1]` bytes and returns
+/// the size of the log. Nothing is copied if the buffer is too small. The buffer must lie in a
+/// single memory region of the VM that the guest may write.
+pub const HVC_READ_MEASUREMENTS: u64 = 0x4158_0001;
 
 pub struct HyperCall {
     _vcpu: VCpuRef,
     vm: VMRef,
-    code: HyperCallCode,
+    /// `None` for [`HVC_READ_MEASUREMENTS`].
+    code: Option<HyperCallCode>,
     args: [u64; 6],
 }
 
 impl HyperCall {
     pub fn new(vcpu: VCpuRef, vm: VMRef, code: u64, args: [u64; 6]) -> AxResult<Self> {
-        let code = HyperCallCode::try_from(code as u32).map_err(|e| {
-            warn!("Invalid hypercall code: {code} e {e:?}");
-            ax_err_type!(InvalidInput)
-        })?;
+        let code = match code {
+            HVC_READ_MEASUREMENTS => None,
+            code => Some(HyperCallCode::try_from(code as u32).map_err(|e| {
+                warn!("Invalid hypercall code: {code} e {e:?}");
+                ax_err_type!(InvalidInput)
+            })?),
+        };
 
         Ok(Self {
             _vcpu: vcpu,
@@ -42,18 +53,16 @@ impl HyperCall {
     }
 
     pub fn execute(&self) -> HyperCallResult {
-        match self.code {
+        let Some(code) = self.code else {
+            return self.read_measurements();
+        };
+        match code {
             HyperCallCode::HIVCPublishChannel => {
                 let key = self.args[0] as usize;
                 let shm_base_gpa_ptr = GuestPhysAddr::from_usize(self.args[1] as usize);
                 let shm_size_ptr = GuestPhysAddr::from_usize(self.args[2] as usize);
 
-                info!(
-                    "VM[{}] HyperCall {:?} key {:#x}",
-                    self.vm.id(),
-                    self.code,
-                    key
-                );
+                info!("VM[{}] HyperCall {:?} key {:#x}", self.vm.id(), code, key);
                 // User will pass the size of the shared memory region,
                 // we will allocate the shared memory region based on this size.
                 let shm_region_size = self.vm.read_from_guest_of::<usize>(shm_size_ptr)?;
@@ -85,7 +94,7 @@ impl HyperCall {
                 info!(
                     "VM[{}] HyperCall {:?} with key {:#x}",
                     self.vm.id(),
-                    self.code,
+                    code,
                     key
                 );
                 let (base_gpa, size) = ivc::unpublish_channel(self.vm.id(), key)?.unwrap();
@@ -102,7 +111,7 @@ impl HyperCall {
                 info!(
                     "VM[{}] HyperCall {:?} to VM[{}]",
                     self.vm.id(),
-                    self.code,
+                    code,
                     publisher_vm_id
                 );
 
@@ -144,7 +153,7 @@ impl HyperCall {
                 info!(
                     "VM[{}] HyperCall {:?} from VM[{}]",
                     self.vm.id(),
-                    self.code,
+                    code,
                     publisher_vm_id
                 );
                 let (base_gpa, size) =
@@ -154,9 +163,60 @@ impl HyperCall {
                 Ok(0)
             }
             _ => {
-                warn!("Unsupported hypercall code: {:?}", self.code);
+                warn!("Unsupported hypercall code: {:?}", code);
                 ax_err!(Unsupported)?
             }
         }
     }
+
+    /// Handles [`HVC_READ_MEASUREMENTS`].
+    fn read_measurements(&self) -> HyperCallResult {
+        let buffer = GuestPhysAddr::from_usize(self.args[0] as usize);
+        let buffer_size = self.args[1] as usize;
+        let Some(log) = measure::log(self.vm.id()) else {
+            return ax_err!(NotFound, "the VM has no measurement log");
+        };
+        let log = measure::encode(&log);
+        debug!(
+            "VM[{}] HyperCall read measurements into {:#x}, {} bytes",
+            self.vm.id(),
+            buffer,
+            buffer_size
+        );
+        if log.len() <= buffer_size {
+            if !self.is_guest_writable(buffer, log.len())? {
+                warn!(
+                    "VM[{}] HyperCall read measurements into {:#x} denied, not writable guest memory",
+                    self.vm.id(),
+                    buffer
+                );
+                return ax_err!(PermissionDenied);
+            }
+            crate::vmm::images::load_vm_image_from_memory(&log, buffer, self.vm.clone())?;
+        }
+        Ok(log.len())
+    }
+
+    /// Returns whether the `size` bytes at `gpa` lie in one memory region of the VM whose config
+    /// lets the guest write it. The hypervisor writes through its own mapping, so it must not
+    /// write what the guest could not, e.g. read-only or shared memory.
+    fn is_guest_writable(&self, gpa: GuestPhysAddr, size: usize) -> AxResult<bool> {
+        let Some(end) = gpa.as_usize().checked_add(size) else {
+            return Ok(false);
+        };
+        let (raw_cfg, _) = ext_config::parse(&ext_config::get(self.vm.id()).source)?;
+        let config = AxVMCrateConfig::from_toml(&raw_cfg)
+            .map_err(|err| ax_err_type!(InvalidData, format!("invalid VM config: {err:?}")))?;
+        // Memory regions are allocated in the order of the config, shared memory is not one.
+        Ok(self
+            .vm
+            .memory_regions()
+            .iter()
+            .zip(&config.kernel.memory_regions)
+            .any(|(region, memory)| {
+                region.gpa <= gpa
+                    && end <= region.gpa.as_usize() + region.size()
+                    && MappingFlags::from_bits_truncate(memory.flags).contains(MappingFlags::WRITE)
+            }))
+    }
 }
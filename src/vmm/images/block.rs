@@ -0,0 +1,256 @@
+// Copyright 2025 The Axvisor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Guest images read from raw block devices, for `image_location = "block"`.
+//!
+//! The image paths of the VM config name a byte range of a block device registered by
+//! `driver::blk`, devices being numbered in registration order:
+//!
+//! - `blk<N>:<offset>:<size>`: `size` bytes at byte `offset` of the block device `N`;
+//! - `gpt:<label>` or `gpt:<label>:<size>`: the GPT partition named `label` on any block device,
+//!   or its first `size` bytes.
+//!
+//! Offsets and sizes are decimal or `0x` prefixed hexadecimal numbers, `_` separators allowed.
+
+use alloc::{string::String, vec::Vec};
+
+use crate::driver::blk;
+
+/// Signature of a GPT header.
+const GPT_SIGNATURE: &[u8; 8] = b"EFI PART";
+/// Size of the fields of a GPT header, the header may be larger.
+const GPT_HEADER_SIZE: usize = 92;
+/// Maximum size of a partition entry array read, 128 entries of 128 bytes usually.
+const GPT_MAX_ENTRIES_SIZE: usize = 0x10_0000;
+/// Maximum number of UTF-16 code units of a partition name.
+const GPT_NAME_LEN: usize = 36;
+
+/// Reads a disk at a byte offset.
+type ReadAt<'a> = &'a mut dyn FnMut(u64, &mut [u8]) -> Result<(), String>;
+
+/// A byte range of a block device holding an image.
+#[derive(Debug, Clone, Copy)]
+pub struct BlockImage {
+    device: usize,
+    offset: u64,
+    size: usize,
+}
+
+impl BlockImage {
+    /// Finds the byte range named by an image path, see the module documentation.
+    pub fn locate(path: &str) -> Result<Self, String> {
+        let image = match path.split(':').collect::<Vec<_>>()[..] {
+            ["gpt", label] => find_gpt_partition(label)?,
+            ["gpt", label, size] => {
+                let partition = find_gpt_partition(label)?;
+                let size = parse_number(size)?;
+                if size > partition.size {
+                    return Err(format!(
+                        "{path}: larger than the partition ({:#x} bytes)",
+                        partition.size
+                    ));
+                }
+                Self { size, ..partition }
+            }
+            [device, offset, size] => {
+                let device = device
+                    .strip_prefix("blk")
+                    .and_then(|index| index.parse().ok())
+                    .ok_or_else(|| format!("invalid block device {device:?}, expected blk<N>"))?;
+                Self {
+                    device,
+                    offset: parse_number(offset)? as u64,
+                    size: parse_number(size)?,
+                }
+            }
+            _ => {
+                return Err(format!(
+                    "invalid block image {path:?}, expected blk<N>:<offset>:<size> or \
+                     gpt:<label>[:<size>]"
+                ));
+            }
+        };
+
+        let (device_size, _) = blk::device_size(image.device)?;
+        if image.offset.saturating_add(image.size as u64) > device_size {
+            return Err(format!(
+                "{path}: [{:#x}, {:#x}) is beyond the end of blk{} ({device_size:#x} bytes)",
+                image.offset,
+                image.offset.saturating_add(image.size as u64),
+                image.device
+            ));
+        }
+        Ok(image)
+    }
+
+    /// Size of the image in bytes.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Reads `buf.len()` bytes at `offset` of the image.
+    pub fn read(&self, offset: usize, buf: &mut [u8]) -> Result<(), String> {
+        if offset.saturating_add(buf.len()) > self.size {
+            return Err("read beyond the end of the image".into());
+        }
+        blk::read_at(self.device, self.offset + offset as u64, buf)
+    }
+
+    /// Reads the next bytes of the image from `*offset` into `buf`, advancing `*offset`.
+    pub fn read_next(&self, offset: &mut usize, buf: &mut [u8]) -> Result<usize, String> {
+        let len = buf.len().min(self.size.saturating_sub(*offset));
+        self.read(*offset, &mut buf[..len])?;
+        *offset += len;
+        Ok(len)
+    }
+
+    /// Reads up to `len` bytes from the start of the image.
+    pub fn read_head(&self, len: usize) -> Result<Vec<u8>, String> {
+        let mut head = vec![0u8; len.min(self.size)];
+        self.read(0, &mut head)?;
+        Ok(head)
+    }
+
+    /// Reads the whole image into memory.
+    pub fn read_all(&self) -> Result<Vec<u8>, String> {
+        self.read_head(self.size)
+    }
+}
+
+/// Reads a whole image named by a path into memory.
+pub fn read_image(path: &str) -> Result<Vec<u8>, String> {
+    BlockImage::locate(path)?
+        .read_all()
+        .map_err(|msg| format!("{path}: {msg}"))
+}
+
+/// Parses a decimal or `0x` prefixed hexadecimal number.
+fn parse_number(value: &str) -> Result<usize, String> {
+    let digits = value.replace('_', "");
+    match digits
+        .strip_prefix("0x")
+        .or_else(|| digits.strip_prefix("0X"))
+    {
+        Some(hex) => usize::from_str_radix(hex, 16),
+        None => digits.parse(),
+    }
+    .map_err(|_| format!("invalid number {value:?}"))
+}
+
+/// Finds the GPT partition named `label` on the block devices.
+fn find_gpt_partition(label: &str) -> Result<BlockImage, String> {
+    for device in 0..blk::device_count() {
+        match find_gpt_partition_on(device, label) {
+            Ok(Some(image)) => return Ok(image),
+            Ok(None) => {}
+            Err(msg) => debug!("blk{device}: no usable GPT: {msg}"),
+        }
+    }
+    Err(format!("no GPT partition named {label:?}"))
+}
+
+/// Finds the GPT partition named `label` on the block device `device`, using its primary GPT.
+fn find_gpt_partition_on(device: usize, label: &str) -> Result<Option<BlockImage>, String> {
+    let (_, block_size) = blk::device_size(device)?;
+    let partition = find_gpt_partition_in(
+        &mut |offset, buf| blk::read_at(device, offset, buf),
+        block_size,
+        label,
+    )?;
+    Ok(partition.map(|(offset, size)| BlockImage {
+        device,
+        offset,
+        size,
+    }))
+}
+
+/// Finds the GPT partition named `label` on a disk of `block_size` byte blocks, `read(offset,
+/// buf)` reading the disk at byte `offset`. Returns the byte offset and size of the partition.
+fn find_gpt_partition_in(
+    read: ReadAt<'_>,
+    block_size: usize,
+    label: &str,
+) -> Result<Option<(u64, usize)>, String> {
+    let mut header = vec![0u8; block_size];
+    read(block_size as u64, &mut header)?;
+    if !header.starts_with(GPT_SIGNATURE) {
+        return Err("no GPT signature".into());
+    }
+
+    let u32_at = |data: &[u8], offset: usize| {
+        u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+    };
+    let u64_at = |data: &[u8], offset: usize| {
+        u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap())
+    };
+
+    let header_size = u32_at(&header, 12) as usize;
+    if !(GPT_HEADER_SIZE..=block_size).contains(&header_size) {
+        return Err(format!("invalid header size {header_size}"));
+    }
+    let mut crc_input = header[..header_size].to_vec();
+    crc_input[16..20].fill(0);
+    if crc32fast::hash(&crc_input) != u32_at(&header, 16) {
+        return Err("header CRC mismatch".into());
+    }
+
+    let entries_lba = u64_at(&header, 72);
+    let entry_count = u32_at(&header, 80) as usize;
+    let entry_size = u32_at(&header, 84) as usize;
+    let entries_size = entry_count.saturating_mul(entry_size);
+    if entry_size < 128 || entries_size > GPT_MAX_ENTRIES_SIZE {
+        return Err(format!(
+            "unsupported partition entries {entry_count} x {entry_size}"
+        ));
+    }
+    let entries_offset = entries_lba
+        .checked_mul(block_size as u64)
+        .ok_or("invalid partition entries LBA")?;
+    let mut entries = vec![0u8; entries_size];
+    read(entries_offset, &mut entries)?;
+    if crc32fast::hash(&entries) != u32_at(&header, 88) {
+        return Err("partition entries CRC mismatch".into());
+    }
+
+    for entry in entries.chunks_exact(entry_size) {
+        // Unused entries have a zero partition type GUID.
+        if entry[..16].iter().all(|&byte| byte == 0) {
+            continue;
+        }
+        let name: Vec<u16> = entry[56..56 + 2 * GPT_NAME_LEN]
+            .chunks_exact(2)
+            .map(|unit| u16::from_le_bytes([unit[0], unit[1]]))
+            .take_while(|&unit| unit != 0)
+            .collect();
+        if !char::decode_utf16(name)
+            .map(Result::ok)
+            .eq(label.chars().map(Some))
+        {
+            continue;
+        }
+        let (first_lba, last_lba) = (u64_at(entry, 32), u64_at(entry, 40));
+        let block_size = block_size as u64;
+        let offset = first_lba.checked_mul(block_size);
+        let size = last_lba
+            .checked_sub(first_lba)
+            .and_then(|blocks| blocks.checked_add(1)?.checked_mul(block_size));
+        let (Some(offset), Some(size)) = (offset, size) else {
+            return Err(format!("partition {label:?} has an invalid range"));
+        };
+        let size =
+            usize::try_from(size).map_err(|_| format!("partition {label:?} is too large"))?;
+        return Ok(Some((offset, size)));
+    }
+    Ok(None)
+}
@@ -0,0 +1,394 @@
+// Copyright 2025 The Axvisor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The x86 Linux boot protocol for bzImage kernels, see `Documentation/arch/x86/boot.rst`.
+//!
+//! The real-mode setup code of the image is not run. Its protected-mode part is loaded at
+//! `kernel_load_addr` and entered at the 64-bit entry point, with the zero page (`struct
+//! boot_params`) built here instead of by the setup code. The BSP starts in real mode, so it first
+//! runs a small trampoline which enters long mode with the first 4 GiB identity mapped and jumps
+//! to the kernel with `rsi` pointing at the zero page.
+//!
+//! Low guest memory used to boot:
+//!
+//! ```text
+//! 0x7000  zero page
+//! 0x8000  trampoline, including its GDT
+//! 0x9000  page tables: PML4, PDPT and four page directories of 2 MiB pages
+//! 0x20000 command line
+//! ```
+
+use alloc::{string::String, vec::Vec};
+
+/// Bytes of the image needed to parse its setup header.
+pub const HEADER_SIZE: usize = 0x400;
+/// Guest physical address of the zero page.
+pub const BOOT_PARAMS_GPA: usize = 0x7000;
+/// Guest physical address of the trampoline, the entry point of the BSP.
+pub const TRAMPOLINE_GPA: usize = 0x8000;
+/// Guest physical address of the page tables used to enter the kernel.
+pub const PAGE_TABLES_GPA: usize = 0x9000;
+/// Guest physical address of the command line.
+pub const CMDLINE_GPA: usize = 0x2_0000;
+
+const PAGE_SIZE: usize = 0x1000;
+/// Identity mapped memory, one page directory of 2 MiB pages per GiB.
+const IDENTITY_MAPPED_GIB: usize = 4;
+/// Size of the page tables: PML4, PDPT and the page directories.
+pub const PAGE_TABLES_SIZE: usize = (2 + IDENTITY_MAPPED_GIB) * PAGE_SIZE;
+
+/// Offsets in the image and in the zero page, which starts with a copy of the image's first page.
+mod offset {
+    pub const EXT_RAMDISK_IMAGE: usize = 0x0c0;
+    pub const EXT_RAMDISK_SIZE: usize = 0x0c4;
+    pub const EXT_CMD_LINE_PTR: usize = 0x0c8;
+    pub const E820_ENTRIES: usize = 0x1e8;
+    pub const SETUP_SECTS: usize = 0x1f1;
+    pub const BOOT_FLAG: usize = 0x1fe;
+    pub const JUMP: usize = 0x200;
+    pub const HEADER: usize = 0x202;
+    pub const VERSION: usize = 0x206;
+    pub const TYPE_OF_LOADER: usize = 0x210;
+    pub const LOADFLAGS: usize = 0x211;
+    pub const CODE32_START: usize = 0x214;
+    pub const RAMDISK_IMAGE: usize = 0x218;
+    pub const RAMDISK_SIZE: usize = 0x21c;
+    pub const CMD_LINE_PTR: usize = 0x228;
+    pub const INITRD_ADDR_MAX: usize = 0x22c;
+    pub const KERNEL_ALIGNMENT: usize = 0x230;
+    pub const RELOCATABLE_KERNEL: usize = 0x234;
+    pub const XLOADFLAGS: usize = 0x236;
+    pub const CMDLINE_SIZE: usize = 0x238;
+    pub const PREF_ADDRESS: usize = 0x258;
+    pub const INIT_SIZE: usize = 0x260;
+    pub const E820_TABLE: usize = 0x2d0;
+}
+
+const BOOT_FLAG: u16 = 0xaa55;
+const HEADER_MAGIC: &[u8; 4] = b"HdrS";
+/// The 64-bit entry point is needed, it is described since protocol 2.12.
+const MIN_VERSION: u16 = 0x020c;
+const LOADED_HIGH: u8 = 1 << 0;
+const XLF_KERNEL_64: u16 = 1 << 0;
+const XLF_CAN_BE_LOADED_ABOVE_4G: u16 = 1 << 1;
+/// `type_of_loader` of boot loaders without an assigned ID.
+const UNDEFINED_LOADER: u8 = 0xff;
+/// Offset of the 64-bit entry point from the start of the protected-mode kernel.
+const ENTRY_64_OFFSET: usize = 0x200;
+
+const E820_MAX_ENTRIES: usize = 128;
+const E820_ENTRY_SIZE: usize = 20;
+/// `type` of usable RAM in the memory map.
+pub const E820_RAM: u32 = 1;
+const E820_RESERVED: u32 = 2;
+/// Legacy VGA and BIOS area, never usable as RAM.
+const LEGACY_HOLE: (usize, usize) = (0xa_0000, 0x10_0000);
+
+/// The boot setup of a bzImage kernel.
+#[derive(Debug, Clone)]
+pub struct BzImage {
+    /// Size of the boot sector and real-mode setup code, the protected-mode kernel follows.
+    pub setup_size: usize,
+    /// Setup header, copied to the zero page.
+    header: Vec<u8>,
+    xloadflags: u16,
+    relocatable: bool,
+    kernel_alignment: usize,
+    pref_address: usize,
+    init_size: usize,
+    initrd_addr_max: usize,
+    /// Maximum length of the command line, without the terminating NUL.
+    pub cmdline_size: usize,
+}
+
+impl BzImage {
+    /// Returns whether the image starts with a Linux boot sector and setup header.
+    pub fn is_bzimage(image: &[u8]) -> bool {
+        read_u16(image, offset::BOOT_FLAG) == Some(BOOT_FLAG)
+            && image.get(offset::HEADER..offset::HEADER + 4) == Some(HEADER_MAGIC)
+    }
+
+    /// Parses the setup header from the first [`HEADER_SIZE`] bytes of the image.
+    pub fn parse(image: &[u8]) -> Result<Self, String> {
+        if !Self::is_bzimage(image) {
+            return Err("not a bzImage".into());
+        }
+        let version = read_u16(image, offset::VERSION).ok_or("truncated setup header")?;
+        if version < MIN_VERSION {
+            return Err(format!(
+                "boot protocol {}.{:02} is too old, {}.{:02} is needed for the 64-bit entry point",
+                version >> 8,
+                version & 0xff,
+                MIN_VERSION >> 8,
+                MIN_VERSION & 0xff
+            ));
+        }
+        let byte = |offset| image.get(offset).copied().ok_or("truncated setup header");
+        let header_end = offset::JUMP + 2 + byte(offset::JUMP + 1)? as usize;
+        let header = image
+            .get(offset::SETUP_SECTS..header_end)
+            .ok_or("truncated setup header")?
+            .to_vec();
+        let field = |offset| read_u32(image, offset).ok_or("truncated setup header");
+
+        let xloadflags = read_u16(image, offset::XLOADFLAGS).unwrap_or(0);
+        if xloadflags & XLF_KERNEL_64 == 0 {
+            return Err("the kernel has no 64-bit entry point".into());
+        }
+        if byte(offset::LOADFLAGS)? & LOADED_HIGH == 0 {
+            return Err("zImage kernels loaded below 1 MiB are not supported".into());
+        }
+        let setup_sects = match byte(offset::SETUP_SECTS)? {
+            0 => 4,
+            sects => sects as usize,
+        };
+
+        Ok(Self {
+            setup_size: (setup_sects + 1) * 512,
+            header,
+            xloadflags,
+            relocatable: byte(offset::RELOCATABLE_KERNEL)? != 0,
+            kernel_alignment: field(offset::KERNEL_ALIGNMENT)?.max(1) as usize,
+            pref_address: read_u64(image, offset::PREF_ADDRESS).ok_or("truncated setup header")?
+                as usize,
+            init_size: field(offset::INIT_SIZE)? as usize,
+            initrd_addr_max: field(offset::INITRD_ADDR_MAX)? as usize,
+            cmdline_size: field(offset::CMDLINE_SIZE)? as usize,
+        })
+    }
+
+    /// Returns whether the protected-mode kernel may be loaded at `load_addr`.
+    pub fn is_valid_load_addr(&self, load_addr: usize) -> bool {
+        if self.relocatable {
+            load_addr >= LEGACY_HOLE.1 && load_addr.is_multiple_of(self.kernel_alignment)
+        } else {
+            load_addr == self.pref_address
+        }
+    }
+
+    /// Describes the load addresses allowed by [`BzImage::is_valid_load_addr`].
+    pub fn load_addr_rule(&self) -> String {
+        if self.relocatable {
+            format!("a multiple of {:#x} above 1 MiB", self.kernel_alignment)
+        } else {
+            format!("{:#x}, the kernel is not relocatable", self.pref_address)
+        }
+    }
+
+    /// End of the memory used by the kernel while it boots. A relocatable kernel loaded below its
+    /// preferred address decompresses itself at the preferred address.
+    pub fn memory_end(&self, load_addr: usize) -> usize {
+        load_addr
+            .max(self.pref_address)
+            .saturating_add(self.init_size)
+    }
+
+    /// Highest address the initial ramdisk may end at.
+    pub fn initrd_end_max(&self) -> usize {
+        if self.xloadflags & XLF_CAN_BE_LOADED_ABOVE_4G != 0 {
+            usize::MAX
+        } else {
+            self.initrd_addr_max.saturating_add(1)
+        }
+    }
+
+    /// Builds the zero page for the kernel loaded at `load_addr`, with the command line at
+    /// [`CMDLINE_GPA`] if there is one and the memory map derived from the guest RAM regions
+    /// `ram`.
+    pub fn boot_params(
+        &self,
+        load_addr: usize,
+        with_cmdline: bool,
+        ramdisk: Option<(usize, usize)>,
+        ram: &[(usize, usize)],
+    ) -> Vec<u8> {
+        let mut params = vec![0u8; PAGE_SIZE];
+        let header_end = offset::SETUP_SECTS + self.header.len();
+        params[offset::SETUP_SECTS..header_end].copy_from_slice(&self.header);
+
+        params[offset::TYPE_OF_LOADER] = UNDEFINED_LOADER;
+        write_u32(&mut params, offset::CODE32_START, load_addr as u32);
+        if with_cmdline {
+            write_u32(&mut params, offset::CMD_LINE_PTR, CMDLINE_GPA as u32);
+            write_u32(
+                &mut params,
+                offset::EXT_CMD_LINE_PTR,
+                (CMDLINE_GPA >> 32) as u32,
+            );
+        }
+        if let Some((addr, size)) = ramdisk {
+            write_u32(&mut params, offset::RAMDISK_IMAGE, addr as u32);
+            write_u32(&mut params, offset::RAMDISK_SIZE, size as u32);
+            write_u32(&mut params, offset::EXT_RAMDISK_IMAGE, (addr >> 32) as u32);
+            write_u32(&mut params, offset::EXT_RAMDISK_SIZE, (size >> 32) as u32);
+        }
+
+        let entries = e820_map(ram);
+        params[offset::E820_ENTRIES] = entries.len() as u8;
+        for (index, (addr, size, ty)) in entries.into_iter().enumerate() {
+            let entry = offset::E820_TABLE + index * E820_ENTRY_SIZE;
+            params[entry..entry + 8].copy_from_slice(&(addr as u64).to_le_bytes());
+            params[entry + 8..entry + 16].copy_from_slice(&(size as u64).to_le_bytes());
+            write_u32(&mut params, entry + 16, ty);
+        }
+        params
+    }
+}
+
+/// The BIOS memory map of guest RAM as `(address, size, type)`, without the legacy hole below
+/// 1 MiB. Multiboot kernels get the same map.
+pub fn e820_map(ram: &[(usize, usize)]) -> Vec<(usize, usize, u32)> {
+    let (hole_start, hole_end) = LEGACY_HOLE;
+    let mut entries = Vec::new();
+    let mut hole_reserved = false;
+    let mut ram: Vec<_> = ram.to_vec();
+    ram.sort_unstable();
+    for (addr, size) in ram {
+        let end = addr + size;
+        if addr < hole_start {
+            entries.push((addr, end.min(hole_start) - addr, E820_RAM));
+        }
+        if addr < hole_end && end > hole_start && !hole_reserved {
+            entries.push((hole_start, hole_end - hole_start, E820_RESERVED));
+            hole_reserved = true;
+        }
+        if end > hole_end {
+            let start = addr.max(hole_end);
+            entries.push((start, end - start, E820_RAM));
+        }
+    }
+    entries.truncate(E820_MAX_ENTRIES);
+    entries
+}
+
+/// Page tables identity mapping the first 4 GiB with 2 MiB pages, to be placed at
+/// [`PAGE_TABLES_GPA`].
+pub fn page_tables() -> Vec<u8> {
+    const PRESENT_WRITABLE: u64 = 0x3;
+    const HUGE_PAGE: u64 = 0x80;
+    let mut tables = vec![0u8; PAGE_TABLES_SIZE];
+    let mut set = |table: usize, index: usize, entry: u64| {
+        let offset = table * PAGE_SIZE + index * 8;
+        tables[offset..offset + 8].copy_from_slice(&entry.to_le_bytes());
+    };
+
+    let table_addr = |table: usize| (PAGE_TABLES_GPA + table * PAGE_SIZE) as u64;
+    set(0, 0, table_addr(1) | PRESENT_WRITABLE);
+    for gib in 0..IDENTITY_MAPPED_GIB {
+        set(1, gib, table_addr(2 + gib) | PRESENT_WRITABLE);
+        for index in 0..512 {
+            let addr = ((gib * 512 + index) << 21) as u64;
+            set(2 + gib, index, addr | PRESENT_WRITABLE | HUGE_PAGE);
+        }
+    }
+    tables
+}
+
+/// The trampoline run by the BSP, to be placed at [`TRAMPOLINE_GPA`].
+pub fn trampoline() -> &'static [u8] {
+    let (start, end): (*const u8, *const u8);
+    // SAFETY: only takes the addresses of the symbols delimiting the trampoline in `.rodata`.
+    unsafe {
+        core::arch::asm!(
+            "lea {start}, [rip + axvisor_bzimage_trampoline_start]",
+            "lea {end}, [rip + axvisor_bzimage_trampoline_end]",
+            start = out(reg) start,
+            end = out(reg) end,
+            options(pure, nomem, nostack),
+        );
+        core::slice::from_raw_parts(start, end.offset_from(start) as usize)
+    }
+}
+
+// Enters long mode from real mode and jumps to the 64-bit entry point of the kernel, which is
+// `code32_start + 0x200`, with the boot GDT loaded and `rsi` pointing at the zero page.
+core::arch::global_asm!(
+    r#"
+    .pushsection .rodata.axvisor_bzimage_trampoline, "a"
+    .balign 16
+    .global axvisor_bzimage_trampoline_start
+    .global axvisor_bzimage_trampoline_end
+    .code16
+axvisor_bzimage_trampoline_start:
+    cli
+    xor ax, ax
+    mov ds, ax
+    lgdt [{trampoline} + .Lbz_gdt_ptr_offset]
+    mov eax, cr4
+    or eax, 1 << 5                  // CR4.PAE
+    mov cr4, eax
+    mov eax, {page_tables}
+    mov cr3, eax
+    mov ecx, 0xc0000080             // IA32_EFER
+    rdmsr
+    or eax, 1 << 8                  // EFER.LME
+    wrmsr
+    mov eax, cr0
+    or eax, 0x80000001              // CR0.PG | CR0.PE
+    mov cr0, eax
+    .byte 0xea                      // ljmp __BOOT_CS, .Lbz_long_mode
+    .2byte {trampoline} + .Lbz_long_mode - axvisor_bzimage_trampoline_start
+    .2byte 0x10
+    .code64
+.Lbz_long_mode:
+    mov eax, 0x18                   // __BOOT_DS
+    mov ds, eax
+    mov es, eax
+    mov ss, eax
+    mov esi, {boot_params}
+    mov eax, dword ptr [rsi + {code32_start}]
+    add rax, {entry_64_offset}
+    jmp rax
+
+    .balign 8
+.Lbz_gdt:
+    .quad 0
+    .quad 0
+    .quad 0x00af9a000000ffff        // __BOOT_CS, 64-bit code
+    .quad 0x00cf92000000ffff        // __BOOT_DS, flat data
+.Lbz_gdt_ptr:
+    .2byte .Lbz_gdt_ptr - .Lbz_gdt - 1
+    .4byte {trampoline} + .Lbz_gdt - axvisor_bzimage_trampoline_start
+axvisor_bzimage_trampoline_end:
+    .set .Lbz_gdt_ptr_offset, .Lbz_gdt_ptr - axvisor_bzimage_trampoline_start
+    .popsection
+"#,
+    trampoline = const TRAMPOLINE_GPA,
+    page_tables = const PAGE_TABLES_GPA,
+    boot_params = const BOOT_PARAMS_GPA,
+    code32_start = const offset::CODE32_START,
+    entry_64_offset = const ENTRY_64_OFFSET,
+);
+
+fn read_u16(data: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(
+        data.get(offset..offset + 2)?.try_into().ok()?,
+    ))
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(
+        data.get(offset..offset + 4)?.try_into().ok()?,
+    ))
+}
+
+fn read_u64(data: &[u8], offset: usize) -> Option<u64> {
+    Some(u64::from_le_bytes(
+        data.get(offset..offset + 8)?.try_into().ok()?,
+    ))
+}
+
+fn write_u32(data: &mut [u8], offset: usize, value: u32) {
+    data[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+}
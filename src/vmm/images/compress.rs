@@ -0,0 +1,390 @@
+// Copyright 2025 The Axvisor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Compressed images.
+//!
+//! Images are recognized by their magic number and decompressed as a stream: the compressed
+//! data is pulled in small chunks and the output is handed over in chunks as well, so neither
+//! the compressed nor the decompressed image has to fit in hypervisor memory. Supported are
+//! gzip (`Image.gz`), zstd and LZ4, both the legacy format of `lz4 -l` used by Linux and the
+//! standard frame format.
+
+use alloc::{boxed::Box, string::String, vec::Vec};
+
+use miniz_oxide::{
+    DataFormat, MZError, MZFlush, MZStatus,
+    inflate::stream::{InflateState, inflate},
+};
+
+/// Size of the chunks read from the source and handed to the sink.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+const ZSTD_MAGIC: [u8; 4] = 0xfd2f_b528_u32.to_le_bytes();
+const LZ4_LEGACY_MAGIC: u32 = 0x184c_2102;
+const LZ4_FRAME_MAGIC: u32 = 0x184d_2204;
+/// Decompressed size of every block of the LZ4 legacy format but the last.
+const LZ4_LEGACY_BLOCK_SIZE: usize = 8 * 1024 * 1024;
+/// Largest compressed size of a block of the LZ4 legacy format, the LZ4 bound of
+/// [`LZ4_LEGACY_BLOCK_SIZE`].
+const LZ4_LEGACY_BLOCK_MAX: usize = LZ4_LEGACY_BLOCK_SIZE + LZ4_LEGACY_BLOCK_SIZE / 255 + 16;
+/// Size of the window of linked LZ4 frame blocks.
+const LZ4_WINDOW_SIZE: usize = 64 * 1024;
+
+/// Reads compressed data into the buffer, returns the number of bytes read, 0 at the end.
+pub type Source<'a> = &'a mut dyn FnMut(&mut [u8]) -> Result<usize, String>;
+/// Takes the next chunk of decompressed data.
+pub type Sink<'a> = &'a mut dyn FnMut(&[u8]) -> Result<(), String>;
+
+/// Compression formats recognized in images.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    Gzip,
+    Zstd,
+    Lz4,
+}
+
+impl Compression {
+    /// Number of bytes needed by [`Compression::detect`].
+    pub const MAGIC_SIZE: usize = 4;
+
+    /// Detects the compression of an image from its first bytes.
+    pub fn detect(header: &[u8]) -> Option<Self> {
+        if header.starts_with(&GZIP_MAGIC) {
+            return Some(Self::Gzip);
+        }
+        if header.starts_with(&ZSTD_MAGIC) {
+            return Some(Self::Zstd);
+        }
+        let magic = u32::from_le_bytes(header.get(..4)?.try_into().ok()?);
+        (magic == LZ4_LEGACY_MAGIC || magic == LZ4_FRAME_MAGIC).then_some(Self::Lz4)
+    }
+
+    /// Decompresses the data read from `source` into `sink`, returns the decompressed size.
+    pub fn decompress(self, source: Source<'_>, sink: Sink<'_>) -> Result<usize, String> {
+        let mut reader = Reader::new(source);
+        let mut sink = CountingSink { sink, total: 0 };
+        match self {
+            Self::Gzip => gunzip(&mut reader, &mut sink)?,
+            Self::Zstd => unzstd(&mut reader, &mut sink)?,
+            Self::Lz4 => unlz4(&mut reader, &mut sink)?,
+        }
+        Ok(sink.total)
+    }
+}
+
+/// Buffered reader over a [`Source`].
+struct Reader<'a> {
+    source: Source<'a>,
+    buf: Vec<u8>,
+    pos: usize,
+    end: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(source: Source<'a>) -> Self {
+        Self {
+            source,
+            buf: vec![0; CHUNK_SIZE],
+            pos: 0,
+            end: 0,
+        }
+    }
+
+    /// Returns the buffered data, reading more if there is none. Empty at the end of the data.
+    fn fill(&mut self) -> Result<&[u8], String> {
+        if self.pos == self.end {
+            self.pos = 0;
+            self.end = (self.source)(&mut self.buf)?;
+        }
+        Ok(&self.buf[self.pos..self.end])
+    }
+
+    fn consume(&mut self, len: usize) {
+        self.pos = (self.pos + len).min(self.end);
+    }
+
+    /// Reads up to `buf.len()` bytes, fewer only at the end of the data.
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, String> {
+        let mut read = 0;
+        while read < buf.len() {
+            let data = self.fill()?;
+            if data.is_empty() {
+                break;
+            }
+            let len = data.len().min(buf.len() - read);
+            buf[read..read + len].copy_from_slice(&data[..len]);
+            self.consume(len);
+            read += len;
+        }
+        Ok(read)
+    }
+
+    fn read_exact(&mut self, buf: &mut [u8]) -> Result<(), String> {
+        if self.read(buf)? < buf.len() {
+            return Err("unexpected end of the compressed data".into());
+        }
+        Ok(())
+    }
+
+    fn read_u8(&mut self) -> Result<u8, String> {
+        let mut byte = [0u8; 1];
+        self.read_exact(&mut byte)?;
+        Ok(byte[0])
+    }
+
+    /// Returns the next little-endian `u32` without consuming it, `None` if fewer than 4 bytes
+    /// are left.
+    fn peek_u32(&mut self) -> Result<Option<u32>, String> {
+        if self.end - self.pos < 4 {
+            self.buf.copy_within(self.pos..self.end, 0);
+            self.end -= self.pos;
+            self.pos = 0;
+            while self.end < 4 {
+                let len = (self.source)(&mut self.buf[self.end..])?;
+                if len == 0 {
+                    return Ok(None);
+                }
+                self.end += len;
+            }
+        }
+        let bytes = self.buf[self.pos..self.pos + 4].try_into().unwrap();
+        Ok(Some(u32::from_le_bytes(bytes)))
+    }
+
+    /// Reads a little-endian `u32`, `None` at the end of the data.
+    fn read_u32(&mut self) -> Result<Option<u32>, String> {
+        let mut bytes = [0u8; 4];
+        match self.read(&mut bytes)? {
+            0 => Ok(None),
+            4 => Ok(Some(u32::from_le_bytes(bytes))),
+            _ => Err("unexpected end of the compressed data".into()),
+        }
+    }
+
+    fn skip(&mut self, mut len: usize) -> Result<(), String> {
+        let mut scratch = [0u8; 64];
+        while len > 0 {
+            let chunk = len.min(scratch.len());
+            self.read_exact(&mut scratch[..chunk])?;
+            len -= chunk;
+        }
+        Ok(())
+    }
+}
+
+struct CountingSink<'a> {
+    sink: Sink<'a>,
+    total: usize,
+}
+
+impl CountingSink<'_> {
+    fn write(&mut self, data: &[u8]) -> Result<(), String> {
+        if !data.is_empty() {
+            (self.sink)(data)?;
+            self.total += data.len();
+        }
+        Ok(())
+    }
+}
+
+fn gunzip(reader: &mut Reader<'_>, sink: &mut CountingSink<'_>) -> Result<(), String> {
+    const FHCRC: u8 = 1 << 1;
+    const FEXTRA: u8 = 1 << 2;
+    const FNAME: u8 = 1 << 3;
+    const FCOMMENT: u8 = 1 << 4;
+
+    let mut header = [0u8; 10];
+    reader.read_exact(&mut header)?;
+    if header[..2] != GZIP_MAGIC || header[2] != 8 {
+        return Err("not a deflate gzip stream".into());
+    }
+    let flags = header[3];
+    if flags & FEXTRA != 0 {
+        let len = u16::from_le_bytes([reader.read_u8()?, reader.read_u8()?]);
+        reader.skip(len as usize)?;
+    }
+    for flag in [FNAME, FCOMMENT] {
+        if flags & flag != 0 {
+            while reader.read_u8()? != 0 {}
+        }
+    }
+    if flags & FHCRC != 0 {
+        reader.skip(2)?;
+    }
+
+    let mut state = InflateState::new_boxed(DataFormat::Raw);
+    let mut output = vec![0u8; CHUNK_SIZE];
+    loop {
+        let input = reader.fill()?;
+        let at_end = input.is_empty();
+        let result = inflate(&mut state, input, &mut output, MZFlush::None);
+        reader.consume(result.bytes_consumed);
+        sink.write(&output[..result.bytes_written])?;
+        match result.status {
+            Ok(MZStatus::StreamEnd) => break,
+            Ok(_) | Err(MZError::Buf) if at_end && result.bytes_written == 0 => {
+                return Err("truncated gzip data".into());
+            }
+            // More input or output space is needed.
+            Ok(_) | Err(MZError::Buf) => {}
+            Err(err) => return Err(format!("corrupted gzip data ({err:?})")),
+        }
+    }
+
+    // The trailer holds the CRC32 and the size modulo 2^32 of the decompressed data.
+    let mut trailer = [0u8; 8];
+    reader.read_exact(&mut trailer)?;
+    let size = u32::from_le_bytes(trailer[4..].try_into().unwrap());
+    if size != sink.total as u32 {
+        return Err(format!(
+            "gzip size mismatch, {:#x} bytes expected, {:#x} decompressed",
+            size, sink.total
+        ));
+    }
+    Ok(())
+}
+
+fn unzstd(reader: &mut Reader<'_>, sink: &mut CountingSink<'_>) -> Result<(), String> {
+    struct ZstdSource<'a, 'b>(&'a mut Reader<'b>);
+
+    impl ruzstd::io::Read for ZstdSource<'_, '_> {
+        fn read(&mut self, buf: &mut [u8]) -> Result<usize, ruzstd::io::Error> {
+            self.0
+                .read(buf)
+                .map_err(|msg| ruzstd::io::Error::new(ruzstd::io::ErrorKind::Other, Box::new(msg)))
+        }
+    }
+
+    let mut decoder = ruzstd::decoding::StreamingDecoder::new(ZstdSource(reader))
+        .map_err(|err| format!("invalid zstd frame: {err}"))?;
+    let mut output = vec![0u8; CHUNK_SIZE];
+    loop {
+        let len = ruzstd::io::Read::read(&mut decoder, &mut output)
+            .map_err(|err| format!("corrupted zstd data: {err}"))?;
+        if len == 0 {
+            return Ok(());
+        }
+        sink.write(&output[..len])?;
+    }
+}
+
+fn unlz4(reader: &mut Reader<'_>, sink: &mut CountingSink<'_>) -> Result<(), String> {
+    // A file may hold several frames, e.g. when images are concatenated.
+    while let Some(magic) = reader.read_u32()? {
+        match magic {
+            LZ4_LEGACY_MAGIC => unlz4_legacy(reader, sink)?,
+            LZ4_FRAME_MAGIC => unlz4_frame(reader, sink)?,
+            _ => return Err(format!("unknown LZ4 magic {magic:#x}")),
+        }
+    }
+    Ok(())
+}
+
+/// The legacy format is a sequence of independent blocks of 8 MiB of decompressed data, it ends
+/// at the end of the data or where another frame starts. Linux appends the decompressed size to
+/// its compressed kernels, so a last 4-byte word is not taken for the size of a block.
+fn unlz4_legacy(reader: &mut Reader<'_>, sink: &mut CountingSink<'_>) -> Result<(), String> {
+    let mut input = Vec::new();
+    let mut output = vec![0u8; LZ4_LEGACY_BLOCK_SIZE];
+    loop {
+        // Peek at the size of the next block, which may be the magic of the next frame.
+        if let Some(LZ4_LEGACY_MAGIC | LZ4_FRAME_MAGIC) = reader.peek_u32()? {
+            return Ok(());
+        }
+        let Some(size) = reader.read_u32()? else {
+            return Ok(());
+        };
+        if reader.fill()?.is_empty() {
+            return Ok(());
+        }
+        let size = size as usize;
+        if size > LZ4_LEGACY_BLOCK_MAX {
+            return Err(format!("LZ4 legacy block of {size:#x} bytes is too large"));
+        }
+        input.resize(size, 0);
+        reader.read_exact(&mut input)?;
+        let len = lz4_flex::block::decompress_into(&input, &mut output)
+            .map_err(|err| format!("corrupted LZ4 data: {err}"))?;
+        sink.write(&output[..len])?;
+    }
+}
+
+fn unlz4_frame(reader: &mut Reader<'_>, sink: &mut CountingSink<'_>) -> Result<(), String> {
+    let flags = reader.read_u8()?;
+    let block_desc = reader.read_u8()?;
+    if flags >> 6 != 0b01 {
+        return Err("unsupported LZ4 frame version".into());
+    }
+    let independent = flags & (1 << 5) != 0;
+    let block_checksum = flags & (1 << 4) != 0;
+    let content_size = flags & (1 << 3) != 0;
+    let content_checksum = flags & (1 << 2) != 0;
+    if flags & 1 != 0 {
+        return Err("LZ4 frames with a dictionary are not supported".into());
+    }
+    let block_max = match (block_desc >> 4) & 0x7 {
+        4 => 64 * 1024,
+        5 => 256 * 1024,
+        6 => 1024 * 1024,
+        7 => 4 * 1024 * 1024,
+        _ => return Err("invalid LZ4 block size".into()),
+    };
+    // Content size and header checksum.
+    reader.skip(if content_size { 8 } else { 0 } + 1)?;
+
+    let mut input = Vec::new();
+    let mut output = vec![0u8; block_max];
+    // Tail of the decompressed data, referenced by linked blocks.
+    let mut window: Vec<u8> = Vec::new();
+    loop {
+        let size = reader
+            .read_u32()?
+            .ok_or("unexpected end of the compressed data")?;
+        if size == 0 {
+            break;
+        }
+        let stored = size & (1 << 31) != 0;
+        let size = (size & !(1 << 31)) as usize;
+        if size > block_max {
+            return Err("LZ4 block larger than the block size of the frame".into());
+        }
+        input.resize(size, 0);
+        reader.read_exact(&mut input)?;
+        if block_checksum {
+            reader.skip(4)?;
+        }
+
+        let len = if stored {
+            output[..size].copy_from_slice(&input);
+            Ok(size)
+        } else if independent {
+            lz4_flex::block::decompress_into(&input, &mut output)
+        } else {
+            lz4_flex::block::decompress_into_with_dict(&input, &mut output, &window)
+        }
+        .map_err(|err| format!("corrupted LZ4 data: {err}"))?;
+        sink.write(&output[..len])?;
+
+        if !independent {
+            window.extend_from_slice(&output[..len]);
+            let excess = window.len().saturating_sub(LZ4_WINDOW_SIZE);
+            window.drain(..excess);
+        }
+    }
+    if content_checksum {
+        reader.skip(4)?;
+    }
+    Ok(())
+}
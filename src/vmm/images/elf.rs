@@ -0,0 +1,262 @@
+// Copyright 2025 The Axvisor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! ELF kernel images.
+//!
+//! Only what is needed to place a statically linked kernel is parsed: the entry point and the
+//! `PT_LOAD` program headers, which are loaded at their physical address (`p_paddr`). Kernels are
+//! ELF64 images, except on x86_64 where ELF32 images are accepted for Multiboot kernels.
+
+use alloc::{string::String, vec::Vec};
+
+/// Size of the ELF64 file header, enough to detect an ELF image.
+pub const HEADER_SIZE: usize = size_of::<Elf64Header>();
+
+const MAGIC: [u8; 4] = *b"\x7fELF";
+const CLASS_32: u8 = 1;
+const CLASS_64: u8 = 2;
+const DATA_LE: u8 = 1;
+const TYPE_EXEC: u16 = 2;
+const TYPE_DYN: u16 = 3;
+const PT_LOAD: u32 = 1;
+/// Upper bound of the program header count, a sanity check for corrupted images.
+const MAX_PHDRS: usize = 256;
+
+#[cfg(target_arch = "aarch64")]
+const MACHINE: u16 = 183; // EM_AARCH64
+#[cfg(target_arch = "riscv64")]
+const MACHINE: u16 = 243; // EM_RISCV
+#[cfg(target_arch = "x86_64")]
+const MACHINE: u16 = 62; // EM_X86_64
+#[cfg(target_arch = "loongarch64")]
+const MACHINE: u16 = 258; // EM_LOONGARCH
+/// Machine of the ELF32 images accepted, if any.
+#[cfg(target_arch = "x86_64")]
+const MACHINE_32: Option<u16> = Some(3); // EM_386
+#[cfg(not(target_arch = "x86_64"))]
+const MACHINE_32: Option<u16> = None;
+
+#[allow(unused)]
+#[repr(C)]
+struct Elf64Header {
+    ident: [u8; 16],
+    ty: u16,
+    machine: u16,
+    version: u32,
+    entry: u64,
+    phoff: u64,
+    shoff: u64,
+    flags: u32,
+    ehsize: u16,
+    phentsize: u16,
+    phnum: u16,
+    shentsize: u16,
+    shnum: u16,
+    shstrndx: u16,
+}
+
+#[allow(unused)]
+#[repr(C)]
+struct Elf32Header {
+    ident: [u8; 16],
+    ty: u16,
+    machine: u16,
+    version: u32,
+    entry: u32,
+    phoff: u32,
+    shoff: u32,
+    flags: u32,
+    ehsize: u16,
+    phentsize: u16,
+    phnum: u16,
+    shentsize: u16,
+    shnum: u16,
+    shstrndx: u16,
+}
+
+#[allow(unused)]
+#[repr(C)]
+struct Elf32ProgramHeader {
+    ty: u32,
+    offset: u32,
+    vaddr: u32,
+    paddr: u32,
+    filesz: u32,
+    memsz: u32,
+    flags: u32,
+    align: u32,
+}
+
+#[allow(unused)]
+#[repr(C)]
+struct Elf64ProgramHeader {
+    ty: u32,
+    flags: u32,
+    offset: u64,
+    vaddr: u64,
+    paddr: u64,
+    filesz: u64,
+    memsz: u64,
+    align: u64,
+}
+
+/// A `PT_LOAD` segment of an ELF image.
+#[derive(Debug, Clone)]
+pub struct Segment {
+    /// Guest physical address the segment is loaded at.
+    pub paddr: usize,
+    /// Offset of the segment content in the image.
+    pub offset: usize,
+    /// Size of the segment content in the image.
+    pub file_size: usize,
+    /// Size of the segment in memory, the part beyond `file_size` is zeroed (BSS).
+    pub mem_size: usize,
+}
+
+/// The load layout of an ELF image.
+#[derive(Debug, Clone)]
+pub struct ElfImage {
+    /// Guest physical address of the entry point.
+    pub entry: usize,
+    /// Loadable segments, in program header order.
+    pub segments: Vec<Segment>,
+}
+
+impl ElfImage {
+    /// Returns whether the image starts with the ELF magic.
+    pub fn is_elf(header: &[u8]) -> bool {
+        header.starts_with(&MAGIC)
+    }
+
+    /// Parses the load layout of an ELF image, `read(offset, len)` returning `len` bytes of the
+    /// image at `offset`.
+    pub fn parse(
+        read: &mut dyn FnMut(usize, usize) -> Result<Vec<u8>, String>,
+    ) -> Result<Self, String> {
+        let data = read(0, HEADER_SIZE)?;
+        let hdr: Elf64Header = match data.get(4) {
+            Some(&CLASS_32) => {
+                let hdr: Elf32Header = read_struct(&data).ok_or("truncated ELF header")?;
+                Elf64Header {
+                    ident: hdr.ident,
+                    ty: hdr.ty,
+                    machine: hdr.machine,
+                    version: hdr.version,
+                    entry: hdr.entry as u64,
+                    phoff: hdr.phoff as u64,
+                    shoff: hdr.shoff as u64,
+                    flags: hdr.flags,
+                    ehsize: hdr.ehsize,
+                    phentsize: hdr.phentsize,
+                    phnum: hdr.phnum,
+                    shentsize: hdr.shentsize,
+                    shnum: hdr.shnum,
+                    shstrndx: hdr.shstrndx,
+                }
+            }
+            _ => read_struct(&data).ok_or("truncated ELF header")?,
+        };
+
+        if hdr.ident[..4] != MAGIC {
+            return Err("not an ELF image".into());
+        }
+        let (machine, phdr_size) = match (hdr.ident[4], MACHINE_32) {
+            (CLASS_64, _) => (MACHINE, size_of::<Elf64ProgramHeader>()),
+            (CLASS_32, Some(machine)) => (machine, size_of::<Elf32ProgramHeader>()),
+            _ => return Err("only ELF64 images are supported".into()),
+        };
+        if hdr.ident[5] != DATA_LE {
+            return Err("only little-endian ELF images are supported".into());
+        }
+        if hdr.ty != TYPE_EXEC && hdr.ty != TYPE_DYN {
+            return Err(format!("ELF type {} is not an executable", hdr.ty));
+        }
+        if hdr.machine != machine {
+            return Err(format!(
+                "ELF machine {} does not match the host ({machine})",
+                hdr.machine
+            ));
+        }
+        let phnum = hdr.phnum as usize;
+        if hdr.phentsize as usize != phdr_size || phnum > MAX_PHDRS {
+            return Err("invalid program header table".into());
+        }
+
+        let table = read(hdr.phoff as usize, phnum * phdr_size)?;
+        let mut segments = Vec::new();
+        for entry in table.chunks_exact(phdr_size) {
+            let phdr: Elf64ProgramHeader = if phdr_size == size_of::<Elf64ProgramHeader>() {
+                read_struct(entry).ok_or("truncated program header")?
+            } else {
+                let phdr: Elf32ProgramHeader =
+                    read_struct(entry).ok_or("truncated program header")?;
+                Elf64ProgramHeader {
+                    ty: phdr.ty,
+                    flags: phdr.flags,
+                    offset: phdr.offset as u64,
+                    vaddr: phdr.vaddr as u64,
+                    paddr: phdr.paddr as u64,
+                    filesz: phdr.filesz as u64,
+                    memsz: phdr.memsz as u64,
+                    align: phdr.align as u64,
+                }
+            };
+            if phdr.ty != PT_LOAD || phdr.memsz == 0 {
+                continue;
+            }
+            if phdr.filesz > phdr.memsz {
+                return Err(format!(
+                    "segment at {:#x} has more file than memory content",
+                    phdr.paddr
+                ));
+            }
+            if usize::try_from(phdr.paddr)
+                .ok()
+                .and_then(|paddr| paddr.checked_add(usize::try_from(phdr.memsz).ok()?))
+                .is_none()
+            {
+                return Err(format!(
+                    "segment at {:#x} of {:#x} bytes overflows the address space",
+                    phdr.paddr, phdr.memsz
+                ));
+            }
+            segments.push(Segment {
+                paddr: phdr.paddr as usize,
+                offset: phdr.offset as usize,
+                file_size: phdr.filesz as usize,
+                mem_size: phdr.memsz as usize,
+            });
+        }
+        if segments.is_empty() {
+            return Err("no loadable segment".into());
+        }
+
+        Ok(Self {
+            entry: hdr.entry as usize,
+            segments,
+        })
+    }
+
+    /// Lowest guest physical address of the image.
+    pub fn base(&self) -> usize {
+        self.segments.iter().map(|seg| seg.paddr).min().unwrap_or(0)
+    }
+}
+
+fn read_struct<T>(data: &[u8]) -> Option<T> {
+    if data.len() < size_of::<T>() {
+        return None;
+    }
+    Some(unsafe { core::ptr::read_unaligned(data.as_ptr() as *const T) })
+}
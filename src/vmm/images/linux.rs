@@ -12,6 +12,18 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+//! The boot header of arm64 and RISC-V Linux `Image` files.
+//!
+//! Besides identifying the image, the header tells where the kernel may be placed: arm64 kernels
+//! are placed `text_offset` bytes above a 2 MiB aligned base and RISC-V kernels at a 2 MiB
+//! aligned address, both need `image_size` bytes of memory from there, including the BSS.
+
+/// Alignment of the base address kernels are placed relative to.
+pub const PLACEMENT_ALIGN: usize = 0x20_0000;
+
+/// `text_offset` of arm64 kernels older than 3.17, whose header has no `image_size`.
+const ARM64_LEGACY_TEXT_OFFSET: usize = 0x8_0000;
+
 /// Architecture variants detected from the image header.
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum ImageArch {
@@ -64,6 +76,81 @@ impl Header {
     pub fn hdr_size() -> usize {
         size_of::<ARM64Header>()
     }
+
+    /// Offset of the kernel from a [`PLACEMENT_ALIGN`] aligned base address.
+    ///
+    /// The `text_offset` of RISC-V kernels is relative to the start of RAM and only a hint, they
+    /// are loaded at an aligned address.
+    pub fn placement_offset(&self) -> usize {
+        match self.arch {
+            ImageArch::Arm64 { .. } if self.image_size == 0 => ARM64_LEGACY_TEXT_OFFSET,
+            ImageArch::Arm64 { .. } => self.text_offset as usize,
+            ImageArch::Riscv { .. } => 0,
+        }
+    }
+
+    /// Memory the kernel needs from its load address, `None` if the header does not say.
+    pub fn memory_size(&self) -> Option<usize> {
+        (self.image_size != 0).then_some(self.image_size as usize)
+    }
+
+    /// Returns whether the kernel may be placed at `load_addr`.
+    pub fn is_valid_load_addr(&self, load_addr: usize) -> bool {
+        load_addr
+            .checked_sub(self.placement_offset())
+            .is_some_and(|base| base.is_multiple_of(PLACEMENT_ALIGN))
+    }
+
+    /// Returns the lowest address at or above `addr` the kernel may be placed at.
+    pub fn next_load_addr(&self, addr: usize) -> Option<usize> {
+        let offset = self.placement_offset();
+        addr.saturating_sub(offset)
+            .checked_next_multiple_of(PLACEMENT_ALIGN)?
+            .checked_add(offset)
+    }
+
+    /// Page size the kernel is built for, `None` if the header does not say.
+    pub fn page_size(&self) -> Option<usize> {
+        match self.arch {
+            ImageArch::Arm64 { page_size, .. } => page_size.bytes(),
+            ImageArch::Riscv { .. } => None,
+        }
+    }
+
+    /// End of the physical range the whole kernel must be placed below, if it is constrained.
+    pub fn placement_limit(&self) -> Option<usize> {
+        match self.arch {
+            ImageArch::Arm64 {
+                phys_placement_48bit: true,
+                ..
+            } => Some(1 << 48),
+            _ => None,
+        }
+    }
+
+    /// Returns whether the kernel should be placed as close as possible to the start of RAM,
+    /// because it cannot use the memory below its base address.
+    pub fn prefers_low_placement(&self) -> bool {
+        matches!(
+            self.arch,
+            ImageArch::Arm64 {
+                phys_placement_48bit: false,
+                ..
+            }
+        )
+    }
+}
+
+impl PageSize {
+    /// Size of the pages in bytes, `None` if the kernel does not say.
+    pub fn bytes(self) -> Option<usize> {
+        match self {
+            PageSize::Unspecified => None,
+            PageSize::Size4K => Some(0x1000),
+            PageSize::Size16K => Some(0x4000),
+            PageSize::Size64K => Some(0x1_0000),
+        }
+    }
 }
 
 #[allow(unused)]
@@ -127,6 +214,7 @@ pub enum PageSize {
 }
 
 #[allow(unused)]
+#[repr(C)]
 struct RiscvHeader {
     code0: u32,
     code1: u32,
@@ -12,8 +12,10 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use alloc::{string::String, vec::Vec};
+
 use axaddrspace::GuestPhysAddr;
-use axerrno::AxResult;
+use axerrno::{AxResult, ax_err, ax_err_type};
 
 use axvm::VMMemoryRegion;
 use axvm::config::AxVMCrateConfig;
@@ -22,34 +24,317 @@ use byte_unit::Byte;
 use crate::hal::CacheOp;
 use crate::vmm::VMRef;
 use crate::vmm::config::{config, get_vm_dtb_arc};
+use crate::vmm::measure;
 
+pub mod block;
+#[cfg(target_arch = "x86_64")]
+pub mod bzimage;
+mod compress;
+mod elf;
 mod linux;
+#[cfg(target_arch = "x86_64")]
+pub mod multiboot;
+mod uboot;
+
+#[cfg(target_arch = "x86_64")]
+pub use bzimage::BzImage;
+pub use compress::Compression;
+pub use elf::ElfImage;
+pub use linux::{Header as LinuxHeader, PLACEMENT_ALIGN};
+#[cfg(target_arch = "x86_64")]
+pub use multiboot::Multiboot;
+pub use uboot::{ComponentKind, UbootImage};
+
+/// Returns the Linux boot header of the kernel, `None` if it has none or cannot be read.
+pub fn get_image_header(config: &AxVMCrateConfig) -> Option<LinuxHeader> {
+    let data = match config.kernel.image_location.as_deref() {
+        Some("memory") => {
+            let mut rest = config::get_memory_images()
+                .iter()
+                .find(|images| images.id == config.base.id)?
+                .kernel;
+            read_image_prefix(
+                &mut |buf| {
+                    let len = buf.len().min(rest.len());
+                    buf[..len].copy_from_slice(&rest[..len]);
+                    rest = &rest[len..];
+                    Ok(len)
+                },
+                LinuxHeader::hdr_size(),
+            )?
+        }
+        #[cfg(any(feature = "fs", feature = "guest-archive"))]
+        Some("fs") => fs::kernal_read(config, LinuxHeader::hdr_size()).ok()?,
+        Some("block") => {
+            let kernel = block::BlockImage::locate(&config.kernel.kernel_path).ok()?;
+            let mut offset = 0;
+            read_image_prefix(
+                &mut |buf| kernel.read_next(&mut offset, buf),
+                LinuxHeader::hdr_size(),
+            )?
+        }
+        _ => return None,
+    };
+    LinuxHeader::parse(&data)
+}
+
+/// Returns the load layout of the kernel if it is an ELF image, `None` for raw images.
+pub fn get_kernel_elf(config: &AxVMCrateConfig) -> Option<Result<ElfImage, String>> {
+    match config.kernel.image_location.as_deref() {
+        Some("memory") => {
+            let kernel = config::get_memory_images()
+                .iter()
+                .find(|images| images.id == config.base.id)?
+                .kernel;
+            if !ElfImage::is_elf(kernel) {
+                return None;
+            }
+            Some(ElfImage::parse(&mut |offset, len| {
+                offset
+                    .checked_add(len)
+                    .and_then(|end| kernel.get(offset..end))
+                    .map(<[u8]>::to_vec)
+                    .ok_or_else(|| "truncated ELF image".into())
+            }))
+        }
+        #[cfg(any(feature = "fs", feature = "guest-archive"))]
+        Some("fs") => fs::kernel_elf(&config.kernel.kernel_path),
+        Some("block") => {
+            let kernel = block::BlockImage::locate(&config.kernel.kernel_path).ok()?;
+            if !ElfImage::is_elf(&kernel.read_head(4).ok()?) {
+                return None;
+            }
+            Some(ElfImage::parse(&mut |offset, len| {
+                let mut buffer = vec![0u8; len];
+                kernel
+                    .read(offset, &mut buffer)
+                    .map_err(|msg| format!("failed to read the ELF headers: {msg}"))?;
+                Ok(buffer)
+            }))
+        }
+        _ => None,
+    }
+}
+
+/// Returns the boot setup of the kernel if it is a bzImage, `None` for other images.
+#[cfg(target_arch = "x86_64")]
+pub fn get_kernel_bzimage(config: &AxVMCrateConfig) -> Option<Result<BzImage, String>> {
+    match config.kernel.image_location.as_deref() {
+        Some("memory") => {
+            let kernel = config::get_memory_images()
+                .iter()
+                .find(|images| images.id == config.base.id)?
+                .kernel;
+            let header = &kernel[..kernel.len().min(bzimage::HEADER_SIZE)];
+            BzImage::is_bzimage(header).then(|| BzImage::parse(header))
+        }
+        #[cfg(any(feature = "fs", feature = "guest-archive"))]
+        Some("fs") => fs::kernel_bzimage(&config.kernel.kernel_path),
+        Some("block") => {
+            let kernel = block::BlockImage::locate(&config.kernel.kernel_path).ok()?;
+            let header = kernel.read_head(bzimage::HEADER_SIZE).ok()?;
+            BzImage::is_bzimage(&header).then(|| BzImage::parse(&header))
+        }
+        _ => None,
+    }
+}
 
-pub fn get_image_header(config: &AxVMCrateConfig) -> Option<linux::Header> {
+/// Returns the header of the kernel if it is a Multiboot or Multiboot2 kernel.
+#[cfg(target_arch = "x86_64")]
+pub fn get_kernel_multiboot(config: &AxVMCrateConfig) -> Option<Result<Multiboot, String>> {
     match config.kernel.image_location.as_deref() {
-        Some("memory") => with_memory_image(config, linux::Header::parse),
-        #[cfg(feature = "fs")]
+        Some("memory") => {
+            let kernel = config::get_memory_images()
+                .iter()
+                .find(|images| images.id == config.base.id)?
+                .kernel;
+            Multiboot::find(kernel, kernel.len())
+        }
+        #[cfg(any(feature = "fs", feature = "guest-archive"))]
         Some("fs") => {
-            let read_size = linux::Header::hdr_size();
-            let data = fs::kernal_read(config, read_size).ok()?;
-            linux::Header::parse(&data)
+            let (head, size) = fs::read_head(&config.kernel.kernel_path, multiboot::SEARCH_SIZE)?;
+            Multiboot::find(&head, size)
         }
-        _ => unimplemented!(
-            "Check your \"image_location\" in config.toml, \"memory\" and \"fs\" are supported,\n NOTE: \"fs\" feature should be enabled if you want to load images from filesystem. (APP_FEATURES=fs)"
-        ),
+        Some("block") => {
+            let kernel = block::BlockImage::locate(&config.kernel.kernel_path).ok()?;
+            Multiboot::find(
+                &kernel.read_head(multiboot::SEARCH_SIZE).ok()?,
+                kernel.size(),
+            )
+        }
+        _ => None,
     }
 }
 
-fn with_memory_image<F, R>(config: &AxVMCrateConfig, func: F) -> R
-where
-    F: FnOnce(&[u8]) -> R,
-{
-    let vm_imags = config::get_memory_images()
-        .iter()
-        .find(|&v| v.id == config.base.id)
-        .expect("VM images is missed, Perhaps add `VM_CONFIGS=PATH/CONFIGS/FILE` command.");
+/// Calls `f` with the kernel if it is a U-Boot FIT image, with the configuration `fit_config`
+/// selected, or a uImage. Such kernels are read into memory as a whole.
+pub fn with_kernel_uboot_image<R>(
+    config: &AxVMCrateConfig,
+    fit_config: Option<&str>,
+    f: impl FnOnce(&UbootImage<'_>) -> R,
+) -> Option<Result<R, String>> {
+    with_kernel_uboot_image_data(config, fit_config, |image, _| f(image))
+}
+
+/// Same as [`with_kernel_uboot_image`], `f` also gets the whole kernel image.
+fn with_kernel_uboot_image_data<R>(
+    config: &AxVMCrateConfig,
+    fit_config: Option<&str>,
+    f: impl FnOnce(&UbootImage<'_>, &[u8]) -> R,
+) -> Option<Result<R, String>> {
+    match config.kernel.image_location.as_deref() {
+        Some("memory") => {
+            let kernel = config::get_memory_images()
+                .iter()
+                .find(|images| images.id == config.base.id)?
+                .kernel;
+            if !UbootImage::is_uboot_image(kernel) {
+                return None;
+            }
+            Some(UbootImage::parse(kernel, fit_config).map(|image| f(&image, kernel)))
+        }
+        #[cfg(any(feature = "fs", feature = "guest-archive"))]
+        Some("fs") => {
+            let path = &config.kernel.kernel_path;
+            let (header, _) = fs::read_head(path, uboot::MAGIC_SIZE)?;
+            if !UbootImage::is_uboot_image(&header) {
+                return None;
+            }
+            let kernel = match fs::read_file(path) {
+                Ok(kernel) => kernel,
+                Err(err) => return Some(Err(format!("{err:?}"))),
+            };
+            Some(UbootImage::parse(&kernel, fit_config).map(|image| f(&image, &kernel)))
+        }
+        Some("block") => {
+            let kernel = block::BlockImage::locate(&config.kernel.kernel_path).ok()?;
+            if !UbootImage::is_uboot_image(&kernel.read_head(uboot::MAGIC_SIZE).ok()?) {
+                return None;
+            }
+            let kernel = match kernel.read_all() {
+                Ok(kernel) => kernel,
+                Err(msg) => return Some(Err(msg)),
+            };
+            Some(UbootImage::parse(&kernel, fit_config).map(|image| f(&image, &kernel)))
+        }
+        _ => None,
+    }
+}
+
+/// Calls `f` with the kernel if it is a U-Boot FIT image or a uImage, parsed from its header only,
+/// see [`UbootImage::parse_header`]. The data of the components is neither read nor checked.
+pub fn with_kernel_uboot_header<R>(
+    config: &AxVMCrateConfig,
+    fit_config: Option<&str>,
+    f: impl FnOnce(&UbootImage<'_>) -> R,
+) -> Option<Result<R, String>> {
+    let read_header = |read_head: &dyn Fn(usize) -> Option<Vec<u8>>| {
+        let head = read_head(uboot::HEADER_SIZE_PEEK)?;
+        if !UbootImage::is_uboot_image(&head) {
+            return None;
+        }
+        UbootImage::header_size(&head).and_then(read_head)
+    };
+    let header = match config.kernel.image_location.as_deref() {
+        Some("memory") => {
+            let kernel = config::get_memory_images()
+                .iter()
+                .find(|images| images.id == config.base.id)?
+                .kernel;
+            if !UbootImage::is_uboot_image(kernel) {
+                return None;
+            }
+            return Some(UbootImage::parse_header(kernel, fit_config).map(|image| f(&image)));
+        }
+        #[cfg(any(feature = "fs", feature = "guest-archive"))]
+        Some("fs") => {
+            let path = &config.kernel.kernel_path;
+            read_header(&|len| fs::read_head(path, len).map(|(head, _)| head))?
+        }
+        Some("block") => {
+            let kernel = block::BlockImage::locate(&config.kernel.kernel_path).ok()?;
+            read_header(&|len| kernel.read_head(len).ok())?
+        }
+        _ => return None,
+    };
+    Some(UbootImage::parse_header(&header, fit_config).map(|image| f(&image)))
+}
+
+/// Returns the verified FDT of a U-Boot image kernel, `None` if the kernel is no U-Boot image or
+/// comes without FDT.
+pub fn get_kernel_uboot_fdt(
+    config: &AxVMCrateConfig,
+    fit_config: Option<&str>,
+) -> Option<Result<Vec<u8>, String>> {
+    let fdt = with_kernel_uboot_image(config, fit_config, |image| {
+        let fdt = image.component(ComponentKind::Fdt)?;
+        Some(
+            fdt.verify()
+                .and_then(|()| fdt.decompressed())
+                .map_err(|msg| format!("{}: {msg}", fdt.name)),
+        )
+    })?;
+    match fdt {
+        Ok(fdt) => fdt,
+        Err(msg) => Some(Err(msg)),
+    }
+}
+
+/// Reads the next bytes of `rest` into `buf`, a [`compress::Source`] over a byte slice.
+fn read_slice(rest: &mut &[u8], buf: &mut [u8]) -> Result<usize, String> {
+    let len = buf.len().min(rest.len());
+    buf[..len].copy_from_slice(&rest[..len]);
+    *rest = &rest[len..];
+    Ok(len)
+}
+
+/// Reads the first `len` bytes of an image, decompressed if the image is compressed.
+fn read_image_prefix(source: compress::Source<'_>, len: usize) -> Option<Vec<u8>> {
+    let mut head = vec![0u8; len];
+    let mut filled = 0;
+    while filled < len {
+        match source(&mut head[filled..]).ok()? {
+            0 => break,
+            read => filled += read,
+        }
+    }
+    head.truncate(filled);
+    let Some(compression) = Compression::detect(&head) else {
+        return (filled == len).then_some(head);
+    };
 
-    func(vm_imags.kernel)
+    let mut pending = head.as_slice();
+    let mut prefix = Vec::with_capacity(len);
+    // Decompression is cut short by the sink once enough bytes are out.
+    let _ = compression.decompress(
+        &mut |buf| {
+            if pending.is_empty() {
+                return source(buf);
+            }
+            let read = buf.len().min(pending.len());
+            buf[..read].copy_from_slice(&pending[..read]);
+            pending = &pending[read..];
+            Ok(read)
+        },
+        &mut |data| {
+            let take = data.len().min(len - prefix.len());
+            prefix.extend_from_slice(&data[..take]);
+            if prefix.len() == len {
+                Err("enough data".into())
+            } else {
+                Ok(())
+            }
+        },
+    );
+    (prefix.len() == len).then_some(prefix)
+}
+
+/// The images of a VM held in memory, built into AxVisor or read from block devices.
+struct ImageSlices<'a> {
+    kernel: &'a [u8],
+    dtb: Option<&'a [u8]>,
+    bios: Option<&'a [u8]>,
+    ramdisk: Option<&'a [u8]>,
 }
 
 pub struct ImageLoader {
@@ -60,6 +345,12 @@ pub struct ImageLoader {
     bios_load_gpa: Option<GuestPhysAddr>,
     dtb_load_gpa: Option<GuestPhysAddr>,
     ramdisk_load_gpa: Option<GuestPhysAddr>,
+    /// Size of the ramdisk once loaded, i.e. decompressed.
+    ramdisk_size: Option<usize>,
+    #[cfg(target_arch = "x86_64")]
+    bzimage: Option<BzImage>,
+    #[cfg(target_arch = "x86_64")]
+    multiboot: Option<Multiboot>,
 }
 
 impl ImageLoader {
@@ -72,6 +363,11 @@ impl ImageLoader {
             bios_load_gpa: None,
             dtb_load_gpa: None,
             ramdisk_load_gpa: None,
+            ramdisk_size: None,
+            #[cfg(target_arch = "x86_64")]
+            bzimage: None,
+            #[cfg(target_arch = "x86_64")]
+            multiboot: None,
         }
     }
 
@@ -91,29 +387,297 @@ impl ImageLoader {
             self.ramdisk_load_gpa = config.image_config.ramdisk_load_gpa;
         });
 
+        #[cfg(target_arch = "x86_64")]
+        {
+            let invalid =
+                |msg| ax_err_type!(InvalidData, format!("VM[{}] kernel: {msg}", self.vm.id()));
+            self.bzimage = get_kernel_bzimage(&self.config)
+                .transpose()
+                .map_err(invalid)?;
+            if self.bzimage.is_none() {
+                self.multiboot = get_kernel_multiboot(&self.config)
+                    .transpose()
+                    .map_err(invalid)?;
+            }
+        }
+
+        // On aarch64 the command line is passed through the FDT, see `fdt::update_fdt`, bzImage
+        // and Multiboot kernels get it through their boot information.
+        #[cfg(target_arch = "x86_64")]
+        let takes_cmdline = self.bzimage.is_some() || self.multiboot.is_some();
+        #[cfg(not(target_arch = "x86_64"))]
+        let takes_cmdline = cfg!(target_arch = "aarch64");
+        if !takes_cmdline && !crate::vmm::ext_config::get(self.vm.id()).cmdline.is_empty() {
+            warn!(
+                "VM[{}] the boot protocol of this guest takes no command line, kernel.cmdline* ignored",
+                self.vm.id()
+            );
+        }
+
         match self.config.kernel.image_location.as_deref() {
-            Some("memory") => self.load_vm_images_from_memory(),
-            #[cfg(feature = "fs")]
-            Some("fs") => fs::load_vm_images_from_filesystem(self),
+            Some("memory") => self.load_vm_images_from_memory()?,
+            #[cfg(any(feature = "fs", feature = "guest-archive"))]
+            Some("fs") => fs::load_vm_images_from_filesystem(self)?,
+            Some("block") => self.load_vm_images_from_block()?,
             _ => unimplemented!(
-                "Check your \"image_location\" in config.toml, \"memory\" and \"fs\" are supported,\n NOTE: \"fs\" feature should be enabled if you want to load images from filesystem. (APP_FEATURES=fs)"
+                "Check your \"image_location\" in config.toml, \"memory\", \"fs\" and \"block\" are supported,\n NOTE: \"fs\" or \"guest-archive\" feature should be enabled if you want to load images from filesystem. (APP_FEATURES=fs)"
             ),
         }
+
+        #[cfg(target_arch = "x86_64")]
+        if let Some(bz) = &self.bzimage {
+            self.setup_bzimage_boot(bz)?;
+        } else if let Some(mb) = &self.multiboot {
+            self.setup_multiboot_boot(mb)?;
+        }
+        Ok(())
+    }
+
+    /// Returns the load layout of the kernel if it is not loaded as a whole at
+    /// `kernel_load_addr`: ELF images, and Multiboot kernels whose header gives their addresses.
+    fn kernel_elf(&self) -> Option<Result<ElfImage, String>> {
+        #[cfg(target_arch = "x86_64")]
+        if let Some(layout) = self.multiboot.as_ref().and_then(Multiboot::layout) {
+            return Some(Ok(layout));
+        }
+        get_kernel_elf(&self.config)
+    }
+
+    /// Address and size of the loaded ramdisk.
+    fn initrd(&self) -> Option<(GuestPhysAddr, usize)> {
+        self.ramdisk_load_gpa.zip(self.ramdisk_size)
+    }
+
+    /// Offset of the part of the kernel image that is loaded at `kernel_load_addr`, bzImage
+    /// kernels start with their real-mode setup code which is not loaded.
+    fn kernel_offset(&self) -> usize {
+        #[cfg(target_arch = "x86_64")]
+        if let Some(bz) = &self.bzimage {
+            return bz.setup_size;
+        }
+        0
+    }
+
+    /// Writes the zero page, the command line, the page tables and the trampoline of a bzImage
+    /// kernel, and makes the trampoline the entry point of the vCPUs.
+    #[cfg(target_arch = "x86_64")]
+    fn setup_bzimage_boot(&self, bz: &BzImage) -> AxResult {
+        let vm_id = self.vm.id();
+        let cmdline = crate::vmm::ext_config::get(vm_id).cmdline.apply("");
+        if cmdline.len() > bz.cmdline_size {
+            return ax_err!(
+                InvalidInput,
+                format!(
+                    "VM[{vm_id}] command line of {} bytes exceeds the {} bytes the kernel accepts",
+                    cmdline.len(),
+                    bz.cmdline_size
+                )
+            );
+        }
+        let with_cmdline = !cmdline.is_empty();
+        if with_cmdline {
+            let mut bytes = cmdline.into_bytes();
+            bytes.push(0);
+            load_vm_image_from_memory(
+                &bytes,
+                GuestPhysAddr::from(bzimage::CMDLINE_GPA),
+                self.vm.clone(),
+            )?;
+        }
+
+        let ram: Vec<_> = self
+            .vm
+            .memory_regions()
+            .iter()
+            .map(|region| (region.gpa.as_usize(), region.size()))
+            .collect();
+        let ramdisk = self.initrd().map(|(gpa, size)| (gpa.as_usize(), size));
+        let boot_params =
+            bz.boot_params(self.kernel_load_gpa.as_usize(), with_cmdline, ramdisk, &ram);
+        for (gpa, data) in [
+            (bzimage::BOOT_PARAMS_GPA, boot_params.as_slice()),
+            (bzimage::PAGE_TABLES_GPA, &bzimage::page_tables()),
+            (bzimage::TRAMPOLINE_GPA, bzimage::trampoline()),
+        ] {
+            load_vm_image_from_memory(data, GuestPhysAddr::from(gpa), self.vm.clone())?;
+        }
+
+        info!(
+            "VM[{vm_id}] bzImage kernel loaded at {:#x}, zero page at {:#x}",
+            self.kernel_load_gpa,
+            bzimage::BOOT_PARAMS_GPA
+        );
+        self.vm.with_config(|config| {
+            config.cpu_config.bsp_entry = GuestPhysAddr::from(bzimage::TRAMPOLINE_GPA);
+            config.cpu_config.ap_entry = GuestPhysAddr::from(bzimage::TRAMPOLINE_GPA);
+        });
+        Ok(())
+    }
+
+    /// Writes the boot information and the trampoline of a Multiboot kernel, and makes the
+    /// trampoline the entry point of the vCPUs.
+    #[cfg(target_arch = "x86_64")]
+    fn setup_multiboot_boot(&self, mb: &Multiboot) -> AxResult {
+        let vm_id = self.vm.id();
+        // The entry point of the header, or the one the kernel was loaded with.
+        let entry = mb.entry.unwrap_or_else(|| {
+            self.vm
+                .with_config(|config| config.cpu_config.bsp_entry.as_usize())
+        });
+        let cmdline = crate::vmm::ext_config::get(vm_id).cmdline.apply("");
+        let module = self.initrd().map(|(gpa, size)| {
+            (
+                gpa.as_usize(),
+                gpa.as_usize() + size,
+                self.config
+                    .kernel
+                    .ramdisk_path
+                    .as_deref()
+                    .unwrap_or_default(),
+            )
+        });
+        let ram: Vec<_> = self
+            .vm
+            .memory_regions()
+            .iter()
+            .map(|region| (region.gpa.as_usize(), region.size()))
+            .collect();
+        let info = mb
+            .boot_info(&cmdline, module, &ram)
+            .map_err(|msg| ax_err_type!(InvalidInput, format!("VM[{vm_id}] {msg}")))?;
+
+        load_vm_image_from_memory(
+            &info,
+            GuestPhysAddr::from(multiboot::BOOT_INFO_GPA),
+            self.vm.clone(),
+        )?;
+        load_vm_image_from_memory(
+            &multiboot::trampoline(entry, mb.boot_magic(), multiboot::BOOT_INFO_GPA),
+            GuestPhysAddr::from(multiboot::TRAMPOLINE_GPA),
+            self.vm.clone(),
+        )?;
+
+        info!(
+            "VM[{vm_id}] {:?} kernel entry {entry:#x}, boot information at {:#x}",
+            mb.version,
+            multiboot::BOOT_INFO_GPA
+        );
+        self.vm.with_config(|config| {
+            config.cpu_config.bsp_entry = GuestPhysAddr::from(multiboot::TRAMPOLINE_GPA);
+            config.cpu_config.ap_entry = GuestPhysAddr::from(multiboot::TRAMPOLINE_GPA);
+        });
+        Ok(())
     }
 
     /// Load VM images from memory
     /// into the guest VM's memory space based on the VM configuration.
-    fn load_vm_images_from_memory(&self) -> AxResult {
+    fn load_vm_images_from_memory(&mut self) -> AxResult {
         info!("Loading VM[{}] images from memory", self.config.base.id);
 
         let vm_imags = config::get_memory_images()
             .iter()
             .find(|&v| v.id == self.config.base.id)
-            .expect("VM images is missed, Perhaps add `VM_CONFIGS=PATH/CONFIGS/FILE` command.");
+            .ok_or_else(|| {
+                ax_err_type!(
+                    NotFound,
+                    "VM images is missed, Perhaps add `VM_CONFIGS=PATH/CONFIGS/FILE` command."
+                )
+            })?;
 
-        load_vm_image_from_memory(vm_imags.kernel, self.kernel_load_gpa, self.vm.clone())
-            .expect("Failed to load VM images");
-        // Load DTB image
+        self.load_vm_images_from_slices(&ImageSlices {
+            kernel: vm_imags.kernel,
+            dtb: vm_imags.dtb,
+            bios: vm_imags.bios,
+            ramdisk: vm_imags.ramdisk,
+        })
+    }
+
+    /// Load VM images from block devices, see [`block`]. The images are read into memory first.
+    fn load_vm_images_from_block(&mut self) -> AxResult {
+        let vm_id = self.vm.id();
+        info!("Loading VM[{vm_id}] images from block devices");
+
+        let read = |path: &str| {
+            block::read_image(path).map_err(|msg| ax_err_type!(Io, format!("VM[{vm_id}] {msg}")))
+        };
+        let kernel = &self.config.kernel;
+        let kernel_image = read(&kernel.kernel_path)?;
+        let dtb = kernel.dtb_path.as_deref().map(read).transpose()?;
+        let bios = kernel.bios_path.as_deref().map(read).transpose()?;
+        let ramdisk = kernel.ramdisk_path.as_deref().map(read).transpose()?;
+
+        self.load_vm_images_from_slices(&ImageSlices {
+            kernel: &kernel_image,
+            dtb: dtb.as_deref(),
+            bios: bios.as_deref(),
+            ramdisk: ramdisk.as_deref(),
+        })
+    }
+
+    /// Loads VM images held in memory into the guest VM's memory space.
+    fn load_vm_images_from_slices(&mut self, vm_imags: &ImageSlices<'_>) -> AxResult {
+        // Measure and check the images before loading any of them.
+        let vm_id = self.vm.id();
+        let digests = crate::vmm::ext_config::get(vm_id).digests;
+        for (name, expected, image) in [
+            ("kernel", digests.kernel, Some(vm_imags.kernel)),
+            ("dtb", digests.dtb, vm_imags.dtb),
+            ("bios", digests.bios, vm_imags.bios),
+            ("ramdisk", digests.ramdisk, vm_imags.ramdisk),
+        ] {
+            if let Some(image) = image {
+                measure_image(vm_id, name, expected, measure::sha256(image))?;
+            }
+        }
+
+        let uboot_image = self.load_uboot_image(false)?;
+        match self.kernel_elf() {
+            _ if uboot_image => {}
+            Some(elf) => {
+                let elf = elf.map_err(|msg| {
+                    ax_err_type!(InvalidData, format!("VM[{}] kernel: {msg}", self.vm.id()))
+                })?;
+                let kernel = vm_imags.kernel;
+                self.load_elf(&elf, &mut |seg| {
+                    let content = seg
+                        .offset
+                        .checked_add(seg.file_size)
+                        .and_then(|end| kernel.get(seg.offset..end));
+                    let Some(content) = content else {
+                        return ax_err!(InvalidData, "ELF segment beyond the end of the image");
+                    };
+                    load_vm_image_from_memory(
+                        content,
+                        GuestPhysAddr::from(seg.paddr),
+                        self.vm.clone(),
+                    )
+                })?;
+            }
+            None => {
+                let Some(kernel) = vm_imags.kernel.get(self.kernel_offset()..) else {
+                    return ax_err!(InvalidData, "kernel image is truncated");
+                };
+                load_image_from_memory(kernel, self.kernel_load_gpa, self.vm.clone())?;
+            }
+        }
+        // Load BIOS image
+        if let Some(buffer) = vm_imags.bios {
+            let load_gpa = self
+                .bios_load_gpa
+                .ok_or_else(|| ax_err_type!(InvalidInput, "missing kernel.bios_load_addr"))?;
+            load_image_from_memory(buffer, load_gpa, self.vm.clone())?;
+        }
+
+        // Load Ramdisk image
+        if let Some(buffer) = vm_imags.ramdisk {
+            let load_gpa = self
+                .ramdisk_load_gpa
+                .ok_or_else(|| ax_err_type!(InvalidInput, "missing kernel.ramdisk_load_addr"))?;
+            let size = load_image_from_memory(buffer, load_gpa, self.vm.clone())?;
+            self.ramdisk_size = Some(size);
+        };
+
+        // Load DTB image, after the ramdisk whose location it carries.
         let vm_config = axvm::config::AxVMConfig::from(self.config.clone());
 
         if let Some(dtb_arc) = get_vm_dtb_arc(&vm_config) {
@@ -123,27 +687,251 @@ impl ImageLoader {
                 core::ptr::NonNull::new(_dtb_slice.as_ptr() as *mut u8).unwrap(),
                 _dtb_slice.len(),
                 self.vm.clone(),
+                self.initrd(),
             );
         } else {
             info!("dtb_load_gpa not provided");
         }
 
-        // Load BIOS image
-        if let Some(buffer) = vm_imags.bios {
-            load_vm_image_from_memory(buffer, self.bios_load_gpa.unwrap(), self.vm.clone())
-                .expect("Failed to load BIOS images");
+        Ok(())
+    }
+
+    /// Loads the components of a U-Boot FIT image or uImage kernel, returns whether the kernel is
+    /// one. The kernel is measured first if `measure_kernel` is set, it is read once into memory.
+    fn load_uboot_image(&mut self, measure_kernel: bool) -> AxResult<bool> {
+        let vm_id = self.vm.id();
+        let ext = crate::vmm::ext_config::get(vm_id);
+        let config = self.config.clone();
+        match with_kernel_uboot_image_data(&config, ext.fit_config.as_deref(), |image, kernel| {
+            if measure_kernel {
+                measure_image(vm_id, "kernel", ext.digests.kernel, measure::sha256(kernel))?;
+            }
+            self.load_uboot_components(image)
+        }) {
+            None => Ok(false),
+            Some(Ok(loaded)) => loaded.map(|()| true),
+            Some(Err(msg)) => ax_err!(InvalidData, format!("VM[{vm_id}] kernel: {msg}")),
         }
+    }
 
-        // Load Ramdisk image
-        if let Some(buffer) = vm_imags.ramdisk {
-            load_vm_image_from_memory(buffer, self.ramdisk_load_gpa.unwrap(), self.vm.clone())
-                .expect("Failed to load Ramdisk images");
-        };
+    /// Verifies the components of a U-Boot image and places them at their load addresses, which
+    /// override the ones of the VM config.
+    fn load_uboot_components(&mut self, image: &UbootImage<'_>) -> AxResult {
+        let vm_id = self.vm.id();
+        info!("VM[{vm_id}] booting {}", image.description);
+        for component in &image.components {
+            component.verify().map_err(|msg| {
+                ax_err_type!(
+                    InvalidData,
+                    format!("VM[{vm_id}] {}: {msg}", component.name)
+                )
+            })?;
+            let load = component.load.map(GuestPhysAddr::from);
+            let gpa = match component.kind {
+                ComponentKind::Kernel => {
+                    self.kernel_load_gpa = load.unwrap_or(self.kernel_load_gpa);
+                    self.kernel_load_gpa
+                }
+                ComponentKind::Ramdisk => {
+                    self.ramdisk_load_gpa = load.or(self.ramdisk_load_gpa);
+                    let Some(gpa) = self.ramdisk_load_gpa else {
+                        return ax_err!(
+                            InvalidInput,
+                            format!("VM[{vm_id}] {} has no load address", component.name)
+                        );
+                    };
+                    gpa
+                }
+                ComponentKind::Fdt => {
+                    self.dtb_load_gpa = load.or(self.dtb_load_gpa);
+                    // On aarch64 the FDT is adapted to the VM and loaded with
+                    // `fdt::update_fdt`, see `fdt::get_developer_provided_dtb`.
+                    if cfg!(target_arch = "aarch64") {
+                        continue;
+                    }
+                    let Some(gpa) = self.dtb_load_gpa else {
+                        warn!(
+                            "VM[{vm_id}] {} has no load address, not loaded",
+                            component.name
+                        );
+                        continue;
+                    };
+                    gpa
+                }
+            };
+
+            let size = match component.compression {
+                None => {
+                    load_vm_image_from_memory(component.data, gpa, self.vm.clone())?;
+                    component.data.len()
+                }
+                Some(compression) => {
+                    let mut rest = component.data;
+                    load_compressed_image(
+                        compression,
+                        &mut |buf| read_slice(&mut rest, buf),
+                        gpa,
+                        &self.vm,
+                    )?
+                }
+            };
+            info!(
+                "VM[{vm_id}] {:?} {} loaded at {gpa:#x}, {:#}",
+                component.kind,
+                component.name,
+                Byte::from(size)
+            );
+            if component.kind == ComponentKind::Ramdisk {
+                self.ramdisk_size = Some(size);
+            }
+        }
+
+        let entry = image
+            .component(ComponentKind::Kernel)
+            .and_then(|kernel| kernel.entry);
+        self.vm.with_config(|config| {
+            config.image_config.kernel_load_gpa = self.kernel_load_gpa;
+            config.image_config.dtb_load_gpa = self.dtb_load_gpa;
+            config.image_config.ramdisk_load_gpa = self.ramdisk_load_gpa;
+            if let Some(entry) = entry {
+                config.cpu_config.bsp_entry = GuestPhysAddr::from(entry);
+                config.cpu_config.ap_entry = GuestPhysAddr::from(entry);
+            }
+        });
+        Ok(())
+    }
 
+    /// Loads the `PT_LOAD` segments of an ELF kernel at their physical addresses, `copy` writing
+    /// the file content of a segment, and makes its entry point the one of the vCPUs.
+    fn load_elf(
+        &self,
+        elf: &ElfImage,
+        copy: &mut dyn FnMut(&elf::Segment) -> AxResult,
+    ) -> AxResult {
+        for seg in &elf.segments {
+            debug!(
+                "VM[{}] ELF segment: gpa={:#x}, file size={:#x}, memory size={:#x}",
+                self.vm.id(),
+                seg.paddr,
+                seg.file_size,
+                seg.mem_size
+            );
+            if seg.file_size > 0 {
+                copy(seg)?;
+            }
+            if seg.mem_size > seg.file_size {
+                let bss = seg
+                    .paddr
+                    .checked_add(seg.file_size)
+                    .ok_or_else(|| ax_err_type!(InvalidData, "ELF segment overflows"))?;
+                zero_guest_memory(
+                    GuestPhysAddr::from(bss),
+                    seg.mem_size - seg.file_size,
+                    &self.vm,
+                )?;
+            }
+        }
+
+        info!(
+            "VM[{}] ELF kernel loaded at {:#x}, entry {:#x}",
+            self.vm.id(),
+            elf.base(),
+            elf.entry
+        );
+        self.vm.with_config(|config| {
+            config.image_config.kernel_load_gpa = GuestPhysAddr::from(elf.base());
+            config.cpu_config.bsp_entry = GuestPhysAddr::from(elf.entry);
+            config.cpu_config.ap_entry = GuestPhysAddr::from(elf.entry);
+        });
         Ok(())
     }
 }
 
+/// Zeroes `size` bytes of guest memory at `gpa`, e.g. the BSS of an ELF segment.
+fn zero_guest_memory(gpa: GuestPhysAddr, size: usize, vm: &VMRef) -> AxResult {
+    for region in vm.get_image_load_region(gpa, size)? {
+        region.fill(0);
+        crate::hal::arch::cache::dcache_range(
+            CacheOp::Clean,
+            (region.as_ptr() as usize).into(),
+            region.len(),
+        );
+    }
+    Ok(())
+}
+
+/// Loads an image built into AxVisor, decompressing it if it is compressed, and returns its
+/// loaded size.
+fn load_image_from_memory(image: &[u8], load_addr: GuestPhysAddr, vm: VMRef) -> AxResult<usize> {
+    let Some(compression) = Compression::detect(image) else {
+        load_vm_image_from_memory(image, load_addr, vm)?;
+        return Ok(image.len());
+    };
+    let mut rest = image;
+    load_compressed_image(
+        compression,
+        &mut |buf| read_slice(&mut rest, buf),
+        load_addr,
+        &vm,
+    )
+}
+
+/// Decompresses an image straight into guest memory at `load_addr` and returns its decompressed
+/// size. The configs are checked with the compressed size of images, so decompression stops with
+/// an error where the memory region holding `load_addr` ends.
+fn load_compressed_image(
+    compression: Compression,
+    source: compress::Source<'_>,
+    load_addr: GuestPhysAddr,
+    vm: &VMRef,
+) -> AxResult<usize> {
+    let ram_end = vm
+        .memory_regions()
+        .iter()
+        .find(|region| region.gpa <= load_addr && load_addr < region.gpa + region.size())
+        .map_or(load_addr, |region| region.gpa + region.size());
+    let mut gpa = load_addr;
+    let size = compression
+        .decompress(source, &mut |data| {
+            if data.len() > ram_end - gpa {
+                return Err(format!(
+                    "the decompressed image does not fit in guest RAM, which ends at {ram_end:#x}"
+                ));
+            }
+            let mut written = 0;
+            for region in vm
+                .get_image_load_region(gpa, data.len())
+                .map_err(|err| format!("{err:?}"))?
+            {
+                let len = region.len().min(data.len() - written);
+                region[..len].copy_from_slice(&data[written..written + len]);
+                crate::hal::arch::cache::dcache_range(
+                    CacheOp::Clean,
+                    (region.as_ptr() as usize).into(),
+                    len,
+                );
+                written += len;
+            }
+            gpa += data.len();
+            Ok(())
+        })
+        .map_err(|msg| {
+            ax_err_type!(
+                InvalidData,
+                format!(
+                    "VM[{}] {compression:?} image at {load_addr:#x}: {msg}",
+                    vm.id()
+                )
+            )
+        })?;
+    info!(
+        "VM[{}] {compression:?} image decompressed at {load_addr:#x}, {:#}",
+        vm.id(),
+        Byte::from(size)
+    );
+    Ok(size)
+}
+
 pub fn load_vm_image_from_memory(
     image_buffer: &[u8],
     load_addr: GuestPhysAddr,
@@ -193,70 +981,129 @@ pub fn load_vm_image_from_memory(
     Ok(())
 }
 
-#[cfg(feature = "fs")]
+/// Records the SHA-256 digest of an image of a VM in its measured boot log, after checking it
+/// against the digest expected by the VM config, if any.
+fn measure_image(
+    vm_id: usize,
+    name: &'static str,
+    expected: Option<[u8; 32]>,
+    digest: [u8; 32],
+) -> AxResult {
+    if let Some(expected) = expected {
+        if expected != digest {
+            return ax_err!(
+                InvalidData,
+                format!(
+                    "VM[{vm_id}] {name} image does not match {name}_sha256, its digest is {}",
+                    measure::hex(&digest)
+                )
+            );
+        }
+        info!("VM[{vm_id}] {name} image matches its SHA-256 digest");
+    }
+    measure::extend(vm_id, name, digest);
+    Ok(())
+}
+
+#[cfg(any(feature = "fs", feature = "guest-archive"))]
 pub mod fs {
     use super::*;
     use crate::hal::CacheOp;
+    use crate::vmm::guestfs::File;
     use axerrno::{AxResult, ax_err, ax_err_type};
-    use std::{fs::File, vec::Vec};
+    use sha2::{Digest, Sha256};
+    use std::{
+        io::{Read, Seek, SeekFrom},
+        string::String,
+        vec::Vec,
+    };
 
+    /// Reads the first `read_size` bytes of the kernel, decompressed if it is compressed.
     pub fn kernal_read(config: &AxVMCrateConfig, read_size: usize) -> AxResult<Vec<u8>> {
-        use std::fs::File;
-        use std::io::Read;
         let file_name = &config.kernel.kernel_path;
+        let (mut file, _) = open_image_file(file_name)?;
 
-        let mut file = File::open(file_name).map_err(|err| {
+        read_image_prefix(
+            &mut |buf| file.read(buf).map_err(|err| format!("{err:?}")),
+            read_size,
+        )
+        .ok_or_else(|| {
             ax_err_type!(
-                NotFound,
+                InvalidData,
                 format!(
-                    "Failed to open {}, err {:?}, please check your disk.img",
-                    file_name, err
+                    "Failed to read {} bytes of {}, please check your disk.img",
+                    read_size, file_name
                 )
             )
-        })?;
-
-        let mut buffer = vec![0u8; read_size];
-
-        file.read_exact(&mut buffer).map_err(|err| {
-            ax_err_type!(
-                NotFound,
-                format!(
-                    "Failed to read {}, err {:?}, please check your disk.img",
-                    file_name, err
-                )
-            )
-        })?;
-
-        Ok(buffer)
+        })
     }
 
     /// Loads the VM image files from the filesystem
     /// into the guest VM's memory space based on the VM configuration.
-    pub(crate) fn load_vm_images_from_filesystem(loader: &ImageLoader) -> AxResult {
+    ///
+    /// Each file is read once, and measured as it is loaded: the digest checked is the one of the
+    /// data in guest memory, and the VM is not created if an image does not match.
+    pub(crate) fn load_vm_images_from_filesystem(loader: &mut ImageLoader) -> AxResult {
         info!("Loading VM images from filesystem");
-        // Load kernel image.
-        load_vm_image(
-            &loader.config.kernel.kernel_path,
-            loader.kernel_load_gpa,
-            loader.vm.clone(),
-        )?;
+        let vm_id = loader.vm.id();
+        let digests = crate::vmm::ext_config::get(vm_id).digests;
+
+        // Load kernel image, U-Boot images are measured as they are read into memory.
+        let uboot_image = loader.load_uboot_image(true)?;
+        let kernel_path = loader.config.kernel.kernel_path.clone();
+        if !uboot_image {
+            let mut file = MeasuredFile::open(&kernel_path)?;
+            match loader.kernel_elf() {
+                Some(elf) => {
+                    let elf = elf.map_err(|msg| {
+                        ax_err_type!(InvalidData, format!("{kernel_path}: {msg}"))
+                    })?;
+                    // Segments are loaded once the file is read in order, after their BSS is
+                    // zeroed.
+                    let mut ranges = Vec::new();
+                    loader.load_elf(&elf, &mut |seg| {
+                        ranges.push((seg.offset, seg.file_size, GuestPhysAddr::from(seg.paddr)));
+                        Ok(())
+                    })?;
+                    load_file_ranges(&mut file, ranges, &loader.vm)?;
+                }
+                None if loader.kernel_offset() > 0 => {
+                    let offset = loader.kernel_offset();
+                    let size = file.size.saturating_sub(offset);
+                    let ranges = vec![(offset, size, loader.kernel_load_gpa)];
+                    load_file_ranges(&mut file, ranges, &loader.vm)?;
+                }
+                None => {
+                    load_vm_image(&mut file, loader.kernel_load_gpa, &loader.vm)?;
+                }
+            }
+            measure_image(vm_id, "kernel", digests.kernel, file.finish()?)?;
+        }
+        // The DTB was read when the VM was set up, see `fdt::get_developer_provided_dtb`, and may
+        // have been updated since. It is measured as stored, as in the other image locations.
+        if let Some(dtb_path) = &loader.config.kernel.dtb_path {
+            let digest = MeasuredFile::open(dtb_path)?.finish()?;
+            measure_image(vm_id, "dtb", digests.dtb, digest)?;
+        }
         // Load BIOS image if needed.
         if let Some(bios_path) = &loader.config.kernel.bios_path {
-            if let Some(bios_load_addr) = loader.bios_load_gpa {
-                load_vm_image(bios_path, bios_load_addr, loader.vm.clone())?;
-            } else {
+            let Some(bios_load_addr) = loader.bios_load_gpa else {
                 return ax_err!(NotFound, "BIOS load addr is missed");
-            }
+            };
+            let mut file = MeasuredFile::open(bios_path)?;
+            load_vm_image(&mut file, bios_load_addr, &loader.vm)?;
+            measure_image(vm_id, "bios", digests.bios, file.finish()?)?;
         };
         // Load Ramdisk image if needed.
         if let Some(ramdisk_path) = &loader.config.kernel.ramdisk_path {
-            if let Some(ramdisk_load_addr) = loader.ramdisk_load_gpa {
-                load_vm_image(ramdisk_path, ramdisk_load_addr, loader.vm.clone())?;
-            } else {
+            let Some(ramdisk_load_addr) = loader.ramdisk_load_gpa else {
                 return ax_err!(NotFound, "Ramdisk load addr is missed");
-            }
+            };
+            let mut file = MeasuredFile::open(ramdisk_path)?;
+            loader.ramdisk_size = Some(load_vm_image(&mut file, ramdisk_load_addr, &loader.vm)?);
+            measure_image(vm_id, "ramdisk", digests.ramdisk, file.finish()?)?;
         };
-        // Load DTB image if needed.
+        // Load DTB image if needed, after the ramdisk whose location it carries.
         let vm_config = axvm::config::AxVMConfig::from(loader.config.clone());
         if let Some(dtb_arc) = get_vm_dtb_arc(&vm_config) {
             let _dtb_slice: &[u8] = &dtb_arc;
@@ -265,26 +1112,48 @@ pub mod fs {
                 core::ptr::NonNull::new(_dtb_slice.as_ptr() as *mut u8).unwrap(),
                 _dtb_slice.len(),
                 loader.vm.clone(),
+                loader.initrd(),
             );
         }
 
         Ok(())
     }
 
-    fn load_vm_image(image_path: &str, image_load_gpa: GuestPhysAddr, vm: VMRef) -> AxResult {
-        use std::io::{BufReader, Read};
-        let (image_file, image_size) = open_image_file(image_path)?;
-
-        let image_load_regions = vm.get_image_load_region(image_load_gpa, image_size)?;
-        let mut file = BufReader::new(image_file);
+    /// Loads an image file, decompressing it if it is compressed, and returns its loaded size.
+    fn load_vm_image(
+        file: &mut MeasuredFile<'_>,
+        image_load_gpa: GuestPhysAddr,
+        vm: &VMRef,
+    ) -> AxResult<usize> {
+        let mut magic = [0u8; Compression::MAGIC_SIZE];
+        let mut magic_len = 0;
+        while magic_len < magic.len() {
+            match file.read(&mut magic[magic_len..])? {
+                0 => break,
+                read => magic_len += read,
+            }
+        }
+        let (path, image_size) = (file.path, file.size);
+        let mut head = &magic[..magic_len];
+        let mut source = |buf: &mut [u8]| {
+            if !head.is_empty() {
+                return read_slice(&mut head, buf);
+            }
+            file.read(buf).map_err(|err| format!("{err:?}"))
+        };
+        if let Some(compression) = Compression::detect(&magic[..magic_len]) {
+            return load_compressed_image(compression, &mut source, image_load_gpa, vm);
+        }
 
-        for buffer in image_load_regions {
-            file.read_exact(buffer).map_err(|err| {
-                ax_err_type!(
-                    Io,
-                    format!("Failed in reading from file {}, err {:?}", image_path, err)
-                )
-            })?;
+        for buffer in vm.get_image_load_region(image_load_gpa, image_size)? {
+            let mut filled = 0;
+            while filled < buffer.len() {
+                match source(&mut buffer[filled..]) {
+                    Ok(0) => return ax_err!(UnexpectedEof, format!("{path} is truncated")),
+                    Ok(read) => filled += read,
+                    Err(msg) => return ax_err!(Io, msg),
+                }
+            }
 
             crate::hal::arch::cache::dcache_range(
                 CacheOp::Clean,
@@ -293,9 +1162,154 @@ pub mod fs {
             );
         }
 
+        Ok(image_size)
+    }
+
+    /// Returns the load layout of a kernel file if it is an ELF image.
+    pub fn kernel_elf(path: &str) -> Option<Result<ElfImage, String>> {
+        let (mut file, _) = open_image_file(path).ok()?;
+        let mut header = [0u8; 4];
+        file.read_exact(&mut header).ok()?;
+        if !ElfImage::is_elf(&header) {
+            return None;
+        }
+        Some(ElfImage::parse(&mut |offset, len| {
+            let mut buffer = vec![0u8; len];
+            file.seek(SeekFrom::Start(offset as u64))
+                .and_then(|_| file.read_exact(&mut buffer))
+                .map_err(|err| format!("failed to read the ELF headers: {err:?}"))?;
+            Ok(buffer)
+        }))
+    }
+
+    /// Returns the boot setup of a kernel file if it is a bzImage.
+    #[cfg(target_arch = "x86_64")]
+    pub fn kernel_bzimage(path: &str) -> Option<Result<BzImage, String>> {
+        let (header, _) = read_head(path, bzimage::HEADER_SIZE)?;
+        BzImage::is_bzimage(&header).then(|| BzImage::parse(&header))
+    }
+
+    /// Reads a whole file into memory.
+    pub fn read_file(path: &str) -> AxResult<Vec<u8>> {
+        let (mut file, size) = open_image_file(path)?;
+        let mut data = vec![0u8; size];
+        file.read_exact(&mut data).map_err(|err| {
+            ax_err_type!(
+                Io,
+                format!("Failed in reading from file {}, err {:?}", path, err)
+            )
+        })?;
+        Ok(data)
+    }
+
+    /// Reads up to `len` bytes from the start of a file, returning them with the file size.
+    pub fn read_head(path: &str, len: usize) -> Option<(Vec<u8>, usize)> {
+        let (mut file, size) = open_image_file(path).ok()?;
+        let mut head = vec![0u8; len];
+        let mut read_len = 0;
+        while read_len < head.len() {
+            match file.read(&mut head[read_len..]).ok()? {
+                0 => break,
+                read => read_len += read,
+            }
+        }
+        head.truncate(read_len);
+        Some((head, size))
+    }
+
+    /// Loads ranges of a file, given as `(offset, size, gpa)`, into guest memory. The file is read
+    /// forward, so the ranges must not overlap.
+    fn load_file_ranges(
+        file: &mut MeasuredFile<'_>,
+        mut ranges: Vec<(usize, usize, GuestPhysAddr)>,
+        vm: &VMRef,
+    ) -> AxResult {
+        ranges.sort_by_key(|&(offset, ..)| offset);
+        for (offset, size, gpa) in ranges {
+            if offset < file.pos {
+                return ax_err!(
+                    InvalidData,
+                    format!("{}: overlapping ranges at {offset:#x}", file.path)
+                );
+            }
+            file.skip_to(offset)?;
+            for buffer in vm.get_image_load_region(gpa, size)? {
+                file.read_exact(buffer)?;
+
+                crate::hal::arch::cache::dcache_range(
+                    CacheOp::Clean,
+                    (buffer.as_ptr() as usize).into(),
+                    buffer.len(),
+                );
+            }
+        }
         Ok(())
     }
 
+    /// An image file read once from start to end. Everything read is hashed, so that the digest
+    /// measured is the one of the data loaded, even if the file changes meanwhile.
+    struct MeasuredFile<'a> {
+        path: &'a str,
+        file: File,
+        size: usize,
+        /// Number of bytes read so far.
+        pos: usize,
+        hasher: Sha256,
+    }
+
+    impl<'a> MeasuredFile<'a> {
+        fn open(path: &'a str) -> AxResult<Self> {
+            let (file, size) = open_image_file(path)?;
+            Ok(Self {
+                path,
+                file,
+                size,
+                pos: 0,
+                hasher: Sha256::new(),
+            })
+        }
+
+        fn read(&mut self, buf: &mut [u8]) -> AxResult<usize> {
+            let read = self.file.read(buf).map_err(|err| {
+                ax_err_type!(
+                    Io,
+                    format!("Failed in reading from file {}, err {:?}", self.path, err)
+                )
+            })?;
+            self.hasher.update(&buf[..read]);
+            self.pos += read;
+            Ok(read)
+        }
+
+        fn read_exact(&mut self, buf: &mut [u8]) -> AxResult {
+            let mut filled = 0;
+            while filled < buf.len() {
+                match self.read(&mut buf[filled..])? {
+                    0 => return ax_err!(UnexpectedEof, format!("{} is truncated", self.path)),
+                    read => filled += read,
+                }
+            }
+            Ok(())
+        }
+
+        /// Reads the file up to `offset`.
+        fn skip_to(&mut self, offset: usize) -> AxResult {
+            let mut buffer = vec![0u8; 0x10000];
+            while self.pos < offset {
+                let len = (offset - self.pos).min(buffer.len());
+                self.read_exact(&mut buffer[..len])?;
+            }
+            Ok(())
+        }
+
+        /// Reads the rest of the file and returns the SHA-256 digest of the whole file.
+        fn finish(mut self) -> AxResult<[u8; 32]> {
+            let mut buffer = vec![0u8; 0x10000];
+            while self.read(&mut buffer)? > 0 {}
+            Ok(self.hasher.finalize().into())
+        }
+    }
+
     pub fn open_image_file(file_name: &str) -> AxResult<(File, usize)> {
         let file = File::open(file_name).map_err(|err| {
             ax_err_type!(
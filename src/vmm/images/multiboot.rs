@@ -0,0 +1,517 @@
+// Copyright 2025 The Axvisor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Multiboot and Multiboot2 kernels.
+//!
+//! A kernel is loaded at the addresses of the address fields (Multiboot) or tag (Multiboot2) of
+//! its header if it has them, or else as an ELF image. The boot information gives the memory map
+//! of the guest RAM, the ramdisk as the only module and the command line. The BSP starts in real
+//! mode, so it first runs a small trampoline which enters 32-bit protected mode with flat segments
+//! and jumps to the kernel with `eax` holding the boot loader magic and `ebx` the address of the
+//! boot information, as both specifications require.
+//!
+//! Low guest memory used to boot:
+//!
+//! ```text
+//! 0x8000  trampoline, including its GDT
+//! 0x9000  boot information, up to 0x10000
+//! ```
+
+use alloc::{string::String, vec::Vec};
+
+use super::bzimage::{E820_RAM, e820_map};
+use super::elf::{ElfImage, Segment};
+
+/// Bytes of the image searched for a header.
+pub const SEARCH_SIZE: usize = MB2_SEARCH;
+/// Guest physical address of the trampoline, the entry point of the BSP.
+pub const TRAMPOLINE_GPA: usize = 0x8000;
+/// Guest physical address of the boot information.
+pub const BOOT_INFO_GPA: usize = 0x9000;
+/// End of the memory available for the boot information.
+pub const BOOT_INFO_END: usize = 0x1_0000;
+
+const MB1_SEARCH: usize = 0x2000;
+const MB1_HEADER_MAGIC: u32 = 0x1bad_b002;
+const MB1_BOOT_MAGIC: u32 = 0x2bad_b002;
+const MB1_PAGE_ALIGN: u32 = 1 << 0;
+const MB1_MEMORY_INFO: u32 = 1 << 1;
+const MB1_VIDEO_MODE: u32 = 1 << 2;
+const MB1_ADDRESS: u32 = 1 << 16;
+/// Flags a boot loader must understand to load the kernel.
+const MB1_REQUIRED: u32 = 0xffff;
+
+const MB2_SEARCH: usize = 0x8000;
+const MB2_HEADER_MAGIC: u32 = 0xe852_50d6;
+const MB2_BOOT_MAGIC: u32 = 0x36d7_6289;
+const MB2_ARCH_I386: u32 = 0;
+const MB2_TAG_OPTIONAL: u16 = 1 << 0;
+
+/// Multiboot2 header tags.
+mod header_tag {
+    pub const END: u16 = 0;
+    pub const INFORMATION_REQUEST: u16 = 1;
+    pub const ADDRESS: u16 = 2;
+    pub const ENTRY_ADDRESS: u16 = 3;
+    pub const CONSOLE_FLAGS: u16 = 4;
+    pub const FRAMEBUFFER: u16 = 5;
+    pub const MODULE_ALIGN: u16 = 6;
+    pub const RELOCATABLE: u16 = 10;
+}
+
+/// Multiboot2 boot information tags.
+mod info_tag {
+    pub const END: u32 = 0;
+    pub const CMDLINE: u32 = 1;
+    pub const BOOT_LOADER_NAME: u32 = 2;
+    pub const MODULE: u32 = 3;
+    pub const BASIC_MEMINFO: u32 = 4;
+    pub const MMAP: u32 = 6;
+}
+
+const BOOT_LOADER_NAME: &str = "AxVisor";
+const MMAP_ENTRY_SIZE: usize = 24;
+/// Size reserved for the Multiboot information structure.
+const MB1_INFO_SIZE: usize = 128;
+
+/// The Multiboot specification a kernel is booted with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Version {
+    V1,
+    V2,
+}
+
+/// The header of a Multiboot or Multiboot2 kernel.
+#[derive(Debug, Clone)]
+pub struct Multiboot {
+    pub version: Version,
+    /// Entry point given by the header, the one of the ELF image is used otherwise.
+    pub entry: Option<usize>,
+    /// Load layout given by the header, ELF images are loaded per their program headers
+    /// otherwise.
+    layout: Option<Segment>,
+    /// Whether modules must be page aligned.
+    pub page_align_modules: bool,
+}
+
+impl Multiboot {
+    /// Looks for a header in the first [`SEARCH_SIZE`] bytes of an image of `image_size` bytes,
+    /// preferring Multiboot2.
+    pub fn find(image: &[u8], image_size: usize) -> Option<Result<Self, String>> {
+        let search = &image[..image.len().min(MB2_SEARCH)];
+        for offset in (0..search.len()).step_by(8) {
+            let field = |index: usize| read_u32(search, offset + index * 4);
+            if let (Some(magic), Some(arch), Some(length), Some(checksum)) =
+                (field(0), field(1), field(2), field(3))
+                && magic == MB2_HEADER_MAGIC
+                && magic
+                    .wrapping_add(arch)
+                    .wrapping_add(length)
+                    .wrapping_add(checksum)
+                    == 0
+            {
+                if arch != MB2_ARCH_I386 {
+                    return Some(Err(format!("Multiboot2 architecture {arch} is not i386")));
+                }
+                return Some(Self::parse_v2(image, offset, length as usize, image_size));
+            }
+        }
+
+        let search = &image[..image.len().min(MB1_SEARCH)];
+        for offset in (0..search.len()).step_by(4) {
+            let field = |index: usize| read_u32(search, offset + index * 4);
+            if let (Some(magic), Some(flags), Some(checksum)) = (field(0), field(1), field(2))
+                && magic == MB1_HEADER_MAGIC
+                && magic.wrapping_add(flags).wrapping_add(checksum) == 0
+            {
+                return Some(Self::parse_v1(image, offset, flags, image_size));
+            }
+        }
+        None
+    }
+
+    fn parse_v1(
+        image: &[u8],
+        offset: usize,
+        flags: u32,
+        image_size: usize,
+    ) -> Result<Self, String> {
+        if flags & MB1_VIDEO_MODE != 0 {
+            return Err("the kernel requires video mode information, which is not provided".into());
+        }
+        let unknown = flags & MB1_REQUIRED & !(MB1_PAGE_ALIGN | MB1_MEMORY_INFO);
+        if unknown != 0 {
+            return Err(format!("the kernel requires unknown features {unknown:#x}"));
+        }
+
+        let (layout, entry) = if flags & MB1_ADDRESS != 0 {
+            let field = |index: usize| {
+                read_u32(image, offset + 12 + index * 4)
+                    .map(|value| value as usize)
+                    .ok_or("truncated Multiboot header")
+            };
+            let layout = address_layout(
+                offset,
+                [field(0)?, field(1)?, field(2)?, field(3)?],
+                image_size,
+            )?;
+            (Some(layout), Some(field(4)?))
+        } else {
+            (None, None)
+        };
+
+        Ok(Self {
+            version: Version::V1,
+            entry,
+            layout,
+            page_align_modules: flags & MB1_PAGE_ALIGN != 0,
+        })
+    }
+
+    fn parse_v2(
+        image: &[u8],
+        offset: usize,
+        length: usize,
+        image_size: usize,
+    ) -> Result<Self, String> {
+        let mut header = Self {
+            version: Version::V2,
+            entry: None,
+            layout: None,
+            page_align_modules: false,
+        };
+        let end = offset.saturating_add(length);
+        let mut tag = offset + 16;
+        while tag < end {
+            let (Some(head), Some(size)) = (read_u32(image, tag), read_u32(image, tag + 4)) else {
+                return Err("truncated Multiboot2 header".into());
+            };
+            let (ty, flags, size) = (head as u16, (head >> 16) as u16, size as usize);
+            let optional = flags & MB2_TAG_OPTIONAL != 0;
+            let field = |index: usize| {
+                read_u32(image, tag + 8 + index * 4)
+                    .map(|value| value as usize)
+                    .ok_or("truncated Multiboot2 header")
+            };
+            match ty {
+                header_tag::END => break,
+                header_tag::INFORMATION_REQUEST if !optional => {
+                    for index in 0..size.saturating_sub(8) / 4 {
+                        let request = field(index)? as u32;
+                        if !matches!(
+                            request,
+                            info_tag::CMDLINE
+                                | info_tag::BOOT_LOADER_NAME
+                                | info_tag::MODULE
+                                | info_tag::BASIC_MEMINFO
+                                | info_tag::MMAP
+                        ) {
+                            return Err(format!(
+                                "the kernel requires boot information tag {request}, which is not provided"
+                            ));
+                        }
+                    }
+                }
+                header_tag::ADDRESS => {
+                    header.layout = Some(address_layout(
+                        offset,
+                        [field(0)?, field(1)?, field(2)?, field(3)?],
+                        image_size,
+                    )?);
+                }
+                header_tag::ENTRY_ADDRESS => header.entry = Some(field(0)?),
+                header_tag::MODULE_ALIGN => header.page_align_modules = true,
+                header_tag::INFORMATION_REQUEST
+                | header_tag::CONSOLE_FLAGS
+                | header_tag::FRAMEBUFFER
+                | header_tag::RELOCATABLE => {}
+                _ if optional => {}
+                _ => return Err(format!("the kernel requires unsupported header tag {ty}")),
+            }
+            if size < 8 {
+                return Err("invalid Multiboot2 header tag".into());
+            }
+            tag += size.next_multiple_of(8);
+        }
+
+        if header.layout.is_some() && header.entry.is_none() {
+            return Err("the header has an address tag but no entry address tag".into());
+        }
+        Ok(header)
+    }
+
+    /// Load layout given by the header, `None` if the kernel is an ELF image to load as such.
+    pub fn layout(&self) -> Option<ElfImage> {
+        Some(ElfImage {
+            entry: self.entry?,
+            segments: vec![self.layout.clone()?],
+        })
+    }
+
+    /// Value of `eax` at the kernel entry point.
+    pub fn boot_magic(&self) -> u32 {
+        match self.version {
+            Version::V1 => MB1_BOOT_MAGIC,
+            Version::V2 => MB2_BOOT_MAGIC,
+        }
+    }
+
+    /// Builds the boot information to be placed at [`BOOT_INFO_GPA`], with the memory map
+    /// derived from the guest RAM regions `ram` and `module` as `(start, end, name)`.
+    pub fn boot_info(
+        &self,
+        cmdline: &str,
+        module: Option<(usize, usize, &str)>,
+        ram: &[(usize, usize)],
+    ) -> Result<Vec<u8>, String> {
+        let mmap = e820_map(ram);
+        let kib_at = |addr: usize, limit: usize| {
+            mmap.iter()
+                .find(|&&(start, _, ty)| start == addr && ty == E820_RAM)
+                .map_or(0, |&(_, size, _)| (size.min(limit) / 1024) as u32)
+        };
+        let (mem_lower, mem_upper) = (
+            kib_at(0, 0xa_0000),
+            kib_at(0x10_0000, u32::MAX as usize * 1024),
+        );
+
+        let info = match self.version {
+            Version::V1 => {
+                let mut info = vec![0u8; MB1_INFO_SIZE];
+                let mut flags = 1 << 0 | 1 << 3 | 1 << 6 | 1 << 9;
+                write_u32(&mut info, 4, mem_lower);
+                write_u32(&mut info, 8, mem_upper);
+
+                let mmap_addr = BOOT_INFO_GPA + info.len();
+                for &(addr, size, ty) in &mmap {
+                    push_u32(&mut info, (MMAP_ENTRY_SIZE - 4) as u32);
+                    push_u64(&mut info, addr as u64);
+                    push_u64(&mut info, size as u64);
+                    push_u32(&mut info, ty);
+                }
+                write_u32(&mut info, 44, (mmap.len() * MMAP_ENTRY_SIZE) as u32);
+                write_u32(&mut info, 48, mmap_addr as u32);
+
+                let name_addr = BOOT_INFO_GPA + info.len();
+                push_str(&mut info, BOOT_LOADER_NAME);
+                write_u32(&mut info, 64, name_addr as u32);
+                if !cmdline.is_empty() {
+                    flags |= 1 << 2;
+                    let cmdline_addr = BOOT_INFO_GPA + info.len();
+                    write_u32(&mut info, 16, cmdline_addr as u32);
+                    push_str(&mut info, cmdline);
+                }
+                if let Some((start, end, name)) = module {
+                    let name_addr = BOOT_INFO_GPA + info.len();
+                    push_str(&mut info, name);
+                    info.resize(info.len().next_multiple_of(4), 0);
+                    write_u32(&mut info, 20, 1);
+                    let mods_addr = BOOT_INFO_GPA + info.len();
+                    write_u32(&mut info, 24, mods_addr as u32);
+                    for value in [start, end, name_addr, 0] {
+                        push_u32(&mut info, value as u32);
+                    }
+                }
+                write_u32(&mut info, 0, flags);
+                info
+            }
+            Version::V2 => {
+                let mut info = vec![0u8; 8];
+                push_tag(&mut info, info_tag::BOOT_LOADER_NAME, |info| {
+                    push_str(info, BOOT_LOADER_NAME)
+                });
+                push_tag(&mut info, info_tag::CMDLINE, |info| push_str(info, cmdline));
+                push_tag(&mut info, info_tag::BASIC_MEMINFO, |info| {
+                    push_u32(info, mem_lower);
+                    push_u32(info, mem_upper);
+                });
+                push_tag(&mut info, info_tag::MMAP, |info| {
+                    push_u32(info, MMAP_ENTRY_SIZE as u32);
+                    push_u32(info, 0);
+                    for &(addr, size, ty) in &mmap {
+                        push_u64(info, addr as u64);
+                        push_u64(info, size as u64);
+                        push_u32(info, ty);
+                        push_u32(info, 0);
+                    }
+                });
+                if let Some((start, end, name)) = module {
+                    push_tag(&mut info, info_tag::MODULE, |info| {
+                        push_u32(info, start as u32);
+                        push_u32(info, end as u32);
+                        push_str(info, name);
+                    });
+                }
+                push_tag(&mut info, info_tag::END, |_| {});
+                let total_size = info.len() as u32;
+                write_u32(&mut info, 0, total_size);
+                info
+            }
+        };
+
+        if BOOT_INFO_GPA + info.len() > BOOT_INFO_END {
+            return Err(format!(
+                "the boot information of {:#x} bytes does not fit below {BOOT_INFO_END:#x}",
+                info.len()
+            ));
+        }
+        Ok(info)
+    }
+}
+
+/// The load layout given by the `header_addr`, `load_addr`, `load_end_addr` and `bss_end_addr`
+/// fields of the header found at `header_offset` in the image.
+fn address_layout(
+    header_offset: usize,
+    [header_addr, load_addr, load_end_addr, bss_end_addr]: [usize; 4],
+    image_size: usize,
+) -> Result<Segment, String> {
+    let offset = header_addr
+        .checked_sub(load_addr)
+        .and_then(|before| header_offset.checked_sub(before))
+        .ok_or("the header load address is above the header")?;
+    let file_size = match load_end_addr {
+        0 => image_size.saturating_sub(offset),
+        end => end
+            .checked_sub(load_addr)
+            .ok_or("the header load end address is below the load address")?,
+    };
+    let mem_size = match bss_end_addr {
+        0 => file_size,
+        end => end.saturating_sub(load_addr).max(file_size),
+    };
+    Ok(Segment {
+        paddr: load_addr,
+        offset,
+        file_size,
+        mem_size,
+    })
+}
+
+/// The trampoline run by the BSP, to be placed at [`TRAMPOLINE_GPA`], jumping to `entry` with
+/// `magic` in `eax` and `info` in `ebx`.
+pub fn trampoline(entry: usize, magic: u32, info: usize) -> Vec<u8> {
+    let (start, end): (*const u8, *const u8);
+    // SAFETY: only takes the addresses of the symbols delimiting the trampoline in `.rodata`.
+    let code = unsafe {
+        core::arch::asm!(
+            "lea {start}, [rip + axvisor_multiboot_trampoline_start]",
+            "lea {end}, [rip + axvisor_multiboot_trampoline_end]",
+            start = out(reg) start,
+            end = out(reg) end,
+            options(pure, nomem, nostack),
+        );
+        core::slice::from_raw_parts(start, end.offset_from(start) as usize)
+    };
+
+    // The trampoline ends with its arguments: entry point, magic and boot information address.
+    let mut code = code.to_vec();
+    let args = code.len() - 12;
+    write_u32(&mut code, args, entry as u32);
+    write_u32(&mut code, args + 4, magic);
+    write_u32(&mut code, args + 8, info as u32);
+    code
+}
+
+// Enters 32-bit protected mode from real mode with flat segments and jumps to the kernel.
+core::arch::global_asm!(
+    r#"
+    .pushsection .rodata.axvisor_multiboot_trampoline, "a"
+    .balign 16
+    .global axvisor_multiboot_trampoline_start
+    .global axvisor_multiboot_trampoline_end
+    .code16
+axvisor_multiboot_trampoline_start:
+    cli
+    xor ax, ax
+    mov ds, ax
+    lgdt [{trampoline} + .Lmb_gdt_ptr_offset]
+    mov eax, cr0
+    or eax, 1                       // CR0.PE
+    mov cr0, eax
+    .byte 0x66, 0xea                // ljmpl 0x08, .Lmb_protected_mode
+    .4byte {trampoline} + .Lmb_protected_mode - axvisor_multiboot_trampoline_start
+    .2byte 0x08
+    .code32
+.Lmb_protected_mode:
+    mov ax, 0x10
+    mov ds, ax
+    mov es, ax
+    mov fs, ax
+    mov gs, ax
+    mov ss, ax
+    mov eax, dword ptr [{trampoline} + .Lmb_magic_offset]
+    mov ebx, dword ptr [{trampoline} + .Lmb_info_offset]
+    jmp dword ptr [{trampoline} + .Lmb_entry_offset]
+
+    .balign 8
+.Lmb_gdt:
+    .quad 0
+    .quad 0x00cf9a000000ffff        // 0x08, flat 32-bit code
+    .quad 0x00cf92000000ffff        // 0x10, flat data
+.Lmb_gdt_ptr:
+    .2byte .Lmb_gdt_ptr - .Lmb_gdt - 1
+    .4byte {trampoline} + .Lmb_gdt - axvisor_multiboot_trampoline_start
+
+    .balign 4
+.Lmb_entry:
+    .4byte 0
+.Lmb_magic:
+    .4byte 0
+.Lmb_info:
+    .4byte 0
+axvisor_multiboot_trampoline_end:
+    .set .Lmb_gdt_ptr_offset, .Lmb_gdt_ptr - axvisor_multiboot_trampoline_start
+    .set .Lmb_entry_offset, .Lmb_entry - axvisor_multiboot_trampoline_start
+    .set .Lmb_magic_offset, .Lmb_magic - axvisor_multiboot_trampoline_start
+    .set .Lmb_info_offset, .Lmb_info - axvisor_multiboot_trampoline_start
+    .code64
+    .popsection
+"#,
+    trampoline = const TRAMPOLINE_GPA,
+);
+
+fn read_u32(data: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(
+        data.get(offset..offset.checked_add(4)?)?.try_into().ok()?,
+    ))
+}
+
+fn write_u32(data: &mut [u8], offset: usize, value: u32) {
+    data[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+/// Appends a Multiboot2 boot information tag, 8 bytes aligned.
+fn push_tag(info: &mut Vec<u8>, ty: u32, body: impl FnOnce(&mut Vec<u8>)) {
+    let start = info.len();
+    push_u32(info, ty);
+    push_u32(info, 0);
+    body(info);
+    let size = info.len() - start;
+    write_u32(info, start + 4, size as u32);
+    info.resize(info.len().next_multiple_of(8), 0);
+}
+
+fn push_u32(data: &mut Vec<u8>, value: u32) {
+    data.extend_from_slice(&value.to_le_bytes());
+}
+
+fn push_u64(data: &mut Vec<u8>, value: u64) {
+    data.extend_from_slice(&value.to_le_bytes());
+}
+
+fn push_str(data: &mut Vec<u8>, value: &str) {
+    data.extend_from_slice(value.as_bytes());
+    data.push(0);
+}
@@ -0,0 +1,528 @@
+// Copyright 2025 The Axvisor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! U-Boot FIT images and legacy uImages.
+//!
+//! A FIT image is a flattened device tree holding images in `/images` and the combinations of
+//! them that can be booted in `/configurations`. One configuration is selected, by name or the
+//! default one, and its kernel, FDT and ramdisk are checked against the `hash` nodes of their
+//! images and placed at their `load` addresses. Image data is either embedded (`data`) or stored
+//! after the device tree (`data-offset` or `data-position` with `data-size`, see `mkimage -E`).
+//! Signatures are not checked.
+//!
+//! A legacy uImage is a 64-byte header followed by the data, checked by the CRC32 of both.
+//! Multi-file uImages hold a kernel, a ramdisk and an FDT, in this order.
+
+use alloc::{
+    string::{String, ToString},
+    vec::Vec,
+};
+
+use fdt_parser::Fdt;
+use sha2::Digest;
+
+use super::Compression;
+
+/// Number of bytes needed by [`UbootImage::is_uboot_image`].
+pub const MAGIC_SIZE: usize = 4;
+/// Number of bytes needed by [`UbootImage::header_size`].
+pub const HEADER_SIZE_PEEK: usize = 8;
+
+const FDT_MAGIC: u32 = 0xd00d_feed;
+const UIMAGE_MAGIC: u32 = 0x2705_1956;
+const UIMAGE_HEADER_SIZE: usize = 64;
+
+// uImage types, `IH_TYPE_*`.
+const UIMAGE_KERNEL: u8 = 2;
+const UIMAGE_MULTI: u8 = 4;
+const UIMAGE_KERNEL_NOLOAD: u8 = 14;
+
+/// FIT `arch` and uImage `IH_ARCH_*` of the guests.
+#[cfg(target_arch = "aarch64")]
+const ARCH: Option<(&str, u8)> = Some(("arm64", 22));
+#[cfg(target_arch = "riscv64")]
+const ARCH: Option<(&str, u8)> = Some(("riscv", 26));
+#[cfg(target_arch = "x86_64")]
+const ARCH: Option<(&str, u8)> = Some(("x86_64", 24));
+#[cfg(not(any(
+    target_arch = "aarch64",
+    target_arch = "riscv64",
+    target_arch = "x86_64"
+)))]
+const ARCH: Option<(&str, u8)> = None;
+
+/// Role of an image in a FIT configuration or a uImage.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ComponentKind {
+    Kernel,
+    Fdt,
+    Ramdisk,
+}
+
+/// A kernel, FDT or ramdisk of a FIT configuration or a uImage.
+pub struct Component<'a> {
+    pub kind: ComponentKind,
+    /// Name of the FIT image node or of the uImage.
+    pub name: String,
+    /// The data as stored, compressed if `compression` is set. Empty for components of an image
+    /// parsed by [`UbootImage::parse_header`].
+    pub data: &'a [u8],
+    /// Size of the data as stored.
+    pub size: usize,
+    pub compression: Option<Compression>,
+    /// Address to load the component at, the one of the VM config is used otherwise.
+    pub load: Option<usize>,
+    /// Entry point of a kernel.
+    pub entry: Option<usize>,
+    hashes: Vec<Hash<'a>>,
+}
+
+/// An expected hash of the data of a component.
+struct Hash<'a> {
+    algo: String,
+    value: &'a [u8],
+    /// The data hashed, the one of the component but for multi-file uImages.
+    data: &'a [u8],
+}
+
+/// A FIT image with a selected configuration, or a uImage.
+pub struct UbootImage<'a> {
+    /// What is booted, e.g. `FIT configuration "conf-1"`.
+    pub description: String,
+    pub components: Vec<Component<'a>>,
+}
+
+impl<'a> UbootImage<'a> {
+    /// Returns whether an image starting with `header` is a FIT image or a uImage.
+    pub fn is_uboot_image(header: &[u8]) -> bool {
+        matches!(be32(header, 0), Some(FDT_MAGIC | UIMAGE_MAGIC))
+    }
+
+    /// Returns the number of bytes from the start of an image that [`UbootImage::parse_header`]
+    /// needs, given its first [`HEADER_SIZE_PEEK`] bytes: the device tree of a FIT image, or the
+    /// header of a uImage followed by the largest size table of a multi-file uImage.
+    pub fn header_size(head: &[u8]) -> Option<usize> {
+        match be32(head, 0)? {
+            FDT_MAGIC => be32(head, 4).map(|size| size as usize),
+            UIMAGE_MAGIC => Some(UIMAGE_HEADER_SIZE + 4 * 4),
+            _ => None,
+        }
+    }
+
+    /// Parses a FIT image, selecting the configuration `config` or the default one, or a uImage.
+    pub fn parse(image: &'a [u8], config: Option<&str>) -> Result<Self, String> {
+        Self::parse_image(image, config, false)
+    }
+
+    /// Same as [`UbootImage::parse`], but only needs the first [`UbootImage::header_size`] bytes
+    /// of the image. The components have no data and are not checked against the image size.
+    pub fn parse_header(head: &'a [u8], config: Option<&str>) -> Result<Self, String> {
+        Self::parse_image(head, config, true)
+    }
+
+    fn parse_image(
+        image: &'a [u8],
+        config: Option<&str>,
+        header_only: bool,
+    ) -> Result<Self, String> {
+        match be32(image, 0) {
+            Some(FDT_MAGIC) => parse_fit(image, config, header_only),
+            Some(UIMAGE_MAGIC) if config.is_none() => parse_uimage(image, header_only),
+            Some(UIMAGE_MAGIC) => Err("a configuration is selected, but this is a uImage".into()),
+            _ => Err("neither a FIT image nor a uImage".into()),
+        }
+    }
+
+    /// Returns the component of the given kind.
+    pub fn component(&self, kind: ComponentKind) -> Option<&Component<'a>> {
+        self.components
+            .iter()
+            .find(|component| component.kind == kind)
+    }
+}
+
+impl Component<'_> {
+    /// Checks the data against the hashes of the component.
+    pub fn verify(&self) -> Result<(), String> {
+        for hash in &self.hashes {
+            if digest(&hash.algo, hash.data)? != hash.value {
+                return Err(format!("{} hash mismatch", hash.algo));
+            }
+            debug!("{}: {} hash verified", self.name, hash.algo);
+        }
+        Ok(())
+    }
+
+    /// Returns the data, decompressed if it is compressed.
+    pub fn decompressed(&self) -> Result<Vec<u8>, String> {
+        let Some(compression) = self.compression else {
+            return Ok(self.data.to_vec());
+        };
+        let mut rest = self.data;
+        let mut data = Vec::new();
+        compression.decompress(&mut |buf| super::read_slice(&mut rest, buf), &mut |chunk| {
+            data.extend_from_slice(chunk);
+            Ok(())
+        })?;
+        Ok(data)
+    }
+}
+
+/// A node of a FIT image with its path below the root and its properties.
+struct FitNode<'a> {
+    path: Vec<String>,
+    props: Vec<(String, &'a [u8])>,
+}
+
+impl<'a> FitNode<'a> {
+    fn is(&self, path: &[&str]) -> bool {
+        self.path
+            .iter()
+            .map(String::as_str)
+            .eq(path.iter().copied())
+    }
+
+    fn prop(&self, name: &str) -> Option<&'a [u8]> {
+        self.props
+            .iter()
+            .find(|(prop, _)| prop == name)
+            .map(|(_, value)| *value)
+    }
+
+    fn strs(&self, name: &str) -> impl Iterator<Item = &'a str> {
+        self.prop(name)
+            .and_then(|value| core::str::from_utf8(value).ok())
+            .unwrap_or_default()
+            .split('\0')
+            .filter(|s| !s.is_empty())
+    }
+
+    fn str(&self, name: &str) -> Option<&'a str> {
+        self.strs(name).next()
+    }
+
+    /// Reads a one or two cell number.
+    fn cells(&self, name: &str) -> Option<usize> {
+        let value = self.prop(name)?;
+        match value.len() {
+            4 => be32(value, 0).map(|n| n as usize),
+            8 => Some(u64::from_be_bytes(value.try_into().ok()?) as usize),
+            _ => None,
+        }
+    }
+}
+
+fn parse_fit<'a>(
+    image: &'a [u8],
+    config: Option<&str>,
+    header_only: bool,
+) -> Result<UbootImage<'a>, String> {
+    let fdt = Fdt::from_bytes(image).map_err(|err| format!("invalid FIT image: {err:?}"))?;
+    let mut nodes = Vec::new();
+    let mut path: Vec<String> = Vec::new();
+    let mut root_level = None;
+    for node in fdt.all_nodes() {
+        let root_level = *root_level.get_or_insert(node.level);
+        path.truncate(node.level.saturating_sub(root_level + 1));
+        if node.level > root_level {
+            path.push(node.name().to_string());
+        }
+        nodes.push(FitNode {
+            path: path.clone(),
+            props: node
+                .propertys()
+                .map(|prop| (prop.name.to_string(), prop.raw_value()))
+                .collect(),
+        });
+    }
+    let find = |path: &[&str]| nodes.iter().find(|node| node.is(path));
+
+    let configs = find(&["configurations"]).ok_or("FIT image without /configurations")?;
+    let name = match config {
+        Some(name) => name,
+        None => configs
+            .str("default")
+            .ok_or("the FIT image has no default configuration, select one")?,
+    };
+    let Some(conf) = find(&["configurations", name]) else {
+        let available: Vec<_> = nodes
+            .iter()
+            .filter(|node| node.path.len() == 2 && node.path[0] == "configurations")
+            .map(|node| node.path[1].as_str())
+            .collect();
+        return Err(format!(
+            "no FIT configuration {name:?}, available: {}",
+            available.join(", ")
+        ));
+    };
+
+    // External data is stored after the device tree, aligned to 4 bytes.
+    let data_base = be32(image, 4).ok_or("truncated FIT image")? as usize;
+    let data_base = data_base.next_multiple_of(4);
+
+    let mut components = Vec::new();
+    for (prop, kind) in [
+        ("kernel", ComponentKind::Kernel),
+        ("fdt", ComponentKind::Fdt),
+        ("ramdisk", ComponentKind::Ramdisk),
+    ] {
+        let mut images = conf.strs(prop);
+        let Some(image_name) = images.next() else {
+            continue;
+        };
+        if images.next().is_some() {
+            return Err(format!(
+                "FIT configuration {name:?} has several {prop} images, overlays are not supported"
+            ));
+        }
+        let node = find(&["images", image_name])
+            .ok_or_else(|| format!("FIT image {image_name:?} not found"))?;
+        components.push(
+            fit_component(image, data_base, &nodes, node, kind, header_only)
+                .map_err(|msg| format!("FIT image {image_name:?}: {msg}"))?,
+        );
+    }
+    if !components.iter().any(|c| c.kind == ComponentKind::Kernel) {
+        return Err(format!("FIT configuration {name:?} has no kernel"));
+    }
+    if conf.prop("loadables").is_some() {
+        warn!("FIT configuration {name:?}: loadables are not loaded");
+    }
+
+    Ok(UbootImage {
+        description: format!("FIT configuration {name:?}"),
+        components,
+    })
+}
+
+fn fit_component<'a>(
+    image: &'a [u8],
+    data_base: usize,
+    nodes: &[FitNode<'a>],
+    node: &FitNode<'a>,
+    kind: ComponentKind,
+    header_only: bool,
+) -> Result<Component<'a>, String> {
+    let (data, size) = match node.prop("data") {
+        Some(data) => (data, data.len()),
+        None => {
+            let size = node.cells("data-size").ok_or("no data")?;
+            let start = node
+                .cells("data-position")
+                .or_else(|| node.cells("data-offset").map(|offset| data_base + offset))
+                .ok_or("no data")?;
+            let end = start
+                .checked_add(size)
+                .ok_or("external data beyond the end of the file")?;
+            if header_only {
+                (&[][..], size)
+            } else {
+                let data = image
+                    .get(start..end)
+                    .ok_or("external data beyond the end of the file")?;
+                (data, size)
+            }
+        }
+    };
+
+    let ty = node.str("type").unwrap_or_default();
+    let expected = match kind {
+        ComponentKind::Kernel => ["kernel", "kernel_noload"].as_slice(),
+        ComponentKind::Fdt => &["flat_dt"],
+        ComponentKind::Ramdisk => &["ramdisk"],
+    };
+    if !expected.contains(&ty) {
+        return Err(format!("type {ty:?} cannot be used as {kind:?}"));
+    }
+    if let Some(arch) = node.str("arch")
+        && let Some((guest_arch, _)) = ARCH
+        && kind != ComponentKind::Fdt
+        && arch != guest_arch
+    {
+        return Err(format!("built for {arch}, not {guest_arch}"));
+    }
+
+    let compression = match node.str("compression").unwrap_or("none") {
+        "none" => None,
+        "gzip" => Some(Compression::Gzip),
+        "zstd" => Some(Compression::Zstd),
+        "lz4" => Some(Compression::Lz4),
+        other => return Err(format!("unsupported compression {other:?}")),
+    };
+
+    let mut hashes = Vec::new();
+    for hash in nodes.iter().filter(|hash| {
+        hash.path.len() == 3 && hash.path[..2] == node.path[..] && hash.path[2].starts_with("hash")
+    }) {
+        hashes.push(Hash {
+            algo: hash.str("algo").ok_or("hash node without algo")?.into(),
+            value: hash.prop("value").ok_or("hash node without value")?,
+            data,
+        });
+    }
+
+    // `kernel_noload` kernels run wherever they are loaded.
+    let noload = ty == "kernel_noload";
+    Ok(Component {
+        kind,
+        name: node.path[1].clone(),
+        data,
+        size,
+        compression,
+        load: node.cells("load").filter(|_| !noload),
+        entry: node.cells("entry").filter(|_| !noload),
+        hashes,
+    })
+}
+
+fn parse_uimage<'a>(image: &'a [u8], header_only: bool) -> Result<UbootImage<'a>, String> {
+    let header = image
+        .get(..UIMAGE_HEADER_SIZE)
+        .ok_or("truncated uImage header")?;
+    let mut unchecked = header.to_vec();
+    unchecked[4..8].fill(0);
+    if Some(crc32fast::hash(&unchecked)) != be32(header, 4) {
+        return Err("uImage header CRC mismatch".into());
+    }
+
+    let field = |offset| be32(header, offset).unwrap_or_default() as usize;
+    let (size, load, entry) = (field(12), field(16), field(20));
+    let (arch, ty, comp) = (header[29], header[30], header[31]);
+    let name = core::str::from_utf8(&header[32..])
+        .unwrap_or_default()
+        .trim_end_matches('\0')
+        .to_string();
+    // Only the size table of a multi-file uImage is known without the data.
+    let data = if header_only {
+        image.get(UIMAGE_HEADER_SIZE..).unwrap_or_default()
+    } else {
+        UIMAGE_HEADER_SIZE
+            .checked_add(size)
+            .and_then(|end| image.get(UIMAGE_HEADER_SIZE..end))
+            .ok_or("truncated uImage data")?
+    };
+
+    if let Some((guest_arch, id)) = ARCH
+        && arch != id
+    {
+        return Err(format!("uImage architecture {arch} is not {guest_arch}"));
+    }
+    let compression = match comp {
+        0 => None,
+        1 => Some(Compression::Gzip),
+        5 => Some(Compression::Lz4),
+        6 => Some(Compression::Zstd),
+        _ => return Err(format!("unsupported uImage compression {comp}")),
+    };
+    let hash = Hash {
+        algo: "crc32".into(),
+        value: &header[24..28],
+        data,
+    };
+    let kernel = |data: &'a [u8], size, noload: bool| Component {
+        kind: ComponentKind::Kernel,
+        name: name.clone(),
+        data,
+        size,
+        compression,
+        load: (!noload).then_some(load),
+        entry: (!noload).then_some(entry),
+        hashes: Vec::new(),
+    };
+
+    let mut components = match ty {
+        UIMAGE_KERNEL | UIMAGE_KERNEL_NOLOAD if header_only => {
+            vec![kernel(&[], size, ty == UIMAGE_KERNEL_NOLOAD)]
+        }
+        UIMAGE_KERNEL | UIMAGE_KERNEL_NOLOAD => {
+            vec![kernel(data, size, ty == UIMAGE_KERNEL_NOLOAD)]
+        }
+        UIMAGE_MULTI => {
+            // The sizes of the images come first, ended by a zero, then the images, each padded
+            // to 4 bytes.
+            let mut sizes = Vec::new();
+            let mut pos = 0;
+            loop {
+                let size = be32(data, pos).ok_or("truncated multi-file uImage")? as usize;
+                pos += 4;
+                if size == 0 {
+                    break;
+                }
+                sizes.push(size);
+            }
+            if sizes.is_empty() || sizes.len() > 3 {
+                return Err(format!("multi-file uImage with {} images", sizes.len()));
+            }
+            let mut components = Vec::new();
+            for (index, size) in sizes.into_iter().enumerate() {
+                let part = if header_only {
+                    &[][..]
+                } else {
+                    pos.checked_add(size)
+                        .and_then(|end| data.get(pos..end))
+                        .ok_or("truncated multi-file uImage")?
+                };
+                pos = pos.saturating_add(size.next_multiple_of(4));
+                components.push(match index {
+                    0 => kernel(part, size, false),
+                    1 => Component {
+                        kind: ComponentKind::Ramdisk,
+                        name: format!("{name} ramdisk"),
+                        data: part,
+                        size,
+                        compression: None,
+                        load: None,
+                        entry: None,
+                        hashes: Vec::new(),
+                    },
+                    _ => Component {
+                        kind: ComponentKind::Fdt,
+                        name: format!("{name} FDT"),
+                        data: part,
+                        size,
+                        compression: None,
+                        load: None,
+                        entry: None,
+                        hashes: Vec::new(),
+                    },
+                });
+            }
+            components
+        }
+        _ => return Err(format!("uImage of type {ty} cannot be booted")),
+    };
+    // The CRC covers all the images of a multi-file uImage, it is checked with the kernel.
+    components[0].hashes.push(hash);
+
+    Ok(UbootImage {
+        description: format!("uImage {name:?}"),
+        components,
+    })
+}
+
+fn digest(algo: &str, data: &[u8]) -> Result<Vec<u8>, String> {
+    Ok(match algo {
+        "crc32" => crc32fast::hash(data).to_be_bytes().to_vec(),
+        "sha1" => sha1::Sha1::digest(data).to_vec(),
+        "sha256" => sha2::Sha256::digest(data).to_vec(),
+        "sha384" => sha2::Sha384::digest(data).to_vec(),
+        "sha512" => sha2::Sha512::digest(data).to_vec(),
+        _ => return Err(format!("unsupported hash algorithm {algo:?}")),
+    })
+}
+
+fn be32(data: &[u8], offset: usize) -> Option<u32> {
+    let bytes = data.get(offset..offset.checked_add(4)?)?;
+    Some(u32::from_be_bytes(bytes.try_into().ok()?))
+}
@@ -21,6 +21,7 @@ use std::sync::Mutex;
 
 use axaddrspace::{GuestPhysAddr, HostPhysAddr};
 use axerrno::AxResult;
+use memory_addr::PAGE_SIZE_4K;
 use page_table_multiarch::PagingHandler;
 
 /// A global btree map to store IVC channels,
@@ -228,11 +229,12 @@ impl<H: PagingHandler> core::fmt::Debug for IVCChannel<H> {
 
 impl<H: PagingHandler> Drop for IVCChannel<H> {
     fn drop(&mut self) {
-        // Free the shared region frame when the channel is dropped.
+        // Zero and free the shared region frame when the channel is dropped.
         debug!(
             "Dropping IVCChannel for VM[{}], shared region base: {:?}",
             self.publisher_vm_id, self.shared_region_base
         );
+        zero_frame::<H>(self.shared_region_base);
         H::dealloc_frame(self.shared_region_base);
     }
 }
@@ -249,6 +251,8 @@ impl<H: PagingHandler> IVCChannel<H> {
         let shared_region_base = H::alloc_frame().ok_or_else(|| {
             axerrno::ax_err_type!(NoMemory, "Failed to allocate shared region frame")
         })?;
+        // Frames are not zeroed by the allocator.
+        zero_frame::<H>(shared_region_base);
 
         let mut channel = IVCChannel {
             publisher_vm_id,
@@ -297,3 +301,8 @@ impl<H: PagingHandler> IVCChannel<H> {
             .collect()
     }
 }
+
+fn zero_frame<H: PagingHandler>(paddr: HostPhysAddr) {
+    // SAFETY: the frame is owned by the channel and mapped in the linear mapping.
+    unsafe { core::ptr::write_bytes(H::phys_to_virt(paddr).as_mut_ptr(), 0, PAGE_SIZE_4K) };
+}
@@ -0,0 +1,216 @@
+// Copyright 2025 The Axvisor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! VM lifecycle operations shared by the shell and the emulated devices.
+//!
+//! Operations requested from a vCPU or timer context (e.g. by a watchdog) must not block until the
+//! VM stops, so they are carried out by a background task.
+
+use core::time::Duration;
+use std::thread;
+
+use axerrno::AxResult;
+use axvm::VMStatus;
+
+use crate::vmm::{VMRef, add_running_vm_count, config, emu, vcpus, vm_list};
+
+/// Maximum time to wait for a VM to stop before giving up.
+const STOP_TIMEOUT: Duration = Duration::from_secs(5);
+/// Interval between two checks of the VM status while waiting for it to stop.
+const STOP_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Sets up the primary vCPU of the VM and boots it.
+///
+/// The caller is responsible for checking that the VM is in `Loaded` or `Stopped` state.
+pub fn start_vm(vm: VMRef) -> Result<(), &'static str> {
+    let vm_id = vm.id();
+
+    vcpus::setup_vm_primary_vcpu(vm.clone());
+
+    match vm.boot() {
+        Ok(_) => {
+            // The VCpu task is created directly in the wait queue (blocked state),
+            // so it can be notified immediately.
+            vcpus::notify_primary_vcpu(vm_id);
+            add_running_vm_count(1);
+            Ok(())
+        }
+        Err(err) => {
+            error!("Failed to boot VM[{}]: {:?}", vm_id, err);
+            Err("Failed to boot VM")
+        }
+    }
+}
+
+/// Sets a `Stopped` VM up again before it is restarted, so that it boots from freshly loaded
+/// images instead of the memory left by its previous run, which is zeroed in place. State kept
+/// by VM ID, such as the RTC offset and the event log, is preserved, the measured boot log is
+/// replaced by the one of the images loaded again. VMs in other states are left as they are.
+///
+/// On failure the VM stays `Stopped` and can be deleted.
+pub fn reset_vm(vm_id: usize) -> AxResult {
+    let Some(vm) = vm_list::get_vm_by_id(vm_id) else {
+        return Ok(());
+    };
+    if !matches!(vm.vm_status(), VMStatus::Stopped) {
+        return Ok(());
+    }
+    // The vCPU tasks of the previous run have exited, new ones are set up by `start_vm`.
+    vcpus::cleanup_vm_vcpus(vm_id);
+    emu::watchdog::reset(vm_id);
+    config::reload_guest_vm(&vm)
+}
+
+/// Sends the shutdown signal to the VM and wakes up its halted vCPUs, without waiting for them
+/// to exit.
+pub fn request_stop(vm: &VMRef) {
+    if matches!(vm.vm_status(), VMStatus::Stopping | VMStatus::Stopped) {
+        return;
+    }
+    if let Err(err) = vm.shutdown() {
+        warn!("VM[{}] shutdown failed: {:?}", vm.id(), err);
+    }
+    vcpus::notify_all_vcpus(vm.id());
+}
+
+/// Waits until the VM reaches `Stopped` state. Returns `false` on timeout or if the VM is gone.
+pub fn wait_for_stopped(vm_id: usize) -> bool {
+    let mut waited = Duration::ZERO;
+    loop {
+        match vm_list::get_vm_by_id(vm_id).map(|vm| vm.vm_status()) {
+            Some(VMStatus::Stopped) => return true,
+            Some(_) if waited < STOP_TIMEOUT => {
+                thread::sleep(STOP_POLL_INTERVAL);
+                waited += STOP_POLL_INTERVAL;
+            }
+            Some(status) => {
+                warn!("VM[{vm_id}] did not stop in time, status {status:?}");
+                return false;
+            }
+            None => return false,
+        }
+    }
+}
+
+/// Sends the shutdown signal to the VM with the given ID.
+pub fn stop_vm(vm_id: usize) {
+    let Some(vm) = vm_list::get_vm_by_id(vm_id) else {
+        return;
+    };
+    request_stop(&vm);
+}
+
+/// Pauses the VM, leaving its state intact for inspection until `vm resume`.
+///
+/// vCPUs enter the wait queue at their next VM exit.
+pub fn pause_vm(vm_id: usize) {
+    let Some(vm) = vm_list::get_vm_by_id(vm_id) else {
+        return;
+    };
+    if matches!(vm.vm_status(), VMStatus::Running) {
+        vm.set_vm_status(VMStatus::Suspended);
+    }
+}
+
+/// Stops and boots the VM again in a background task.
+pub fn spawn_restart(vm_id: usize) {
+    let Some(vm) = vm_list::get_vm_by_id(vm_id) else {
+        return;
+    };
+    request_stop(&vm);
+    drop(vm);
+
+    thread::spawn(move || {
+        if !wait_for_stopped(vm_id) {
+            error!("VM[{vm_id}] restart aborted, the VM did not stop");
+            return;
+        }
+        if let Err(err) = reset_vm(vm_id) {
+            error!("VM[{vm_id}] restart aborted, it cannot be set up again: {err:?}");
+            return;
+        }
+        let Some(vm) = vm_list::get_vm_by_id(vm_id) else {
+            return;
+        };
+        match start_vm(vm) {
+            Ok(()) => info!("VM[{vm_id}] restarted"),
+            Err(err) => error!("VM[{vm_id}] restart failed: {err}"),
+        }
+    });
+}
+
+/// Stops the VM in a background task, then saves its memory for later inspection.
+///
+/// Memory regions are written to `/guest/snapshots/vm<ID>-<SECS>-<N>.bin` when the `fs`
+/// feature is enabled. Otherwise the VM is only stopped, and its memory stays untouched until it
+/// is deleted.
+pub fn spawn_snapshot_and_stop(vm_id: usize) {
+    let Some(vm) = vm_list::get_vm_by_id(vm_id) else {
+        return;
+    };
+    request_stop(&vm);
+    drop(vm);
+
+    thread::spawn(move || {
+        if !wait_for_stopped(vm_id) {
+            error!("VM[{vm_id}] snapshot aborted, the VM did not stop");
+            return;
+        }
+        if let Some(vm) = vm_list::get_vm_by_id(vm_id) {
+            save_memory_snapshot(&vm);
+        }
+    });
+}
+
+#[cfg(feature = "fs")]
+fn save_memory_snapshot(vm: &VMRef) {
+    use std::fs::{self, File};
+    use std::io::Write;
+    use std::os::arceos::modules::axhal;
+
+    const SNAPSHOT_DIR: &str = "/guest/snapshots";
+
+    if let Err(err) = fs::create_dir(SNAPSHOT_DIR)
+        && fs::metadata(SNAPSHOT_DIR).is_err()
+    {
+        error!("Failed to create {SNAPSHOT_DIR}: {err:?}");
+        return;
+    }
+
+    let secs = axhal::time::wall_time().as_secs();
+    for (index, region) in vm.memory_regions().iter().enumerate() {
+        let path = format!("{SNAPSHOT_DIR}/vm{}-{secs}-{index}.bin", vm.id());
+        // SAFETY: the VM is stopped and keeps the region mapped until it is dropped.
+        let data = unsafe {
+            core::slice::from_raw_parts(region.hva.as_usize() as *const u8, region.size())
+        };
+        match File::create(&path).and_then(|mut file| file.write_all(data)) {
+            Ok(()) => info!(
+                "VM[{}] memory region GPA {:#x} saved to {}",
+                vm.id(),
+                region.gpa,
+                path
+            ),
+            Err(err) => error!("VM[{}] failed to write {}: {:?}", vm.id(), path, err),
+        }
+    }
+}
+
+#[cfg(not(feature = "fs"))]
+fn save_memory_snapshot(vm: &VMRef) {
+    warn!(
+        "VM[{}] snapshots need the \"fs\" feature, the VM is left stopped with its memory intact",
+        vm.id()
+    );
+}
@@ -0,0 +1,180 @@
+// Copyright 2025 The Axvisor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Boot manifest: which VM configs of the filesystem are created at boot, in which order, and
+//! which of them are started.
+//!
+//! ```toml
+//! # /guest/axvisor.toml
+//! fallback = ["rescue.toml"]   # created only if none of the VMs below could be created
+//!
+//! [[vm]]
+//! config = "linux.toml"        # relative to the VM config directory
+//! autostart = true             # default
+//!
+//! [[vm]]
+//! config = "/guest/extra/arceos.toml"
+//! autostart = false            # created in `Loaded` state, start it with `vm start`
+//! ```
+//!
+//! The VM config directory defaults to [`DEFAULT_CONFIG_DIR`] and the manifest to
+//! [`DEFAULT_MANIFEST_PATH`]. Both can be changed at build time with the
+//! `AXVISOR_VM_CONFIG_DIR` and `AXVISOR_BOOT_MANIFEST` environment variables, and at boot with
+//! the `axvisor.config_dir=` and `axvisor.manifest=` options of the hypervisor command line
+//! (the `bootargs` of the host FDT, aarch64 only). The command line takes precedence.
+
+use alloc::{
+    string::{String, ToString},
+    vec::Vec,
+};
+
+use axerrno::{AxResult, ax_err, ax_err_type};
+use toml::{Table, Value};
+
+/// VM config directory used when neither the build config nor the command line sets one.
+pub const DEFAULT_CONFIG_DIR: &str = "/guest/vm_default";
+/// Boot manifest used when neither the build config nor the command line sets one.
+pub const DEFAULT_MANIFEST_PATH: &str = "/guest/axvisor.toml";
+
+/// A VM config listed in the manifest.
+#[derive(Debug, Clone)]
+pub struct ManifestEntry {
+    /// Absolute path of the VM config file.
+    pub config: String,
+    /// Whether the VM is booted once created.
+    pub autostart: bool,
+}
+
+/// The parsed boot manifest.
+#[derive(Debug, Clone, Default)]
+pub struct Manifest {
+    /// VMs to create, in creation and boot order.
+    pub vms: Vec<ManifestEntry>,
+    /// VMs to create, and boot, if none of [`Manifest::vms`] could be created.
+    pub fallback: Vec<ManifestEntry>,
+}
+
+/// Returns the value of the `axvisor.<key>=` option of the hypervisor command line.
+fn cmdline_option(_key: &str) -> Option<String> {
+    #[cfg(target_arch = "aarch64")]
+    {
+        let bootargs = crate::vmm::fdt::host_bootargs()?;
+        bootargs.split_whitespace().find_map(|arg| {
+            arg.strip_prefix("axvisor.")?
+                .strip_prefix(_key)?
+                .strip_prefix('=')
+                .map(String::from)
+        })
+    }
+    #[cfg(not(target_arch = "aarch64"))]
+    None
+}
+
+/// Directory holding the VM configs.
+pub fn config_dir() -> String {
+    cmdline_option("config_dir")
+        .or_else(|| option_env!("AXVISOR_VM_CONFIG_DIR").map(String::from))
+        .unwrap_or_else(|| DEFAULT_CONFIG_DIR.to_string())
+}
+
+/// Path of the boot manifest.
+pub fn manifest_path() -> String {
+    cmdline_option("manifest")
+        .or_else(|| option_env!("AXVISOR_BOOT_MANIFEST").map(String::from))
+        .unwrap_or_else(|| DEFAULT_MANIFEST_PATH.to_string())
+}
+
+/// Parses a boot manifest, relative config paths are resolved against `config_dir`.
+pub fn parse(text: &str, config_dir: &str) -> AxResult<Manifest> {
+    let table: Table = text
+        .parse()
+        .map_err(|err| ax_err_type!(InvalidInput, format!("invalid boot manifest: {err}")))?;
+
+    let mut manifest = Manifest::default();
+    for (key, value) in table.iter() {
+        match (key.as_str(), value) {
+            ("vm", Value::Array(vms)) => {
+                for (index, vm) in vms.iter().enumerate() {
+                    manifest
+                        .vms
+                        .push(parse_entry(vm, config_dir).map_err(|msg| {
+                            ax_err_type!(InvalidInput, format!("vm[{index}]: {msg}"))
+                        })?);
+                }
+            }
+            ("fallback", Value::Array(configs)) => {
+                for (index, config) in configs.iter().enumerate() {
+                    let Some(config) = config.as_str() else {
+                        return ax_err!(
+                            InvalidInput,
+                            format!("fallback[{index}] must be a string")
+                        );
+                    };
+                    manifest.fallback.push(ManifestEntry {
+                        config: resolve(config_dir, config),
+                        autostart: true,
+                    });
+                }
+            }
+            ("vm" | "fallback", _) => {
+                return ax_err!(InvalidInput, format!("{key} must be an array"));
+            }
+            _ => warn!("Unknown key {key:?} in boot manifest, ignored"),
+        }
+    }
+    Ok(manifest)
+}
+
+fn parse_entry(value: &Value, config_dir: &str) -> Result<ManifestEntry, String> {
+    let table = value.as_table().ok_or("must be a table")?;
+    let config = match table.get("config") {
+        Some(Value::String(config)) => resolve(config_dir, config),
+        Some(_) => return Err("config must be a string".into()),
+        None => return Err("missing config".into()),
+    };
+    let autostart = match table.get("autostart") {
+        None => true,
+        Some(value) => value.as_bool().ok_or("autostart must be a boolean")?,
+    };
+    Ok(ManifestEntry { config, autostart })
+}
+
+fn resolve(config_dir: &str, path: &str) -> String {
+    if path.starts_with('/') {
+        path.to_string()
+    } else {
+        format!("{}/{}", config_dir.trim_end_matches('/'), path)
+    }
+}
+
+/// Reads the boot manifest, `None` if there is none or it cannot be used.
+#[cfg(any(feature = "fs", feature = "guest-archive"))]
+pub fn load() -> Option<Manifest> {
+    let path = manifest_path();
+    let text = super::guestfs::read_to_string(&path).ok()?;
+    match parse(&text, &config_dir()) {
+        Ok(manifest) => {
+            info!(
+                "Boot manifest {path}: {} VM(s), {} fallback",
+                manifest.vms.len(),
+                manifest.fallback.len()
+            );
+            Some(manifest)
+        }
+        Err(err) => {
+            error!("Ignoring boot manifest {path}: {err:?}");
+            None
+        }
+    }
+}
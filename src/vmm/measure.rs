@@ -0,0 +1,131 @@
+// Copyright 2025 The Axvisor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Per-VM measured boot log.
+//!
+//! While a VM is created, the SHA-256 digests of its config and of everything loaded into it are
+//! recorded in order and extended into a hash chain, starting from zeros with
+//! `chain = SHA-256(chain || digest)` like a TPM PCR. The log is kept until the VM is deleted,
+//! and started again when the VM is reconfigured or restarted, as its images are loaded again.
+//! It is shown by `vm show --measurements` and read by the guest with the `HVC_READ_MEASUREMENTS`
+//! hypercall, see [`encode`].
+//!
+//! Images are measured as stored, whatever their location: the bytes embedded in AxVisor, of the
+//! file or of the block device range. In particular `dtb` is the digest of the `dtb_path` image
+//! before AxVisor updates it, the FDT the guest boots with is measured as `guest-dtb`.
+
+use alloc::{collections::BTreeMap, string::String, vec::Vec};
+
+use kspin::SpinNoIrq;
+use sha2::{Digest, Sha256};
+
+/// Magic number of an encoded measurement log, "AXML".
+const LOG_MAGIC: &[u8; 4] = b"AXML";
+/// Version of the encoding of the measurement log.
+const LOG_VERSION: u32 = 1;
+/// Size of the name of a measurement in an encoded log, NUL padded.
+const NAME_SIZE: usize = 16;
+
+/// A measured item of a VM.
+#[derive(Debug, Clone)]
+pub struct Measurement {
+    /// What was measured, e.g. `kernel` or `guest-dtb`.
+    pub name: &'static str,
+    /// SHA-256 digest of the item.
+    pub digest: [u8; 32],
+}
+
+/// The measurements of a VM, in the order they were taken.
+#[derive(Debug, Clone, Default)]
+pub struct MeasurementLog {
+    pub measurements: Vec<Measurement>,
+    /// Value of the hash chain after the last measurement.
+    pub chain: [u8; 32],
+}
+
+static VM_MEASUREMENTS: SpinNoIrq<BTreeMap<usize, MeasurementLog>> =
+    SpinNoIrq::new(BTreeMap::new());
+
+/// Starts a new log for the VM, its first measurement is the config it is created from.
+pub fn start(vm_id: usize, config: &str) {
+    VM_MEASUREMENTS
+        .lock()
+        .insert(vm_id, MeasurementLog::default());
+    extend(vm_id, "config", sha256(config.as_bytes()));
+}
+
+/// Records a measurement of the VM and extends its hash chain with it.
+pub fn extend(vm_id: usize, name: &'static str, digest: [u8; 32]) {
+    info!("VM[{vm_id}] measured {name}: {}", hex(&digest));
+    let mut logs = VM_MEASUREMENTS.lock();
+    let log = logs.entry(vm_id).or_default();
+    log.chain = Sha256::new()
+        .chain_update(log.chain)
+        .chain_update(digest)
+        .finalize()
+        .into();
+    log.measurements.push(Measurement { name, digest });
+}
+
+/// Returns the measurement log of the VM, if it has one.
+pub fn log(vm_id: usize) -> Option<MeasurementLog> {
+    VM_MEASUREMENTS.lock().get(&vm_id).cloned()
+}
+
+/// Puts back a log of the VM returned by [`log`].
+pub fn restore(vm_id: usize, log: MeasurementLog) {
+    VM_MEASUREMENTS.lock().insert(vm_id, log);
+}
+
+/// Forgets the measurements of the VM, called when it is deleted.
+pub fn clear(vm_id: usize) {
+    VM_MEASUREMENTS.lock().remove(&vm_id);
+}
+
+/// Returns the SHA-256 digest of `data`.
+pub fn sha256(data: &[u8]) -> [u8; 32] {
+    Sha256::digest(data).into()
+}
+
+/// Formats a digest as lowercase hexadecimal digits.
+pub fn hex(digest: &[u8]) -> String {
+    digest.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+/// Encodes a measurement log for the guest, all integers are little-endian:
+///
+/// | Offset | Size | Content                                                 |
+/// |--------|------|---------------------------------------------------------|
+/// | 0      | 4    | magic, `AXML`                                           |
+/// | 4      | 4    | version, 1                                              |
+/// | 8      | 4    | number of measurements `N`                              |
+/// | 12     | 4    | reserved, 0                                             |
+/// | 16     | 32   | final value of the hash chain                           |
+/// | 48     | 48*N | measurements: NUL padded name (16), SHA-256 digest (32) |
+pub fn encode(log: &MeasurementLog) -> Vec<u8> {
+    let mut data = Vec::with_capacity(48 + log.measurements.len() * (NAME_SIZE + 32));
+    data.extend_from_slice(LOG_MAGIC);
+    data.extend_from_slice(&LOG_VERSION.to_le_bytes());
+    data.extend_from_slice(&(log.measurements.len() as u32).to_le_bytes());
+    data.extend_from_slice(&0u32.to_le_bytes());
+    data.extend_from_slice(&log.chain);
+    for measurement in &log.measurements {
+        let mut name = [0u8; NAME_SIZE];
+        let len = measurement.name.len().min(NAME_SIZE);
+        name[..len].copy_from_slice(&measurement.name.as_bytes()[..len]);
+        data.extend_from_slice(&name);
+        data.extend_from_slice(&measurement.digest);
+    }
+    data
+}
@@ -16,14 +16,31 @@ mod hvc;
 mod ivc;
 
 pub mod config;
+pub mod emu;
+pub mod events;
+pub mod export;
+pub mod ext_config;
+#[cfg(any(feature = "fs", feature = "guest-archive"))]
+pub mod guestfs;
 pub mod images;
+pub mod lifecycle;
+#[cfg(any(feature = "fs", feature = "guest-archive"))]
+pub mod manifest;
+pub mod measure;
+#[cfg(any(feature = "fs", feature = "guest-archive"))]
+pub mod overlay;
+pub mod reconfig;
+pub mod scrub;
+pub mod shm;
 pub mod timer;
+pub mod validate;
 pub mod vcpus;
 pub mod vm_list;
 
 #[cfg(target_arch = "aarch64")]
 pub mod fdt;
 
+use alloc::vec::Vec;
 use core::sync::atomic::{AtomicUsize, Ordering};
 use std::os::arceos::{
     api::task::{self, AxWaitQueueHandle},
@@ -31,6 +48,7 @@ use std::os::arceos::{
 };
 
 use axerrno::{AxResult, ax_err_type};
+use kspin::SpinNoIrq;
 
 use crate::{
     hal::{AxVCpuHalImpl, AxVMHalImpl},
@@ -50,25 +68,33 @@ static VMM: AxWaitQueueHandle = AxWaitQueueHandle::new();
 /// The number of running VMs. This is used to determine when to exit the VMM.
 static RUNNING_VM_COUNT: AtomicUsize = AtomicUsize::new(0);
 
+/// The VMs booted by [`start`], in boot order.
+static AUTOSTART_VMS: SpinNoIrq<Vec<usize>> = SpinNoIrq::new(Vec::new());
+
 /// Initialize the VMM.
 ///
-/// This function creates the VM structures and sets up the primary VCpu for each VM.
+/// This function creates the VM structures and sets up the primary VCpu for each VM to be
+/// booted. The other VMs stay in `Loaded` state until started from the shell.
 pub fn init() {
     info!("Initializing VMM...");
     // Initialize guest VM according to config file.
-    config::init_guest_vms();
+    let autostart = config::init_guest_vms();
 
     // Setup vcpus, spawn axtask for primary VCpu.
     info!("Setting up vcpus...");
-    for vm in vm_list::get_vm_list() {
-        vcpus::setup_vm_primary_vcpu(vm);
+    for &vm_id in &autostart {
+        if let Some(vm) = vm_list::get_vm_by_id(vm_id) {
+            vcpus::setup_vm_primary_vcpu(vm);
+        }
     }
+    *AUTOSTART_VMS.lock() = autostart;
 }
 
 /// Start the VMM.
 pub fn start() {
     info!("VMM starting, booting VMs...");
-    for vm in vm_list::get_vm_list() {
+    let autostart = AUTOSTART_VMS.lock().clone();
+    for vm in autostart.into_iter().filter_map(vm_list::get_vm_by_id) {
         match vm.boot() {
             Ok(_) => {
                 vcpus::notify_primary_vcpu(vm.id());
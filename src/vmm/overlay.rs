@@ -0,0 +1,233 @@
+// Copyright 2025 The Axvisor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Inheritance of VM configs.
+//!
+//! A config may start with `extends = "base.toml"`, the path being relative to the directory of
+//! the config. The base is resolved first (it may extend another config itself), then the config
+//! is merged on top of it:
+//!
+//! - tables are merged key by key;
+//! - the arrays listed in [`KEYED_ARRAYS`] are merged entry by entry: an entry replaces the
+//!   inherited entry with the same key (the first element of an array entry, the `tag` or `name`
+//!   of a table entry) in place, other entries are appended in order;
+//! - any other value replaces the inherited one;
+//! - `unset = ["kernel.dtb_path", ...]` removes inherited keys before the merge, e.g. to replace
+//!   a keyed array as a whole.
+//!
+//! Relative image paths (`kernel.*_path`) are relative to the config that sets them, inherited
+//! ones are rewritten to be relative to the directory of the config being resolved. Block device
+//! paths, for `image_location = "block"`, are left as they are.
+//!
+//! This file is also used by `build.rs` for the static VM configs, it must only depend on `alloc`
+//! and `toml`.
+
+use alloc::{
+    format,
+    string::{String, ToString},
+    vec::Vec,
+};
+
+use toml::{Table, Value};
+
+/// Maximum length of an `extends` chain, which also catches cycles.
+pub const MAX_DEPTH: usize = 8;
+
+/// Arrays merged entry by entry instead of being replaced.
+pub const KEYED_ARRAYS: &[&str] = &[
+    "kernel.memory_regions",
+    "devices.passthrough_devices",
+    "devices.passthrough_addresses",
+    "devices.excluded_devices",
+    "devices.emu_devices",
+    "devices.shares",
+    "shared_memory",
+];
+
+/// A config with its bases merged in.
+pub struct Resolved {
+    pub table: Table,
+    /// Paths of the configs it extends, nearest first.
+    pub bases: Vec<String>,
+}
+
+/// Merges the config read from `path` on top of the configs it extends, `read` returning the
+/// content of a file.
+pub fn resolve_content(
+    path: &str,
+    content: &str,
+    read: &mut dyn FnMut(&str) -> Result<String, String>,
+) -> Result<Resolved, String> {
+    let mut chain = Vec::new();
+    let mut table: Table = content
+        .parse()
+        .map_err(|err| format!("{path}: invalid TOML: {err}"))?;
+    let mut current = path.to_string();
+
+    // Collect the chain from the config to its root base.
+    loop {
+        let base = match table.remove("extends") {
+            None => None,
+            Some(Value::String(base)) => Some(join(&current, &base)),
+            Some(_) => return Err(format!("{current}: extends must be a string")),
+        };
+        chain.push((current, table));
+        let Some(base) = base else {
+            break;
+        };
+        if chain.len() > MAX_DEPTH {
+            return Err(format!(
+                "{path}: extends chain longer than {MAX_DEPTH}, is there a cycle?"
+            ));
+        }
+        let content = read(&base)?;
+        table = content
+            .parse()
+            .map_err(|err| format!("{base}: invalid TOML: {err}"))?;
+        current = base;
+    }
+
+    let location = chain.iter().find_map(|(_, table)| {
+        table
+            .get("kernel")?
+            .get("image_location")?
+            .as_str()
+            .map(String::from)
+    });
+    if location.as_deref() != Some("block") {
+        for (base, table) in &mut chain[1..] {
+            rebase_image_paths(table, path, base);
+        }
+    }
+
+    let bases = chain[1..].iter().map(|(path, _)| path.clone()).collect();
+    let (root_path, mut merged) = chain.pop().unwrap();
+    if merged.contains_key("unset") {
+        return Err(format!(
+            "{root_path}: unset is only allowed in a config with extends"
+        ));
+    }
+    while let Some((path, mut overlay)) = chain.pop() {
+        apply_unset(&mut merged, &mut overlay).map_err(|msg| format!("{path}: {msg}"))?;
+        merge_table(&mut merged, overlay, "");
+    }
+    Ok(Resolved {
+        table: merged,
+        bases,
+    })
+}
+
+/// Path of `base` relative to the directory of `config`.
+fn join(config: &str, base: &str) -> String {
+    if base.starts_with('/') {
+        return base.to_string();
+    }
+    match config.rfind('/') {
+        Some(pos) => format!("{}/{}", &config[..pos], base),
+        None => base.to_string(),
+    }
+}
+
+/// Rewrites the relative image paths set by the base config `base` of `config` to be relative to
+/// the directory of `config`.
+fn rebase_image_paths(table: &mut Table, config: &str, base: &str) {
+    let Some(Value::Table(kernel)) = table.get_mut("kernel") else {
+        return;
+    };
+    let config_dir = config.rfind('/').map(|pos| &config[..=pos]);
+    for (key, value) in kernel.iter_mut() {
+        let Value::String(image_path) = value else {
+            continue;
+        };
+        if !key.ends_with("_path") || image_path.starts_with('/') {
+            continue;
+        }
+        let joined = join(base, image_path);
+        *image_path = match config_dir.and_then(|dir| joined.strip_prefix(dir)) {
+            Some(relative) => relative.to_string(),
+            None => joined,
+        };
+    }
+}
+
+fn apply_unset(base: &mut Table, overlay: &mut Table) -> Result<(), String> {
+    let Some(unset) = overlay.remove("unset") else {
+        return Ok(());
+    };
+    let Value::Array(keys) = unset else {
+        return Err("unset must be an array of strings".to_string());
+    };
+    for key in keys {
+        let Value::String(key) = key else {
+            return Err("unset must be an array of strings".to_string());
+        };
+        let mut table = &mut *base;
+        let mut parts = key.split('.').peekable();
+        while let Some(part) = parts.next() {
+            if parts.peek().is_none() {
+                table.remove(part);
+            } else if let Some(Value::Table(inner)) = table.get_mut(part) {
+                table = inner;
+            } else {
+                break;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn merge_table(base: &mut Table, overlay: Table, prefix: &str) {
+    for (key, value) in overlay {
+        let path = if prefix.is_empty() {
+            key.clone()
+        } else {
+            format!("{prefix}.{key}")
+        };
+        match (base.get_mut(&key), value) {
+            (Some(Value::Table(base_table)), Value::Table(table)) => {
+                merge_table(base_table, table, &path)
+            }
+            (Some(Value::Array(base_array)), Value::Array(array))
+                if KEYED_ARRAYS.contains(&path.as_str()) =>
+            {
+                merge_keyed_array(base_array, array)
+            }
+            (_, value) => {
+                base.insert(key, value);
+            }
+        }
+    }
+}
+
+fn merge_keyed_array(base: &mut Vec<Value>, overlay: Vec<Value>) {
+    for entry in overlay {
+        // Entries without a key never replace an inherited one.
+        let inherited = entry_key(&entry).and_then(|key| {
+            base.iter()
+                .position(|inherited| entry_key(inherited) == Some(key))
+        });
+        match inherited.map(|index| &mut base[index]) {
+            Some(inherited) => *inherited = entry,
+            None => base.push(entry),
+        }
+    }
+}
+
+fn entry_key(entry: &Value) -> Option<&Value> {
+    match entry {
+        Value::Array(fields) => fields.first(),
+        Value::Table(table) => table.get("tag").or_else(|| table.get("name")),
+        value => Some(value),
+    }
+}
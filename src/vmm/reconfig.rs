@@ -0,0 +1,247 @@
+// Copyright 2025 The Axvisor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Reconfiguration of VMs that are not running.
+//!
+//! The vCPUs and the memory of an `AxVM` are fixed when it is created, so a new config is applied
+//! by setting the VM up again under the same ID: the config is checked first, then the VM goes
+//! through memory allocation, FDT generation and image loading as at creation. The previous setup
+//! is put back if this fails, and is released once the new one is loaded, so the memory of both
+//! is needed meanwhile. State kept by VM ID, such as the event log and the RTC offset, is
+//! preserved, the measured boot log is started again. The VM stays attached to its shared memory
+//! regions meanwhile, so the size and type of those the new config keeps cannot change.
+//!
+//! [`with_assignments`] changes single keys of the config the VM was created from:
+//!
+//! ```text
+//! base.cpu_num=2                   # dotted path of any key of the config
+//! kernel.memory_regions[0][1]=0x4000_0000
+//! memory_size=0x4000_0000          # size of the first memory region
+//! cmdline=console=ttyAMA0 rw       # kernel.cmdline
+//! ```
+//!
+//! Values are parsed as TOML and taken as a string if they are not valid TOML.
+
+use alloc::{
+    string::{String, ToString},
+    sync::Arc,
+    vec::Vec,
+};
+
+use axerrno::{AxResult, ax_err, ax_err_type};
+use axvm::VMStatus;
+use toml::{Table, Value};
+
+use super::{
+    VMRef,
+    ext_config::{self, ExtConfig},
+    measure::{self, MeasurementLog},
+    shm, vcpus, vm_list,
+};
+
+/// Short names of frequently changed keys.
+const ALIASES: &[(&str, &str)] = &[
+    ("name", "base.name"),
+    ("cpu_num", "base.cpu_num"),
+    ("phys_cpu_ids", "base.phys_cpu_ids"),
+    ("memory_size", "kernel.memory_regions[0][1]"),
+    ("cmdline", "kernel.cmdline"),
+    ("cmdline_append", "kernel.cmdline_append"),
+    ("cmdline_remove", "kernel.cmdline_remove"),
+    ("fit_config", "kernel.fit_config"),
+    ("kernel_path", "kernel.kernel_path"),
+    ("dtb_path", "kernel.dtb_path"),
+    ("bios_path", "kernel.bios_path"),
+    ("ramdisk_path", "kernel.ramdisk_path"),
+    ("passthrough_devices", "devices.passthrough_devices"),
+    ("passthrough_addresses", "devices.passthrough_addresses"),
+];
+
+/// Returns the config of a VM with the keys changed by `key=value` assignments.
+pub fn with_assignments(vm_id: usize, assignments: &[String]) -> AxResult<String> {
+    let source = ext_config::get(vm_id).source;
+    let mut table: Table = source.parse().map_err(|err| {
+        ax_err_type!(
+            InvalidData,
+            format!("VM[{vm_id}] has no usable source config: {err}")
+        )
+    })?;
+    for assignment in assignments {
+        let Some((key, value)) = assignment.split_once('=') else {
+            return ax_err!(
+                InvalidInput,
+                format!("{assignment:?} is not of the form key=value")
+            );
+        };
+        assign(&mut table, key.trim(), parse_value(value.trim()))
+            .map_err(|msg| ax_err_type!(InvalidInput, format!("{key}: {msg}")))?;
+    }
+    toml::to_string(&table)
+        .map_err(|err| ax_err_type!(InvalidInput, format!("invalid VM config: {err}")))
+}
+
+/// Returns a config with `base.id` set to `vm_id`, the ID of a VM is kept whatever config it is
+/// given.
+pub fn with_vm_id(vm_id: usize, raw_cfg: &str) -> AxResult<String> {
+    let mut table: Table = raw_cfg
+        .parse()
+        .map_err(|err| ax_err_type!(InvalidInput, format!("invalid VM config: {err}")))?;
+    match table.get_mut("base") {
+        Some(Value::Table(base)) => {
+            base.insert("id".into(), Value::Integer(vm_id as i64));
+        }
+        _ => return ax_err!(InvalidInput, "missing [base] table"),
+    }
+    toml::to_string(&table)
+        .map_err(|err| ax_err_type!(InvalidInput, format!("invalid VM config: {err}")))
+}
+
+/// Sets a `Loaded` or `Stopped` VM up again with a new config, leaving it in `Loaded` state.
+///
+/// The VM is left as it was if the config is invalid or the VM cannot be set up with it.
+pub fn reconfigure(vm_id: usize, raw_cfg: &str) -> AxResult {
+    let Some(vm) = vm_list::get_vm_by_id(vm_id) else {
+        return ax_err!(NotFound, format!("VM[{vm_id}] not found"));
+    };
+    let status = vm.vm_status();
+    if !matches!(status, VMStatus::Loaded | VMStatus::Stopped) {
+        return ax_err!(
+            BadState,
+            format!("VM[{vm_id}] is {status:?}, it must be Loaded or Stopped")
+        );
+    }
+
+    let (vm_create_config, ext_cfg) = super::validate::parse_and_validate_replacing(raw_cfg, vm_id)
+        .map_err(|diagnostics| {
+            for diagnostic in &diagnostics {
+                error!("Invalid VM config: {diagnostic}");
+            }
+            ax_err_type!(
+                InvalidInput,
+                format!("VM config has {} error(s)", diagnostics.len())
+            )
+        })?;
+
+    info!("VM[{vm_id}] reconfiguring");
+    let old_ext = ext_config::get(vm_id);
+    let old_log = measure::log(vm_id);
+    drop(vm);
+    let Some(old_vm) = vm_list::remove_vm(vm_id) else {
+        return ax_err!(NotFound, format!("VM[{vm_id}] not found"));
+    };
+    vcpus::cleanup_vm_vcpus(vm_id);
+
+    // The VM stays attached to its shared memory regions, so that the new setup maps the same
+    // ones and the previous setup can be restored with their content.
+    let shared_memory = ext_cfg.shared_memory.clone();
+    if let Err(err) = super::config::build_guest_vm(vm_create_config, ext_cfg) {
+        error!("VM[{vm_id}] cannot be set up with its new config, keeping the previous one");
+        shm::detach_except(vm_id, &old_ext.shared_memory);
+        restore(old_vm, old_ext, old_log);
+        return Err(err);
+    }
+    shm::detach_except(vm_id, &shared_memory);
+
+    if Arc::strong_count(&old_vm) > 1 {
+        // The memory of the VM is only freed once the last reference is dropped.
+        warn!(
+            "VM[{vm_id}] previous setup is still referenced {} times, its memory is not freed yet",
+            Arc::strong_count(&old_vm) - 1
+        );
+    }
+    super::scrub::release_replaced(old_vm, old_ext.scrub_all_memory);
+    info!("VM[{vm_id}] reconfigured");
+    Ok(())
+}
+
+/// Parses the value of an assignment, as TOML or else as a plain string.
+fn parse_value(value: &str) -> Value {
+    format!("value = {value}")
+        .parse::<Table>()
+        .ok()
+        .and_then(|mut table| table.remove("value"))
+        .unwrap_or_else(|| Value::String(value.to_string()))
+}
+
+/// Sets the value at a dotted path such as `kernel.memory_regions[0][1]`, creating missing
+/// tables on the way.
+fn assign(table: &mut Table, key: &str, value: Value) -> Result<(), String> {
+    let key = ALIASES
+        .iter()
+        .find(|(alias, _)| *alias == key)
+        .map_or(key, |(_, path)| path);
+
+    let mut segments = Vec::new();
+    for part in key.split('.') {
+        let (name, indices) = part.split_at(part.find('[').unwrap_or(part.len()));
+        if name.is_empty() {
+            return Err("empty key".into());
+        }
+        let mut path = Vec::new();
+        for index in indices.split_terminator(']') {
+            let index = index
+                .strip_prefix('[')
+                .and_then(|index| index.parse::<usize>().ok())
+                .ok_or("invalid array index")?;
+            path.push(index);
+        }
+        if !indices.is_empty() && !indices.ends_with(']') {
+            return Err("invalid array index".into());
+        }
+        segments.push((name, path));
+    }
+
+    let ((last_name, last_path), parents) = segments.split_last().ok_or("empty key")?;
+    let mut current = table;
+    for (name, path) in parents {
+        if !path.is_empty() {
+            return Err(format!("{name}: only the last key may be indexed"));
+        }
+        let entry = current
+            .entry(name.to_string())
+            .or_insert_with(|| Value::Table(Table::new()));
+        current = entry
+            .as_table_mut()
+            .ok_or_else(|| format!("{name} is not a table"))?;
+    }
+
+    if last_path.is_empty() {
+        current.insert(last_name.to_string(), value);
+        return Ok(());
+    }
+    let mut slot = current
+        .get_mut(*last_name)
+        .ok_or_else(|| format!("{last_name} is not set"))?;
+    for &index in last_path {
+        slot = slot
+            .as_array_mut()
+            .ok_or("indexed value is not an array")?
+            .get_mut(index)
+            .ok_or_else(|| format!("index {index} is out of bounds"))?;
+    }
+    *slot = value;
+    Ok(())
+}
+
+/// Puts back the previous setup of a VM that could not be set up with a new config, with the
+/// state kept by its ID. Its shared memory regions are still mapped.
+fn restore(vm: VMRef, ext: ExtConfig, log: Option<MeasurementLog>) {
+    let vm_id = vm.id();
+    ext_config::insert(vm_id, ext);
+    if let Some(log) = log {
+        measure::restore(vm_id, log);
+    }
+    vm_list::push_vm(vm);
+    info!("VM[{vm_id}] previous setup restored");
+}
@@ -0,0 +1,102 @@
+// Copyright 2025 The Axvisor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Scrubbing of the memory of VMs that are deleted or restarted.
+//!
+//! The memory allocated for a VM goes back to the hypervisor allocator when the last reference to
+//! the VM is dropped, and would be handed out with the data of the guest to whatever allocates it
+//! next. [`release`] takes that reference and zeroes the allocated regions in a background task
+//! before dropping it, so that deleting a VM stays fast. A restarted VM keeps its memory, which
+//! [`zero_memory`] zeroes in place before its images are loaded again, and a reconfigured VM
+//! keeps its previous setup until the new one is loaded, see [`release_replaced`]. Identically
+//! mapped and reserved regions are only zeroed when the VM config sets `kernel.scrub_all_memory`.
+
+use alloc::{sync::Arc, vec::Vec};
+use std::thread;
+
+use axvm::VMMemoryRegion;
+
+use crate::hal::{CacheOp, arch::cache::dcache_range};
+use crate::vmm::VMRef;
+
+/// Zeroes the memory of a deleted VM whose vCPUs have exited, then drops the given reference to
+/// it.
+///
+/// Reserved regions are not freed with the VM and may be mapped again right away, e.g. by the
+/// next VM created, so they are zeroed before returning. The other regions are zeroed in a
+/// background task.
+pub fn release(vm: VMRef, scrub_all: bool) {
+    let vm_id = vm.id();
+    let (reserved, allocated): (Vec<_>, Vec<_>) = scrubbed_regions(&vm, scrub_all)
+        .into_iter()
+        .partition(|region| !region.needs_dealloc);
+
+    for region in &reserved {
+        zero_region(vm_id, region);
+    }
+    if allocated.is_empty() {
+        return;
+    }
+
+    thread::spawn(move || {
+        for region in &allocated {
+            zero_region(vm_id, region);
+        }
+        let references = Arc::strong_count(&vm) - 1;
+        if references > 0 {
+            warn!("VM[{vm_id}] memory scrubbed, but it is still referenced {references} times");
+        } else {
+            info!("VM[{vm_id}] memory scrubbed and freed");
+        }
+    });
+}
+
+/// Zeroes in place the memory of a stopped VM that is about to be set up again.
+pub fn zero_memory(vm: &VMRef, scrub_all: bool) {
+    for region in &scrubbed_regions(vm, scrub_all) {
+        zero_region(vm.id(), region);
+    }
+}
+
+/// Zeroes the memory of the previous setup of a reconfigured VM, then drops the given reference
+/// to it. Reserved regions are mapped by the new setup of the VM and left as they are.
+pub fn release_replaced(vm: VMRef, scrub_all: bool) {
+    for region in scrubbed_regions(&vm, scrub_all)
+        .iter()
+        .filter(|region| region.needs_dealloc)
+    {
+        zero_region(vm.id(), region);
+    }
+}
+
+/// The memory regions of the VM that are scrubbed.
+fn scrubbed_regions(vm: &VMRef, scrub_all: bool) -> Vec<VMMemoryRegion> {
+    vm.memory_regions()
+        .into_iter()
+        .filter(|region| scrub_all || (region.needs_dealloc && !region.is_identical()))
+        .collect()
+}
+
+fn zero_region(vm_id: usize, region: &VMMemoryRegion) {
+    debug!(
+        "VM[{vm_id}] zeroing memory region [{:#x}, {:#x})",
+        region.gpa,
+        region.gpa + region.size()
+    );
+    // SAFETY: the vCPUs of the VM have exited, and the region stays mapped in the hypervisor
+    // address space until the VM is dropped.
+    unsafe { core::ptr::write_bytes(region.hva.as_mut_ptr(), 0, region.size()) };
+    // Reserved regions may be mapped uncached by the next VM.
+    dcache_range(CacheOp::Clean, region.hva, region.size());
+}
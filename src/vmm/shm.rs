@@ -0,0 +1,190 @@
+// Copyright 2025 The Axvisor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Memory regions shared between VMs, declared by the `[[shared_memory]]` tables of their
+//! configs.
+//!
+//! A region is allocated, zeroed, when the first VM declaring its name is created, and mapped into
+//! every VM declaring it at the GPA and with the access given by the config of that VM. It is
+//! zeroed and freed once the last of them is deleted. Regions are made of whole pages taken from
+//! the page allocator, so that memory mapped uncached or as device memory by the guests shares no
+//! page with hypervisor data. Unlike IVC channels, no hypercall is needed: the guests
+//! find the region in a `reserved-memory` node of their FDT.
+
+use alloc::{
+    collections::{BTreeMap, BTreeSet, btree_map::Entry},
+    string::String,
+    vec::Vec,
+};
+use std::os::arceos::modules::{axalloc, axhal};
+
+use axaddrspace::{GuestPhysAddr, HostPhysAddr, MappingFlags};
+use axerrno::{AxResult, ax_err, ax_err_type};
+use kspin::SpinNoIrq;
+use memory_addr::PAGE_SIZE_4K;
+
+use crate::hal::{CacheOp, arch::cache::dcache_range};
+
+use super::VM;
+use super::ext_config::{MemoryType, SharedMemoryConfig};
+
+/// A shared memory region.
+struct SharedRegion {
+    /// Host virtual address of the allocation.
+    hva: usize,
+    size: usize,
+    memory_type: MemoryType,
+    /// IDs of the VMs it is mapped into.
+    users: BTreeSet<usize>,
+}
+
+impl SharedRegion {
+    fn alloc(shm: &SharedMemoryConfig) -> AxResult<Self> {
+        // The size is a non-zero multiple of the page size, checked by `validate`.
+        let hva = axalloc::global_allocator()
+            .alloc_pages(shm.size / PAGE_SIZE_4K, PAGE_SIZE_4K)
+            .map_err(|_| {
+                ax_err_type!(
+                    NoMemory,
+                    format!("cannot allocate shared memory {:?}", shm.name)
+                )
+            })?;
+        let region = Self {
+            hva,
+            size: shm.size,
+            memory_type: shm.memory_type,
+            users: BTreeSet::new(),
+        };
+        region.zero();
+        info!(
+            "Shared memory {:?} allocated: {:#x} bytes, {:?}",
+            shm.name, shm.size, shm.memory_type
+        );
+        Ok(region)
+    }
+
+    /// Zeroes the region, and writes it back to memory for the guests that map it uncached.
+    fn zero(&self) {
+        // SAFETY: the region is allocated and only accessed by stopped VMs or none.
+        unsafe { core::ptr::write_bytes(self.hva as *mut u8, 0, self.size) };
+        dcache_range(CacheOp::CleanAndInvalidate, self.hva.into(), self.size);
+    }
+
+    fn hpa(&self) -> HostPhysAddr {
+        axhal::mem::virt_to_phys(self.hva.into())
+    }
+
+    /// Why the region cannot be what `shm` declares, if it cannot.
+    fn mismatch(&self, shm: &SharedMemoryConfig) -> Option<String> {
+        if self.size != shm.size {
+            Some(format!(
+                "size {:#x} differs from the {:#x} bytes of the region shared with VM {:?}",
+                shm.size, self.size, self.users
+            ))
+        } else if self.memory_type != shm.memory_type {
+            Some(format!(
+                "memory type {:?} differs from the {:?} region shared with VM {:?}",
+                shm.memory_type, self.memory_type, self.users
+            ))
+        } else {
+            None
+        }
+    }
+}
+
+impl Drop for SharedRegion {
+    fn drop(&mut self) {
+        // The data of the guests must not reach whatever gets the pages next.
+        self.zero();
+        axalloc::global_allocator().dealloc_pages(self.hva, self.size / PAGE_SIZE_4K);
+    }
+}
+
+/// The shared memory regions, by name.
+static SHARED_REGIONS: SpinNoIrq<BTreeMap<String, SharedRegion>> = SpinNoIrq::new(BTreeMap::new());
+
+/// Returns why `shm` cannot be mapped into a VM given the regions already shared, if it cannot.
+/// The regions of a reconfigured VM are kept while it is set up again, so they are checked too.
+pub fn conflict(shm: &SharedMemoryConfig) -> Option<String> {
+    SHARED_REGIONS.lock().get(&shm.name)?.mismatch(shm)
+}
+
+/// Maps the shared memory regions of its config into a VM, allocating those that are not
+/// shared yet.
+///
+/// On failure, the regions already mapped stay attached to the VM until [`detach`] is called.
+pub fn attach(vm: &VM, regions: &[SharedMemoryConfig]) -> AxResult {
+    for shm in regions {
+        let hpa = {
+            let mut shared = SHARED_REGIONS.lock();
+            let region = match shared.entry(shm.name.clone()) {
+                Entry::Occupied(entry) => entry.into_mut(),
+                Entry::Vacant(entry) => entry.insert(SharedRegion::alloc(shm)?),
+            };
+            if let Some(msg) = region.mismatch(shm) {
+                return ax_err!(InvalidInput, format!("shared memory {:?}: {msg}", shm.name));
+            }
+            region.users.insert(vm.id());
+            region.hpa()
+        };
+
+        let mut flags = MappingFlags::READ;
+        if !shm.read_only {
+            flags |= MappingFlags::WRITE;
+        }
+        match shm.memory_type {
+            MemoryType::Normal => {}
+            MemoryType::Uncached => flags |= MappingFlags::UNCACHED,
+            MemoryType::Device => flags |= MappingFlags::DEVICE,
+        }
+        info!(
+            "VM[{}] shared memory {:?} mapped at {:#x} ({})",
+            vm.id(),
+            shm.name,
+            shm.gpa,
+            if shm.read_only { "ro" } else { "rw" }
+        );
+        vm.map_region(GuestPhysAddr::from(shm.gpa), hpa, shm.size, flags)?;
+    }
+    Ok(())
+}
+
+/// Detaches a deleted VM from its shared memory regions, freeing those no other VM uses.
+pub fn detach(vm_id: usize) {
+    detach_except(vm_id, &[]);
+}
+
+/// Detaches a VM from its shared memory regions but those of `kept`, e.g. the regions that the
+/// config of a reconfigured VM still declares, freeing those no other VM uses.
+pub fn detach_except(vm_id: usize, kept: &[SharedMemoryConfig]) {
+    let freed: Vec<(String, SharedRegion)> = {
+        let mut shared = SHARED_REGIONS.lock();
+        let unused: Vec<String> = shared
+            .iter_mut()
+            .filter(|(name, _)| !kept.iter().any(|shm| shm.name == **name))
+            .filter_map(|(name, region)| {
+                (region.users.remove(&vm_id) && region.users.is_empty()).then(|| name.clone())
+            })
+            .collect();
+        unused
+            .into_iter()
+            .filter_map(|name| shared.remove_entry(&name))
+            .collect()
+    };
+    // Regions are zeroed when dropped, which must not happen with IRQs disabled by the lock.
+    for (name, region) in freed {
+        drop(region);
+        info!("Shared memory {name:?} freed, VM[{vm_id}] was its last user");
+    }
+}
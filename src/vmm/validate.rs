@@ -0,0 +1,883 @@
+// Copyright 2025 The Axvisor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Semantic validation of VM configs.
+//!
+//! `axvmconfig` only checks that a config is well-formed TOML with the expected fields. The
+//! checks here catch the mistakes that would otherwise only show up as panics in the middle of
+//! VM creation, and report all of them at once with the path of the offending field.
+
+use alloc::{string::String, vec::Vec};
+use core::fmt;
+use std::os::arceos::modules::axhal;
+
+use axaddrspace::MappingFlags;
+use axvm::config::{AxVMCrateConfig, VmMemMappingType};
+
+use super::ext_config::{self, ExtConfig};
+use super::images::PLACEMENT_ALIGN;
+use crate::vmm::vm_list;
+
+/// A problem found in a VM config.
+#[derive(Debug, Clone)]
+pub struct Diagnostic {
+    /// Path of the offending field, e.g. `kernel.memory_regions[1]`, empty for the whole file.
+    pub path: String,
+    pub message: String,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.path.is_empty() {
+            write!(f, "{}", self.message)
+        } else {
+            write!(f, "{}: {}", self.path, self.message)
+        }
+    }
+}
+
+#[derive(Default)]
+struct Diagnostics(Vec<Diagnostic>);
+
+impl Diagnostics {
+    fn push(&mut self, path: impl Into<String>, message: impl Into<String>) {
+        self.0.push(Diagnostic {
+            path: path.into(),
+            message: message.into(),
+        });
+    }
+}
+
+/// Parses a raw VM config and checks it, returning either the parsed config or every problem
+/// found in it.
+pub fn parse_and_validate(raw_cfg: &str) -> Result<(AxVMCrateConfig, ExtConfig), Vec<Diagnostic>> {
+    parse_and_validate_for(raw_cfg, None)
+}
+
+/// Same as [`parse_and_validate`] for a config meant to replace the one of the existing VM
+/// `vm_id`, whose ID may be reused.
+pub fn parse_and_validate_replacing(
+    raw_cfg: &str,
+    vm_id: usize,
+) -> Result<(AxVMCrateConfig, ExtConfig), Vec<Diagnostic>> {
+    parse_and_validate_for(raw_cfg, Some(vm_id))
+}
+
+fn parse_and_validate_for(
+    raw_cfg: &str,
+    replacing: Option<usize>,
+) -> Result<(AxVMCrateConfig, ExtConfig), Vec<Diagnostic>> {
+    let mut diags = Diagnostics::default();
+
+    let parsed = ext_config::parse(raw_cfg)
+        .map_err(|err| format!("{err:?}"))
+        .and_then(|(raw_cfg, ext)| {
+            AxVMCrateConfig::from_toml(&raw_cfg)
+                .map(|cfg| (cfg, ext))
+                .map_err(|err| format!("{err:?}"))
+        });
+    let (mut cfg, ext) = match parsed {
+        Ok(parsed) => parsed,
+        Err(err) => {
+            diags.push("", format!("cannot parse VM config: {err}"));
+            return Err(diags.0);
+        }
+    };
+
+    check_base(&cfg, replacing, &mut diags);
+    check_memory_regions(&cfg, &mut diags);
+    check_kernel_placement(&mut cfg, &mut diags);
+    let uboot_image = check_uboot_image(&cfg, &ext, &mut diags);
+    check_images(&cfg, uboot_image, &mut diags);
+    check_digests(&cfg, &ext, &mut diags);
+    #[cfg(target_arch = "x86_64")]
+    check_bzimage(&cfg, &ext, &mut diags);
+    #[cfg(target_arch = "x86_64")]
+    check_multiboot(&cfg, &mut diags);
+    check_devices(&cfg, &ext, &mut diags);
+    check_shared_memory(&cfg, &ext, &mut diags);
+
+    if diags.0.is_empty() {
+        Ok((cfg, ext))
+    } else {
+        Err(diags.0)
+    }
+}
+
+/// Checks a raw VM config, returning every problem found in it.
+pub fn validate(raw_cfg: &str) -> Vec<Diagnostic> {
+    parse_and_validate(raw_cfg).err().unwrap_or_default()
+}
+
+/// Checks a raw VM config meant to replace the one of the existing VM `vm_id`.
+pub fn validate_replacing(raw_cfg: &str, vm_id: usize) -> Vec<Diagnostic> {
+    parse_and_validate_replacing(raw_cfg, vm_id)
+        .err()
+        .unwrap_or_default()
+}
+
+fn check_base(cfg: &AxVMCrateConfig, replacing: Option<usize>, diags: &mut Diagnostics) {
+    let base = &cfg.base;
+
+    if replacing != Some(base.id) && vm_list::get_vm_by_id(base.id).is_some() {
+        diags.push("base.id", format!("VM[{}] already exists", base.id));
+    }
+    if base.cpu_num == 0 {
+        diags.push("base.cpu_num", "a VM needs at least one vCPU");
+    }
+
+    let Some(phys_cpu_ids) = &base.phys_cpu_ids else {
+        if cfg!(target_arch = "aarch64") {
+            diags.push("base.phys_cpu_ids", "missing, required on aarch64");
+        }
+        return;
+    };
+
+    if phys_cpu_ids.len() != base.cpu_num {
+        diags.push(
+            "base.phys_cpu_ids",
+            format!(
+                "has {} entries but base.cpu_num is {}",
+                phys_cpu_ids.len(),
+                base.cpu_num
+            ),
+        );
+    }
+
+    let host_cpu_num = axhal::cpu_num();
+    #[cfg(target_arch = "aarch64")]
+    let host_cpu_ids = crate::vmm::fdt::host_cpu_ids();
+    for (index, &id) in phys_cpu_ids.iter().enumerate() {
+        let path = format!("base.phys_cpu_ids[{index}]");
+        if phys_cpu_ids[..index].contains(&id) {
+            diags.push(path, format!("{id:#x} is listed twice"));
+            continue;
+        }
+        // On aarch64 the IDs are the MPIDR values of the CPU nodes of the host FDT.
+        #[cfg(target_arch = "aarch64")]
+        let cpu_index = match host_cpu_ids.iter().position(|&host_id| host_id == id) {
+            Some(cpu_index) => cpu_index,
+            None => {
+                diags.push(
+                    path,
+                    format!("{id:#x} is not a CPU of the host device tree"),
+                );
+                continue;
+            }
+        };
+        #[cfg(not(target_arch = "aarch64"))]
+        let cpu_index = id;
+        if cpu_index >= host_cpu_num {
+            diags.push(
+                path,
+                format!("{id:#x} is CPU {cpu_index}, but the host only has {host_cpu_num} CPUs"),
+            );
+        }
+    }
+}
+
+fn check_memory_regions(cfg: &AxVMCrateConfig, diags: &mut Diagnostics) {
+    let regions = &cfg.kernel.memory_regions;
+    if regions.is_empty() {
+        diags.push(
+            "kernel.memory_regions",
+            "a VM needs at least one memory region",
+        );
+        return;
+    }
+
+    // The kernel runs from the region it is loaded into, which is the first one if it is
+    // identical, see `config_guest_address`.
+    let kernel_addr = cfg.kernel.kernel_load_addr;
+    let kernel_region = if matches!(regions[0].map_type, VmMemMappingType::MapIdentical) {
+        Some(0)
+    } else {
+        regions.iter().position(|region| {
+            !matches!(region.map_type, VmMemMappingType::MapIdentical)
+                && region.gpa <= kernel_addr
+                && kernel_addr - region.gpa < region.size
+        })
+    };
+    if let Some(index) = kernel_region
+        && !MappingFlags::from_bits_truncate(regions[index].flags).contains(MappingFlags::EXECUTE)
+    {
+        diags.push(
+            format!("kernel.memory_regions[{index}]"),
+            "the kernel is loaded into this region, its flags must include EXECUTE (0x4)",
+        );
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    let host_reserved = crate::vmm::fdt::host_reserved_memory();
+
+    for (index, region) in regions.iter().enumerate() {
+        let path = format!("kernel.memory_regions[{index}]");
+        if region.size == 0 {
+            diags.push(path, "size is zero");
+            continue;
+        }
+        if region.gpa.checked_add(region.size).is_none() {
+            diags.push(path, "range overflows the address space");
+            continue;
+        }
+        match MappingFlags::from_bits(region.flags) {
+            None => diags.push(
+                path.clone(),
+                format!(
+                    "unknown flags {:#x}, expected READ (0x1), WRITE (0x2), EXECUTE (0x4), \
+                     USER (0x8), DEVICE (0x10) and UNCACHED (0x20)",
+                    region.flags
+                ),
+            ),
+            Some(flags) if !flags.contains(MappingFlags::READ) => {
+                diags.push(path.clone(), "flags must include READ (0x1)")
+            }
+            Some(_) => {}
+        }
+        // Identical regions get their address from the allocator, `gpa` is not used.
+        if matches!(region.map_type, VmMemMappingType::MapIdentical) {
+            continue;
+        }
+        for (other_index, other) in regions[..index].iter().enumerate() {
+            if !matches!(other.map_type, VmMemMappingType::MapIdentical)
+                && overlaps(region.gpa, region.size, other.gpa, other.size)
+            {
+                diags.push(
+                    path.clone(),
+                    format!(
+                        "[{:#x}, {:#x}) overlaps kernel.memory_regions[{other_index}]",
+                        region.gpa,
+                        region.gpa + region.size
+                    ),
+                );
+            }
+        }
+        // Some shipped configs map reserved memory that the host device tree does not describe,
+        // so this is only a warning.
+        #[cfg(target_arch = "aarch64")]
+        if matches!(region.map_type, VmMemMappingType::MapReserved)
+            && !host_reserved.iter().any(|&(start, size)| {
+                start <= region.gpa && region.gpa + region.size <= start.saturating_add(size)
+            })
+        {
+            warn!(
+                "VM[{}] {path}: MapReserved region [{:#x}, {:#x}) is not in the host reserved \
+                 memory",
+                cfg.base.id,
+                region.gpa,
+                region.gpa + region.size
+            );
+        }
+    }
+}
+
+/// `uboot_image` tells whether the kernel is a U-Boot image, whose components are checked by
+/// `check_uboot_image`.
+fn check_images(cfg: &AxVMCrateConfig, uboot_image: bool, diags: &mut Diagnostics) {
+    let kernel = &cfg.kernel;
+
+    if kernel.kernel_path.is_empty() {
+        diags.push("kernel.kernel_path", "missing kernel image");
+    }
+
+    match kernel.image_location.as_deref() {
+        Some("memory") => {
+            if !super::config::config::get_memory_images()
+                .iter()
+                .any(|images| images.id == cfg.base.id)
+            {
+                diags.push(
+                    "kernel.image_location",
+                    format!("no images of VM[{}] are built into AxVisor", cfg.base.id),
+                );
+            }
+        }
+        #[cfg(any(feature = "fs", feature = "guest-archive"))]
+        Some("fs") => {}
+        #[cfg(not(any(feature = "fs", feature = "guest-archive")))]
+        Some("fs") => diags.push(
+            "kernel.image_location",
+            "\"fs\" requires AxVisor to be built with the fs or guest-archive feature",
+        ),
+        Some("block") => {}
+        Some(other) => diags.push(
+            "kernel.image_location",
+            format!("unknown location {other:?}, expected \"memory\", \"fs\" or \"block\""),
+        ),
+        None => diags.push("kernel.image_location", "missing"),
+    }
+
+    let images = [
+        (
+            "kernel",
+            Some(kernel.kernel_path.as_str()).filter(|p| !p.is_empty()),
+            Some(kernel.kernel_load_addr),
+        ),
+        ("bios", kernel.bios_path.as_deref(), kernel.bios_load_addr),
+        ("dtb", kernel.dtb_path.as_deref(), kernel.dtb_load_addr),
+        (
+            "ramdisk",
+            kernel.ramdisk_path.as_deref(),
+            kernel.ramdisk_load_addr,
+        ),
+    ];
+
+    let relocated = is_relocated(cfg);
+
+    // ELF kernels are loaded at the addresses of their segments, not at `kernel_load_addr`.
+    let kernel_elf = super::images::get_kernel_elf(cfg);
+    match &kernel_elf {
+        Some(Err(msg)) => diags.push("kernel.kernel_path", format!("invalid ELF image: {msg}")),
+        Some(Ok(elf)) if !relocated => {
+            for seg in &elf.segments {
+                if !in_guest_ram(cfg, seg.paddr, seg.mem_size) {
+                    diags.push(
+                        "kernel.kernel_path",
+                        format!(
+                            "ELF segment [{:#x}, {:#x}) is outside guest RAM",
+                            seg.paddr,
+                            seg.paddr.saturating_add(seg.mem_size)
+                        ),
+                    );
+                }
+            }
+        }
+        _ => {}
+    }
+
+    // U-Boot images place their kernel, Multiboot kernels may give their load address in their
+    // header.
+    let kernel_placed = kernel_elf.is_some() || uboot_image;
+    #[cfg(target_arch = "x86_64")]
+    let kernel_placed = kernel_placed
+        || super::images::get_kernel_multiboot(cfg)
+            .is_some_and(|mb| mb.is_ok_and(|mb| mb.layout().is_some()));
+
+    for (name, image_path, load_addr) in images {
+        let size = image_path.and_then(|path| image_size(cfg, name, path));
+        if let Some(path) = image_path
+            && kernel.image_location.as_deref() == Some("fs")
+            && size.is_none()
+        {
+            diags.push(format!("kernel.{name}_path"), format!("{path} not found"));
+        }
+        if let Some(path) = image_path
+            && kernel.image_location.as_deref() == Some("block")
+            && let Err(msg) = super::images::block::BlockImage::locate(path)
+        {
+            diags.push(format!("kernel.{name}_path"), msg);
+        }
+
+        let Some(load_addr) = load_addr else {
+            // The DTB is placed automatically when it has no load address.
+            if image_path.is_some() && name != "dtb" {
+                diags.push(
+                    format!("kernel.{name}_load_addr"),
+                    format!("missing, kernel.{name}_path is set"),
+                );
+            }
+            continue;
+        };
+        if relocated || (name == "kernel" && kernel_placed) {
+            continue;
+        }
+        let len = size.unwrap_or(1);
+        if !in_guest_ram(cfg, load_addr, len) {
+            diags.push(
+                format!("kernel.{name}_load_addr"),
+                format!(
+                    "[{load_addr:#x}, {:#x}) is outside guest RAM",
+                    load_addr.saturating_add(len)
+                ),
+            );
+        }
+    }
+}
+
+/// Checks that the images with an expected digest are part of the VM.
+fn check_digests(cfg: &AxVMCrateConfig, ext: &ExtConfig, diags: &mut Diagnostics) {
+    let kernel = &cfg.kernel;
+    for (name, digest, path) in [
+        ("dtb", &ext.digests.dtb, &kernel.dtb_path),
+        ("bios", &ext.digests.bios, &kernel.bios_path),
+        ("ramdisk", &ext.digests.ramdisk, &kernel.ramdisk_path),
+    ] {
+        if digest.is_some() && path.is_none() {
+            diags.push(
+                format!("kernel.{name}_sha256"),
+                format!("set, but kernel.{name}_path is not"),
+            );
+        }
+    }
+}
+
+/// Checks the components of a U-Boot FIT image or uImage kernel, see `images::uboot`. Returns
+/// whether the kernel is one. Only the header of the image is read, its data is checked when it
+/// is loaded.
+fn check_uboot_image(cfg: &AxVMCrateConfig, ext: &ExtConfig, diags: &mut Diagnostics) -> bool {
+    use super::images::{ComponentKind, with_kernel_uboot_header};
+
+    let kernel = &cfg.kernel;
+    let relocated = is_relocated(cfg);
+    let checked = with_kernel_uboot_header(cfg, ext.fit_config.as_deref(), |image| {
+        let mut problems = Vec::new();
+        for component in &image.components {
+            let (name, path, load_addr) = match component.kind {
+                ComponentKind::Kernel => ("kernel", None, Some(kernel.kernel_load_addr)),
+                ComponentKind::Fdt => ("dtb", kernel.dtb_path.as_deref(), kernel.dtb_load_addr),
+                ComponentKind::Ramdisk => (
+                    "ramdisk",
+                    kernel.ramdisk_path.as_deref(),
+                    kernel.ramdisk_load_addr,
+                ),
+            };
+            if path.is_some() {
+                problems.push((
+                    format!("kernel.{name}_path"),
+                    format!("the kernel image already has one, {}", component.name),
+                ));
+            }
+            let Some(addr) = component.load.or(load_addr) else {
+                if component.kind == ComponentKind::Ramdisk {
+                    problems.push((
+                        format!("kernel.{name}_load_addr"),
+                        format!("missing, {} has no load address", component.name),
+                    ));
+                }
+                continue;
+            };
+            // Compressed components are only checked by their compressed size, their
+            // decompressed size is checked when they are loaded.
+            let len = component.size;
+            if !relocated && !in_guest_ram(cfg, addr, len) {
+                problems.push((
+                    String::from("kernel.kernel_path"),
+                    format!(
+                        "{} is loaded at [{addr:#x}, {:#x}), outside guest RAM",
+                        component.name,
+                        addr.saturating_add(len)
+                    ),
+                ));
+            }
+        }
+        problems
+    });
+    match checked {
+        None => {
+            if ext.fit_config.is_some() {
+                diags.push("kernel.fit_config", "the kernel is not a FIT image");
+            }
+            false
+        }
+        Some(Err(msg)) => {
+            diags.push("kernel.kernel_path", format!("invalid U-Boot image: {msg}"));
+            true
+        }
+        Some(Ok(problems)) => {
+            for (path, message) in problems {
+                diags.push(path, message);
+            }
+            true
+        }
+    }
+}
+
+/// Checks the placement of a Linux kernel against its boot header. A kernel load address that
+/// breaks the alignment rules is moved up to the next valid one if the kernel still fits in the
+/// same RAM region.
+fn check_kernel_placement(cfg: &mut AxVMCrateConfig, diags: &mut Diagnostics) {
+    let Some(header) = super::images::get_image_header(cfg) else {
+        return;
+    };
+    debug!("VM[{}] Linux header: {:#x?}", cfg.base.id, header);
+
+    #[cfg(target_arch = "aarch64")]
+    if let Some(page_size) = header.page_size()
+        && !crate::hal::arch::page_size_supported(page_size)
+    {
+        diags.push(
+            "kernel.kernel_path",
+            format!(
+                "the kernel uses {} KiB pages, which this CPU does not support",
+                page_size / 1024
+            ),
+        );
+    }
+
+    // Relocated kernels are placed by `config_guest_address`.
+    if is_relocated(cfg) {
+        return;
+    }
+    let load_addr = cfg.kernel.kernel_load_addr;
+    let Some((ram_base, ram_end)) = guest_ram(cfg)
+        .map(|(gpa, size)| (gpa, gpa + size))
+        .find(|&(base, end)| base <= load_addr && load_addr < end)
+    else {
+        // Reported by `check_images`.
+        return;
+    };
+    let offset = header.placement_offset();
+    let size = header
+        .memory_size()
+        .or_else(|| image_size(cfg, "kernel", &cfg.kernel.kernel_path))
+        .unwrap_or(1);
+    let fits = |addr: usize| addr.checked_add(size).is_some_and(|end| end <= ram_end);
+
+    if !header.is_valid_load_addr(load_addr) {
+        let Some(addr) = header.next_load_addr(load_addr).filter(|&addr| fits(addr)) else {
+            diags.push(
+                "kernel.kernel_load_addr",
+                format!(
+                    "{load_addr:#x} is not {offset:#x} above a 2 MiB aligned address as the \
+                     kernel requires, and there is no room for it above"
+                ),
+            );
+            return;
+        };
+        warn!(
+            "VM[{}] kernel.kernel_load_addr {load_addr:#x} is not {offset:#x} above a 2 MiB \
+             aligned address as the kernel requires, moved to {addr:#x}",
+            cfg.base.id
+        );
+        if cfg.kernel.entry_point == load_addr {
+            cfg.kernel.entry_point = addr;
+        }
+        cfg.kernel.kernel_load_addr = addr;
+    }
+
+    let load_addr = cfg.kernel.kernel_load_addr;
+    if !fits(load_addr) {
+        diags.push(
+            "kernel.kernel_load_addr",
+            format!(
+                "the kernel needs {size:#x} bytes from {load_addr:#x}, but guest RAM ends at \
+                 {ram_end:#x}"
+            ),
+        );
+    }
+    if let Some(limit) = header.placement_limit()
+        && load_addr.saturating_add(size) > limit
+    {
+        diags.push(
+            "kernel.kernel_load_addr",
+            format!("the kernel must be placed below {limit:#x}"),
+        );
+    }
+    let lowest_base = ram_base.next_multiple_of(PLACEMENT_ALIGN);
+    if header.prefers_low_placement() && load_addr - offset > lowest_base {
+        warn!(
+            "VM[{}] the kernel cannot use the {:#x} bytes of RAM below its load address, \
+             kernel.kernel_load_addr {:#x} would avoid that",
+            cfg.base.id,
+            load_addr - offset - ram_base,
+            lowest_base + offset
+        );
+    }
+}
+
+/// Checks the memory a bzImage kernel needs to boot, see `images::bzimage`.
+#[cfg(target_arch = "x86_64")]
+fn check_bzimage(cfg: &AxVMCrateConfig, ext: &ExtConfig, diags: &mut Diagnostics) {
+    use super::images::bzimage;
+
+    let bz = match super::images::get_kernel_bzimage(cfg) {
+        Some(Ok(bz)) => bz,
+        Some(Err(msg)) => {
+            diags.push("kernel.kernel_path", format!("invalid bzImage: {msg}"));
+            return;
+        }
+        None => return,
+    };
+    let kernel = &cfg.kernel;
+
+    if kernel.bios_path.is_some() {
+        diags.push(
+            "kernel.bios_path",
+            "bzImage kernels are booted without BIOS, remove it",
+        );
+    }
+    let boot_end = bzimage::PAGE_TABLES_GPA + bzimage::PAGE_TABLES_SIZE;
+    if !in_guest_ram(
+        cfg,
+        bzimage::BOOT_PARAMS_GPA,
+        boot_end - bzimage::BOOT_PARAMS_GPA,
+    ) {
+        diags.push(
+            "kernel.memory_regions",
+            format!(
+                "[{:#x}, {boot_end:#x}) must be guest RAM to boot a bzImage kernel",
+                bzimage::BOOT_PARAMS_GPA
+            ),
+        );
+    }
+
+    let cmdline = ext.cmdline.apply("");
+    if cmdline.len() > bz.cmdline_size {
+        diags.push(
+            "kernel.cmdline",
+            format!(
+                "{} bytes long, the kernel accepts at most {}",
+                cmdline.len(),
+                bz.cmdline_size
+            ),
+        );
+    } else if !cmdline.is_empty() && !in_guest_ram(cfg, bzimage::CMDLINE_GPA, cmdline.len() + 1) {
+        diags.push(
+            "kernel.cmdline",
+            format!(
+                "the command line is placed at {:#x}, which is not guest RAM",
+                bzimage::CMDLINE_GPA
+            ),
+        );
+    }
+
+    let load_addr = kernel.kernel_load_addr;
+    if !bz.is_valid_load_addr(load_addr) {
+        diags.push(
+            "kernel.kernel_load_addr",
+            format!(
+                "{load_addr:#x} is invalid, it must be {}",
+                bz.load_addr_rule()
+            ),
+        );
+    } else if !in_guest_ram(cfg, load_addr, bz.memory_end(load_addr) - load_addr) {
+        diags.push(
+            "kernel.kernel_load_addr",
+            format!(
+                "the kernel needs guest RAM up to {:#x} to boot",
+                bz.memory_end(load_addr)
+            ),
+        );
+    }
+
+    if let (Some(path), Some(addr)) = (&kernel.ramdisk_path, kernel.ramdisk_load_addr) {
+        let size = image_size(cfg, "ramdisk", path).unwrap_or(0);
+        if addr.saturating_add(size) > bz.initrd_end_max() {
+            diags.push(
+                "kernel.ramdisk_load_addr",
+                format!(
+                    "the ramdisk must end below {:#x} for this kernel",
+                    bz.initrd_end_max()
+                ),
+            );
+        }
+    }
+}
+
+/// Checks the memory a Multiboot kernel needs to boot, see `images::multiboot`.
+#[cfg(target_arch = "x86_64")]
+fn check_multiboot(cfg: &AxVMCrateConfig, diags: &mut Diagnostics) {
+    use super::images::multiboot;
+
+    if super::images::get_kernel_bzimage(cfg).is_some() {
+        return;
+    }
+    let mb = match super::images::get_kernel_multiboot(cfg) {
+        Some(Ok(mb)) => mb,
+        Some(Err(msg)) => {
+            diags.push(
+                "kernel.kernel_path",
+                format!("invalid Multiboot kernel: {msg}"),
+            );
+            return;
+        }
+        None => return,
+    };
+    let kernel = &cfg.kernel;
+
+    if kernel.bios_path.is_some() {
+        diags.push(
+            "kernel.bios_path",
+            "Multiboot kernels are booted without BIOS, remove it",
+        );
+    }
+    if !in_guest_ram(
+        cfg,
+        multiboot::TRAMPOLINE_GPA,
+        multiboot::BOOT_INFO_END - multiboot::TRAMPOLINE_GPA,
+    ) {
+        diags.push(
+            "kernel.memory_regions",
+            format!(
+                "[{:#x}, {:#x}) must be guest RAM to boot a Multiboot kernel",
+                multiboot::TRAMPOLINE_GPA,
+                multiboot::BOOT_INFO_END
+            ),
+        );
+    }
+    if let Some(layout) = mb.layout() {
+        for seg in &layout.segments {
+            if !in_guest_ram(cfg, seg.paddr, seg.mem_size) {
+                diags.push(
+                    "kernel.kernel_path",
+                    format!(
+                        "the Multiboot header loads the kernel at [{:#x}, {:#x}), outside guest RAM",
+                        seg.paddr,
+                        seg.paddr.saturating_add(seg.mem_size)
+                    ),
+                );
+            }
+        }
+    }
+    if mb.page_align_modules
+        && let Some(addr) = kernel.ramdisk_load_addr
+        && !addr.is_multiple_of(0x1000)
+    {
+        diags.push(
+            "kernel.ramdisk_load_addr",
+            format!("{addr:#x} must be page aligned, the kernel requires page aligned modules"),
+        );
+    }
+}
+
+fn check_devices(cfg: &AxVMCrateConfig, ext: &ExtConfig, diags: &mut Diagnostics) {
+    for (index, dev) in cfg.devices.emu_devices.iter().enumerate() {
+        if overlaps_guest_ram(cfg, dev.base_gpa, dev.length) {
+            diags.push(
+                format!("devices.emu_devices[{index}]"),
+                format!("{} at {:#x} overlaps guest RAM", dev.name, dev.base_gpa),
+            );
+        }
+    }
+    for (index, share) in ext.shares.iter().enumerate() {
+        if share.tag.is_empty() {
+            diags.push(format!("devices.shares[{index}].tag"), "must not be empty");
+        }
+        if overlaps_guest_ram(cfg, share.base_gpa, share.length) {
+            diags.push(
+                format!("devices.shares[{index}].base_gpa"),
+                format!("{:#x} overlaps guest RAM", share.base_gpa),
+            );
+        }
+    }
+}
+
+fn check_shared_memory(cfg: &AxVMCrateConfig, ext: &ExtConfig, diags: &mut Diagnostics) {
+    const PAGE_SIZE: usize = 0x1000;
+
+    for (index, shm) in ext.shared_memory.iter().enumerate() {
+        let path = format!("shared_memory[{index}]");
+        if shm.name.is_empty()
+            || !shm
+                .name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+        {
+            diags.push(
+                format!("{path}.name"),
+                format!(
+                    "{:?} must be made of letters, digits, '_' and '-'",
+                    shm.name
+                ),
+            );
+        } else if ext.shared_memory[..index]
+            .iter()
+            .any(|other| other.name == shm.name)
+        {
+            diags.push(
+                format!("{path}.name"),
+                format!("{:?} is declared twice", shm.name),
+            );
+        }
+        if shm.size == 0 || !shm.size.is_multiple_of(PAGE_SIZE) {
+            diags.push(
+                format!("{path}.size"),
+                format!(
+                    "{:#x} must be a non-zero multiple of {PAGE_SIZE:#x}",
+                    shm.size
+                ),
+            );
+        }
+        if !shm.gpa.is_multiple_of(PAGE_SIZE) {
+            diags.push(
+                format!("{path}.gpa"),
+                format!("{:#x} must be page aligned", shm.gpa),
+            );
+        }
+        if overlaps_guest_ram(cfg, shm.gpa, shm.size) {
+            diags.push(
+                format!("{path}.gpa"),
+                format!("{:#x} overlaps guest RAM", shm.gpa),
+            );
+        }
+        if let Some((other, _)) = ext.shared_memory[..index]
+            .iter()
+            .enumerate()
+            .find(|(_, other)| overlaps(shm.gpa, shm.size, other.gpa, other.size))
+        {
+            diags.push(
+                format!("{path}.gpa"),
+                format!("{:#x} overlaps shared_memory[{other}]", shm.gpa),
+            );
+        }
+        if let Some(msg) = super::shm::conflict(shm) {
+            diags.push(path, msg);
+        }
+    }
+}
+
+fn overlaps(a: usize, a_len: usize, b: usize, b_len: usize) -> bool {
+    a_len != 0 && b_len != 0 && a < b.saturating_add(b_len) && b < a.saturating_add(a_len)
+}
+
+fn guest_ram(cfg: &AxVMCrateConfig) -> impl Iterator<Item = (usize, usize)> + '_ {
+    cfg.kernel
+        .memory_regions
+        .iter()
+        .filter(|region| !matches!(region.map_type, VmMemMappingType::MapIdentical))
+        .map(|region| (region.gpa, region.size))
+}
+
+/// Returns whether the images of a VM are relocated at creation, which is the case when its main
+/// memory is identically mapped.
+fn is_relocated(cfg: &AxVMCrateConfig) -> bool {
+    cfg.kernel
+        .memory_regions
+        .first()
+        .is_some_and(|main| matches!(main.map_type, VmMemMappingType::MapIdentical))
+}
+
+fn in_guest_ram(cfg: &AxVMCrateConfig, addr: usize, len: usize) -> bool {
+    guest_ram(cfg).any(|(gpa, size)| gpa <= addr && addr.saturating_add(len) <= gpa + size)
+}
+
+fn overlaps_guest_ram(cfg: &AxVMCrateConfig, addr: usize, len: usize) -> bool {
+    guest_ram(cfg).any(|(gpa, size)| overlaps(addr, len, gpa, size))
+}
+
+/// Size of an image, if it can be found.
+fn image_size(cfg: &AxVMCrateConfig, name: &str, path: &str) -> Option<usize> {
+    match cfg.kernel.image_location.as_deref() {
+        Some("memory") => {
+            let images = super::config::config::get_memory_images()
+                .iter()
+                .find(|images| images.id == cfg.base.id)?;
+            match name {
+                "kernel" => Some(images.kernel),
+                "bios" => images.bios,
+                "dtb" => images.dtb,
+                _ => images.ramdisk,
+            }
+            .map(|image| image.len())
+        }
+        #[cfg(any(feature = "fs", feature = "guest-archive"))]
+        Some("fs") => super::guestfs::metadata(path)
+            .ok()
+            .map(|m| m.size() as usize),
+        Some("block") => super::images::block::BlockImage::locate(path)
+            .ok()
+            .map(|image| image.size()),
+        _ => None,
+    }
+}
@@ -98,7 +98,6 @@ pub fn push_vm(vm: VMRef) {
 /// # Returns
 ///
 /// * `Option<VMRef>` - The removed VM reference if it exists, or `None` if not.
-#[allow(unused)]
 pub fn remove_vm(vm_id: usize) -> Option<VMRef> {
     GLOBAL_VM_LIST.lock().remove_vm(vm_id)
 }